@@ -0,0 +1,48 @@
+// The gateway may relay to several targets, /targets lists them and
+// the clients connect to the one named by ?target= in the page url
+
+function targetName() {
+    return new URLSearchParams(window.location.search).get("target");
+}
+
+// A portal may hand out pages with a signed token for the gateway
+function websocketUrl() {
+    var scheme = window.location.protocol.startsWith("https") ? "wss" : "ws";
+    var name = targetName();
+    // replay/<id> names a recording
+    var path = name == null ? "/websockify" : "/websockify/" + name.split("/").map(encodeURIComponent).join("/");
+    var token = new URLSearchParams(window.location.search).get("token");
+    if (token != null) {
+        path += "?token=" + encodeURIComponent(token);
+    }
+    return scheme + "://" + window.location.host + path;
+}
+
+// Fill the #target select with the targets speaking one of `protocols`,
+// `onChange` gets the protocol of the picked one
+function listTargets(protocols, onChange) {
+    var select = $("#target");
+    var current = targetName();
+    $.getJSON("/targets", function (targets) {
+        targets.forEach(function (target) {
+            // targets of unknown protocol may be anything
+            if (target.protocol != null && !protocols.includes(target.protocol)) {
+                return;
+            }
+            var label = target.protocol == null ? target.name : target.name + " (" + target.protocol + ")";
+            $("<option>")
+                .val(target.name)
+                .text(label)
+                .data("protocol", target.protocol)
+                .prop("selected", current == null ? target.default : current == target.name)
+                .appendTo(select);
+        });
+        select.prop("hidden", select.children("option[value!='']").length == 0);
+    });
+    select.change(function () {
+        var url = new URL(window.location);
+        url.searchParams.set("target", this.value);
+        window.history.replaceState(null, "", url);
+        onChange(select.find(":selected").data("protocol"));
+    });
+}
@@ -0,0 +1,238 @@
+// The targets the gateway relays to
+//
+// A TOML file names them, the clients pick one with /websockify/<name>:
+//
+//     # relayed by /websockify
+//     default = "desktop"
+//
+//     [targets.desktop]
+//     address = "localhost:5900"
+//     protocol = "vnc"
+//
+//     [targets.router]
+//     address = "192.168.1.1:23"
+//     protocol = "telnet"
+//
+// The gateway logs in to VNC and RDP targets holding a `login`, the browsers
+// never see the password. The clients then need a token naming the target,
+// see the token module:
+//
+//     [targets.kiosk]
+//     address = "10.0.0.5:3389"
+//     protocol = "rdp"
+//     login = { username = "kiosk", password = "secret", domain = "CORP" }
+//
+// The clients of a `shared` VNC target share one connection, the first one
+// controls it and the others watch:
+//
+//     [targets.support]
+//     address = "10.0.0.7:5900"
+//     protocol = "vnc"
+//     shared = true
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    /// The TCP server, as host:port
+    pub address: String,
+    /// What the target speaks, tells the pages which client fits
+    #[serde(default)]
+    pub protocol: Option<String>,
+    /// The gateway logs in with these instead of the user
+    #[serde(default)]
+    pub login: Option<Credentials>,
+    /// The clients share one connection, see the share module
+    #[serde(default)]
+    pub shared: bool,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Credentials {
+    /// VNC has only a password
+    #[serde(default)]
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub domain: String,
+}
+
+// Keep the password out of the logs
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("domain", &self.domain)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What `/targets` lists, the addresses stay on the gateway
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct TargetInfo {
+    pub name: String,
+    pub protocol: Option<String>,
+    /// Relayed by `/websockify` too
+    pub default: bool,
+    /// The gateway logs in, the client asks for no credentials
+    pub login: bool,
+    /// The clients after the first one only watch
+    pub shared: bool,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub default: Option<String>,
+    #[serde(default)]
+    pub targets: BTreeMap<String, Target>,
+}
+
+// Names end up in urls
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let config: Self = toml::from_str(text).map_err(|e| e.to_string())?;
+        if let Some(name) = config.targets.keys().find(|name| !valid_name(name)) {
+            return Err(format!(
+                "invalid target name {}, use letters, digits, - and _",
+                name
+            ));
+        }
+        for (name, target) in &config.targets {
+            if target.shared && target.protocol.as_deref() != Some("vnc") {
+                return Err(format!("only vnc targets are shared, not {}", name));
+            }
+            if target.login.is_none() {
+                continue;
+            }
+            match target.protocol.as_deref() {
+                Some("vnc") => {}
+                Some("rdp") if cfg!(feature = "ssl") => {}
+                Some("rdp") => {
+                    return Err(format!(
+                        "logging in to {} takes TLS, build with the ssl feature",
+                        name
+                    ))
+                }
+                _ => {
+                    return Err(format!(
+                        "the gateway logs in to vnc and rdp targets only, not {}",
+                        name
+                    ))
+                }
+            }
+        }
+        if let Some(name) = &config.default {
+            if !config.targets.contains_key(name) {
+                return Err(format!("the default target {} is not defined", name));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// The target of `/websockify`
+    pub fn default_target(&self) -> Option<&Target> {
+        self.default
+            .as_ref()
+            .and_then(|name| self.targets.get(name))
+    }
+
+    pub fn target(&self, name: &str) -> Option<&Target> {
+        self.targets.get(name)
+    }
+
+    pub fn list(&self) -> Vec<TargetInfo> {
+        self.targets
+            .iter()
+            .map(|(name, target)| TargetInfo {
+                name: name.clone(),
+                protocol: target.protocol.clone(),
+                default: self.default.as_ref() == Some(name),
+                login: target.login.is_some(),
+                shared: target.shared,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+            default = "desktop"
+
+            [targets.desktop]
+            address = "localhost:5900"
+            protocol = "vnc"
+            login = { password = "secret" }
+            shared = true
+
+            [targets.switch-1]
+            address = "10.0.0.2:23"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.default_target().unwrap().address, "localhost:5900");
+        assert_eq!(config.target("switch-1").unwrap().protocol, None);
+        assert_eq!(
+            config.list(),
+            [
+                TargetInfo {
+                    name: "desktop".to_owned(),
+                    protocol: Some("vnc".to_owned()),
+                    default: true,
+                    login: true,
+                    shared: true,
+                },
+                TargetInfo {
+                    name: "switch-1".to_owned(),
+                    protocol: None,
+                    default: false,
+                    login: false,
+                    shared: false,
+                },
+            ]
+        );
+
+        assert!(Config::parse("").unwrap().default_target().is_none());
+        assert!(Config::parse("default = \"missing\"").is_err());
+        assert!(Config::parse("[targets.\"a/b\"]\naddress = \"localhost:22\"").is_err());
+        assert!(Config::parse("[targets.a]\nprotocol = \"ssh\"").is_err());
+        assert!(Config::parse("target = \"localhost:22\"").is_err());
+
+        let login = config.default_target().unwrap().login.as_ref().unwrap();
+        assert_eq!(login.password, "secret");
+        assert!(!format!("{:?}", login).contains("secret"));
+        assert!(Config::parse(
+            "[targets.a]\naddress = \"a:22\"\nprotocol = \"ssh\"\nlogin = { password = \"x\" }"
+        )
+        .is_err());
+        let rdp =
+            "[targets.a]\naddress = \"a:3389\"\nprotocol = \"rdp\"\nlogin = { password = \"x\" }";
+        assert_eq!(Config::parse(rdp).is_ok(), cfg!(feature = "ssl"));
+        assert!(Config::parse("[targets.a]\naddress = \"a:22\"\nshared = true").is_err());
+    }
+}
@@ -0,0 +1,421 @@
+// CredSSP, the client side of the network level authentication of RDP
+// https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-cssp
+
+use super::ntlm::Ntlm;
+use crate::config::Credentials;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// The first version binding the public key with a nonce is 5
+const VERSION: u32 = 6;
+const NONCE_VERSION: u32 = 5;
+
+const CLIENT_SERVER_HASH: &[u8] = b"CredSSP Client-To-Server Binding Hash\0";
+const SERVER_CLIENT_HASH: &[u8] = b"CredSSP Server-To-Client Binding Hash\0";
+
+// Password credentials
+const CRED_TYPE_PASSWORD: u32 = 1;
+
+// DER tags
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const SEQUENCE: u8 = 0x30;
+const CONTEXT: u8 = 0xa0;
+
+// Messages are a few kilobytes at most
+const MAX_MESSAGE: usize = 64 * 1024;
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("CredSSP: {}", what))
+}
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn integer(n: u32) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count().min(3);
+    let mut content = bytes[skip..].to_vec();
+    // positive numbers must not look negative
+    if content[0] & 0x80 != 0 {
+        content.insert(0, 0);
+    }
+    der(INTEGER, &content)
+}
+
+fn context(n: u8, content: &[u8]) -> Vec<u8> {
+    der(CONTEXT | n, content)
+}
+
+/// Reads DER elements one after the other
+struct DerReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> DerReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    fn read(&mut self) -> io::Result<(u8, &'a [u8])> {
+        let truncated = || invalid("truncated DER");
+        let (&tag, rest) = self.data.split_first().ok_or_else(truncated)?;
+        let (&first, mut rest) = rest.split_first().ok_or_else(truncated)?;
+        let len = if first < 0x80 {
+            first as usize
+        } else {
+            let n = (first & 0x7f) as usize;
+            if n == 0 || n > 4 || rest.len() < n {
+                return Err(invalid("bad DER length"));
+            }
+            let len = rest[..n].iter().fold(0, |len, &b| len << 8 | b as usize);
+            rest = &rest[n..];
+            len
+        };
+        if rest.len() < len {
+            return Err(truncated());
+        }
+        self.data = &rest[len..];
+        Ok((tag, &rest[..len]))
+    }
+
+    fn expect(&mut self, tag: u8) -> io::Result<&'a [u8]> {
+        match self.read()? {
+            (t, content) if t == tag => Ok(content),
+            _ => Err(invalid("unexpected DER element")),
+        }
+    }
+
+    fn integer(&mut self) -> io::Result<u32> {
+        let content = self.expect(INTEGER)?;
+        if content.is_empty() || content.len() > 5 {
+            return Err(invalid("bad integer"));
+        }
+        // error codes come negative too
+        let sign = if content[0] & 0x80 != 0 { -1 } else { 0 };
+        Ok(content.iter().fold(sign, |n: i64, &b| n << 8 | b as i64) as u32)
+    }
+}
+
+#[derive(Default, Debug, PartialEq, Eq)]
+struct TsRequest {
+    version: u32,
+    nego_token: Option<Vec<u8>>,
+    auth_info: Option<Vec<u8>>,
+    pub_key_auth: Option<Vec<u8>>,
+    error_code: Option<u32>,
+    client_nonce: Option<Vec<u8>>,
+}
+
+impl TsRequest {
+    fn to_der(&self) -> Vec<u8> {
+        let mut content = context(0, &integer(self.version));
+        if let Some(token) = &self.nego_token {
+            // a sequence of one NegoData
+            let nego_data = der(SEQUENCE, &context(0, &der(OCTET_STRING, token)));
+            content.extend(context(1, &der(SEQUENCE, &nego_data)));
+        }
+        if let Some(auth_info) = &self.auth_info {
+            content.extend(context(2, &der(OCTET_STRING, auth_info)));
+        }
+        if let Some(pub_key_auth) = &self.pub_key_auth {
+            content.extend(context(3, &der(OCTET_STRING, pub_key_auth)));
+        }
+        if let Some(error_code) = self.error_code {
+            content.extend(context(4, &integer(error_code)));
+        }
+        if let Some(nonce) = &self.client_nonce {
+            content.extend(context(5, &der(OCTET_STRING, nonce)));
+        }
+        der(SEQUENCE, &content)
+    }
+
+    fn from_der(data: &[u8]) -> io::Result<Self> {
+        let mut request = Self::default();
+        let mut fields = DerReader::new(DerReader::new(data).expect(SEQUENCE)?);
+        while !fields.is_empty() {
+            let (tag, field) = fields.read()?;
+            let mut field = DerReader::new(field);
+            match tag {
+                0xa0 => request.version = field.integer()?,
+                0xa1 => {
+                    let mut nego_data = DerReader::new(field.expect(SEQUENCE)?);
+                    let mut first = DerReader::new(nego_data.expect(SEQUENCE)?);
+                    let mut token = DerReader::new(first.expect(CONTEXT)?);
+                    request.nego_token = Some(token.expect(OCTET_STRING)?.to_vec());
+                }
+                0xa2 => request.auth_info = Some(field.expect(OCTET_STRING)?.to_vec()),
+                0xa3 => request.pub_key_auth = Some(field.expect(OCTET_STRING)?.to_vec()),
+                0xa4 => request.error_code = Some(field.integer()?),
+                0xa5 => request.client_nonce = Some(field.expect(OCTET_STRING)?.to_vec()),
+                _ => return Err(invalid("unknown TSRequest field")),
+            }
+        }
+        Ok(request)
+    }
+}
+
+fn utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn ts_credentials(credentials: &Credentials) -> Vec<u8> {
+    let mut password = context(0, &der(OCTET_STRING, &utf16(&credentials.domain)));
+    password.extend(context(
+        1,
+        &der(OCTET_STRING, &utf16(&credentials.username)),
+    ));
+    password.extend(context(
+        2,
+        &der(OCTET_STRING, &utf16(&credentials.password)),
+    ));
+    let password = der(SEQUENCE, &password);
+    let mut content = context(0, &integer(CRED_TYPE_PASSWORD));
+    content.extend(context(1, &der(OCTET_STRING, &password)));
+    der(SEQUENCE, &content)
+}
+
+/// The subjectPublicKey of a DER certificate, what CredSSP binds to
+fn subject_public_key(cert: &[u8]) -> io::Result<Vec<u8>> {
+    let mut cert = DerReader::new(DerReader::new(cert).expect(SEQUENCE)?);
+    let mut tbs = DerReader::new(cert.expect(SEQUENCE)?);
+    // the version is optional
+    if tbs.peek() == Some(CONTEXT) {
+        tbs.read()?;
+    }
+    // serial number, signature, issuer, validity and subject
+    for _ in 0..5 {
+        tbs.read()?;
+    }
+    let mut key_info = DerReader::new(tbs.expect(SEQUENCE)?);
+    let _algorithm = key_info.expect(SEQUENCE)?;
+    // a bit string starts with its count of unused bits
+    match key_info.expect(BIT_STRING)?.split_first() {
+        // older servers bind to it with its first byte incremented
+        Some((0, [])) => Err(invalid("empty public key")),
+        Some((0, key)) => Ok(key.to_vec()),
+        _ => Err(invalid("bad subjectPublicKey")),
+    }
+}
+
+fn binding_hash(magic: &[u8], nonce: &[u8], public_key: &[u8]) -> Vec<u8> {
+    Sha256::new()
+        .chain_update(magic)
+        .chain_update(nonce)
+        .chain_update(public_key)
+        .finalize()
+        .to_vec()
+}
+
+async fn send<S: AsyncWrite + Unpin>(stream: &mut S, request: &TsRequest) -> io::Result<()> {
+    stream.write_all(&request.to_der()).await?;
+    stream.flush().await
+}
+
+async fn recv<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<TsRequest> {
+    let mut message = vec![0; 2];
+    stream.read_exact(&mut message).await?;
+    if message[1] & 0x80 != 0 {
+        let n = (message[1] & 0x7f) as usize;
+        if n == 0 || n > 4 {
+            return Err(invalid("bad DER length"));
+        }
+        message.resize(2 + n, 0);
+        stream.read_exact(&mut message[2..]).await?;
+    }
+    let len = match message[1] {
+        len if len < 0x80 => len as usize,
+        _ => message[2..].iter().fold(0, |len, &b| len << 8 | b as usize),
+    };
+    if len > MAX_MESSAGE {
+        return Err(invalid("message too long"));
+    }
+    let header = message.len();
+    message.resize(header + len, 0);
+    stream.read_exact(&mut message[header..]).await?;
+    let request = TsRequest::from_der(&message)?;
+    if let Some(code) = request.error_code {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "CredSSP: the server refused the login, error {:#010x}",
+                code
+            ),
+        ));
+    }
+    Ok(request)
+}
+
+/// Authenticate on the TLS `stream` whose server presented `cert`
+pub async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    cert: &[u8],
+    credentials: &Credentials,
+) -> io::Result<()> {
+    let public_key = subject_public_key(cert)?;
+    let mut ntlm = Ntlm::new(
+        &credentials.username,
+        &credentials.domain,
+        &credentials.password,
+    );
+    let mut nonce = [0; 32];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let negotiate = TsRequest {
+        version: VERSION,
+        nego_token: Some(ntlm.negotiate()),
+        ..Default::default()
+    };
+    send(stream, &negotiate).await?;
+    let challenge = recv(stream).await?;
+    let token = challenge
+        .nego_token
+        .ok_or_else(|| invalid("no NTLM challenge"))?;
+    let (authenticate, mut session) = ntlm.authenticate(&token)?;
+
+    // prove the key of the TLS server is the one we authenticate to
+    let nonce_binding = challenge.version >= NONCE_VERSION;
+    let (client_key, server_key) = if nonce_binding {
+        (
+            binding_hash(CLIENT_SERVER_HASH, &nonce, &public_key),
+            binding_hash(SERVER_CLIENT_HASH, &nonce, &public_key),
+        )
+    } else {
+        // older servers answer with the first byte incremented
+        let mut server_key = public_key.clone();
+        server_key[0] = server_key[0].wrapping_add(1);
+        (public_key, server_key)
+    };
+    let authenticate = TsRequest {
+        version: VERSION,
+        nego_token: Some(authenticate),
+        pub_key_auth: Some(session.seal(&client_key)),
+        client_nonce: nonce_binding.then(|| nonce.to_vec()),
+        ..Default::default()
+    };
+    send(stream, &authenticate).await?;
+    let reply = recv(stream).await?;
+    let pub_key_auth = reply
+        .pub_key_auth
+        .ok_or_else(|| invalid("no public key from the server"))?;
+    if session.unseal(&pub_key_auth)? != server_key {
+        return Err(invalid(
+            "the server does not hold the key of its certificate",
+        ));
+    }
+
+    let credentials = TsRequest {
+        version: VERSION,
+        auth_info: Some(session.seal(&ts_credentials(credentials))),
+        ..Default::default()
+    };
+    send(stream, &credentials).await
+}
+
+#[cfg(all(test, feature = "ssl"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ts_request() {
+        let request = TsRequest {
+            version: 6,
+            nego_token: Some(vec![1; 200]),
+            pub_key_auth: Some(vec![2; 16]),
+            client_nonce: Some(vec![3; 32]),
+            ..Default::default()
+        };
+        let data = request.to_der();
+        assert_eq!(data[..4], [0x30, 0x82, 0x01, 0x14]);
+        assert_eq!(TsRequest::from_der(&data).unwrap(), request);
+
+        // a refusal, STATUS_LOGON_FAILURE encoded negative
+        let refusal = [0x30, 13, 0xa0, 3, 2, 1, 6, 0xa4, 6, 2, 4, 0xc0, 0, 0, 0x6d];
+        let refusal = TsRequest::from_der(&refusal).unwrap();
+        assert_eq!(refusal.error_code, Some(0xc000_006d));
+        let refusal = TsRequest {
+            error_code: Some(0xc000_006d),
+            ..Default::default()
+        };
+        assert_eq!(
+            refusal.to_der(),
+            [0x30, 14, 0xa0, 3, 2, 1, 0, 0xa4, 7, 2, 5, 0, 0xc0, 0, 0, 0x6d]
+        );
+        assert!(TsRequest::from_der(&data[..100]).is_err());
+    }
+
+    #[test]
+    fn test_subject_public_key() {
+        use openssl::{asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa, x509::X509};
+
+        let rsa = Rsa::generate(2048).unwrap();
+        let key = PKey::from_rsa(rsa.clone()).unwrap();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = builder.build().to_der().unwrap();
+        assert_eq!(
+            subject_public_key(&cert).unwrap(),
+            rsa.public_key_to_der_pkcs1().unwrap()
+        );
+        assert!(subject_public_key(&cert[..50]).is_err());
+
+        // a certificate from the server with nothing in the bit string
+        let mut tbs = integer(1);
+        for _ in 0..4 {
+            tbs.extend(der(SEQUENCE, &[]));
+        }
+        let mut key_info = der(SEQUENCE, &[]);
+        key_info.extend(der(BIT_STRING, &[0]));
+        tbs.extend(der(SEQUENCE, &key_info));
+        let cert = der(SEQUENCE, &der(SEQUENCE, &tbs));
+        let e = subject_public_key(&cert).unwrap_err();
+        assert_eq!(e.to_string(), invalid("empty public key").to_string());
+    }
+
+    #[test]
+    fn test_ts_credentials() {
+        let credentials = Credentials {
+            username: "u".to_owned(),
+            password: "p".to_owned(),
+            domain: String::new(),
+        };
+        assert_eq!(
+            ts_credentials(&credentials),
+            [
+                0x30, 27, 0xa0, 3, 2, 1, 1, 0xa1, 20, 4, 18, 0x30, 16, 0xa0, 2, 4, 0, 0xa1, 4, 4,
+                2, b'u', 0, 0xa2, 4, 4, 2, b'p', 0
+            ]
+        );
+    }
+}
@@ -0,0 +1,99 @@
+// Log in to the targets configured with credentials on behalf of the
+// clients, which never learn the passwords
+//
+// VNC authentication runs over the relayed stream, the client is offered
+// no authentication. RDP needs TLS terminated by the gateway: the server is
+// asked for network level authentication, which the gateway completes with
+// CredSSP when the client starts TLS.
+
+#[cfg(feature = "ssl")]
+pub mod credssp;
+#[cfg(feature = "ssl")]
+mod ntlm;
+#[cfg(feature = "ssl")]
+mod rdp;
+pub mod vnc;
+
+#[cfg(feature = "ssl")]
+use crate::config::Credentials;
+use crate::config::Target;
+use std::io;
+
+#[cfg(feature = "ssl")]
+use rdp::RdpLogin;
+use vnc::VncLogin;
+
+pub enum Login {
+    Vnc(VncLogin),
+    #[cfg(feature = "ssl")]
+    Rdp {
+        login: RdpLogin,
+        credentials: Credentials,
+    },
+}
+
+impl Login {
+    /// The login for `target`, if it has credentials
+    pub fn new(target: &Target) -> Option<Self> {
+        let credentials = target.login.as_ref()?;
+        match target.protocol.as_deref() {
+            Some("vnc") => Some(Login::Vnc(VncLogin::new(&credentials.password))),
+            #[cfg(feature = "ssl")]
+            Some("rdp") => Some(Login::Rdp {
+                login: RdpLogin::default(),
+                credentials: credentials.clone(),
+            }),
+            // the configuration allows no other
+            _ => None,
+        }
+    }
+
+    /// The credentials for CredSSP once TLS started
+    #[cfg(feature = "ssl")]
+    pub fn nla(&self) -> Option<&Credentials> {
+        match self {
+            Login::Rdp { login, credentials } if login.nla() => Some(credentials),
+            _ => None,
+        }
+    }
+
+    pub fn done(&self) -> bool {
+        match self {
+            Login::Vnc(login) => login.done(),
+            #[cfg(feature = "ssl")]
+            Login::Rdp { login, .. } => login.done(),
+        }
+    }
+
+    pub fn client_data(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Login::Vnc(login) => login.client_data(data),
+            #[cfg(feature = "ssl")]
+            Login::Rdp { login, .. } => login.client_data(data),
+        }
+    }
+
+    pub fn server_data(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Login::Vnc(login) => login.server_data(data),
+            #[cfg(feature = "ssl")]
+            Login::Rdp { login, .. } => login.server_data(data),
+        }
+    }
+
+    pub fn take_for_server(&mut self) -> Vec<u8> {
+        match self {
+            Login::Vnc(login) => login.take_for_server(),
+            #[cfg(feature = "ssl")]
+            Login::Rdp { login, .. } => login.take_for_server(),
+        }
+    }
+
+    pub fn take_for_client(&mut self) -> Vec<u8> {
+        match self {
+            Login::Vnc(login) => login.take_for_client(),
+            #[cfg(feature = "ssl")]
+            Login::Rdp { login, .. } => login.take_for_client(),
+        }
+    }
+}
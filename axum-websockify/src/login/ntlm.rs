@@ -0,0 +1,493 @@
+// NTLMv2 with extended session security, the client side
+// https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-nlmp
+
+use hmac::{Hmac, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+use rand::RngCore;
+use std::io;
+
+type HmacMd5 = Hmac<Md5>;
+
+const SIGNATURE: &[u8] = b"NTLMSSP\0";
+const NEGOTIATE_MESSAGE: u32 = 1;
+const CHALLENGE_MESSAGE: u32 = 2;
+const AUTHENTICATE_MESSAGE: u32 = 3;
+
+// Negotiate flags
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_SIGN: u32 = 0x0000_0010;
+const NEGOTIATE_SEAL: u32 = 0x0000_0020;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_VERSION: u32 = 0x0200_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_KEY_EXCH: u32 = 0x4000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+const FLAGS: u32 = NEGOTIATE_UNICODE
+    | REQUEST_TARGET
+    | NEGOTIATE_SIGN
+    | NEGOTIATE_SEAL
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY
+    | NEGOTIATE_TARGET_INFO
+    | NEGOTIATE_VERSION
+    | NEGOTIATE_128
+    | NEGOTIATE_KEY_EXCH
+    | NEGOTIATE_56;
+
+// Windows 10, NTLM revision 15
+const VERSION: [u8; 8] = [10, 0, 0x61, 0x4a, 0, 0, 0, 15];
+
+// Attribute-value pairs of the target info
+const MSV_AV_EOL: u16 = 0;
+const MSV_AV_FLAGS: u16 = 6;
+const MSV_AV_TIMESTAMP: u16 = 7;
+// the AUTHENTICATE message carries a MIC
+const AV_FLAG_MIC: u32 = 0x2;
+
+const AUTHENTICATE_HEADER_LEN: usize = 88;
+const MIC_OFFSET: usize = 72;
+
+const CLIENT_SIGNING: &[u8] = b"session key to client-to-server signing key magic constant\0";
+const CLIENT_SEALING: &[u8] = b"session key to client-to-server sealing key magic constant\0";
+const SERVER_SIGNING: &[u8] = b"session key to server-to-client signing key magic constant\0";
+const SERVER_SEALING: &[u8] = b"session key to server-to-client sealing key magic constant\0";
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("NTLM: {}", what))
+}
+
+fn utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = HmacMd5::new_from_slice(key).expect("HMAC takes any key size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn md5(parts: &[&[u8]]) -> [u8; 16] {
+    let mut md5 = Md5::new();
+    for part in parts {
+        md5.update(part);
+    }
+    md5.finalize().into()
+}
+
+/// The RC4 stream cipher NTLM seals with
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut s = [0; 256];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Self { s, i: 0, j: 0 }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for b in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.i as usize].wrapping_add(self.s[self.j as usize]);
+            *b ^= self.s[k as usize];
+        }
+    }
+}
+
+fn nt_owf_v2(username: &str, domain: &str, password: &str) -> [u8; 16] {
+    let nt_hash: [u8; 16] = Md4::digest(utf16(password)).into();
+    let identity = utf16(&(username.to_uppercase() + domain));
+    hmac_md5(&nt_hash, &[&identity])
+}
+
+/// The NTProofStr and the blob it covers
+fn nt_response(
+    nt_owf: &[u8; 16],
+    server_challenge: &[u8],
+    client_challenge: &[u8; 8],
+    timestamp: &[u8],
+    target_info: &[u8],
+) -> ([u8; 16], Vec<u8>) {
+    let mut temp = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    temp.extend_from_slice(timestamp);
+    temp.extend_from_slice(client_challenge);
+    temp.extend_from_slice(&[0; 4]);
+    temp.extend_from_slice(target_info);
+    temp.extend_from_slice(&[0; 4]);
+    let proof = hmac_md5(nt_owf, &[server_challenge, &temp]);
+    (proof, temp)
+}
+
+// The security buffer fields of a message: length, allocated length and offset
+fn field(message: &[u8], at: usize) -> io::Result<&[u8]> {
+    let header = message
+        .get(at..at + 8)
+        .ok_or_else(|| invalid("truncated message"))?;
+    let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+    let offset = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    message
+        .get(offset..offset + len)
+        .ok_or_else(|| invalid("field out of bounds"))
+}
+
+/// Copy the target info, adding the flag announcing the MIC,
+/// returns it with the timestamp of the server if it sent one
+fn target_info_with_mic(target_info: &[u8]) -> io::Result<(Vec<u8>, Option<[u8; 8]>)> {
+    let mut out = Vec::with_capacity(target_info.len() + 8);
+    let mut flags = AV_FLAG_MIC;
+    let mut timestamp = None;
+    let mut rest = target_info;
+    loop {
+        if rest.len() < 4 {
+            return Err(invalid("truncated target info"));
+        }
+        let id = u16::from_le_bytes([rest[0], rest[1]]);
+        let len = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+        let value = rest
+            .get(4..4 + len)
+            .ok_or_else(|| invalid("truncated target info"))?;
+        match id {
+            MSV_AV_EOL => break,
+            MSV_AV_FLAGS if len == 4 => {
+                flags |= u32::from_le_bytes(value.try_into().unwrap());
+            }
+            _ => {
+                if id == MSV_AV_TIMESTAMP && len == 8 {
+                    timestamp = Some(value.try_into().unwrap());
+                }
+                out.extend_from_slice(&rest[..4 + len]);
+            }
+        }
+        rest = &rest[4 + len..];
+    }
+    out.extend_from_slice(&MSV_AV_FLAGS.to_le_bytes());
+    out.extend_from_slice(&4u16.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    Ok((out, timestamp))
+}
+
+// Now as a FILETIME, 100ns since 1601
+fn filetime() -> [u8; 8] {
+    const UNIX_EPOCH: u64 = 11_644_473_600;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    ((now.as_secs() + UNIX_EPOCH) * 10_000_000 + now.subsec_nanos() as u64 / 100).to_le_bytes()
+}
+
+/// One direction of the sealed channel
+struct Sealing {
+    sign_key: [u8; 16],
+    rc4: Rc4,
+    seq: u32,
+}
+
+impl Sealing {
+    fn new(session_key: &[u8; 16], signing: &[u8], sealing: &[u8]) -> Self {
+        Self {
+            sign_key: md5(&[session_key, signing]),
+            rc4: Rc4::new(&md5(&[session_key, sealing])),
+            seq: 0,
+        }
+    }
+
+    // The message is encrypted before its checksum, both with one stream
+    fn checksum(&mut self, message: &[u8]) -> [u8; 16] {
+        let seq = self.seq.to_le_bytes();
+        self.seq += 1;
+        let mut checksum = hmac_md5(&self.sign_key, &[&seq, message]);
+        self.rc4.apply(&mut checksum[..8]);
+        let mut signature = [0; 16];
+        signature[..4].copy_from_slice(&1u32.to_le_bytes());
+        signature[4..12].copy_from_slice(&checksum[..8]);
+        signature[12..].copy_from_slice(&seq);
+        signature
+    }
+}
+
+/// An authenticated NTLM session sealing messages for the server
+pub struct NtlmSession {
+    client: Sealing,
+    server: Sealing,
+}
+
+impl NtlmSession {
+    fn new(session_key: &[u8; 16]) -> Self {
+        Self {
+            client: Sealing::new(session_key, CLIENT_SIGNING, CLIENT_SEALING),
+            server: Sealing::new(session_key, SERVER_SIGNING, SERVER_SEALING),
+        }
+    }
+
+    /// The signature followed by the encrypted message
+    pub fn seal(&mut self, message: &[u8]) -> Vec<u8> {
+        let mut sealed = message.to_vec();
+        self.client.rc4.apply(&mut sealed);
+        let mut out = self.client.checksum(message).to_vec();
+        out.append(&mut sealed);
+        out
+    }
+
+    /// Decrypt a message of the server and check its signature
+    pub fn unseal(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if data.len() < 16 {
+            return Err(invalid("truncated sealed message"));
+        }
+        let mut message = data[16..].to_vec();
+        self.server.rc4.apply(&mut message);
+        if self.server.checksum(&message) != data[..16] {
+            return Err(invalid("invalid signature"));
+        }
+        Ok(message)
+    }
+}
+
+pub struct Ntlm {
+    username: String,
+    domain: String,
+    nt_owf: [u8; 16],
+    negotiate: Vec<u8>,
+}
+
+impl Ntlm {
+    pub fn new(username: &str, domain: &str, password: &str) -> Self {
+        Self {
+            username: username.to_owned(),
+            domain: domain.to_owned(),
+            nt_owf: nt_owf_v2(username, domain, password),
+            negotiate: Vec::new(),
+        }
+    }
+
+    pub fn negotiate(&mut self) -> Vec<u8> {
+        let mut message = SIGNATURE.to_vec();
+        message.extend_from_slice(&NEGOTIATE_MESSAGE.to_le_bytes());
+        message.extend_from_slice(&FLAGS.to_le_bytes());
+        // no domain and workstation
+        message.extend_from_slice(&[0; 16]);
+        message.extend_from_slice(&VERSION);
+        self.negotiate = message.clone();
+        message
+    }
+
+    /// Answer the CHALLENGE message of the server
+    pub fn authenticate(&self, challenge: &[u8]) -> io::Result<(Vec<u8>, NtlmSession)> {
+        let mut client_challenge = [0; 8];
+        let mut session_key = [0; 16];
+        rand::thread_rng().fill_bytes(&mut client_challenge);
+        rand::thread_rng().fill_bytes(&mut session_key);
+        self.authenticate_with(challenge, client_challenge, session_key, filetime())
+    }
+
+    fn authenticate_with(
+        &self,
+        challenge: &[u8],
+        client_challenge: [u8; 8],
+        exported_session_key: [u8; 16],
+        now: [u8; 8],
+    ) -> io::Result<(Vec<u8>, NtlmSession)> {
+        if challenge.len() < 48
+            || &challenge[..8] != SIGNATURE
+            || challenge[8..12] != CHALLENGE_MESSAGE.to_le_bytes()
+        {
+            return Err(invalid("not a CHALLENGE message"));
+        }
+        let flags = u32::from_le_bytes(challenge[20..24].try_into().unwrap());
+        if flags & NEGOTIATE_EXTENDED_SESSIONSECURITY == 0 || flags & NEGOTIATE_128 == 0 {
+            return Err(invalid("the server refuses extended session security"));
+        }
+        let server_challenge = &challenge[24..32];
+        let (target_info, timestamp) = target_info_with_mic(field(challenge, 40)?)?;
+
+        let (proof, temp) = nt_response(
+            &self.nt_owf,
+            server_challenge,
+            &client_challenge,
+            &timestamp.unwrap_or(now),
+            &target_info,
+        );
+        let mut nt_response = proof.to_vec();
+        nt_response.extend_from_slice(&temp);
+        // the LMv2 response is left out once the server tells the time
+        let lm_response = match timestamp {
+            Some(_) => vec![0; 24],
+            None => {
+                let mut lm =
+                    hmac_md5(&self.nt_owf, &[server_challenge, &client_challenge]).to_vec();
+                lm.extend_from_slice(&client_challenge);
+                lm
+            }
+        };
+        let key_exchange_key = hmac_md5(&self.nt_owf, &[&proof]);
+        let (session_key, encrypted_key) = if flags & NEGOTIATE_KEY_EXCH != 0 {
+            let mut encrypted = exported_session_key;
+            Rc4::new(&key_exchange_key).apply(&mut encrypted);
+            (exported_session_key, encrypted.to_vec())
+        } else {
+            (key_exchange_key, Vec::new())
+        };
+
+        let domain = utf16(&self.domain);
+        let username = utf16(&self.username);
+        let payload: [&[u8]; 6] = [
+            &lm_response,
+            &nt_response,
+            &domain,
+            &username,
+            // no workstation
+            &[],
+            &encrypted_key,
+        ];
+        let mut message = SIGNATURE.to_vec();
+        message.extend_from_slice(&AUTHENTICATE_MESSAGE.to_le_bytes());
+        let mut offset = AUTHENTICATE_HEADER_LEN;
+        for data in payload {
+            message.extend_from_slice(&(data.len() as u16).to_le_bytes());
+            message.extend_from_slice(&(data.len() as u16).to_le_bytes());
+            message.extend_from_slice(&(offset as u32).to_le_bytes());
+            offset += data.len();
+        }
+        message.extend_from_slice(&(FLAGS & flags).to_le_bytes());
+        message.extend_from_slice(&VERSION);
+        // the MIC covers the message with a zeroed MIC
+        message.extend_from_slice(&[0; 16]);
+        for data in payload {
+            message.extend_from_slice(data);
+        }
+        let mic = hmac_md5(&session_key, &[&self.negotiate, challenge, &message]);
+        message[MIC_OFFSET..MIC_OFFSET + 16].copy_from_slice(&mic);
+
+        Ok((message, NtlmSession::new(&session_key)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // The NTLMv2 example of MS-NLMP 4.2.4
+    #[test]
+    fn test_ntlm_v2() {
+        let nt_owf = nt_owf_v2("User", "Domain", "Password");
+        assert_eq!(nt_owf.to_vec(), hex("0c868a403bfd7a93a3001ef22ef02e3f"));
+
+        let server_challenge = hex("0123456789abcdef");
+        let target_info = hex("02000c0044006f006d00610069006e00
+             01000c005300650072007600650072000000 0000");
+        let (proof, _) = nt_response(
+            &nt_owf,
+            &server_challenge,
+            &[0xaa; 8],
+            &[0; 8],
+            &target_info,
+        );
+        assert_eq!(proof.to_vec(), hex("68cd0ab851e51c96aabc927bebef6a1c"));
+
+        let session_base_key = hmac_md5(&nt_owf, &[&proof]);
+        assert_eq!(
+            session_base_key.to_vec(),
+            hex("8de40ccadbc14a82f15cb0ad0de95ca3")
+        );
+        let mut encrypted = [0x55; 16];
+        Rc4::new(&session_base_key).apply(&mut encrypted);
+        assert_eq!(encrypted.to_vec(), hex("c5dad2544fc9799094ce1ce90bc9d03e"));
+
+        let mut session = NtlmSession::new(&[0x55; 16]);
+        assert_eq!(
+            session.seal(&utf16("Plaintext")),
+            hex("01000000 7fb38ec5c55d4976 00000000 54e50165bf1936dc996020c1811b0f06fb5f")
+        );
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let target_info = hex("02000c0044006f006d00610069006e00
+             0700080000000000000000 00
+             0000 0000");
+        let mut challenge = SIGNATURE.to_vec();
+        challenge.extend_from_slice(&CHALLENGE_MESSAGE.to_le_bytes());
+        challenge.extend_from_slice(&[0; 8]);
+        challenge.extend_from_slice(&FLAGS.to_le_bytes());
+        challenge.extend_from_slice(&hex("0123456789abcdef"));
+        challenge.extend_from_slice(&[0; 8]);
+        challenge.extend_from_slice(&(target_info.len() as u16).to_le_bytes());
+        challenge.extend_from_slice(&(target_info.len() as u16).to_le_bytes());
+        challenge.extend_from_slice(&56u32.to_le_bytes());
+        challenge.extend_from_slice(&VERSION);
+        challenge.extend_from_slice(&target_info);
+
+        let mut ntlm = Ntlm::new("User", "Domain", "Password");
+        let negotiate = ntlm.negotiate();
+        assert_eq!(negotiate.len(), 40);
+        let (message, _) = ntlm
+            .authenticate_with(&challenge, [0xaa; 8], [0x55; 16], [1; 8])
+            .unwrap();
+        assert_eq!(&message[..8], SIGNATURE);
+        // the server told the time, so no LMv2 and a MIC
+        assert_eq!(field(&message, 12).unwrap(), [0; 24]);
+        assert_eq!(field(&message, 36).unwrap(), utf16("User"));
+        let nt = field(&message, 20).unwrap();
+        assert_eq!(nt[16 + 8..16 + 16], [0; 8]);
+        let (info, timestamp) = target_info_with_mic(&nt[16 + 28..]).unwrap();
+        assert_eq!(timestamp, Some([0; 8]));
+        assert_eq!(info[info.len() - 8..info.len() - 4], [0x02, 0, 0, 0]);
+
+        let mut zeroed = message.clone();
+        zeroed[MIC_OFFSET..MIC_OFFSET + 16].fill(0);
+        let mic = hmac_md5(&[0x55; 16], &[&negotiate, &challenge, &zeroed]);
+        assert_eq!(message[MIC_OFFSET..MIC_OFFSET + 16], mic);
+
+        assert!(ntlm
+            .authenticate_with(&challenge[..40], [0; 8], [0; 16], [0; 8])
+            .is_err());
+    }
+
+    #[test]
+    fn test_seal() {
+        let mut client = NtlmSession::new(&[7; 16]);
+        // the server side is the client side mirrored
+        let mut server = NtlmSession {
+            client: Sealing::new(&[7; 16], SERVER_SIGNING, SERVER_SEALING),
+            server: Sealing::new(&[7; 16], CLIENT_SIGNING, CLIENT_SEALING),
+        };
+        for message in [&b"first"[..], b"second"] {
+            let sealed = client.seal(message);
+            assert_eq!(server.unseal(&sealed).unwrap(), message);
+            let sealed = server.seal(message);
+            assert_eq!(client.unseal(&sealed).unwrap(), message);
+        }
+        let mut sealed = client.seal(b"third");
+        sealed[20] ^= 1;
+        assert!(server.unseal(&sealed).is_err());
+    }
+}
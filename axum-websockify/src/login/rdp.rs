@@ -0,0 +1,276 @@
+// Have the server of an RDP client ask for network level authentication,
+// which the gateway completes with CredSSP, while the client believes
+// the server settled for plain TLS
+// https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-rdpbcgr
+
+use std::io;
+use tracing::warn;
+
+const TPKT_VERSION: u8 = 3;
+const TPKT_HEADER_LEN: usize = 4;
+
+// RDP_NEG_REQ and RDP_NEG_RSP end the X.224 connection request and confirm
+const NEG_LEN: usize = 8;
+const TYPE_RDP_NEG_REQ: u8 = 1;
+const TYPE_RDP_NEG_RSP: u8 = 2;
+
+// Security protocols
+const PROTOCOL_SSL: u32 = 0x1;
+const PROTOCOL_HYBRID: u32 = 0x2;
+// would make the server send an authorization result the client does not expect
+const PROTOCOL_HYBRID_EX: u32 = 0x8;
+
+// The client core data of the MCS connect initial tells the protocol selected
+const H221_CS_KEY: &[u8] = b"Duca";
+const CS_CORE: u16 = 0xc001;
+const SERVER_SELECTED_PROTOCOL: usize = 212;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+enum State {
+    #[default]
+    Request,
+    Confirm,
+    ConnectInitial,
+    Done,
+}
+
+/// A sans-io rewriter of the RDP connection sequence, see
+/// [`super::vnc::VncLogin`] for how to drive it
+#[derive(Default)]
+pub struct RdpLogin {
+    state: State,
+    nla: bool,
+    server: Vec<u8>,
+    client: Vec<u8>,
+    for_server: Vec<u8>,
+    for_client: Vec<u8>,
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("RDP login: {}", what))
+}
+
+/// Take a whole TPKT off the front of `buf`
+fn take_tpkt(buf: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+    if buf.len() < TPKT_HEADER_LEN {
+        return Ok(None);
+    }
+    if buf[0] != TPKT_VERSION {
+        return Err(invalid("not a TPKT"));
+    }
+    let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    if len < TPKT_HEADER_LEN {
+        return Err(invalid("bad TPKT length"));
+    }
+    if buf.len() < len {
+        return Ok(None);
+    }
+    Ok(Some(buf.drain(..len).collect()))
+}
+
+/// The security protocols of the negotiation structure ending `tpkt`
+fn negotiation(tpkt: &mut [u8], kind: u8) -> Option<&mut [u8]> {
+    // TPKT header and X.224 header at least
+    if tpkt.len() < TPKT_HEADER_LEN + 7 + NEG_LEN {
+        return None;
+    }
+    let at = tpkt.len() - NEG_LEN;
+    let neg = &mut tpkt[at..];
+    if neg[0] != kind || u16::from_le_bytes([neg[2], neg[3]]) as usize != NEG_LEN {
+        return None;
+    }
+    Some(&mut neg[4..])
+}
+
+/// The offset of serverSelectedProtocol in the MCS connect initial
+fn server_selected_protocol(tpkt: &[u8]) -> Option<usize> {
+    let key = tpkt
+        .windows(H221_CS_KEY.len())
+        .position(|w| w == H221_CS_KEY)?;
+    // the PER length of the user data
+    let mut at = key + H221_CS_KEY.len();
+    at += if *tpkt.get(at)? & 0x80 != 0 { 2 } else { 1 };
+    while at + 4 <= tpkt.len() {
+        let kind = u16::from_le_bytes([tpkt[at], tpkt[at + 1]]);
+        let len = u16::from_le_bytes([tpkt[at + 2], tpkt[at + 3]]) as usize;
+        if len < 4 {
+            return None;
+        }
+        if kind == CS_CORE {
+            // older clients end the block earlier
+            return (len >= SERVER_SELECTED_PROTOCOL + 4 && at + len <= tpkt.len())
+                .then_some(at + SERVER_SELECTED_PROTOCOL);
+        }
+        at += len;
+    }
+    None
+}
+
+impl RdpLogin {
+    pub fn done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Whether the server selected network level authentication, for the
+    /// gateway to complete once TLS started
+    pub fn nla(&self) -> bool {
+        self.nla
+    }
+
+    pub fn take_for_server(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.for_server)
+    }
+
+    pub fn take_for_client(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.for_client)
+    }
+
+    pub fn client_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.client.extend_from_slice(data);
+        self.process()
+    }
+
+    pub fn server_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.server.extend_from_slice(data);
+        self.process()
+    }
+
+    fn process(&mut self) -> io::Result<()> {
+        loop {
+            match self.state {
+                State::Request => {
+                    let Some(mut request) = take_tpkt(&mut self.client)? else {
+                        return Ok(());
+                    };
+                    match negotiation(&mut request, TYPE_RDP_NEG_REQ) {
+                        Some(protocols) => {
+                            let requested = u32::from_le_bytes(protocols.try_into().unwrap());
+                            let requested =
+                                (requested | PROTOCOL_SSL | PROTOCOL_HYBRID) & !PROTOCOL_HYBRID_EX;
+                            protocols.copy_from_slice(&requested.to_le_bytes());
+                            self.state = State::Confirm;
+                        }
+                        None => {
+                            warn!("The RDP client negotiates no security, not logging in");
+                            self.state = State::Done;
+                        }
+                    }
+                    self.for_server.extend(request);
+                }
+                State::Confirm => {
+                    let Some(mut confirm) = take_tpkt(&mut self.server)? else {
+                        return Ok(());
+                    };
+                    match negotiation(&mut confirm, TYPE_RDP_NEG_RSP) {
+                        Some(protocol) if protocol == PROTOCOL_HYBRID.to_le_bytes() => {
+                            self.nla = true;
+                            protocol.copy_from_slice(&PROTOCOL_SSL.to_le_bytes());
+                            self.state = State::ConnectInitial;
+                        }
+                        _ => {
+                            warn!("The RDP server did not select NLA, not logging in");
+                            self.state = State::Done;
+                        }
+                    }
+                    self.for_client.extend(confirm);
+                }
+                State::ConnectInitial => {
+                    // the server says nothing before it
+                    let Some(mut connect) = take_tpkt(&mut self.client)? else {
+                        return Ok(());
+                    };
+                    match server_selected_protocol(&connect) {
+                        Some(at) => {
+                            connect[at..at + 4].copy_from_slice(&PROTOCOL_HYBRID.to_le_bytes())
+                        }
+                        None => warn!("No selected protocol in the MCS connect initial"),
+                    }
+                    self.for_server.extend(connect);
+                    self.state = State::Done;
+                }
+                State::Done => {
+                    self.for_server.append(&mut self.client);
+                    self.for_client.append(&mut self.server);
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tpkt(payload: &[u8]) -> Vec<u8> {
+        let mut tpkt = vec![TPKT_VERSION, 0];
+        tpkt.extend_from_slice(&((payload.len() + TPKT_HEADER_LEN) as u16).to_be_bytes());
+        tpkt.extend_from_slice(payload);
+        tpkt
+    }
+
+    // X.224 connection request or confirm with a negotiation structure
+    fn x224(code: u8, kind: u8, protocols: u32) -> Vec<u8> {
+        let mut payload = vec![14, code, 0, 0, 0, 0, 0, kind, 0, 8, 0];
+        payload.extend_from_slice(&protocols.to_le_bytes());
+        tpkt(&payload)
+    }
+
+    fn connect_initial(protocol: u32) -> Vec<u8> {
+        let mut core = vec![0; SERVER_SELECTED_PROTOCOL + 4];
+        core[..2].copy_from_slice(&CS_CORE.to_le_bytes());
+        let len = core.len() as u16;
+        core[2..4].copy_from_slice(&len.to_le_bytes());
+        core[SERVER_SELECTED_PROTOCOL..].copy_from_slice(&protocol.to_le_bytes());
+        let mut payload = vec![2, 0xf0, 0x80, 0x7f, 0x65];
+        payload.extend_from_slice(H221_CS_KEY);
+        payload.extend_from_slice(&[0x80 | (core.len() >> 8) as u8, core.len() as u8]);
+        payload.extend(core);
+        // a CS_SECURITY block
+        payload.extend_from_slice(&[2, 0xc0, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        tpkt(&payload)
+    }
+
+    #[test]
+    fn test_login() {
+        let mut login = RdpLogin::default();
+        let request = x224(0xe0, TYPE_RDP_NEG_REQ, PROTOCOL_SSL | PROTOCOL_HYBRID_EX);
+        login.client_data(&request[..10]).unwrap();
+        assert!(login.take_for_server().is_empty());
+        login.client_data(&request[10..]).unwrap();
+        assert_eq!(
+            login.take_for_server(),
+            x224(0xe0, TYPE_RDP_NEG_REQ, PROTOCOL_SSL | PROTOCOL_HYBRID)
+        );
+
+        login
+            .server_data(&x224(0xd0, TYPE_RDP_NEG_RSP, PROTOCOL_HYBRID))
+            .unwrap();
+        assert_eq!(
+            login.take_for_client(),
+            x224(0xd0, TYPE_RDP_NEG_RSP, PROTOCOL_SSL)
+        );
+        assert!(login.nla());
+        assert!(!login.done());
+
+        login.client_data(&connect_initial(PROTOCOL_SSL)).unwrap();
+        assert_eq!(login.take_for_server(), connect_initial(PROTOCOL_HYBRID));
+        assert!(login.done());
+        login.server_data(b"anything").unwrap();
+        assert_eq!(login.take_for_client(), b"anything");
+
+        // a server without NLA leaves the client alone
+        let mut login = RdpLogin::default();
+        login
+            .client_data(&x224(0xe0, TYPE_RDP_NEG_REQ, PROTOCOL_SSL))
+            .unwrap();
+        login.take_for_server();
+        let confirm = x224(0xd0, TYPE_RDP_NEG_RSP, PROTOCOL_SSL);
+        login.server_data(&confirm).unwrap();
+        assert_eq!(login.take_for_client(), confirm);
+        assert!(!login.nla());
+        assert!(login.done());
+
+        assert!(RdpLogin::default().client_data(b"RFB 003.008\n").is_err());
+    }
+}
@@ -0,0 +1,347 @@
+// Log in to a VNC server, offer the client no authentication
+// https://datatracker.ietf.org/doc/html/rfc6143#section-7.1
+
+use des::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use des::Des;
+use std::io;
+
+pub const VERSION_LEN: usize = 12;
+
+// Security types
+pub const SECURITY_INVALID: u8 = 0;
+pub const SECURITY_NONE: u8 = 1;
+pub const SECURITY_VNC: u8 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    ServerVersion,
+    // 3.7 and later offer a list
+    SecurityTypes,
+    // 3.3 decides alone
+    SecurityType,
+    Challenge,
+    SecurityResult,
+    // waiting for the client to tell its version
+    Authenticated,
+    ClientSecurity,
+    Done,
+}
+
+/// A sans-io VNC authentication on behalf of the client
+///
+/// Feed it the bytes of both sides and pass on whatever
+/// [`VncLogin::take_for_server`] and [`VncLogin::take_for_client`] return.
+/// Once [`VncLogin::done`] the rest of the session needs no help.
+pub struct VncLogin {
+    password: String,
+    state: State,
+    // minor versions, the major is 3
+    server_minor: u8,
+    client_minor: Option<u8>,
+    server: Vec<u8>,
+    client: Vec<u8>,
+    for_server: Vec<u8>,
+    for_client: Vec<u8>,
+}
+
+// "RFB 003.008\n"
+pub fn parse_version(data: &[u8]) -> io::Result<u8> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid RFB version");
+    let version = std::str::from_utf8(data).map_err(|_| invalid())?;
+    let (major, minor) = version
+        .strip_prefix("RFB ")
+        .and_then(|v| v.strip_suffix('\n'))
+        .and_then(|v| v.split_once('.'))
+        .ok_or_else(invalid)?;
+    let major: u32 = major.parse().map_err(|_| invalid())?;
+    let minor: u32 = minor.parse().map_err(|_| invalid())?;
+    // the versions in between behave like 3.3, later ones like 3.8
+    Ok(match (major, minor) {
+        (3, 7) => 7,
+        (3, 0..=6) => 3,
+        _ => 8,
+    })
+}
+
+pub fn version(minor: u8) -> Vec<u8> {
+    format!("RFB 003.{:03}\n", minor).into_bytes()
+}
+
+/// The DES response to the challenge, the password is the key
+/// with the bits of every byte reversed
+pub fn vnc_response(password: &str, challenge: &[u8]) -> Vec<u8> {
+    let mut key = [0; 8];
+    for (k, b) in key.iter_mut().zip(password.bytes()) {
+        *k = b.reverse_bits();
+    }
+    let cipher = Des::new(&key.into());
+    let mut response = challenge.to_vec();
+    for block in response.chunks_exact_mut(8) {
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+    response
+}
+
+fn take(buf: &mut Vec<u8>, n: usize) -> Vec<u8> {
+    buf.drain(..n).collect()
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+impl VncLogin {
+    pub fn new(password: &str) -> Self {
+        Self {
+            password: password.to_owned(),
+            state: State::ServerVersion,
+            server_minor: 8,
+            client_minor: None,
+            server: Vec::new(),
+            client: Vec::new(),
+            for_server: Vec::new(),
+            for_client: Vec::new(),
+        }
+    }
+
+    pub fn done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    pub fn take_for_server(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.for_server)
+    }
+
+    pub fn take_for_client(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.for_client)
+    }
+
+    pub fn client_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.client.extend_from_slice(data);
+        self.process()
+    }
+
+    pub fn server_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.server.extend_from_slice(data);
+        self.process()
+    }
+
+    /// Tell the client why, if it is far enough to understand
+    fn fail(&mut self, reason: &str) -> io::Error {
+        let reason = format!("VNC login failed: {}", reason);
+        match self.client_minor {
+            Some(3) => self.for_client.extend_from_slice(&0u32.to_be_bytes()),
+            Some(_) => self.for_client.push(SECURITY_INVALID),
+            None => {}
+        }
+        if self.client_minor.is_some() {
+            self.for_client
+                .extend_from_slice(&(reason.len() as u32).to_be_bytes());
+            self.for_client.extend_from_slice(reason.as_bytes());
+        }
+        self.state = State::Done;
+        io::Error::other(reason)
+    }
+
+    // The reason string following a failure, if complete
+    fn server_reason(&self, at: usize) -> Option<String> {
+        let len = u32_at(self.server.get(at..at + 4)?, 0) as usize;
+        let reason = self.server.get(at + 4..at + 4 + len)?;
+        Some(String::from_utf8_lossy(reason).into_owned())
+    }
+
+    fn process(&mut self) -> io::Result<()> {
+        loop {
+            if self.client_minor.is_none()
+                && self.state != State::ServerVersion
+                && self.client.len() >= VERSION_LEN
+            {
+                let version = take(&mut self.client, VERSION_LEN);
+                self.client_minor = Some(parse_version(&version)?);
+            }
+            match self.state {
+                State::ServerVersion if self.server.len() >= VERSION_LEN => {
+                    let version = take(&mut self.server, VERSION_LEN);
+                    self.server_minor = parse_version(&version)?;
+                    self.for_server.extend(self::version(self.server_minor));
+                    self.for_client.extend(self::version(8));
+                    self.state = match self.server_minor {
+                        3 => State::SecurityType,
+                        _ => State::SecurityTypes,
+                    };
+                }
+                State::SecurityTypes if !self.server.is_empty() => {
+                    let count = self.server[0] as usize;
+                    if count == 0 {
+                        let Some(reason) = self.server_reason(1) else {
+                            return Ok(());
+                        };
+                        return Err(self.fail(&reason));
+                    }
+                    if self.server.len() < 1 + count {
+                        return Ok(());
+                    }
+                    let types = take(&mut self.server, 1 + count);
+                    if types[1..].contains(&SECURITY_VNC) {
+                        self.for_server.push(SECURITY_VNC);
+                        self.state = State::Challenge;
+                    } else if types[1..].contains(&SECURITY_NONE) {
+                        self.for_server.push(SECURITY_NONE);
+                        // 3.8 confirms even no authentication
+                        self.state = match self.server_minor {
+                            8 => State::SecurityResult,
+                            _ => State::Authenticated,
+                        };
+                    } else {
+                        return Err(self.fail("no supported security type"));
+                    }
+                }
+                State::SecurityType if self.server.len() >= 4 => {
+                    match u32_at(&self.server, 0) {
+                        0 => {
+                            let Some(reason) = self.server_reason(4) else {
+                                return Ok(());
+                            };
+                            return Err(self.fail(&reason));
+                        }
+                        1 => self.state = State::Authenticated,
+                        2 => self.state = State::Challenge,
+                        _ => return Err(self.fail("no supported security type")),
+                    }
+                    take(&mut self.server, 4);
+                }
+                State::Challenge if self.server.len() >= 16 => {
+                    let challenge = take(&mut self.server, 16);
+                    let response = vnc_response(&self.password, &challenge);
+                    self.for_server.extend(response);
+                    self.state = State::SecurityResult;
+                }
+                State::SecurityResult if self.server.len() >= 4 => {
+                    if u32_at(&self.server, 0) == 0 {
+                        take(&mut self.server, 4);
+                        self.state = State::Authenticated;
+                        continue;
+                    }
+                    let reason = match self.server_minor {
+                        8 => match self.server_reason(4) {
+                            Some(reason) => reason,
+                            None => return Ok(()),
+                        },
+                        _ => "authentication failed".to_owned(),
+                    };
+                    return Err(self.fail(&reason));
+                }
+                State::Authenticated => match self.client_minor {
+                    Some(3) => {
+                        self.for_client
+                            .extend_from_slice(&(SECURITY_NONE as u32).to_be_bytes());
+                        self.state = State::Done;
+                    }
+                    Some(_) => {
+                        self.for_client.extend_from_slice(&[1, SECURITY_NONE]);
+                        self.state = State::ClientSecurity;
+                    }
+                    None => return Ok(()),
+                },
+                State::ClientSecurity if !self.client.is_empty() => {
+                    if take(&mut self.client, 1) != [SECURITY_NONE] {
+                        return Err(self.fail("the client must choose no authentication"));
+                    }
+                    if self.client_minor == Some(8) {
+                        self.for_client.extend_from_slice(&0u32.to_be_bytes());
+                    }
+                    self.state = State::Done;
+                }
+                State::Done => {
+                    // ClientInit and whatever follows
+                    self.for_server.append(&mut self.client);
+                    self.for_client.append(&mut self.server);
+                    return Ok(());
+                }
+                // waiting for more data
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_response() {
+        let challenge: Vec<u8> = (0..16).collect();
+        assert_eq!(
+            vnc_response("secret", &challenge),
+            [
+                0xee, 0x22, 0x53, 0x9f, 0x33, 0xa5, 0x98, 0x3e, 0xc1, 0x2f, 0x9c, 0x2e, 0xdb, 0xc9,
+                0x95, 0xdd
+            ]
+        );
+        // only the first 8 bytes of a password count
+        assert_eq!(
+            vnc_response("password", &challenge),
+            vnc_response("password1", &challenge)
+        );
+    }
+
+    #[test]
+    fn test_login() {
+        let challenge: Vec<u8> = (0..16).collect();
+
+        // a 3.8 server and a 3.3 client
+        let mut login = VncLogin::new("secret");
+        login.server_data(b"RFB 003.008\n").unwrap();
+        assert_eq!(login.take_for_server(), b"RFB 003.008\n");
+        assert_eq!(login.take_for_client(), b"RFB 003.008\n");
+        login.client_data(b"RFB 003.003\n").unwrap();
+        login
+            .server_data(&[2, SECURITY_NONE, SECURITY_VNC])
+            .unwrap();
+        assert_eq!(login.take_for_server(), [SECURITY_VNC]);
+        // a challenge in two pieces
+        login.server_data(&challenge[..10]).unwrap();
+        login.server_data(&challenge[10..]).unwrap();
+        assert_eq!(login.take_for_server(), vnc_response("secret", &challenge));
+        assert!(login.take_for_client().is_empty());
+        login.server_data(&[0, 0, 0, 0]).unwrap();
+        assert_eq!(login.take_for_client(), [0, 0, 0, SECURITY_NONE]);
+        assert!(login.done());
+        // ClientInit passes
+        login.client_data(&[1]).unwrap();
+        assert_eq!(login.take_for_server(), [1]);
+
+        // a 3.3 server and a 3.8 client sending ClientInit early
+        let mut login = VncLogin::new("secret");
+        login.server_data(b"RFB 003.003\n").unwrap();
+        assert_eq!(login.take_for_server(), b"RFB 003.003\n");
+        login.take_for_client();
+        login.client_data(b"RFB 003.008\n").unwrap();
+        login.server_data(&[0, 0, 0, SECURITY_VNC]).unwrap();
+        login.server_data(&challenge).unwrap();
+        login.take_for_server();
+        login.server_data(&[0, 0, 0, 0]).unwrap();
+        assert_eq!(login.take_for_client(), [1, SECURITY_NONE]);
+        login.client_data(&[SECURITY_NONE, 1]).unwrap();
+        assert_eq!(login.take_for_client(), [0, 0, 0, 0]);
+        assert_eq!(login.take_for_server(), [1]);
+        assert!(login.done());
+
+        // a wrong password, the client learns why
+        let mut login = VncLogin::new("wrong");
+        login.server_data(b"RFB 003.008\n").unwrap();
+        login.client_data(b"RFB 003.008\n").unwrap();
+        login.server_data(&[1, SECURITY_VNC]).unwrap();
+        login.server_data(&challenge).unwrap();
+        login.take_for_client();
+        login.server_data(&[0, 0, 0, 1, 0, 0, 0, 3]).unwrap();
+        let e = login.server_data(b"bad").unwrap_err();
+        assert_eq!(e.to_string(), "VNC login failed: bad");
+        let client = login.take_for_client();
+        assert_eq!(client[..5], [SECURITY_INVALID, 0, 0, 0, 21]);
+        assert_eq!(&client[5..], b"VNC login failed: bad");
+
+        assert!(VncLogin::new("").server_data(b"SSH-2.0-OpenSSH\n").is_err());
+    }
+}
@@ -0,0 +1,1043 @@
+mod config;
+mod login;
+mod metrics;
+mod proxy;
+mod record;
+mod share;
+mod token;
+
+use axum::{
+    extract::{
+        ws::{WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use clap::Parser;
+use config::{Config, Target, TargetInfo};
+use futures::future::join_all;
+use login::Login;
+use metrics::{Metrics, TargetMetrics};
+use proxy::Client;
+use record::{Header, RecordingInfo, Recordings};
+use serde::Deserialize;
+use share::Sessions;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use token::{TokenError, TokenKey};
+use tokio::net::TcpStream;
+use tower_http::{services::ServeDir, trace::TraceLayer};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// Serve the web clients and bridge their websockets to a TCP server
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Port, or address and port, to listen on
+    #[arg(value_parser = parse_listen)]
+    listen: SocketAddr,
+
+    /// The TCP server relayed by /websockify, as host:port
+    #[arg(required_unless_present_any = ["config", "token_key"])]
+    target: Option<String>,
+
+    /// TOML file naming the targets relayed by /websockify/<name>
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// File holding the secret the tokens are signed with, connections
+    /// then go only to the target of the token in their url
+    #[arg(long)]
+    token_key: Option<PathBuf>,
+
+    /// Directory to record the relayed sessions to, for /recordings and
+    /// replay at /websockify/replay/<id> with tokens of --token-key
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Have /readyz connect to every configured target, not ready while
+    /// one is unreachable
+    #[arg(long)]
+    probe_targets: bool,
+
+    /// Directory of the static files to serve
+    #[arg(long)]
+    web: PathBuf,
+
+    /// PEM certificate to serve https and wss with
+    #[cfg(feature = "ssl")]
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// PEM private key of the certificate
+    #[cfg(feature = "ssl")]
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+}
+
+// A bare port listens on every interface
+fn parse_listen(listen: &str) -> Result<SocketAddr, String> {
+    if let Ok(port) = listen.parse::<u16>() {
+        return Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port));
+    }
+    listen
+        .parse()
+        .map_err(|_| format!("invalid listen address {}", listen))
+}
+
+// The target on the command line takes the place of the configured default
+fn load_config(args: &Args) -> Result<Config, String> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if let Some(address) = &args.target {
+        let name = "default".to_owned();
+        let target = Target {
+            address: address.clone(),
+            protocol: None,
+            login: None,
+            shared: false,
+        };
+        config.targets.insert(name.clone(), target);
+        config.default = Some(name);
+    }
+    Ok(config)
+}
+
+struct Gateway {
+    config: Config,
+    token_key: Option<TokenKey>,
+    sessions: Sessions,
+    recordings: Option<Recordings>,
+    metrics: Metrics,
+    probe_targets: bool,
+}
+
+impl Gateway {
+    fn from_args(args: &Args) -> Result<Self, String> {
+        let config = load_config(args)?;
+        let token_key = args.token_key.as_deref().map(TokenKey::load).transpose()?;
+        // anyone reaching the gateway would be logged in
+        if let Some((name, _)) = config.targets.iter().find(|(_, t)| t.login.is_some()) {
+            if token_key.is_none() {
+                return Err(format!(
+                    "the target {} has a login, the gateway then takes --token-key",
+                    name
+                ));
+            }
+        }
+        // the recordings hold what was typed, passwords too
+        if args.record.is_some() && token_key.is_none() {
+            return Err("--record takes --token-key".to_owned());
+        }
+        Ok(Self {
+            config,
+            token_key,
+            sessions: Sessions::default(),
+            recordings: args.record.as_deref().map(Recordings::new).transpose()?,
+            metrics: Metrics::default(),
+            probe_targets: args.probe_targets,
+        })
+    }
+}
+
+// Of each target /readyz connects to
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Deserialize)]
+struct Params {
+    token: Option<String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Record the session when the gateway records, refusing it when it cannot
+async fn accept(
+    socket: WebSocket,
+    recordings: Option<Recordings>,
+    header: Header,
+    metrics: Arc<TargetMetrics>,
+) -> Option<Client> {
+    let Some(recordings) = recordings else {
+        return Some(Client::new(socket, None, &metrics));
+    };
+    let target = header.target.clone();
+    match recordings.create(header).await {
+        Ok(recorder) => Some(Client::new(socket, Some(recorder), &metrics)),
+        Err(e) => {
+            warn!("Unable to record the session to {}: {}", target, e);
+            proxy::refuse(socket, 1011, "unable to record").await;
+            None
+        }
+    }
+}
+
+fn upgrade(
+    ws: WebSocketUpgrade,
+    gateway: &Gateway,
+    target: Option<(&str, &Target)>,
+    token: Option<&str>,
+) -> Response {
+    // the clients ask for the binary subprotocol
+    let ws = ws.protocols(["binary"]);
+    let recordings = gateway.recordings.clone();
+    let (target, header) = match &gateway.token_key {
+        // browsers hide why an upgrade failed, refuse with a close code instead
+        Some(key) => match token
+            .ok_or(TokenError::Missing)
+            .and_then(|token| key.verify(token, now()))
+        {
+            Ok(claims) => {
+                info!(
+                    "Token of {} for {} {}",
+                    claims.user.as_deref().unwrap_or("anonymous"),
+                    claims.protocol.as_deref().unwrap_or("a connection"),
+                    claims.target
+                );
+                // names of the config have no port, addresses do
+                let target = match gateway.config.target(&claims.target) {
+                    Some(target) => target.clone(),
+                    None => Target {
+                        address: claims.target.clone(),
+                        protocol: claims.protocol,
+                        login: None,
+                        shared: false,
+                    },
+                };
+                let header = Header {
+                    target: claims.target,
+                    protocol: target.protocol.clone(),
+                    user: claims.user,
+                    start: now(),
+                };
+                (target, header)
+            }
+            Err(e) => {
+                warn!("Refused a connection: {}", e.reason());
+                return ws
+                    .on_upgrade(move |socket| proxy::refuse(socket, e.close_code(), e.reason()));
+            }
+        },
+        None => {
+            let Some((name, target)) = target else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            let header = Header {
+                target: name.to_owned(),
+                protocol: target.protocol.clone(),
+                user: None,
+                start: now(),
+            };
+            (target.clone(), header)
+        }
+    };
+    let metrics = gateway.metrics.target(header.labels());
+    if target.shared {
+        let viewer = gateway.sessions.join(&target, &metrics);
+        // tell the clients that only watch, if they understand
+        let ws = match viewer.owner() {
+            true => ws,
+            false => ws.protocols([share::VIEW_ONLY_PROTOCOL, "binary"]),
+        };
+        return ws.on_upgrade(move |socket| async move {
+            if let Some(client) = accept(socket, recordings, header, metrics).await {
+                viewer.run(client).await;
+            }
+        });
+    }
+    let login = Login::new(&target);
+    ws.on_upgrade(move |socket| async move {
+        if let Some(client) = accept(socket, recordings, header, metrics).await {
+            proxy::relay(client, target.address, login).await;
+        }
+    })
+}
+
+async fn websockify(
+    ws: WebSocketUpgrade,
+    Query(params): Query<Params>,
+    State(gateway): State<Arc<Gateway>>,
+) -> Response {
+    let config = &gateway.config;
+    let target = config.default.as_deref().zip(config.default_target());
+    upgrade(ws, &gateway, target, params.token.as_deref())
+}
+
+async fn websockify_named(
+    ws: WebSocketUpgrade,
+    Path(name): Path<String>,
+    Query(params): Query<Params>,
+    State(gateway): State<Arc<Gateway>>,
+) -> Response {
+    let target = gateway
+        .config
+        .target(&name)
+        .map(|target| (name.as_str(), target));
+    upgrade(ws, &gateway, target, params.token.as_deref())
+}
+
+// Whether `token` was signed for `target`, recordings are only served to
+// the auditors the portal hands tokens to
+fn authorize(gateway: &Gateway, token: Option<&str>, target: &str) -> Result<(), TokenError> {
+    let key = gateway.token_key.as_ref().ok_or(TokenError::Invalid)?;
+    let claims = key.verify(token.ok_or(TokenError::Missing)?, now())?;
+    match claims.target == target {
+        true => Ok(()),
+        false => Err(TokenError::Invalid),
+    }
+}
+
+async fn replay(
+    ws: WebSocketUpgrade,
+    Path(id): Path<String>,
+    Query(params): Query<Params>,
+    State(gateway): State<Arc<Gateway>>,
+) -> Response {
+    let Some(recordings) = &gateway.recordings else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let ws = ws.protocols(["binary"]);
+    let target = format!("replay/{}", id);
+    if let Err(e) = authorize(&gateway, params.token.as_deref(), &target) {
+        warn!("Refused a replay: {}", e.reason());
+        return ws.on_upgrade(move |socket| proxy::refuse(socket, e.close_code(), e.reason()));
+    }
+    match recordings.open(&id).await {
+        Ok(Some(player)) => {
+            info!("Replaying {} of {}", id, player.header().target);
+            ws.on_upgrade(move |socket| player.replay(socket))
+        }
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            warn!("Unable to open the recording {}: {}", id, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+// A token lists only the target it names, an address lists nothing
+async fn targets(
+    Query(params): Query<Params>,
+    State(gateway): State<Arc<Gateway>>,
+) -> Json<Vec<TargetInfo>> {
+    let targets = gateway.config.list();
+    let (Some(key), Some(token)) = (&gateway.token_key, &params.token) else {
+        return Json(targets);
+    };
+    let Ok(claims) = key.verify(token, now()) else {
+        return Json(Vec::new());
+    };
+    Json(
+        targets
+            .into_iter()
+            .filter(|target| target.name == claims.target)
+            .collect(),
+    )
+}
+
+// Listed with a token for the target `recordings`
+async fn recordings(
+    Query(params): Query<Params>,
+    State(gateway): State<Arc<Gateway>>,
+) -> Result<Json<Vec<RecordingInfo>>, StatusCode> {
+    let Some(recordings) = &gateway.recordings else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    if let Err(e) = authorize(&gateway, params.token.as_deref(), "recordings") {
+        warn!("Refused to list the recordings: {}", e.reason());
+        return Err(StatusCode::FORBIDDEN);
+    }
+    recordings.list().map(Json).map_err(|e| {
+        warn!("Unable to list the recordings: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+async fn metrics(State(gateway): State<Arc<Gateway>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        gateway.metrics.render(),
+    )
+}
+
+async fn healthz() -> &'static str {
+    "ok\n"
+}
+
+// Whether every configured target accepts connections, tells which do not
+async fn readyz(State(gateway): State<Arc<Gateway>>) -> (StatusCode, String) {
+    if !gateway.probe_targets {
+        return (StatusCode::OK, "ok\n".to_owned());
+    }
+    let probes = gateway
+        .config
+        .targets
+        .iter()
+        .map(|(name, target)| async move {
+            let probe = tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect(&target.address));
+            match probe.await {
+                Ok(Ok(_)) => None,
+                Ok(Err(e)) => Some(format!("{}: {}\n", name, e)),
+                Err(_) => Some(format!("{}: timed out\n", name)),
+            }
+        });
+    let unreachable: String = join_all(probes).await.into_iter().flatten().collect();
+    match unreachable.is_empty() {
+        true => (StatusCode::OK, "ok\n".to_owned()),
+        false => {
+            warn!(
+                "Not ready, unreachable targets:\n{}",
+                unreachable.trim_end()
+            );
+            (StatusCode::SERVICE_UNAVAILABLE, unreachable)
+        }
+    }
+}
+
+fn app(gateway: Gateway, web: &FsPath) -> Router {
+    Router::new()
+        .route("/websockify", get(websockify))
+        .route("/websockify/:name", get(websockify_named))
+        .route("/websockify/replay/:id", get(replay))
+        .route("/targets", get(targets))
+        .route("/recordings", get(recordings))
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(Arc::new(gateway))
+        .fallback_service(ServeDir::new(web))
+        .layer(TraceLayer::new_for_http())
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let args = Args::parse();
+    let gateway = Gateway::from_args(&args).map_err(std::io::Error::other)?;
+    if gateway.token_key.is_some() {
+        info!(
+            "Listening on {}, relaying to the targets of signed tokens, serving {}",
+            args.listen,
+            args.web.display()
+        );
+    } else {
+        info!(
+            "Listening on {}, relaying to {} targets, serving {}",
+            args.listen,
+            gateway.config.targets.len(),
+            args.web.display()
+        );
+    }
+    let app = app(gateway, &args.web);
+
+    #[cfg(feature = "ssl")]
+    if let (Some(cert), Some(key)) = (&args.cert, &args.key) {
+        let tls = axum_server::tls_openssl::OpenSSLConfig::from_pem_file(cert, key)
+            .map_err(std::io::Error::other)?;
+        return axum_server::bind_openssl(args.listen, tls)
+            .serve(app.into_make_service())
+            .await;
+    }
+
+    let listener = tokio::net::TcpListener::bind(args.listen).await?;
+    axum::serve(listener, app).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use std::future::IntoFuture;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::{client::IntoClientRequest, http::HeaderValue, Message};
+
+    #[test]
+    fn test_args() {
+        let args = Args::parse_from(["axum-websockify", "8080", "localhost:5900", "--web", "."]);
+        assert_eq!(args.listen, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(args.target.as_deref(), Some("localhost:5900"));
+        assert_eq!(args.web, PathBuf::from("."));
+        let config = load_config(&args).unwrap();
+        assert_eq!(config.default_target().unwrap().address, "localhost:5900");
+
+        assert_eq!(
+            parse_listen("127.0.0.1:8080"),
+            Ok("127.0.0.1:8080".parse().unwrap())
+        );
+        assert!(parse_listen("localhost").is_err());
+        assert!(Args::try_parse_from(["axum-websockify", "8080", "localhost:5900"]).is_err());
+        assert!(Args::try_parse_from(["axum-websockify", "8080", "--web", "."]).is_err());
+        let args = Args::parse_from([
+            "axum-websockify",
+            "8080",
+            "--config",
+            "gw.toml",
+            "--web",
+            ".",
+        ]);
+        assert_eq!(args.target, None);
+        assert!(load_config(&args).is_err());
+    }
+
+    // An echo server to relay to
+    async fn echo() -> String {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = echo.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut read, mut write) = stream.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+        });
+        target
+    }
+
+    async fn serve(gateway: Gateway) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::serve(listener, app(gateway, FsPath::new("."))).into_future());
+        addr
+    }
+
+    async fn connect(
+        addr: SocketAddr,
+        path: &str,
+        protocols: &'static str,
+    ) -> tokio_tungstenite::tungstenite::Result<(
+        tokio_tungstenite::WebSocketStream<
+            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+        >,
+        tokio_tungstenite::tungstenite::handshake::client::Response,
+    )> {
+        let mut request = format!("ws://{}{}", addr, path)
+            .into_client_request()
+            .unwrap();
+        request.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static(protocols),
+        );
+        tokio_tungstenite::connect_async(request).await
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let target = echo().await;
+        let config = Config::parse(&format!(
+            "[targets.echo]\naddress = \"{}\"\nprotocol = \"raw\"",
+            target
+        ))
+        .unwrap();
+        let addr = serve(Gateway {
+            config,
+            token_key: None,
+            sessions: Sessions::default(),
+            recordings: None,
+            metrics: Metrics::default(),
+            probe_targets: false,
+        })
+        .await;
+
+        // no default without a target on the command line
+        assert!(connect(addr, "/websockify", "binary").await.is_err());
+        assert!(connect(addr, "/websockify/missing", "binary")
+            .await
+            .is_err());
+
+        let (mut ws, response) = connect(addr, "/websockify/echo", "binary").await.unwrap();
+        assert_eq!(
+            response.headers().get("Sec-WebSocket-Protocol").unwrap(),
+            "binary"
+        );
+        ws.send(Message::Binary(b"hello".to_vec())).await.unwrap();
+        assert_eq!(
+            ws.next().await.unwrap().unwrap(),
+            Message::Binary(b"hello".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token() {
+        let target = echo().await;
+        let key = TokenKey::new(&[1; 32]).unwrap();
+        let claims = token::Claims {
+            target,
+            protocol: None,
+            exp: now() + 60,
+            user: Some("alice".to_owned()),
+        };
+        let token = key.sign(&claims);
+        let expired = key.sign(&token::Claims {
+            exp: now() - 1,
+            ..claims.clone()
+        });
+        let addr = serve(Gateway {
+            config: Config::default(),
+            token_key: Some(key),
+            sessions: Sessions::default(),
+            recordings: None,
+            metrics: Metrics::default(),
+            probe_targets: false,
+        })
+        .await;
+
+        for (path, code) in [
+            ("/websockify".to_owned(), 4000),
+            (format!("/websockify?token={}", expired), 4002),
+            (format!("/websockify?token={}x", token), 4001),
+        ] {
+            let (mut ws, _) = connect(addr, &path, "binary").await.unwrap();
+            match ws.next().await.unwrap().unwrap() {
+                Message::Close(Some(frame)) => assert_eq!(u16::from(frame.code), code),
+                message => panic!("unexpected {:?}", message),
+            }
+        }
+
+        let (mut ws, _) = connect(addr, &format!("/websockify?token={}", token), "binary")
+            .await
+            .unwrap();
+        ws.send(Message::Binary(b"hello".to_vec())).await.unwrap();
+        assert_eq!(
+            ws.next().await.unwrap().unwrap(),
+            Message::Binary(b"hello".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let target = echo().await;
+        let config = Config::parse(&format!(
+            "[targets.echo]\naddress = \"{}\"\nprotocol = \"raw\"",
+            target
+        ))
+        .unwrap();
+        let dir = std::env::temp_dir().join(format!("axum-websockify-main-{}", std::process::id()));
+        // anyone could replay what was typed
+        let args = Args::parse_from(["axum-websockify", "8080", "--web", ".", "echo:1"]);
+        assert!(Gateway::from_args(&Args {
+            record: Some(dir.clone()),
+            ..args
+        })
+        .is_err());
+        let recordings = Recordings::new(&dir).unwrap();
+        let key = TokenKey::new(&[1; 32]).unwrap();
+        let sign = |target: &str| {
+            key.sign(&token::Claims {
+                target: target.to_owned(),
+                protocol: None,
+                exp: now() + 60,
+                user: Some("auditor".to_owned()),
+            })
+        };
+        let echo_token = sign("echo");
+        let list_token = sign("recordings");
+        let addr = serve(Gateway {
+            config,
+            token_key: Some(TokenKey::new(&[1; 32]).unwrap()),
+            sessions: Sessions::default(),
+            recordings: Some(recordings.clone()),
+            metrics: Metrics::default(),
+            probe_targets: false,
+        })
+        .await;
+
+        let path = format!("/websockify/echo?token={}", echo_token);
+        let (mut ws, _) = connect(addr, &path, "binary").await.unwrap();
+        ws.send(Message::Binary(b"hello".to_vec())).await.unwrap();
+        assert_eq!(recv(&mut ws).await, b"hello");
+        ws.close(None).await.unwrap();
+        while ws.next().await.is_some() {}
+
+        let missing = format!(
+            "/websockify/replay/missing?token={}",
+            sign("replay/missing")
+        );
+        assert!(connect(addr, &missing, "binary").await.is_err());
+        assert_eq!(get(addr, "/recordings").await.0, 403);
+        assert_eq!(
+            get(addr, &format!("/recordings?token={}", echo_token))
+                .await
+                .0,
+            403
+        );
+        let (status, body) = get(addr, &format!("/recordings?token={}", list_token)).await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            serde_json::to_string(&recordings.list().unwrap()).unwrap(),
+            body
+        );
+        let list = recordings.list().unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].header.target, "echo");
+        assert_eq!(list[0].header.protocol.as_deref(), Some("raw"));
+
+        // a token for one recording replays only that one
+        let (mut ws, _) = connect(
+            addr,
+            &format!("/websockify/replay/{}", list[0].id),
+            "binary",
+        )
+        .await
+        .unwrap();
+        match ws.next().await.unwrap().unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(u16::from(frame.code), 4000),
+            message => panic!("unexpected {:?}", message),
+        }
+        // the recorder may still be writing
+        let replay = format!("replay/{}", list[0].id);
+        let path = format!("/websockify/{}?token={}", replay, sign(&replay));
+        loop {
+            let (mut ws, _) = connect(addr, &path, "binary").await.unwrap();
+            match ws.next().await.unwrap().unwrap() {
+                Message::Binary(data) => {
+                    assert_eq!(data, b"hello");
+                    break;
+                }
+                _ => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // The status and body of a plain GET
+    async fn get(addr: SocketAddr, path: &str) -> (u16, String) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.0\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head[9..12].parse().unwrap(), body.to_owned())
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let up = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // nothing listens there anymore
+        let gone = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = format!(
+            "[targets.up]\naddress = \"{}\"\n[targets.gone]\naddress = \"{}\"",
+            up.local_addr().unwrap(),
+            gone
+        );
+        let gateway = |config, probe_targets| Gateway {
+            config,
+            token_key: None,
+            sessions: Sessions::default(),
+            recordings: None,
+            metrics: Metrics::default(),
+            probe_targets,
+        };
+        let addr = serve(gateway(Config::parse(&config).unwrap(), true)).await;
+        assert_eq!(get(addr, "/healthz").await, (200, "ok\n".to_owned()));
+        let (status, body) = get(addr, "/readyz").await;
+        assert_eq!(status, 503);
+        assert!(body.starts_with("gone: ") && body.lines().count() == 1);
+        let addr = serve(gateway(Config::parse(&config).unwrap(), false)).await;
+        assert_eq!(get(addr, "/readyz").await.0, 200);
+
+        let target = echo().await;
+        let config = Config::parse(&format!(
+            "[targets.echo]\naddress = \"{}\"\nprotocol = \"raw\"\n\
+             [targets.gone]\naddress = \"{}\"",
+            target, gone
+        ))
+        .unwrap();
+        let addr = serve(gateway(config, false)).await;
+        let (mut ws, _) = connect(addr, "/websockify/echo", "binary").await.unwrap();
+        ws.send(Message::Binary(b"hello".to_vec())).await.unwrap();
+        assert_eq!(recv(&mut ws).await, b"hello");
+        let (mut ws, _) = connect(addr, "/websockify/gone", "binary").await.unwrap();
+        while ws.next().await.is_some() {}
+
+        let (status, body) = get(addr, "/metrics").await;
+        assert_eq!(status, 200);
+        for line in [
+            "websockify_sessions_active{target=\"echo\",protocol=\"raw\"} 1",
+            "websockify_received_bytes_total{target=\"echo\",protocol=\"raw\"} 5",
+            "websockify_sent_bytes_total{target=\"echo\",protocol=\"raw\"} 5",
+            "websockify_connect_duration_seconds_count{target=\"echo\",protocol=\"raw\"} 1",
+            "websockify_connect_failures_total{target=\"gone\",protocol=\"\"} 1",
+        ] {
+            assert!(body.lines().any(|l| l == line), "no {} in\n{}", line, body);
+        }
+    }
+
+    // A VNC server of two pixels, what the clients type comes out of `keys`.
+    // With a `password` it asks for VNC authentication.
+    async fn vnc_server(
+        password: Option<&'static str>,
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<u8>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let (keys, typed) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"RFB 003.008\n").await.unwrap();
+            let mut buf = vec![0; 12];
+            stream.read_exact(&mut buf).await.unwrap();
+            match password {
+                Some(password) => {
+                    stream.write_all(&[1, 2]).await.unwrap();
+                    assert_eq!(stream.read_u8().await.unwrap(), 2);
+                    let challenge = [7; 16];
+                    stream.write_all(&challenge).await.unwrap();
+                    let mut response = [0; 16];
+                    stream.read_exact(&mut response).await.unwrap();
+                    let expected = login::vnc::vnc_response(password, &challenge);
+                    assert_eq!(response.as_slice(), expected);
+                }
+                None => {
+                    stream.write_all(&[1, 1]).await.unwrap();
+                    stream.read_exact(&mut buf[..1]).await.unwrap();
+                }
+            }
+            stream.write_all(&[0, 0, 0, 0]).await.unwrap();
+            // shared
+            assert_eq!(stream.read_u8().await.unwrap(), 1);
+            let mut init = vec![0, 2, 0, 1];
+            init.extend_from_slice(&RGBA);
+            init.extend_from_slice(&[0, 0, 0, 4]);
+            init.extend_from_slice(b"test");
+            stream.write_all(&init).await.unwrap();
+            loop {
+                match stream.read_u8().await.unwrap() {
+                    // SetPixelFormat
+                    0 => {
+                        stream.read_exact(&mut [0; 19]).await.unwrap();
+                    }
+                    // SetEncodings
+                    2 => {
+                        stream.read_u8().await.unwrap();
+                        let count = stream.read_u16().await.unwrap();
+                        stream
+                            .read_exact(&mut vec![0; 4 * count as usize])
+                            .await
+                            .unwrap();
+                    }
+                    // the whole screen is asked for once
+                    3 => {
+                        stream.read_exact(&mut buf[..9]).await.unwrap();
+                        if buf[0] == 0 {
+                            let mut update = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 2, 0, 1, 0, 0, 0, 0];
+                            update.extend(1..=8);
+                            stream.write_all(&update).await.unwrap();
+                        }
+                    }
+                    4 => {
+                        stream.read_exact(&mut buf[..7]).await.unwrap();
+                        keys.send(buf[6]).unwrap();
+                    }
+                    message => panic!("unexpected message {}", message),
+                }
+            }
+        });
+        (target, typed)
+    }
+
+    const RGBA: [u8; 16] = [32, 24, 0, 1, 0, 255, 0, 255, 0, 255, 0, 8, 16, 0, 0, 0];
+
+    type Ws = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    async fn recv(ws: &mut Ws) -> Vec<u8> {
+        match ws.next().await.unwrap().unwrap() {
+            Message::Binary(data) => data,
+            message => panic!("unexpected {:?}", message),
+        }
+    }
+
+    // Join the shared session and ask for the screen, whether it is view only
+    async fn view(addr: SocketAddr) -> (Ws, bool) {
+        let (mut ws, response) = connect(addr, "/websockify/desktop", "binary,binary.view-only")
+            .await
+            .unwrap();
+        let protocol = response.headers().get("Sec-WebSocket-Protocol").unwrap();
+        let view_only = protocol == share::VIEW_ONLY_PROTOCOL;
+        handshake(&mut ws).await;
+        (ws, view_only)
+    }
+
+    // Get to the ServerInit of the 2x1 screen with no authentication, and ask
+    // for the screen
+    async fn handshake(ws: &mut Ws) {
+        assert_eq!(recv(ws).await, b"RFB 003.008\n");
+        ws.send(Message::Binary(b"RFB 003.008\n".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(recv(ws).await, [1, 1]);
+        ws.send(Message::Binary(vec![1])).await.unwrap();
+        assert_eq!(recv(ws).await, [0, 0, 0, 0]);
+        ws.send(Message::Binary(vec![1])).await.unwrap();
+        let init = recv(ws).await;
+        assert_eq!(init[..4], [0, 2, 0, 1]);
+        assert_eq!(init[4..20], RGBA);
+        assert_eq!(&init[24..], b"test");
+
+        let mut set_pixel_format = vec![0; 4];
+        set_pixel_format.extend_from_slice(&RGBA);
+        ws.send(Message::Binary(set_pixel_format)).await.unwrap();
+        let request = vec![3, 0, 0, 0, 0, 0, 0, 2, 0, 1];
+        ws.send(Message::Binary(request)).await.unwrap();
+    }
+
+    // The snapshot of a 2x1 screen
+    fn snapshot(pixels: &[u8]) -> Vec<u8> {
+        let mut update = vec![0, 0, 0, 2];
+        update.extend_from_slice(&[0, 0, 0, 0, 0, 2, 0, 1, 0xff, 0xff, 0xff, 0x21]);
+        update.extend_from_slice(&[0, 0, 0, 0, 0, 2, 0, 1, 0, 0, 0, 0]);
+        update.extend_from_slice(pixels);
+        update
+    }
+
+    #[tokio::test]
+    async fn test_share() {
+        let (target, mut typed) = vnc_server(None).await;
+        let config = Config::parse(&format!(
+            "[targets.desktop]\naddress = \"{}\"\nprotocol = \"vnc\"\nshared = true",
+            target
+        ))
+        .unwrap();
+        let addr = serve(Gateway {
+            config,
+            token_key: None,
+            sessions: Sessions::default(),
+            recordings: None,
+            metrics: Metrics::default(),
+            probe_targets: false,
+        })
+        .await;
+
+        let (mut owner, view_only) = view(addr).await;
+        assert!(!view_only);
+        // the screen, black if the server did not send it yet
+        let pixels: Vec<u8> = (1..=8).collect();
+        if recv(&mut owner).await == snapshot(&[0; 8]) {
+            let mut update = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 2, 0, 1, 0, 0, 0, 0];
+            update.extend_from_slice(&pixels);
+            assert_eq!(recv(&mut owner).await, update);
+        }
+
+        // late joiners see the screen as it is
+        let (mut watcher, view_only) = view(addr).await;
+        assert!(view_only);
+        assert_eq!(recv(&mut watcher).await, snapshot(&pixels));
+
+        // only the owner types, the snapshot tells the key was handled
+        watcher
+            .send(Message::Binary(vec![4, 1, 0, 0, 0, 0, 0, b'w']))
+            .await
+            .unwrap();
+        watcher
+            .send(Message::Binary(vec![3, 0, 0, 0, 0, 0, 0, 2, 0, 1]))
+            .await
+            .unwrap();
+        assert_eq!(recv(&mut watcher).await, snapshot(&pixels));
+        owner
+            .send(Message::Binary(vec![4, 1, 0, 0, 0, 0, 0, b'o']))
+            .await
+            .unwrap();
+        assert_eq!(typed.recv().await, Some(b'o'));
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let (target, _typed) = vnc_server(Some("secret")).await;
+        let config = format!(
+            "[targets.desktop]\naddress = \"{}\"\nprotocol = \"vnc\"\n\
+             login = {{ password = \"secret\" }}",
+            target
+        );
+
+        // without tokens anyone would be logged in
+        let dir =
+            std::env::temp_dir().join(format!("axum-websockify-login-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("gw.toml"), &config).unwrap();
+        std::fs::write(dir.join("key"), [b'k'; 32]).unwrap();
+        let config_path = dir.join("gw.toml").display().to_string();
+        let key_path = dir.join("key").display().to_string();
+        let args = |token_key: bool| {
+            let mut args = vec![
+                "axum-websockify",
+                "8080",
+                "--web",
+                ".",
+                "--config",
+                &config_path,
+            ];
+            if token_key {
+                args.extend(["--token-key", &key_path]);
+            }
+            Args::parse_from(args)
+        };
+        assert!(Gateway::from_args(&args(false)).is_err());
+        assert!(Gateway::from_args(&args(true)).is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let key = TokenKey::new(&[1; 32]).unwrap();
+        let token = key.sign(&token::Claims {
+            target: "desktop".to_owned(),
+            protocol: None,
+            exp: now() + 60,
+            user: Some("alice".to_owned()),
+        });
+        let addr = serve(Gateway {
+            config: Config::parse(&config).unwrap(),
+            token_key: Some(key),
+            sessions: Sessions::default(),
+            recordings: None,
+            metrics: Metrics::default(),
+            probe_targets: false,
+        })
+        .await;
+
+        let (mut ws, _) = connect(addr, "/websockify/desktop", "binary")
+            .await
+            .unwrap();
+        match ws.next().await.unwrap().unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(u16::from(frame.code), 4000),
+            message => panic!("unexpected {:?}", message),
+        }
+
+        // the clients learn the gateway logs in from the token
+        let (status, body) = get(addr, &format!("/targets?token={}", token)).await;
+        assert_eq!(status, 200);
+        let targets: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(targets[0]["name"], "desktop");
+        assert_eq!(targets[0]["login"], true);
+        let (_, body) = get(addr, &format!("/targets?token={}x", token)).await;
+        assert_eq!(body, "[]");
+
+        // the token names the target, the gateway answers the challenge
+        let path = format!("/websockify?token={}", token);
+        let (mut ws, _) = connect(addr, &path, "binary").await.unwrap();
+        handshake(&mut ws).await;
+        let mut update = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 2, 0, 1, 0, 0, 0, 0];
+        update.extend(1..=8);
+        assert_eq!(recv(&mut ws).await, update);
+    }
+}
@@ -0,0 +1,321 @@
+// Prometheus metrics of the relayed sessions, served at /metrics in the
+// text format
+//
+//     websockify_sessions_active             gauge, sessions being relayed
+//     websockify_sessions_total              counter
+//     websockify_session_duration_seconds    histogram
+//     websockify_received_bytes_total        counter, from the clients
+//     websockify_sent_bytes_total            counter, to the clients
+//     websockify_connect_duration_seconds    histogram, of connecting to the target
+//     websockify_connect_failures_total      counter
+//
+// all labelled with the `target`, its name in the config or the address of a
+// token, and the `protocol`, empty when unknown. A shared session connects
+// once for all its clients.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+// Seconds
+const CONNECT_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const SESSION_BUCKETS: &[f64] = &[
+    1.0, 10.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 14400.0, 43200.0,
+];
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Labels {
+    pub target: String,
+    pub protocol: String,
+}
+
+// Label values are quoted
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl fmt::Display for Labels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "target=\"{}\",protocol=\"{}\"",
+            escape(&self.target),
+            escape(&self.protocol)
+        )
+    }
+}
+
+struct Histogram {
+    buckets: &'static [f64],
+    // not cumulative, the last one counts those above every bucket
+    counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: (0..=buckets.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|&le| seconds <= le)
+            .unwrap_or(self.buckets.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &Labels) {
+        let mut count = 0;
+        for (le, n) in self.buckets.iter().zip(&self.counts) {
+            count += n.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, count);
+        }
+        count += self.counts[self.buckets.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// The metrics of the sessions with one target
+pub struct TargetMetrics {
+    active: AtomicU64,
+    sessions: AtomicU64,
+    received: AtomicU64,
+    sent: AtomicU64,
+    connect_failures: AtomicU64,
+    session_duration: Histogram,
+    connect_duration: Histogram,
+}
+
+impl Default for TargetMetrics {
+    fn default() -> Self {
+        Self {
+            active: AtomicU64::new(0),
+            sessions: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            connect_failures: AtomicU64::new(0),
+            session_duration: Histogram::new(SESSION_BUCKETS),
+            connect_duration: Histogram::new(CONNECT_BUCKETS),
+        }
+    }
+}
+
+impl TargetMetrics {
+    /// Count a session until the returned one is dropped
+    pub fn start(self: &Arc<Self>) -> Session {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+        Session {
+            metrics: self.clone(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Connect to the target, timed
+    pub async fn connect(&self, address: &str) -> io::Result<TcpStream> {
+        let start = Instant::now();
+        let result = TcpStream::connect(address).await;
+        match &result {
+            Ok(_) => self.connect_duration.observe(start.elapsed()),
+            Err(_) => {
+                self.connect_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+}
+
+/// A session being relayed
+pub struct Session {
+    metrics: Arc<TargetMetrics>,
+    start: Instant,
+}
+
+impl Session {
+    pub async fn connect(&self, address: &str) -> io::Result<TcpStream> {
+        self.metrics.connect(address).await
+    }
+
+    /// Bytes from the client
+    pub fn received(&self, n: usize) {
+        self.metrics.received.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Bytes to the client
+    pub fn sent(&self, n: usize) {
+        self.metrics.sent.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.metrics.active.fetch_sub(1, Ordering::Relaxed);
+        self.metrics.session_duration.observe(self.start.elapsed());
+    }
+}
+
+type Value = fn(&TargetMetrics) -> u64;
+
+const VALUES: [(&str, &str, &str, Value); 5] = [
+    (
+        "websockify_sessions_active",
+        "gauge",
+        "Sessions being relayed",
+        |m| m.active.load(Ordering::Relaxed),
+    ),
+    (
+        "websockify_sessions_total",
+        "counter",
+        "Sessions relayed",
+        |m| m.sessions.load(Ordering::Relaxed),
+    ),
+    (
+        "websockify_received_bytes_total",
+        "counter",
+        "Bytes received from the clients",
+        |m| m.received.load(Ordering::Relaxed),
+    ),
+    (
+        "websockify_sent_bytes_total",
+        "counter",
+        "Bytes sent to the clients",
+        |m| m.sent.load(Ordering::Relaxed),
+    ),
+    (
+        "websockify_connect_failures_total",
+        "counter",
+        "Failed connections to the target",
+        |m| m.connect_failures.load(Ordering::Relaxed),
+    ),
+];
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// The metrics of every target relayed to since the start
+#[derive(Default)]
+pub struct Metrics {
+    targets: Mutex<BTreeMap<Labels, Arc<TargetMetrics>>>,
+}
+
+impl Metrics {
+    pub fn target(&self, labels: Labels) -> Arc<TargetMetrics> {
+        let mut targets = self.targets.lock().unwrap();
+        targets.entry(labels).or_default().clone()
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let targets = self.targets.lock().unwrap();
+        let mut out = String::new();
+        for (name, kind, help, value) in VALUES {
+            family(&mut out, name, kind, help);
+            for (labels, metrics) in targets.iter() {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, value(metrics));
+            }
+        }
+        for (name, help, connect) in [
+            (
+                "websockify_session_duration_seconds",
+                "How long the sessions lasted",
+                false,
+            ),
+            (
+                "websockify_connect_duration_seconds",
+                "How long connecting to the target took",
+                true,
+            ),
+        ] {
+            family(&mut out, name, "histogram", help);
+            for (labels, metrics) in targets.iter() {
+                let histogram = match connect {
+                    true => &metrics.connect_duration,
+                    false => &metrics.session_duration,
+                };
+                histogram.render(&mut out, name, labels);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        let labels = Labels {
+            target: "desk\"top".to_owned(),
+            protocol: "vnc".to_owned(),
+        };
+        let target = metrics.target(labels.clone());
+        let session = target.start();
+        session.received(12);
+        session.sent(30);
+        target.connect_duration.observe(Duration::from_millis(20));
+        target.connect_duration.observe(Duration::from_secs(20));
+        assert!(Arc::ptr_eq(&metrics.target(labels), &target));
+
+        let text = metrics.render();
+        let labels = "target=\"desk\\\"top\",protocol=\"vnc\"";
+        for line in [
+            format!("websockify_sessions_active{{{}}} 1", labels),
+            format!("websockify_received_bytes_total{{{}}} 12", labels),
+            format!("websockify_sent_bytes_total{{{}}} 30", labels),
+            format!(
+                "websockify_connect_duration_seconds_bucket{{{},le=\"0.01\"}} 0",
+                labels
+            ),
+            format!(
+                "websockify_connect_duration_seconds_bucket{{{},le=\"0.025\"}} 1",
+                labels
+            ),
+            format!(
+                "websockify_connect_duration_seconds_bucket{{{},le=\"+Inf\"}} 2",
+                labels
+            ),
+            format!(
+                "websockify_connect_duration_seconds_sum{{{}}} 20.02",
+                labels
+            ),
+            "# TYPE websockify_session_duration_seconds histogram".to_owned(),
+        ] {
+            assert!(text.lines().any(|l| l == line), "no {} in\n{}", line, text);
+        }
+
+        drop(session);
+        let text = metrics.render();
+        assert!(text.contains(&format!("websockify_sessions_active{{{}}} 0", labels)));
+        assert!(text.contains(&format!("websockify_sessions_total{{{}}} 1", labels)));
+        assert!(text.contains(&format!(
+            "websockify_session_duration_seconds_count{{{}}} 1",
+            labels
+        )));
+    }
+}
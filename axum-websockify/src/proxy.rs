@@ -0,0 +1,352 @@
+// Relay between a websocket client and a TCP server, like websockify
+// https://github.com/novnc/websockify
+//
+// Besides binary data a client may send the text message "SSL" to have the
+// bridge terminate TLS with the server, as browsers cannot. The bridge runs
+// the handshake, answers with a single binary message holding the DER
+// certificate of the server behind its length as a big endian u32, and
+// relays plaintext from then on.
+//
+// A target configured with credentials is logged in to by the bridge, see
+// the login module, until then the data of both sides passes through it.
+// What the client sends and is sent may be recorded, see the record module.
+
+#[cfg(feature = "ssl")]
+use crate::login::credssp;
+use crate::login::Login;
+use crate::metrics::{self, TargetMetrics};
+use crate::record::Recorder;
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use std::io;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+const BUF_SIZE: usize = 64 * 1024;
+const START_TLS: &str = "SSL";
+
+enum Target {
+    Tcp(TcpStream),
+    #[cfg(feature = "ssl")]
+    Tls(tokio_openssl::SslStream<TcpStream>),
+}
+
+impl Target {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Target::Tcp(stream) => stream.read(buf).await,
+            #[cfg(feature = "ssl")]
+            Target::Tls(stream) => stream.read(buf).await,
+        }
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Target::Tcp(stream) => stream.write_all(data).await,
+            #[cfg(feature = "ssl")]
+            Target::Tls(stream) => {
+                stream.write_all(data).await?;
+                stream.flush().await
+            }
+        }
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            Target::Tcp(stream) => stream.shutdown().await,
+            #[cfg(feature = "ssl")]
+            Target::Tls(stream) => stream.shutdown().await,
+        }
+    }
+
+    /// Handshake with the server, returns the stream and the DER certificate
+    /// the server presented
+    #[cfg(feature = "ssl")]
+    async fn start_tls(self, host: &str) -> io::Result<(Self, Vec<u8>)> {
+        use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+        use std::pin::Pin;
+
+        let tcp = match self {
+            Target::Tcp(tcp) => tcp,
+            Target::Tls(_) => return Err(io::Error::other("TLS already started")),
+        };
+        // remote desktops mostly use self-signed certificates,
+        // the client checks the certificate it is sent
+        let mut connector = SslConnector::builder(SslMethod::tls()).map_err(io::Error::other)?;
+        connector.set_verify(SslVerifyMode::NONE);
+        let ssl = connector
+            .build()
+            .configure()
+            .map_err(io::Error::other)?
+            .verify_hostname(false)
+            .into_ssl(host)
+            .map_err(io::Error::other)?;
+        let mut stream = tokio_openssl::SslStream::new(ssl, tcp).map_err(io::Error::other)?;
+        Pin::new(&mut stream)
+            .connect()
+            .await
+            .map_err(io::Error::other)?;
+        let cert = stream
+            .ssl()
+            .peer_certificate()
+            .ok_or_else(|| io::Error::other("the server sent no certificate"))?
+            .to_der()
+            .map_err(io::Error::other)?;
+        Ok((Target::Tls(stream), cert))
+    }
+
+    #[cfg(not(feature = "ssl"))]
+    async fn start_tls(self, _host: &str) -> io::Result<(Self, Vec<u8>)> {
+        Err(io::Error::other("TLS requires the ssl feature"))
+    }
+}
+
+/// The websocket of a client, recorded when the gateway records
+pub struct Client {
+    socket: WebSocket,
+    recorder: Option<Recorder>,
+    session: metrics::Session,
+}
+
+impl Client {
+    pub fn new(
+        socket: WebSocket,
+        recorder: Option<Recorder>,
+        metrics: &Arc<TargetMetrics>,
+    ) -> Self {
+        Self {
+            socket,
+            recorder,
+            session: metrics.start(),
+        }
+    }
+
+    /// The next binary or text message, none once the client left,
+    /// cancel safe
+    pub async fn recv(&mut self) -> io::Result<Option<Message>> {
+        loop {
+            let message = self.socket.recv().await.transpose();
+            let message = match message.map_err(io::Error::other)? {
+                None | Some(Message::Close(_)) => return Ok(None),
+                // pings are answered by axum
+                Some(Message::Ping(_) | Message::Pong(_)) => continue,
+                Some(message) => message,
+            };
+            match &message {
+                Message::Binary(data) => self.session.received(data.len()),
+                Message::Text(command) => self.session.received(command.len()),
+                _ => {}
+            }
+            if let Some(recorder) = &self.recorder {
+                match &message {
+                    Message::Binary(data) => recorder.client(data),
+                    Message::Text(command) => recorder.command(command),
+                    _ => {}
+                }
+            }
+            return Ok(Some(message));
+        }
+    }
+
+    pub async fn send(&mut self, data: Vec<u8>) -> io::Result<()> {
+        if let Some(recorder) = &self.recorder {
+            recorder.server(&data);
+        }
+        self.session.sent(data.len());
+        self.socket
+            .send(Message::Binary(data))
+            .await
+            .map_err(io::Error::other)
+    }
+
+    pub async fn close(mut self) {
+        let _ = self.socket.send(Message::Close(None)).await;
+        if let Some(recorder) = self.recorder {
+            recorder.finish().await;
+        }
+    }
+}
+
+/// Relay the binary messages of `client` to `target` and back until
+/// either side closes, logging in with `login` first
+pub async fn relay(mut client: Client, target: String, login: Option<Login>) {
+    let tcp = match client.session.connect(&target).await {
+        Ok(tcp) => tcp,
+        Err(e) => {
+            warn!("Unable to connect to {}: {}", target, e);
+            client.close().await;
+            return;
+        }
+    };
+    // terminals and remote desktops send small interactive messages
+    let _ = tcp.set_nodelay(true);
+    info!("Relaying to {}", target);
+
+    match run(&mut client, Target::Tcp(tcp), &target, login).await {
+        Ok(()) => info!("Connection to {} closed", target),
+        Err(e) => warn!("Connection to {} failed: {}", target, e),
+    }
+    client.close().await;
+}
+
+/// Close `socket` with an application `code` without relaying anything
+pub async fn refuse(mut socket: WebSocket, code: u16, reason: &'static str) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    let _ = socket.send(Message::Close(Some(frame))).await;
+}
+
+// Pass on what the login has for either side
+async fn flush(login: &mut Login, client: &mut Client, stream: &mut Target) -> io::Result<()> {
+    let for_server = login.take_for_server();
+    if !for_server.is_empty() {
+        stream.write_all(&for_server).await?;
+    }
+    let for_client = login.take_for_client();
+    if !for_client.is_empty() {
+        client.send(for_client).await?;
+    }
+    Ok(())
+}
+
+async fn run(
+    client: &mut Client,
+    mut stream: Target,
+    target: &str,
+    mut login: Option<Login>,
+) -> io::Result<()> {
+    let mut buf = vec![0; BUF_SIZE];
+    loop {
+        tokio::select! {
+            msg = client.recv() => match msg? {
+                None => return stream.shutdown().await,
+                Some(Message::Binary(data)) => match &mut login {
+                    Some(l) => {
+                        // a failing login may still have a reason for the client
+                        let result = l.client_data(&data);
+                        flush(l, client, &mut stream).await?;
+                        result?;
+                    }
+                    None => stream.write_all(&data).await?,
+                },
+                Some(Message::Text(command)) if command == START_TLS => {
+                    let host = target.rsplit_once(':').map_or(target, |(host, _)| host);
+                    let (tls, cert) = stream.start_tls(host).await?;
+                    stream = tls;
+                    info!("TLS started with {}", target);
+                    #[cfg(feature = "ssl")]
+                    if let (Some(credentials), Target::Tls(tls)) =
+                        (login.as_ref().and_then(Login::nla), &mut stream)
+                    {
+                        credssp::authenticate(tls, &cert, credentials).await?;
+                        info!("Logged in to {} as {}", target, credentials.username);
+                    }
+                    let mut reply = (cert.len() as u32).to_be_bytes().to_vec();
+                    reply.extend_from_slice(&cert);
+                    client.send(reply).await?;
+                }
+                Some(Message::Text(command)) => warn!("Unknown command {}", command),
+                Some(_) => {}
+            },
+            n = stream.read(&mut buf) => {
+                let n = n?;
+                if n == 0 {
+                    return Ok(());
+                }
+                match &mut login {
+                    Some(l) => {
+                        let result = l.server_data(&buf[..n]);
+                        flush(l, client, &mut stream).await?;
+                        result?;
+                    }
+                    None => client.send(buf[..n].to_vec()).await?,
+                }
+            }
+        }
+        // relay without the login once it is over
+        if login.as_ref().is_some_and(Login::done) {
+            login = None;
+        }
+    }
+}
+
+#[cfg(all(test, feature = "ssl"))]
+mod test {
+    use crate::{app, Args, Gateway};
+    use clap::Parser;
+    use futures::{SinkExt, StreamExt};
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslAcceptor, SslMethod};
+    use openssl::x509::X509;
+    use std::future::IntoFuture;
+    use std::pin::Pin;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::{client::IntoClientRequest, http::HeaderValue, Message};
+
+    fn self_signed() -> (PKey<openssl::pkey::Private>, X509) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut builder = X509::builder().unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (key, builder.build())
+    }
+
+    #[tokio::test]
+    async fn test_start_tls() {
+        let (key, cert) = self_signed();
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        let acceptor = acceptor.build();
+
+        // a TLS echo server
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = server.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (tcp, _) = server.accept().await.unwrap();
+            let ssl = openssl::ssl::Ssl::new(acceptor.context()).unwrap();
+            let mut stream = tokio_openssl::SslStream::new(ssl, tcp).unwrap();
+            Pin::new(&mut stream).accept().await.unwrap();
+            let (mut read, mut write) = tokio::io::split(stream);
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+        });
+
+        let args = Args::parse_from(["axum-websockify", "0", &target, "--web", "."]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = app(Gateway::from_args(&args).unwrap(), &args.web);
+        tokio::spawn(axum::serve(listener, app).into_future());
+
+        let mut request = format!("ws://{}/websockify", addr)
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert("Sec-WebSocket-Protocol", HeaderValue::from_static("binary"));
+        let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+        ws.send(Message::Text("SSL".to_owned())).await.unwrap();
+        let der = cert.to_der().unwrap();
+        let mut expected = (der.len() as u32).to_be_bytes().to_vec();
+        expected.extend_from_slice(&der);
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::Binary(expected));
+
+        ws.send(Message::Binary(b"hello".to_vec())).await.unwrap();
+        assert_eq!(
+            ws.next().await.unwrap().unwrap(),
+            Message::Binary(b"hello".to_vec())
+        );
+    }
+}
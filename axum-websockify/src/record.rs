@@ -0,0 +1,362 @@
+// Recordings of the relayed sessions, for audits
+//
+// Every websocket relayed while `--record <dir>` is given is written to
+// `<dir>/<id>.rec`: a JSON header line telling who connected where, then the
+// messages of both directions as they crossed the gateway,
+//
+//     direction u8 | microseconds since the start u64 | length u32 | data
+//
+// all big endian, where the direction is one of CLIENT, SERVER and COMMAND.
+// The messages are what the client sent and received, after the gateway
+// terminated TLS or logged in, so `/websockify/replay/<id>` plays the
+// session to the unmodified pages: `vnc.html?target=replay/<id>`.
+//
+// A client that authenticated itself with NLA cannot be replayed, CredSSP
+// binds to keys of the live session; the gateway login of the config avoids
+// that.
+
+use crate::metrics::Labels;
+use axum::extract::ws::{Message, WebSocket};
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+// Directions
+const CLIENT: u8 = 0;
+const SERVER: u8 = 1;
+// text messages of the client, like the SSL command
+const COMMAND: u8 = 2;
+
+const EXTENSION: &str = "rec";
+// Whole screens of the shared sessions at most
+const MAX_RECORD: usize = 256 * 1024 * 1024;
+
+/// Who connected where
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// The name of the target, or its address for tokens
+    pub target: String,
+    #[serde(default)]
+    pub protocol: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Seconds since the unix epoch
+    pub start: u64,
+}
+
+impl Header {
+    pub fn labels(&self) -> Labels {
+        Labels {
+            target: self.target.clone(),
+            protocol: self.protocol.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// What `/recordings` lists
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct RecordingInfo {
+    pub id: String,
+    #[serde(flatten)]
+    pub header: Header,
+}
+
+/// The directory of the recordings
+#[derive(Clone)]
+pub struct Recordings {
+    dir: PathBuf,
+    sequence: Arc<AtomicU64>,
+}
+
+// Ids end up in urls
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+impl Recordings {
+    pub fn new(dir: &Path) -> Result<Self, String> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("unable to create {}: {}", dir.display(), e))?;
+        Ok(Self {
+            dir: dir.to_owned(),
+            sequence: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(id).with_extension(EXTENSION)
+    }
+
+    /// Start recording a session
+    pub async fn create(&self, header: Header) -> io::Result<Recorder> {
+        let mut line = serde_json::to_vec(&header)?;
+        line.push(b'\n');
+        // a restarted gateway numbers from 0 again
+        loop {
+            let n = self.sequence.fetch_add(1, Ordering::Relaxed);
+            let id = format!("{}-{}", header.start, n);
+            let file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.path(&id))
+                .await;
+            let file = match file {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            };
+            info!("Recording the session to {} as {}", header.target, id);
+            let mut file = BufWriter::new(file);
+            file.write_all(&line).await?;
+            let (records, receiver) = mpsc::unbounded_channel();
+            let writer = tokio::spawn(async move {
+                if let Err(e) = write(file, receiver).await {
+                    warn!("Unable to record the session {}: {}", id, e);
+                }
+            });
+            return Ok(Recorder {
+                records,
+                writer,
+                start: Instant::now(),
+            });
+        }
+    }
+
+    /// The recordings, oldest first
+    pub fn list(&self) -> io::Result<Vec<RecordingInfo>> {
+        let mut recordings = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let mut line = String::new();
+            let file = std::fs::File::open(&path)?;
+            io::BufRead::read_line(&mut io::BufReader::new(file), &mut line)?;
+            match serde_json::from_str(&line) {
+                Ok(header) => recordings.push(RecordingInfo {
+                    id: id.to_owned(),
+                    header,
+                }),
+                Err(e) => warn!("Skipping the recording {}: {}", path.display(), e),
+            }
+        }
+        recordings.sort_by(|a, b| (a.header.start, &a.id).cmp(&(b.header.start, &b.id)));
+        Ok(recordings)
+    }
+
+    /// Open the recording `id` for replay, none if there is no such
+    pub async fn open(&self, id: &str) -> io::Result<Option<Player>> {
+        if !valid_id(id) {
+            return Ok(None);
+        }
+        let file = match File::open(self.path(id)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut file = BufReader::new(file);
+        let mut line = String::new();
+        file.read_line(&mut line).await?;
+        let header = serde_json::from_str(&line)?;
+        Ok(Some(Player { file, header }))
+    }
+}
+
+/// Writes a session as it goes
+///
+/// The messages are written by a task of their own, recording them never
+/// waits, so the relay can record what it received in a `select!`.
+pub struct Recorder {
+    records: mpsc::UnboundedSender<(u8, Duration, Vec<u8>)>,
+    writer: JoinHandle<()>,
+    start: Instant,
+}
+
+async fn write(
+    mut file: BufWriter<File>,
+    mut records: mpsc::UnboundedReceiver<(u8, Duration, Vec<u8>)>,
+) -> io::Result<()> {
+    while let Some((direction, at, data)) = records.recv().await {
+        file.write_u8(direction).await?;
+        file.write_u64(at.as_micros() as u64).await?;
+        file.write_u32(data.len() as u32).await?;
+        file.write_all(&data).await?;
+        // keep what happened before a crash
+        if records.is_empty() {
+            file.flush().await?;
+        }
+    }
+    file.flush().await
+}
+
+impl Recorder {
+    fn record(&self, direction: u8, data: &[u8]) {
+        let _ = self
+            .records
+            .send((direction, self.start.elapsed(), data.to_vec()));
+    }
+
+    /// What the client sent
+    pub fn client(&self, data: &[u8]) {
+        self.record(CLIENT, data)
+    }
+
+    /// What the client was sent
+    pub fn server(&self, data: &[u8]) {
+        self.record(SERVER, data)
+    }
+
+    pub fn command(&self, command: &str) {
+        self.record(COMMAND, command.as_bytes())
+    }
+
+    /// Wait for the messages to be written
+    pub async fn finish(self) {
+        drop(self.records);
+        let _ = self.writer.await;
+    }
+}
+
+/// Reads a recording back
+pub struct Player {
+    file: BufReader<File>,
+    header: Header,
+}
+
+impl Player {
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The next message, its direction and when it was relayed
+    async fn next(&mut self) -> io::Result<Option<(u8, Duration, Vec<u8>)>> {
+        let direction = match self.file.read_u8().await {
+            Ok(direction) => direction,
+            // a recording ends between messages
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let at = Duration::from_micros(self.file.read_u64().await?);
+        let len = self.file.read_u32().await? as usize;
+        if len > MAX_RECORD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "recorded message too long",
+            ));
+        }
+        let mut data = vec![0; len];
+        self.file.read_exact(&mut data).await?;
+        Ok(Some((direction, at, data)))
+    }
+
+    async fn play(&mut self, sink: &mut SplitSink<WebSocket, Message>) -> io::Result<()> {
+        let start = Instant::now();
+        while let Some((direction, at, data)) = self.next().await? {
+            if direction != SERVER {
+                continue;
+            }
+            tokio::time::sleep_until(start + at).await;
+            sink.send(Message::Binary(data))
+                .await
+                .map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    /// Send the client what the recorded one was sent, in its own time
+    pub async fn replay(mut self, socket: WebSocket) {
+        let (mut sink, mut stream) = socket.split();
+        let result = tokio::select! {
+            result = self.play(&mut sink) => result,
+            // whatever else the client says is ignored
+            _ = wait_close(&mut stream) => Ok(()),
+        };
+        match result {
+            Ok(()) => info!("Replayed the session to {}", self.header.target),
+            Err(e) => warn!(
+                "Replaying the session to {} failed: {}",
+                self.header.target, e
+            ),
+        }
+        let _ = sink.send(Message::Close(None)).await;
+    }
+}
+
+async fn wait_close(stream: &mut SplitStream<WebSocket>) {
+    while let Some(Ok(message)) = stream.next().await {
+        if let Message::Close(_) = message {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record() {
+        let dir = std::env::temp_dir().join(format!("axum-websockify-{}", std::process::id()));
+        let recordings = Recordings::new(&dir).unwrap();
+        let header = Header {
+            target: "desktop".to_owned(),
+            protocol: Some("vnc".to_owned()),
+            user: None,
+            start: 1700000000,
+        };
+        let recorder = recordings.create(header.clone()).await.unwrap();
+        recorder.server(b"RFB 003.008\n");
+        recorder.client(b"RFB 003.003\n");
+        recorder.command("SSL");
+        recorder.finish().await;
+        // another session of the same second
+        recordings
+            .create(header.clone())
+            .await
+            .unwrap()
+            .finish()
+            .await;
+
+        let list = recordings.list().unwrap();
+        assert_eq!(
+            list.iter().map(|r| r.id.as_str()).collect::<Vec<_>>(),
+            ["1700000000-0", "1700000000-1"]
+        );
+        assert_eq!(list[0].header, header);
+
+        let mut player = recordings.open("1700000000-0").await.unwrap().unwrap();
+        assert_eq!(player.header(), &header);
+        let (direction, _, data) = player.next().await.unwrap().unwrap();
+        assert_eq!(
+            (direction, data.as_slice()),
+            (SERVER, &b"RFB 003.008\n"[..])
+        );
+        let (direction, at, _) = player.next().await.unwrap().unwrap();
+        assert_eq!(direction, CLIENT);
+        assert!(at < Duration::from_secs(1));
+        assert_eq!(player.next().await.unwrap().unwrap().0, COMMAND);
+        assert!(player.next().await.unwrap().is_none());
+
+        assert!(recordings.open("missing").await.unwrap().is_none());
+        assert!(recordings.open("../etc/passwd").await.unwrap().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
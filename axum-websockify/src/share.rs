@@ -0,0 +1,835 @@
+// Share one VNC connection between the clients of a target
+// https://datatracker.ietf.org/doc/html/rfc6143
+//
+// The gateway is the only RFB client of the server and an RFB server to
+// every client. The first client controls the session, its input goes to
+// the server; the others only watch, and learn it from the subprotocol the
+// gateway answers them with. The gateway keeps a copy of the framebuffer for
+// the clients joining late, so it asks the server for the encodings it can
+// apply only: raw, copy rect and desktop size.
+//
+// The pixel format of the session is the first one a client sets, clients
+// setting another one are refused.
+
+use crate::config::Target;
+use crate::login::vnc::{
+    parse_version, version, vnc_response, SECURITY_NONE, SECURITY_VNC, VERSION_LEN,
+};
+use crate::metrics::TargetMetrics;
+use crate::proxy::Client;
+use axum::extract::ws::Message;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{tcp::OwnedReadHalf, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, watch};
+use tracing::{info, warn};
+
+/// The subprotocol the clients that only watch are answered with
+pub const VIEW_ONLY_PROTOCOL: &str = "binary.view-only";
+
+// Client messages
+const SET_PIXEL_FORMAT: u8 = 0;
+const SET_ENCODINGS: u8 = 2;
+const FRAMEBUFFER_UPDATE_REQUEST: u8 = 3;
+const KEY_EVENT: u8 = 4;
+const POINTER_EVENT: u8 = 5;
+const CLIENT_CUT_TEXT: u8 = 6;
+
+// Server messages
+const FRAMEBUFFER_UPDATE: u8 = 0;
+const SET_COLOUR_MAP_ENTRIES: u8 = 1;
+const BELL: u8 = 2;
+const SERVER_CUT_TEXT: u8 = 3;
+
+// Encodings
+const ENCODING_RAW: i32 = 0;
+const ENCODING_COPY_RECT: i32 = 1;
+const ENCODING_DESKTOP_SIZE: i32 = -223;
+
+const PIXEL_FORMAT_LEN: usize = 16;
+const SET_PIXEL_FORMAT_LEN: usize = 4 + PIXEL_FORMAT_LEN;
+// Updates a client may lag behind before it is sent the whole screen again
+const BACKLOG: usize = 64;
+// Cut texts, reasons and names
+const MAX_TEXT: usize = 1024 * 1024;
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_owned())
+}
+
+fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([data[at], data[at + 1]])
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn rect(x: u16, y: u16, width: u16, height: u16, encoding: i32) -> Vec<u8> {
+    [x, y, width, height]
+        .iter()
+        .flat_map(|n| n.to_be_bytes())
+        .chain(encoding.to_be_bytes())
+        .collect()
+}
+
+#[derive(Default)]
+struct Screen {
+    width: u16,
+    height: u16,
+    // bits per pixel, depth, big endian, true colour, then the colours
+    format: [u8; PIXEL_FORMAT_LEN],
+    // a client set the format, updates are asked for
+    settled: bool,
+    name: Vec<u8>,
+    pixels: Vec<u8>,
+}
+
+impl Screen {
+    fn new(width: u16, height: u16, format: [u8; PIXEL_FORMAT_LEN], name: Vec<u8>) -> Self {
+        let mut screen = Self {
+            format,
+            name,
+            ..Default::default()
+        };
+        screen.resize(width, height);
+        screen
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        (self.format[0] / 8) as usize
+    }
+
+    fn resize(&mut self, width: u16, height: u16) {
+        self.width = width;
+        self.height = height;
+        self.pixels = vec![0; width as usize * height as usize * self.bytes_per_pixel()];
+    }
+
+    fn contains(&self, x: u16, y: u16, width: u16, height: u16) -> bool {
+        x as u32 + width as u32 <= self.width as u32
+            && y as u32 + height as u32 <= self.height as u32
+    }
+
+    fn server_init(&self) -> Vec<u8> {
+        let mut init = Vec::new();
+        init.extend_from_slice(&self.width.to_be_bytes());
+        init.extend_from_slice(&self.height.to_be_bytes());
+        init.extend_from_slice(&self.format);
+        init.extend_from_slice(&(self.name.len() as u32).to_be_bytes());
+        init.extend_from_slice(&self.name);
+        init
+    }
+
+    /// The whole screen as a framebuffer update
+    fn snapshot(&self) -> Vec<u8> {
+        let (width, height) = (self.width, self.height);
+        let mut update = vec![FRAMEBUFFER_UPDATE, 0, 0, 2];
+        // the size may have changed since the client was told
+        update.extend(rect(0, 0, width, height, ENCODING_DESKTOP_SIZE));
+        update.extend(rect(0, 0, width, height, ENCODING_RAW));
+        update.extend_from_slice(&self.pixels);
+        update
+    }
+
+    fn update_request(&self, incremental: bool) -> Vec<u8> {
+        let mut request = vec![FRAMEBUFFER_UPDATE_REQUEST, incremental as u8, 0, 0, 0, 0];
+        request.extend_from_slice(&self.width.to_be_bytes());
+        request.extend_from_slice(&self.height.to_be_bytes());
+        request
+    }
+
+    /// Fix the pixel format of the session, returns what to tell the server
+    fn settle(&mut self, format: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        let mut message = Vec::new();
+        match format {
+            Some(format) if format == self.format => {}
+            Some(format) => {
+                // a colour map would have to be kept too
+                if ![8, 16, 32].contains(&format[0]) || format[3] == 0 {
+                    return Err(invalid("only true colour formats are shared"));
+                }
+                self.format.copy_from_slice(format);
+                self.resize(self.width, self.height);
+                message.extend_from_slice(&[SET_PIXEL_FORMAT, 0, 0, 0]);
+                message.extend_from_slice(format);
+            }
+            None => {}
+        }
+        self.settled = true;
+        message.extend(self.update_request(false));
+        Ok(message)
+    }
+
+    fn apply(&mut self, update: &Update, message: &[u8]) {
+        let bpp = self.bytes_per_pixel();
+        let stride = self.width as usize * bpp;
+        match *update {
+            Update::Raw {
+                x,
+                y,
+                width,
+                height,
+                at,
+            } => {
+                let row = width as usize * bpp;
+                for line in 0..height as usize {
+                    let to = (y as usize + line) * stride + x as usize * bpp;
+                    let from = at + line * row;
+                    self.pixels[to..to + row].copy_from_slice(&message[from..from + row]);
+                }
+            }
+            Update::Copy {
+                x,
+                y,
+                width,
+                height,
+                src_x,
+                src_y,
+            } => {
+                let row = width as usize * bpp;
+                // the areas may overlap
+                let mut area = Vec::with_capacity(row * height as usize);
+                for line in 0..height as usize {
+                    let from = (src_y as usize + line) * stride + src_x as usize * bpp;
+                    area.extend_from_slice(&self.pixels[from..from + row]);
+                }
+                for (line, data) in area.chunks_exact(row.max(1)).enumerate() {
+                    let to = (y as usize + line) * stride + x as usize * bpp;
+                    self.pixels[to..to + row].copy_from_slice(data);
+                }
+            }
+            Update::Resize { width, height } => self.resize(width, height),
+        }
+    }
+}
+
+// A rectangle of a framebuffer update, checked against the screen
+enum Update {
+    // the pixels are in the message from `at`
+    Raw {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        at: usize,
+    },
+    Copy {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        src_x: u16,
+        src_y: u16,
+    },
+    Resize {
+        width: u16,
+        height: u16,
+    },
+}
+
+// Read `n` more bytes of `message`, returns where they start
+async fn read_more<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    message: &mut Vec<u8>,
+    n: usize,
+) -> io::Result<usize> {
+    let at = message.len();
+    message.resize(at + n, 0);
+    stream.read_exact(&mut message[at..]).await?;
+    Ok(at)
+}
+
+async fn read_text<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<String> {
+    let len = stream.read_u32().await? as usize;
+    if len > MAX_TEXT {
+        return Err(invalid("text too long"));
+    }
+    let mut text = vec![0; len];
+    stream.read_exact(&mut text).await?;
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+/// Read a message of the server, `screen` is what the server knows
+async fn read_message<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    mut screen: Screen,
+) -> io::Result<(Vec<u8>, Vec<Update>)> {
+    let mut message = Vec::new();
+    let mut updates = Vec::new();
+    read_more(stream, &mut message, 1).await?;
+    match message[0] {
+        FRAMEBUFFER_UPDATE => {
+            read_more(stream, &mut message, 3).await?;
+            for _ in 0..u16_at(&message, 2) {
+                let at = read_more(stream, &mut message, 12).await?;
+                let [x, y, width, height] = [0, 2, 4, 6].map(|i| u16_at(&message, at + i));
+                let encoding = u32_at(&message, at + 8) as i32;
+                if encoding == ENCODING_DESKTOP_SIZE {
+                    screen.width = width;
+                    screen.height = height;
+                    updates.push(Update::Resize { width, height });
+                    continue;
+                }
+                if !screen.contains(x, y, width, height) {
+                    return Err(invalid("rectangle out of the screen"));
+                }
+                match encoding {
+                    ENCODING_RAW => {
+                        let len = width as usize * height as usize * screen.bytes_per_pixel();
+                        let at = read_more(stream, &mut message, len).await?;
+                        updates.push(Update::Raw {
+                            x,
+                            y,
+                            width,
+                            height,
+                            at,
+                        });
+                    }
+                    ENCODING_COPY_RECT => {
+                        let at = read_more(stream, &mut message, 4).await?;
+                        let (src_x, src_y) = (u16_at(&message, at), u16_at(&message, at + 2));
+                        if !screen.contains(src_x, src_y, width, height) {
+                            return Err(invalid("rectangle out of the screen"));
+                        }
+                        updates.push(Update::Copy {
+                            x,
+                            y,
+                            width,
+                            height,
+                            src_x,
+                            src_y,
+                        });
+                    }
+                    _ => return Err(invalid("unexpected encoding")),
+                }
+            }
+        }
+        SET_COLOUR_MAP_ENTRIES => {
+            read_more(stream, &mut message, 5).await?;
+            let count = u16_at(&message, 4) as usize;
+            read_more(stream, &mut message, 6 * count).await?;
+        }
+        BELL => {}
+        SERVER_CUT_TEXT => {
+            read_more(stream, &mut message, 7).await?;
+            let len = u32_at(&message, 4) as usize;
+            if len > MAX_TEXT {
+                return Err(invalid("cut text too long"));
+            }
+            read_more(stream, &mut message, len).await?;
+        }
+        _ => return Err(invalid("unknown server message")),
+    }
+    Ok((message, updates))
+}
+
+/// Take a whole client message off the front of `buf`
+fn client_message(buf: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+    let Some(&kind) = buf.first() else {
+        return Ok(None);
+    };
+    let len = match kind {
+        SET_PIXEL_FORMAT => SET_PIXEL_FORMAT_LEN,
+        SET_ENCODINGS if buf.len() >= 4 => 4 + 4 * u16_at(buf, 2) as usize,
+        FRAMEBUFFER_UPDATE_REQUEST => 10,
+        KEY_EVENT => 8,
+        POINTER_EVENT => 6,
+        CLIENT_CUT_TEXT if buf.len() >= 8 => match u32_at(buf, 4) as usize {
+            len if len > MAX_TEXT => return Err(invalid("cut text too long")),
+            len => 8 + len,
+        },
+        SET_ENCODINGS | CLIENT_CUT_TEXT => return Ok(None),
+        _ => return Err(invalid("unknown client message")),
+    };
+    if buf.len() < len {
+        return Ok(None);
+    }
+    Ok(Some(buf.drain(..len).collect()))
+}
+
+/// Log in to the server as a shared client
+async fn handshake(stream: &mut TcpStream, password: Option<&str>) -> io::Result<Screen> {
+    let mut server_version = [0; VERSION_LEN];
+    stream.read_exact(&mut server_version).await?;
+    let minor = parse_version(&server_version)?;
+    stream.write_all(&version(minor)).await?;
+
+    let refused = |reason: String| io::Error::other(format!("VNC login failed: {}", reason));
+    let security = if minor == 3 {
+        match stream.read_u32().await? {
+            0 => return Err(refused(read_text(stream).await?)),
+            security => security.try_into().unwrap_or(u8::MAX),
+        }
+    } else {
+        let count = stream.read_u8().await? as usize;
+        if count == 0 {
+            return Err(refused(read_text(stream).await?));
+        }
+        let mut types = vec![0; count];
+        stream.read_exact(&mut types).await?;
+        let security = if types.contains(&SECURITY_NONE) {
+            SECURITY_NONE
+        } else if types.contains(&SECURITY_VNC) {
+            SECURITY_VNC
+        } else {
+            return Err(refused("no supported security type".to_owned()));
+        };
+        stream.write_u8(security).await?;
+        security
+    };
+    match security {
+        SECURITY_NONE => {}
+        SECURITY_VNC => {
+            let password = password.ok_or_else(|| {
+                io::Error::other("the server asks for a password, give the target a login")
+            })?;
+            let mut challenge = [0; 16];
+            stream.read_exact(&mut challenge).await?;
+            stream
+                .write_all(&vnc_response(password, &challenge))
+                .await?;
+        }
+        _ => return Err(refused("no supported security type".to_owned())),
+    }
+    // 3.8 confirms even no authentication
+    if (security == SECURITY_VNC || minor == 8) && stream.read_u32().await? != 0 {
+        let reason = match minor {
+            8 => read_text(stream).await?,
+            _ => "authentication failed".to_owned(),
+        };
+        return Err(refused(reason));
+    }
+
+    // ClientInit, sharing the desktop with other clients of the server
+    stream.write_u8(1).await?;
+    let width = stream.read_u16().await?;
+    let height = stream.read_u16().await?;
+    let mut format = [0; PIXEL_FORMAT_LEN];
+    stream.read_exact(&mut format).await?;
+    let name = read_text(stream).await?;
+    Ok(Screen::new(width, height, format, name.into_bytes()))
+}
+
+fn set_encodings() -> Vec<u8> {
+    let encodings = [ENCODING_COPY_RECT, ENCODING_RAW, ENCODING_DESKTOP_SIZE];
+    let mut message = vec![SET_ENCODINGS, 0, 0, encodings.len() as u8];
+    for encoding in encodings {
+        message.extend_from_slice(&encoding.to_be_bytes());
+    }
+    message
+}
+
+// What the server task and the clients share
+struct Shared {
+    screen: Mutex<Screen>,
+    // server messages as they came
+    updates: broadcast::Sender<Arc<[u8]>>,
+}
+
+/// The connection to a server, alive as long as a client is
+pub struct Session {
+    shared: Arc<Shared>,
+    to_server: mpsc::UnboundedSender<Vec<u8>>,
+    // whether the server is logged in to, closed when the connection is over
+    running: watch::Receiver<bool>,
+    owned: AtomicBool,
+}
+
+impl Session {
+    fn start(target: &Target, metrics: Arc<TargetMetrics>) -> Arc<Self> {
+        let shared = Arc::new(Shared {
+            screen: Mutex::new(Screen::default()),
+            updates: broadcast::channel(BACKLOG).0,
+        });
+        let (to_server, from_clients) = mpsc::unbounded_channel();
+        let (running_sender, running) = watch::channel(false);
+        let address = target.address.clone();
+        let password = target.login.as_ref().map(|login| login.password.clone());
+        let task_shared = shared.clone();
+        tokio::spawn(async move {
+            let password = password.as_deref();
+            match serve(
+                &address,
+                password,
+                &metrics,
+                task_shared,
+                from_clients,
+                &running_sender,
+            )
+            .await
+            {
+                Ok(()) => info!("Shared session with {} closed", address),
+                Err(e) => warn!("Shared session with {} failed: {}", address, e),
+            }
+        });
+        Arc::new(Self {
+            shared,
+            to_server,
+            running,
+            owned: AtomicBool::new(false),
+        })
+    }
+
+    fn closed(&self) -> bool {
+        self.running.has_changed().is_err()
+    }
+}
+
+async fn serve(
+    address: &str,
+    password: Option<&str>,
+    metrics: &TargetMetrics,
+    shared: Arc<Shared>,
+    mut from_clients: mpsc::UnboundedReceiver<Vec<u8>>,
+    running: &watch::Sender<bool>,
+) -> io::Result<()> {
+    let mut tcp = metrics.connect(address).await?;
+    let _ = tcp.set_nodelay(true);
+    let screen = handshake(&mut tcp, password).await?;
+    info!("Sharing {}, {}x{}", address, screen.width, screen.height);
+    *shared.screen.lock().unwrap() = screen;
+    tcp.write_all(&set_encodings()).await?;
+    running.send_replace(true);
+
+    let (read, mut write) = tcp.into_split();
+    let (requests, mut from_reader) = mpsc::unbounded_channel();
+    let mut reader = tokio::spawn(read_updates(read, shared, requests));
+    let result = loop {
+        tokio::select! {
+            message = from_clients.recv() => match message {
+                Some(message) => write.write_all(&message).await?,
+                // the last client left
+                None => break Ok(()),
+            },
+            Some(request) = from_reader.recv() => write.write_all(&request).await?,
+            result = &mut reader => break result?,
+        }
+    };
+    reader.abort();
+    result
+}
+
+// Apply the updates of the server and pass them on
+async fn read_updates(
+    read: OwnedReadHalf,
+    shared: Arc<Shared>,
+    requests: mpsc::UnboundedSender<Vec<u8>>,
+) -> io::Result<()> {
+    let mut read = BufReader::new(read);
+    loop {
+        let known = {
+            let screen = shared.screen.lock().unwrap();
+            Screen {
+                width: screen.width,
+                height: screen.height,
+                format: screen.format,
+                ..Default::default()
+            }
+        };
+        let (message, updates) = read_message(&mut read, known).await?;
+        let mut screen = shared.screen.lock().unwrap();
+        for update in &updates {
+            screen.apply(update, &message);
+        }
+        // keep the updates coming
+        if message[0] == FRAMEBUFFER_UPDATE {
+            let _ = requests.send(screen.update_request(true));
+        }
+        // under the lock, the clients subscribe with a snapshot
+        let _ = shared.updates.send(message.into());
+    }
+}
+
+/// The sessions by address of the server
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Weak<Session>>>,
+}
+
+impl Sessions {
+    /// Join the session with `target`, the first client starts it
+    pub fn join(&self, target: &Target, metrics: &Arc<TargetMetrics>) -> Viewer {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.strong_count() > 0);
+        let session = match sessions.get(&target.address).and_then(Weak::upgrade) {
+            Some(session) if !session.closed() => session,
+            _ => {
+                let session = Session::start(target, metrics.clone());
+                sessions.insert(target.address.clone(), Arc::downgrade(&session));
+                session
+            }
+        };
+        // the control is free again once its client left
+        let owner = !session.owned.swap(true, Ordering::SeqCst);
+        Viewer { session, owner }
+    }
+}
+
+/// A client of a shared session
+pub struct Viewer {
+    session: Arc<Session>,
+    owner: bool,
+}
+
+impl Drop for Viewer {
+    fn drop(&mut self) {
+        if self.owner {
+            self.session.owned.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+// The RFB stream of a websocket
+struct Stream<'a> {
+    client: &'a mut Client,
+    buf: Vec<u8>,
+}
+
+impl Stream<'_> {
+    /// Wait for data, false once the client left
+    async fn recv(&mut self) -> io::Result<bool> {
+        loop {
+            match self.client.recv().await? {
+                None => return Ok(false),
+                Some(Message::Binary(data)) => {
+                    self.buf.extend(data);
+                    return Ok(true);
+                }
+                // no commands in a shared session
+                Some(_) => {}
+            }
+        }
+    }
+
+    async fn read_exact(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        while self.buf.len() < n {
+            if !self.recv().await? {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(self.buf.drain(..n).collect())
+    }
+
+    async fn send(&mut self, data: Vec<u8>) -> io::Result<()> {
+        self.client.send(data).await
+    }
+
+    /// Welcome the client without authentication, it is past the gateway
+    async fn handshake(&mut self, screen: &Mutex<Screen>) -> io::Result<()> {
+        self.send(version(8)).await?;
+        let minor = parse_version(&self.read_exact(VERSION_LEN).await?)?;
+        if minor == 3 {
+            self.send((SECURITY_NONE as u32).to_be_bytes().to_vec())
+                .await?;
+        } else {
+            self.send(vec![1, SECURITY_NONE]).await?;
+            if self.read_exact(1).await? != [SECURITY_NONE] {
+                return Err(invalid("the client must choose no authentication"));
+            }
+            if minor == 8 {
+                self.send(0u32.to_be_bytes().to_vec()).await?;
+            }
+        }
+        // ClientInit, the session is shared anyway
+        self.read_exact(1).await?;
+        let init = screen.lock().unwrap().server_init();
+        self.send(init).await
+    }
+}
+
+async fn recv_update(
+    updates: &mut Option<broadcast::Receiver<Arc<[u8]>>>,
+) -> Result<Arc<[u8]>, RecvError> {
+    match updates {
+        Some(updates) => updates.recv().await,
+        // nothing until the client asks
+        None => std::future::pending().await,
+    }
+}
+
+impl Viewer {
+    /// Whether the input of the client goes to the server
+    pub fn owner(&self) -> bool {
+        self.owner
+    }
+
+    /// Serve `client` until it or the server leaves
+    pub async fn run(self, mut client: Client) {
+        let role = if self.owner { "controls" } else { "watches" };
+        info!("A client {} the shared session", role);
+        match self.serve(&mut client).await {
+            Ok(()) => info!("A client left the shared session"),
+            Err(e) => warn!("A client of the shared session failed: {}", e),
+        }
+        client.close().await;
+    }
+
+    /// The whole screen, the updates that follow it go to the client
+    fn subscribe(&self, updates: &mut Option<broadcast::Receiver<Arc<[u8]>>>) -> Vec<u8> {
+        let shared = &self.session.shared;
+        let mut screen = shared.screen.lock().unwrap();
+        if !screen.settled {
+            // the format stays the one of the server
+            let request = screen.settle(None).expect("no format to check");
+            let _ = self.session.to_server.send(request);
+        }
+        *updates = Some(shared.updates.subscribe());
+        screen.snapshot()
+    }
+
+    async fn serve(&self, client: &mut Client) -> io::Result<()> {
+        let mut running = self.session.running.clone();
+        running
+            .wait_for(|running| *running)
+            .await
+            .map_err(|_| io::Error::other("the server is gone"))?;
+        let mut client = Stream {
+            client,
+            buf: Vec::new(),
+        };
+        client.handshake(&self.session.shared.screen).await?;
+
+        let mut updates = None;
+        loop {
+            tokio::select! {
+                data = client.recv() => {
+                    if !data? {
+                        return Ok(());
+                    }
+                    while let Some(message) = client_message(&mut client.buf)? {
+                        if let Some(reply) = self.handle(&message, &mut updates)? {
+                            client.send(reply).await?;
+                        }
+                    }
+                }
+                update = recv_update(&mut updates) => match update {
+                    Ok(update) => client.send(update.to_vec()).await?,
+                    Err(RecvError::Lagged(_)) => {
+                        let snapshot = self.subscribe(&mut updates);
+                        client.send(snapshot).await?;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                Err(_) = running.changed() => {
+                    return Err(io::Error::other("the server closed the session"));
+                }
+            }
+        }
+    }
+
+    // Returns what to answer the client
+    fn handle(
+        &self,
+        message: &[u8],
+        updates: &mut Option<broadcast::Receiver<Arc<[u8]>>>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        match message[0] {
+            SET_PIXEL_FORMAT => {
+                let format = &message[4..];
+                let mut screen = self.session.shared.screen.lock().unwrap();
+                if !screen.settled {
+                    let request = screen.settle(Some(format))?;
+                    let _ = self.session.to_server.send(request);
+                } else if format != screen.format {
+                    return Err(invalid("the shared session uses another pixel format"));
+                }
+            }
+            // the gateway picks them for every client
+            SET_ENCODINGS => {}
+            FRAMEBUFFER_UPDATE_REQUEST => {
+                let incremental = message[1] != 0;
+                if updates.is_none() || !incremental {
+                    return Ok(Some(self.subscribe(updates)));
+                }
+            }
+            _ if self.owner => {
+                let _ = self.session.to_server.send(message.to_vec());
+            }
+            // the input of those watching
+            _ => {}
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_client_message() {
+        let mut buf = vec![
+            KEY_EVENT,
+            1,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0x61,
+            SET_ENCODINGS,
+            0,
+            0,
+            2,
+            0,
+            0,
+        ];
+        assert_eq!(
+            client_message(&mut buf).unwrap().unwrap(),
+            [KEY_EVENT, 1, 0, 0, 0, 0, 0, 0x61]
+        );
+        assert_eq!(client_message(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        assert_eq!(client_message(&mut buf).unwrap().unwrap().len(), 12);
+        assert!(buf.is_empty());
+        assert!(client_message(&mut vec![9]).is_err());
+        assert!(client_message(&mut vec![CLIENT_CUT_TEXT, 0, 0, 0, 0xff, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut format = [0; PIXEL_FORMAT_LEN];
+        format[0] = 8;
+        format[3] = 1;
+        let mut screen = Screen::new(3, 2, format, Vec::new());
+        let mut message = vec![0; 4];
+        message.extend_from_slice(&[1, 2, 3, 4]);
+        let raw = Update::Raw {
+            x: 1,
+            y: 0,
+            width: 2,
+            height: 2,
+            at: 4,
+        };
+        screen.apply(&raw, &message);
+        assert_eq!(screen.pixels, [0, 1, 2, 0, 3, 4]);
+        // overlapping areas
+        let copy = Update::Copy {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 2,
+            src_x: 1,
+            src_y: 0,
+        };
+        screen.apply(&copy, &message);
+        assert_eq!(screen.pixels, [1, 2, 2, 3, 4, 4]);
+        screen.apply(
+            &Update::Resize {
+                width: 1,
+                height: 1,
+            },
+            &message,
+        );
+        assert_eq!(screen.pixels, [0]);
+        assert_eq!(
+            screen.snapshot()[4..16],
+            rect(0, 0, 1, 1, ENCODING_DESKTOP_SIZE)[..]
+        );
+    }
+}
@@ -0,0 +1,159 @@
+// Signed tokens naming the target of a connection
+//
+// A portal embedding the gateway signs `/websockify?token=<token>` urls,
+// the clients cannot pick any other target. A token is
+//
+//     base64url(claims) "." base64url(HMAC-SHA256(secret, base64url(claims)))
+//
+// without padding, where the claims are a JSON object:
+//
+//     {"target": "10.0.0.5:3389", "protocol": "rdp", "exp": 1700000000, "user": "alice"}
+//
+// `exp` is in seconds since the unix epoch, `protocol` and `user` are optional.
+// The target is an address or the name of a target of the config, whose
+// `login` the gateway then uses. A token with the target `replay/<id>` lets
+// the client watch that recording, one with the target `recordings` list them.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::Path;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Claims {
+    pub target: String,
+    #[serde(default)]
+    pub protocol: Option<String>,
+    pub exp: u64,
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenError {
+    Missing,
+    Invalid,
+    Expired,
+}
+
+impl TokenError {
+    /// The websocket close code telling the client why it was refused,
+    /// from the range left to applications
+    pub fn close_code(self) -> u16 {
+        match self {
+            TokenError::Missing => 4000,
+            TokenError::Invalid => 4001,
+            TokenError::Expired => 4002,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            TokenError::Missing => "token required",
+            TokenError::Invalid => "invalid token",
+            TokenError::Expired => "token expired",
+        }
+    }
+}
+
+/// The secret shared with the portal
+pub struct TokenKey {
+    secret: Vec<u8>,
+}
+
+impl TokenKey {
+    pub fn new(secret: &[u8]) -> Result<Self, String> {
+        // a guessable secret lets anyone reach any host
+        if secret.len() < 32 {
+            return Err("the token secret must be at least 32 bytes".to_owned());
+        }
+        Ok(Self {
+            secret: secret.to_vec(),
+        })
+    }
+
+    /// Read the secret from `path`, a trailing newline is not part of it
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let secret =
+            std::fs::read(path).map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+        let secret = secret.strip_suffix(b"\n").unwrap_or(&secret);
+        let secret = secret.strip_suffix(b"\r").unwrap_or(secret);
+        Self::new(secret).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn mac(&self, payload: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC takes any key size");
+        mac.update(payload);
+        mac
+    }
+
+    /// Check the signature and the expiry, `now` is in seconds since the unix epoch
+    pub fn verify(&self, token: &str, now: u64) -> Result<Claims, TokenError> {
+        let (payload, signature) = token.split_once('.').ok_or(TokenError::Invalid)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| TokenError::Invalid)?;
+        // constant time comparison
+        self.mac(payload.as_bytes())
+            .verify_slice(&signature)
+            .map_err(|_| TokenError::Invalid)?;
+        let claims = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| TokenError::Invalid)?;
+        let claims: Claims = serde_json::from_slice(&claims).map_err(|_| TokenError::Invalid)?;
+        if now >= claims.exp {
+            return Err(TokenError::Expired);
+        }
+        Ok(claims)
+    }
+
+    #[cfg(test)]
+    pub fn sign(&self, claims: &Claims) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap());
+        let signature = self.mac(payload.as_bytes()).finalize().into_bytes();
+        format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(signature))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        assert!(TokenKey::new(b"short").is_err());
+        let key = TokenKey::new(&[7; 32]).unwrap();
+        let claims = Claims {
+            target: "10.0.0.5:3389".to_owned(),
+            protocol: Some("rdp".to_owned()),
+            exp: 1000,
+            user: Some("alice".to_owned()),
+        };
+        let token = key.sign(&claims);
+        assert_eq!(key.verify(&token, 999), Ok(claims.clone()));
+        assert_eq!(key.verify(&token, 1000), Err(TokenError::Expired));
+
+        // another secret, another claim or a cut token
+        let other = TokenKey::new(&[8; 32]).unwrap();
+        assert_eq!(other.verify(&token, 0), Err(TokenError::Invalid));
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = Claims {
+            target: "10.0.0.6:22".to_owned(),
+            ..claims
+        };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+        let tampered = format!("{}.{}", payload, signature);
+        assert_eq!(key.verify(&tampered, 0), Err(TokenError::Invalid));
+        assert_eq!(key.verify(&token[..20], 0), Err(TokenError::Invalid));
+
+        // the minimal claims
+        let payload = URL_SAFE_NO_PAD.encode(br#"{"target":"host:22","exp":5}"#);
+        let signature = key.mac(payload.as_bytes()).finalize().into_bytes();
+        let token = format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(signature));
+        assert_eq!(key.verify(&token, 0).unwrap().user, None);
+    }
+}
@@ -6,4 +6,5 @@ else
     cargo make install-debug
 fi
 
-cd build && ./axum-websockify 8080 $2 --web `pwd` 
+shift
+cd build && ./axum-websockify 8080 "$@" --web `pwd` 
@@ -7,7 +7,7 @@ use rdp_ws::Rdp;
 use tracing::warn;
 use tracing_wasm::WASMLayerConfigBuilder;
 use wasm_bindgen::prelude::*;
-use wasm_bindgen_futures::spawn_local;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
 
 #[wasm_bindgen]
 extern "C" {
@@ -23,24 +23,84 @@ fn read_credentials(user: &mut String, password: &mut String, domain: &mut Strin
     *domain = prompt("Domain:");
 }
 
+// Whether the gateway holds the credentials of the target, from its /targets.
+// With a token it lists only the target of the token.
+async fn gateway_logs_in(target: Option<&str>, token: Option<&str>) -> Result<bool, JsValue> {
+    let window = web_sys::window().unwrap();
+    let url = match token {
+        Some(token) => format!("/targets?token={}", js_sys::encode_uri_component(token)),
+        None => "/targets".to_owned(),
+    };
+    let response: web_sys::Response = JsFuture::from(window.fetch_with_str(&url))
+        .await?
+        .dyn_into()?;
+    if !response.ok() {
+        return Ok(false);
+    }
+    let targets: js_sys::Array = JsFuture::from(response.json()?).await?.dyn_into()?;
+    let field = |target: &JsValue, name: &str| js_sys::Reflect::get(target, &name.into());
+    for target_info in targets.iter() {
+        let chosen = match (token, target) {
+            (Some(_), _) => true,
+            (None, Some(name)) => field(&target_info, "name")?.as_string().as_deref() == Some(name),
+            (None, None) => field(&target_info, "default")?.is_truthy(),
+        };
+        if chosen {
+            return Ok(field(&target_info, "login")?.is_truthy());
+        }
+    }
+    Ok(false)
+}
+
+// The target as a path of the url, replay/<id> names a recording
+fn encode_path(name: &str) -> String {
+    name.split('/')
+        .map(|segment| String::from(js_sys::encode_uri_component(segment)))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
 fn start_websocket() -> Result<(), JsValue> {
     // connect
+    let location = web_sys::window().unwrap().location();
+    let params = web_sys::UrlSearchParams::new_with_str(&location.search()?)?;
+    // ?target=<name> picks one of the targets of the gateway
+    let target = params.get("target");
+    let mut path = match &target {
+        Some(name) => format!("websockify/{}", encode_path(name)),
+        None => "websockify".to_owned(),
+    };
+    // a portal hands out pages with a signed token for the gateway
+    let token = params.get("token");
+    if let Some(token) = &token {
+        path = format!("{}?token={}", path, js_sys::encode_uri_component(token));
+    }
     let url = format!(
-        "{scheme}://{host}/websockify",
-        scheme = if web_sys::window()
-            .unwrap()
-            .location()
-            .protocol()?
-            .starts_with("https")
-        {
+        "{scheme}://{host}/{path}",
+        scheme = if location.protocol()?.starts_with("https") {
             "wss"
         } else {
             "ws"
         },
-        host = web_sys::window().unwrap().location().host()?
+        host = location.host()?
     );
 
     spawn_local(async move {
+        let logs_in = gateway_logs_in(target.as_deref(), token.as_deref())
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Unable to list the targets: {:?}", e);
+                false
+            });
+        if logs_in {
+            // the gateway logs in with NLA, the client needs no credentials
+            let mut rdp = Rdp::new(&url, "", "", "");
+            if !rdp.start().await {
+                alert("The gateway could not log in to the target");
+                return;
+            }
+            return rdp.main_loop().await;
+        }
         let mut username = String::new();
         let mut password = String::new();
         let mut domain = String::new();
@@ -18,11 +18,13 @@ pub struct WsSecureBio {
 #[async_trait]
 impl AsyncSecureBio<WsStream> for WsSecureBio {
     async fn start_ssl(&mut self, _check_certificate: bool) -> RdpResult<()> {
+        // the proxy answers with the server certificate behind its length
         let _ = self.ws_meta.wrapped().send_with_str("SSL");
-        let mut ber_cert = [0; 1500];
-        let size = self.ws_stream.read(&mut ber_cert).await.unwrap();
+        let size = self.ws_stream.read_u32().await? as usize;
+        let mut ber_cert = vec![0; size];
+        self.ws_stream.read_exact(&mut ber_cert).await?;
         trace!("Read {} byte public cert", size);
-        self.peer_cert = ber_cert.to_vec();
+        self.peer_cert = ber_cert;
         Ok(())
     }
     fn get_peer_certificate_der(&self) -> RdpResult<Option<Vec<u8>>> {
@@ -0,0 +1,209 @@
+// SSH agent protocol, served over forwarded agent channels
+// https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent
+
+use crate::keys::KeyStore;
+use crate::ssh::{auth, SshError, SshReader, SshResult, SshWriter};
+use ssh_key::PrivateKey;
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+const AGENT_FAILURE: u8 = 5;
+const AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const AGENT_IDENTITIES_ANSWER: u8 = 12;
+const AGENTC_SIGN_REQUEST: u8 = 13;
+const AGENT_SIGN_RESPONSE: u8 = 14;
+
+// Signature flags
+const AGENT_RSA_SHA2_256: u32 = 2;
+const AGENT_RSA_SHA2_512: u32 = 4;
+
+// Larger requests than this are refused by OpenSSH too
+const MAX_MESSAGE: usize = 256 * 1024;
+
+/// An agent holding the keys the user imported
+///
+/// The keys never leave the page, remote hosts can only ask for signatures.
+pub struct Agent {
+    keys: KeyStore,
+    // partially received requests of every open channel
+    channels: HashMap<u32, Vec<u8>>,
+}
+
+impl Agent {
+    pub fn new(keys: KeyStore) -> Self {
+        Self {
+            keys,
+            channels: HashMap::new(),
+        }
+    }
+
+    pub fn open(&mut self, id: u32) {
+        self.channels.insert(id, Vec::new());
+    }
+
+    pub fn close(&mut self, id: u32) {
+        self.channels.remove(&id);
+    }
+
+    pub fn is_agent_channel(&self, id: u32) -> bool {
+        self.channels.contains_key(&id)
+    }
+
+    /// Process data received on an agent channel and return the replies
+    ///
+    /// `confirm` is asked before every signature
+    pub fn feed(
+        &mut self,
+        id: u32,
+        data: &[u8],
+        mut confirm: impl FnMut(&PrivateKey) -> bool,
+    ) -> SshResult<Vec<u8>> {
+        let mut buf = self.channels.remove(&id).unwrap_or_default();
+        buf.extend_from_slice(data);
+        let mut output = Vec::new();
+        loop {
+            if buf.len() < 4 {
+                break;
+            }
+            let len = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+            if len == 0 || len > MAX_MESSAGE {
+                return Err(SshError::Protocol(format!(
+                    "Invalid agent message length {}",
+                    len
+                )));
+            }
+            if buf.len() < 4 + len {
+                break;
+            }
+            let request: Vec<u8> = buf.drain(..4 + len).skip(4).collect();
+            let response = self
+                .request(&request, &mut confirm)
+                .unwrap_or_else(|_| vec![AGENT_FAILURE]);
+            output.extend_from_slice(&(response.len() as u32).to_be_bytes());
+            output.extend_from_slice(&response);
+        }
+        self.channels.insert(id, buf);
+        Ok(output)
+    }
+
+    fn request(
+        &self,
+        request: &[u8],
+        confirm: &mut impl FnMut(&PrivateKey) -> bool,
+    ) -> SshResult<Vec<u8>> {
+        let mut reader = SshReader::new(&request[1..]);
+        match request[0] {
+            AGENTC_REQUEST_IDENTITIES => {
+                let keys = self.keys.keys();
+                let mut writer = SshWriter::new(Vec::with_capacity(512));
+                writer.write_u8(AGENT_IDENTITIES_ANSWER);
+                writer.write_u32(keys.len() as u32);
+                for key in &keys {
+                    let blob = key
+                        .public_key()
+                        .to_bytes()
+                        .map_err(|e| SshError::Key(e.to_string()))?;
+                    writer.write_string(&blob);
+                    writer.write_str(key.comment());
+                }
+                Ok(writer.into_inner())
+            }
+            AGENTC_SIGN_REQUEST => {
+                let blob = reader.read_string()?;
+                let data = reader.read_string()?;
+                let flags = reader.read_u32()?;
+                let key = self
+                    .keys
+                    .keys()
+                    .into_iter()
+                    .find(|key| key.public_key().to_bytes().is_ok_and(|b| b == blob))
+                    .ok_or_else(|| SshError::Key("Unknown key".to_owned()))?;
+                let algorithm = match auth::signature_algorithms(&key) {
+                    [auth::RSA_SHA2_512, auth::RSA_SHA2_256] if flags & AGENT_RSA_SHA2_512 != 0 => {
+                        auth::RSA_SHA2_512
+                    }
+                    [auth::RSA_SHA2_512, auth::RSA_SHA2_256] if flags & AGENT_RSA_SHA2_256 != 0 => {
+                        auth::RSA_SHA2_256
+                    }
+                    // SHA-1 ssh-rsa signatures are not supported
+                    [auth::RSA_SHA2_512, auth::RSA_SHA2_256] | [] => {
+                        return Err(SshError::Key("Unsupported signature".to_owned()))
+                    }
+                    [algorithm, ..] => algorithm,
+                };
+                if !confirm(&key) {
+                    warn!("Signature request refused");
+                    return Ok(vec![AGENT_FAILURE]);
+                }
+                info!("Agent signing with {}", algorithm);
+                let signature = auth::sign(&key, algorithm, data)?;
+                let mut writer = SshWriter::new(Vec::with_capacity(signature.len() + 8));
+                writer.write_u8(AGENT_SIGN_RESPONSE);
+                writer.write_string(&signature);
+                Ok(writer.into_inner())
+            }
+            _ => Ok(vec![AGENT_FAILURE]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand_core::OsRng;
+    use signature::Verifier;
+    use ssh_key::{Algorithm, Signature};
+
+    fn message(payload: &[u8]) -> Vec<u8> {
+        let mut buf = (payload.len() as u32).to_be_bytes().to_vec();
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn test_agent() {
+        let keys = KeyStore::new();
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        keys.add(key.clone());
+        let mut agent = Agent::new(keys);
+        agent.open(1);
+
+        let request = message(&[AGENTC_REQUEST_IDENTITIES]);
+        // split requests are buffered until complete
+        assert!(agent.feed(1, &request[..3], |_| true).unwrap().is_empty());
+        let reply = agent.feed(1, &request[3..], |_| true).unwrap();
+        let mut reader = SshReader::new(&reply[4..]);
+        assert_eq!(reader.read_u8().unwrap(), AGENT_IDENTITIES_ANSWER);
+        assert_eq!(reader.read_u32().unwrap(), 1);
+        let blob = reader.read_string().unwrap().to_vec();
+        assert_eq!(blob, key.public_key().to_bytes().unwrap());
+
+        let mut writer = SshWriter::new(Vec::new());
+        writer.write_u8(AGENTC_SIGN_REQUEST);
+        writer.write_string(&blob);
+        writer.write_string(b"data");
+        writer.write_u32(0);
+        let request = message(writer.get_inner());
+
+        assert_eq!(
+            agent.feed(1, &request, |_| false).unwrap(),
+            message(&[AGENT_FAILURE])
+        );
+        let reply = agent.feed(1, &request, |_| true).unwrap();
+        let mut reader = SshReader::new(&reply[4..]);
+        assert_eq!(reader.read_u8().unwrap(), AGENT_SIGN_RESPONSE);
+        let signature = Signature::try_from(reader.read_string().unwrap()).unwrap();
+        assert!(key
+            .public_key()
+            .key_data()
+            .verify(b"data", &signature)
+            .is_ok());
+
+        assert_eq!(
+            agent.feed(1, &message(&[42]), |_| true).unwrap(),
+            message(&[AGENT_FAILURE])
+        );
+        agent.close(1);
+        assert!(!agent.is_agent_channel(1));
+    }
+}
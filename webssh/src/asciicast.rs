@@ -0,0 +1,259 @@
+// Terminal session recordings in the asciicast v2 format
+// https://docs.asciinema.org/manual/asciicast/v2/
+
+use crate::term::Terminal;
+use serde_json::{json, Value};
+
+/// Records the output of a terminal as it arrives
+pub struct Recorder {
+    start: f64,
+    cast: String,
+    // the start of a UTF-8 sequence split across output chunks
+    pending: Vec<u8>,
+}
+
+impl Recorder {
+    /// `now` is in milliseconds since the unix epoch
+    pub fn new(cols: usize, rows: usize, term: &str, now: f64) -> Self {
+        let header = json!({
+            "version": 2,
+            "width": cols,
+            "height": rows,
+            "timestamp": (now / 1000.0) as u64,
+            "env": { "TERM": term },
+        });
+        Self {
+            start: now,
+            cast: format!("{}\n", header),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn output(&mut self, now: f64, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(_) => self.pending.len(),
+            // an incomplete sequence at the end waits for the next chunk
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => self.pending.len(),
+        };
+        if valid == 0 {
+            return;
+        }
+        let text = String::from_utf8_lossy(&self.pending[..valid]).into_owned();
+        self.pending.drain(..valid);
+        self.event(now, "o", &text);
+    }
+
+    pub fn resize(&mut self, now: f64, cols: usize, rows: usize) {
+        self.event(now, "r", &format!("{}x{}", cols, rows));
+    }
+
+    fn event(&mut self, now: f64, kind: &str, data: &str) {
+        // microsecond precision like asciinema
+        let time = ((now - self.start).max(0.0) * 1000.0).round() / 1_000_000.0;
+        self.cast.push_str(&json!([time, kind, data]).to_string());
+        self.cast.push('\n');
+    }
+
+    pub fn finish(self) -> String {
+        self.cast
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CastEvent {
+    Output(String),
+    Resize(usize, usize),
+}
+
+/// A parsed recording, events are ordered by time in seconds
+#[derive(Debug)]
+pub struct Cast {
+    pub width: usize,
+    pub height: usize,
+    pub events: Vec<(f64, CastEvent)>,
+}
+
+impl Cast {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines().enumerate();
+        let header: Value = lines
+            .next()
+            .and_then(|(_, line)| serde_json::from_str(line).ok())
+            .ok_or("Missing asciicast header")?;
+        if header["version"] != 2 {
+            return Err("Only asciicast version 2 is supported".to_owned());
+        }
+        let size = |key: &str| {
+            header[key]
+                .as_u64()
+                .map(|n| n as usize)
+                .ok_or_else(|| format!("Invalid {} in header", key))
+        };
+        let (width, height) = (size("width")?, size("height")?);
+
+        let mut events: Vec<(f64, CastEvent)> = Vec::new();
+        for (idx, line) in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = || format!("line {}: invalid event", idx + 1);
+            let event: Value = serde_json::from_str(line).map_err(|_| invalid())?;
+            let time = event[0].as_f64().ok_or_else(invalid)?;
+            let data = event[2].as_str().ok_or_else(invalid)?;
+            let event = match event[1].as_str() {
+                Some("o") => CastEvent::Output(data.to_owned()),
+                Some("r") => {
+                    let (cols, rows) = data.split_once('x').ok_or_else(invalid)?;
+                    CastEvent::Resize(
+                        cols.parse().map_err(|_| invalid())?,
+                        rows.parse().map_err(|_| invalid())?,
+                    )
+                }
+                // input and markers do not change the screen
+                Some(_) => continue,
+                None => return Err(invalid()),
+            };
+            // keep the order even if a recorder got the clock wrong
+            let time = events.last().map_or(time, |(last, _)| time.max(*last));
+            events.push((time, event));
+        }
+        Ok(Self {
+            width,
+            height,
+            events,
+        })
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.events.last().map_or(0.0, |(time, _)| *time)
+    }
+}
+
+/// Replays a recording through the terminal emulator
+pub struct Player {
+    cast: Cast,
+    terminal: Terminal,
+    // index of the first event not shown yet
+    next: usize,
+    time: f64,
+}
+
+impl Player {
+    pub fn new(cast: Cast) -> Self {
+        let terminal = Terminal::new(cast.width, cast.height);
+        Self {
+            cast,
+            terminal,
+            next: 0,
+            time: 0.0,
+        }
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.cast.duration()
+    }
+
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.cast.events.len()
+    }
+
+    pub fn terminal_mut(&mut self) -> &mut Terminal {
+        &mut self.terminal
+    }
+
+    /// Show the screen as it was `time` seconds into the recording
+    pub fn seek(&mut self, time: f64) {
+        let time = time.clamp(0.0, self.duration());
+        // the screen can only be rebuilt from the start
+        if time < self.time {
+            self.terminal = Terminal::new(self.cast.width, self.cast.height);
+            self.next = 0;
+        }
+        while let Some((event_time, event)) = self.cast.events.get(self.next) {
+            if *event_time > time {
+                break;
+            }
+            match event {
+                CastEvent::Output(data) => self.terminal.feed(data.as_bytes()),
+                CastEvent::Resize(cols, rows) => self.terminal.resize(*cols, *rows),
+            }
+            self.next += 1;
+        }
+        // replies to queries only matter to a live host
+        self.terminal.screen_mut().take_response();
+        self.time = time;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn text(terminal: &mut Terminal) -> String {
+        terminal
+            .screen_mut()
+            .view_row(0)
+            .iter()
+            .map(|c| c.c)
+            .collect::<String>()
+            .trim_end()
+            .to_owned()
+    }
+
+    #[test]
+    fn test_record_and_parse() {
+        let mut recorder = Recorder::new(80, 24, "xterm-256color", 1_700_000_000_000.0);
+        recorder.output(1_700_000_000_500.0, b"hi \xe4\xb8");
+        recorder.output(1_700_000_001_000.0, b"\xad\"\n");
+        recorder.resize(1_700_000_002_000.0, 100, 30);
+        let cast = recorder.finish();
+        let mut lines = cast.lines();
+        let header: Value = serde_json::from_str(lines.next().unwrap()).unwrap();
+        assert_eq!(header["timestamp"], 1_700_000_000);
+        assert_eq!(header["env"]["TERM"], "xterm-256color");
+        assert_eq!(lines.next(), Some(r#"[0.5,"o","hi "]"#));
+        assert_eq!(lines.next(), Some(r#"[1.0,"o","中\"\n"]"#));
+        assert_eq!(lines.next(), Some(r#"[2.0,"r","100x30"]"#));
+
+        let cast = Cast::parse(&cast).unwrap();
+        assert_eq!((cast.width, cast.height), (80, 24));
+        assert_eq!(cast.duration(), 2.0);
+        assert_eq!(cast.events[2], (2.0, CastEvent::Resize(100, 30)));
+
+        assert!(Cast::parse("").is_err());
+        assert!(Cast::parse(r#"{"version": 1, "width": 80, "height": 24}"#).is_err());
+        assert!(
+            Cast::parse("{\"version\": 2, \"width\": 80, \"height\": 24}\n[1, \"o\"]").is_err()
+        );
+    }
+
+    #[test]
+    fn test_player() {
+        let cast = Cast::parse(
+            "{\"version\": 2, \"width\": 10, \"height\": 2}\n\
+             [0.5, \"o\", \"one\"]\n\
+             [1.0, \"i\", \"x\"]\n\
+             [1.5, \"o\", \"\\r\\u001b[Ktwo\"]\n\
+             [2.0, \"r\", \"20x3\"]\n",
+        )
+        .unwrap();
+        let mut player = Player::new(cast);
+        assert_eq!(player.duration(), 2.0);
+        player.seek(1.0);
+        assert_eq!(text(player.terminal_mut()), "one");
+        player.seek(1.5);
+        assert_eq!(text(player.terminal_mut()), "two");
+        player.seek(0.7);
+        assert_eq!(text(player.terminal_mut()), "one");
+        player.seek(5.0);
+        assert!(player.is_finished());
+        assert_eq!(player.time(), 2.0);
+        assert_eq!(player.terminal_mut().screen().cols(), 20);
+    }
+}
@@ -0,0 +1,300 @@
+use crate::input;
+use crate::term::{self, Attr, Color, Screen};
+
+use std::cell::Cell;
+use std::rc::Rc;
+use tokio::sync::mpsc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, KeyboardEvent, WheelEvent};
+
+const FONT_SIZE: f64 = 15.0;
+const FONT_FAMILY: &str = "monospace";
+const MIN_COLS: usize = 20;
+const MIN_ROWS: usize = 5;
+const SCROLL_LINES: isize = 3;
+const DEFAULT_FG: (u8, u8, u8) = (0xe5, 0xe5, 0xe5);
+const DEFAULT_BG: (u8, u8, u8) = (0x00, 0x00, 0x00);
+
+fn css_color(rgb: (u8, u8, u8)) -> String {
+    format!("rgb({},{},{})", rgb.0, rgb.1, rgb.2)
+}
+
+fn resolve(color: Color, default: (u8, u8, u8)) -> (u8, u8, u8) {
+    match color {
+        Color::Default => default,
+        Color::Indexed(idx) => term::palette(idx),
+        Color::Rgb(r, g, b) => (r, g, b),
+    }
+}
+
+fn colors(attr: &Attr) -> ((u8, u8, u8), (u8, u8, u8)) {
+    let fg = match attr.fg {
+        // bold text is drawn in the bright variant of the first 8 colours
+        Color::Indexed(idx) if attr.bold && idx < 8 => term::palette(idx + 8),
+        color => resolve(color, DEFAULT_FG),
+    };
+    let bg = resolve(attr.bg, DEFAULT_BG);
+    let fg = if attr.dim {
+        (fg.0 / 2, fg.1 / 2, fg.2 / 2)
+    } else {
+        fg
+    };
+    if attr.inverse {
+        (bg, fg)
+    } else {
+        (fg, bg)
+    }
+}
+
+struct Canvas {
+    canvas: HtmlCanvasElement,
+    ctx: CanvasRenderingContext2d,
+    output: mpsc::Sender<Vec<u8>>,
+    app_cursor: Rc<Cell<bool>>,
+    cell_width: Cell<f64>,
+    cell_height: Cell<f64>,
+    // where the cursor was drawn last time
+    last_cursor: Cell<Option<(usize, usize)>>,
+}
+
+impl Canvas {
+    fn new(sender: mpsc::Sender<Vec<u8>>, canvas: HtmlCanvasElement) -> Self {
+        let ctx = canvas
+            .get_context("2d")
+            .unwrap()
+            .unwrap()
+            .dyn_into::<CanvasRenderingContext2d>()
+            .unwrap();
+        Self {
+            canvas,
+            ctx,
+            output: sender,
+            app_cursor: Rc::new(Cell::new(false)),
+            cell_width: Cell::new(0.0),
+            cell_height: Cell::new(0.0),
+            last_cursor: Cell::new(None),
+        }
+    }
+
+    fn font(&self, attr: &Attr) -> String {
+        format!(
+            "{}{}{}px {}",
+            if attr.italic { "italic " } else { "" },
+            if attr.bold { "bold " } else { "" },
+            FONT_SIZE,
+            FONT_FAMILY
+        )
+    }
+
+    fn cell_size(&self) -> (f64, f64) {
+        self.ctx.set_font(&self.font(&Attr::default()));
+        let width = self
+            .ctx
+            .measure_text("M")
+            .map(|m| m.width())
+            .unwrap_or(FONT_SIZE * 0.6)
+            .ceil();
+        (width, (FONT_SIZE * 1.2).ceil())
+    }
+
+    // rows and columns fitting in the page below the status and tab bars
+    fn grid_size(&self) -> (usize, usize) {
+        let document = web_sys::window().unwrap().document().unwrap();
+        let body = document.body().unwrap();
+        let bars_height: i32 = ["ssh_status", "ssh_tabs"]
+            .iter()
+            .filter_map(|id| document.get_element_by_id(id))
+            .map(|e| e.client_height())
+            .sum();
+        let width = body.client_width() as f64;
+        let height = (body.client_height() - bars_height) as f64;
+        let (cell_width, cell_height) = self.cell_size();
+        (
+            ((width / cell_width) as usize).max(MIN_COLS),
+            ((height / cell_height) as usize).max(MIN_ROWS),
+        )
+    }
+
+    fn set_resolution(&self, cols: usize, rows: usize) {
+        let (width, height) = self.cell_size();
+        self.cell_width.set(width);
+        self.cell_height.set(height);
+
+        // set hight & width
+        self.canvas.set_width((width * cols as f64) as u32);
+        self.canvas.set_height((height * rows as f64) as u32);
+        self.ctx.set_fill_style_str(&css_color(DEFAULT_BG));
+        self.ctx.fill_rect(
+            0_f64,
+            0_f64,
+            self.canvas.width() as f64,
+            self.canvas.height() as f64,
+        );
+    }
+
+    fn bind(&self) {
+        let sender = self.output.clone();
+        let app_cursor = self.app_cursor.clone();
+        let key_down = move |e: KeyboardEvent| {
+            if let Some(data) = input::key_to_bytes(&e, app_cursor.get()) {
+                e.prevent_default();
+                e.stop_propagation();
+                let sender = sender.clone();
+                futures::executor::block_on(async move {
+                    let _ = sender.send(data).await;
+                });
+            }
+        };
+
+        let handler = Box::new(key_down) as Box<dyn FnMut(_)>;
+
+        let cb = Closure::wrap(handler);
+
+        self.canvas
+            .add_event_listener_with_callback("keydown", cb.as_ref().unchecked_ref())
+            .unwrap();
+        cb.forget();
+    }
+
+    fn draw_row(&self, screen: &Screen, y: usize, cursor: Option<usize>) {
+        let (w, h) = (self.cell_width.get(), self.cell_height.get());
+        let top = y as f64 * h;
+        self.ctx.set_text_baseline("middle");
+        let row = screen.view_row(y);
+
+        // scrollback lines may be narrower than the screen
+        self.ctx.set_fill_style_str(&css_color(DEFAULT_BG));
+        self.ctx.fill_rect(0.0, top, self.canvas.width() as f64, h);
+
+        for (x, cell) in row.iter().enumerate() {
+            if cell.is_spacer() {
+                continue;
+            }
+            let wide = row.get(x + 1).is_some_and(|c| c.is_spacer());
+            let width = if wide { w * 2.0 } else { w };
+            let left = x as f64 * w;
+
+            let (mut fg, mut bg) = colors(&cell.attr);
+            if cursor == Some(x) {
+                std::mem::swap(&mut fg, &mut bg);
+            }
+            self.ctx.set_fill_style_str(&css_color(bg));
+            self.ctx.fill_rect(left, top, width, h);
+
+            if cell.c == ' ' || cell.attr.hidden {
+                continue;
+            }
+            self.ctx.set_fill_style_str(&css_color(fg));
+            self.ctx.set_font(&self.font(&cell.attr));
+            let _ = self.ctx.fill_text(&cell.c.to_string(), left, top + h / 2.0);
+            if cell.attr.underline {
+                self.ctx.fill_rect(left, top + h - 1.0, width, 1.0);
+            }
+            if cell.attr.strike {
+                self.ctx.fill_rect(left, top + h / 2.0, width, 1.0);
+            }
+        }
+    }
+
+    fn draw(&self, screen: &mut Screen) {
+        let (x, y) = screen.cursor();
+        let cursor = (screen.modes().cursor_visible && screen.view_offset() == 0).then_some((x, y));
+        let last_cursor = self.last_cursor.get();
+        for row in 0..screen.rows() {
+            let cursor_moved = cursor != last_cursor
+                && (cursor.map(|c| c.1) == Some(row) || last_cursor.map(|c| c.1) == Some(row));
+            if screen.is_dirty(row) || cursor_moved {
+                let cursor_x = cursor.filter(|c| c.1 == row).map(|c| c.0);
+                self.draw_row(screen, row, cursor_x);
+            }
+        }
+        self.last_cursor.set(cursor);
+        self.app_cursor.set(screen.modes().app_cursor);
+        screen.clear_dirty();
+    }
+}
+
+pub struct CanvasUtils {
+    inner: Rc<Canvas>,
+    bind: bool,
+}
+
+impl Clone for CanvasUtils {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            bind: self.bind,
+        }
+    }
+}
+
+impl CanvasUtils {
+    pub fn new(sender: mpsc::Sender<Vec<u8>>, canvas: HtmlCanvasElement) -> Self {
+        Self {
+            inner: Rc::new(Canvas::new(sender, canvas)),
+            bind: false,
+        }
+    }
+
+    pub fn init(&mut self, cols: usize, rows: usize) {
+        self.inner.as_ref().set_resolution(cols, rows);
+        if !self.bind {
+            self.inner.as_ref().bind();
+            self.bind = true;
+        }
+    }
+
+    pub fn draw(&self, screen: &mut Screen) {
+        self.inner.as_ref().draw(screen);
+    }
+
+    /// Rows and columns filling the page with the current font
+    pub fn grid_size(&self) -> (usize, usize) {
+        self.inner.grid_size()
+    }
+
+    /// Report the new grid size whenever the browser window is resized
+    pub fn bind_resize(&self, sender: mpsc::Sender<(usize, usize)>) {
+        let canvas = self.inner.clone();
+        let resize = move || {
+            // resize events come in bursts, only the latest size matters
+            let _ = sender.try_send(canvas.grid_size());
+        };
+        let handler = Box::new(resize) as Box<dyn FnMut()>;
+
+        let cb = Closure::wrap(handler);
+
+        web_sys::window()
+            .unwrap()
+            .add_event_listener_with_callback("resize", cb.as_ref().unchecked_ref())
+            .unwrap();
+        cb.forget();
+    }
+
+    /// Report the lines to scroll the view back by when the wheel turns
+    pub fn bind_scroll(&self, sender: mpsc::Sender<isize>) {
+        let wheel = move |e: WheelEvent| {
+            e.prevent_default();
+            let lines = if e.delta_y() < 0.0 {
+                SCROLL_LINES
+            } else {
+                -SCROLL_LINES
+            };
+            let _ = sender.try_send(lines);
+        };
+        let handler = Box::new(wheel) as Box<dyn FnMut(_)>;
+
+        let cb = Closure::wrap(handler);
+
+        self.inner
+            .canvas
+            .add_event_listener_with_callback("wheel", cb.as_ref().unchecked_ref())
+            .unwrap();
+        cb.forget();
+    }
+
+    pub fn focus(&self) {
+        let _ = self.inner.canvas.focus();
+    }
+}
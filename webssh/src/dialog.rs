@@ -0,0 +1,94 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use tokio::sync::oneshot;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{Event, HtmlElement, HtmlInputElement};
+
+fn element(id: &str) -> HtmlElement {
+    web_sys::window()
+        .unwrap()
+        .document()
+        .unwrap()
+        .get_element_by_id(id)
+        .unwrap()
+        .dyn_into::<HtmlElement>()
+        .map_err(|_| ())
+        .unwrap()
+}
+
+/// Ask the user to fill in `prompts` in the page's dialog
+///
+/// Each prompt comes with whether its answer may be shown on screen.
+/// Unlike `prompt()` this does not block the page, returns `None`
+/// when the dialog is cancelled.
+pub async fn ask(
+    title: &str,
+    instruction: &str,
+    prompts: &[(String, bool)],
+) -> Option<Vec<String>> {
+    let document = web_sys::window().unwrap().document().unwrap();
+    let dialog = element("authdialog");
+    let form = element("authform");
+    let cancel = element("authcancel");
+    let fields = element("authprompts");
+    element("authtitle").set_text_content(Some(title));
+    element("authinstruction").set_text_content(Some(instruction));
+
+    fields.set_text_content(None);
+    let mut inputs = Vec::with_capacity(prompts.len());
+    for (prompt, echo) in prompts {
+        let label = document.create_element("label").unwrap();
+        label.set_text_content(Some(prompt));
+        let input = document
+            .create_element("input")
+            .unwrap()
+            .dyn_into::<HtmlInputElement>()
+            .map_err(|_| ())
+            .unwrap();
+        input.set_type(if *echo { "text" } else { "password" });
+        input.set_autocomplete("off");
+        label.append_child(&input).unwrap();
+        fields.append_child(&label).unwrap();
+        inputs.push(input);
+    }
+    dialog.set_hidden(false);
+    if let Some(input) = inputs.first() {
+        let _ = input.focus();
+    }
+
+    let (sender, reciver) = oneshot::channel();
+    let sender = Rc::new(RefCell::new(Some(sender)));
+
+    let s = sender.clone();
+    let submit = move |e: Event| {
+        e.prevent_default();
+        if let Some(s) = s.borrow_mut().take() {
+            let _ = s.send(true);
+        }
+    };
+    let handler = Box::new(submit) as Box<dyn FnMut(_)>;
+    let submit_cb = Closure::wrap(handler);
+    form.set_onsubmit(Some(submit_cb.as_ref().unchecked_ref()));
+
+    let s = sender;
+    let close = move || {
+        if let Some(s) = s.borrow_mut().take() {
+            let _ = s.send(false);
+        }
+    };
+    let handler = Box::new(close) as Box<dyn FnMut()>;
+    let cancel_cb = Closure::wrap(handler);
+    cancel.set_onclick(Some(cancel_cb.as_ref().unchecked_ref()));
+
+    let submitted = reciver.await.unwrap_or(false);
+
+    // the callbacks are only needed while this dialog is shown
+    form.set_onsubmit(None);
+    cancel.set_onclick(None);
+    dialog.set_hidden(true);
+    let answers = inputs.iter().map(|input| input.value()).collect();
+    // never keep the answers around in the page
+    fields.set_text_content(None);
+    submitted.then_some(answers)
+}
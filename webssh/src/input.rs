@@ -0,0 +1,76 @@
+use web_sys::KeyboardEvent;
+
+/// Translate a key press into the bytes an xterm would send
+///
+/// `app_cursor` selects the application cursor key mode (DECCKM)
+pub fn key_to_bytes(e: &KeyboardEvent, app_cursor: bool) -> Option<Vec<u8>> {
+    let key = e.key();
+    let cursor = |c: char| {
+        if app_cursor {
+            format!("\x1bO{}", c).into_bytes()
+        } else {
+            format!("\x1b[{}", c).into_bytes()
+        }
+    };
+    let out = match key.as_str() {
+        "Enter" => b"\r".to_vec(),
+        "Backspace" => b"\x7f".to_vec(),
+        "Tab" => {
+            if e.shift_key() {
+                b"\x1b[Z".to_vec()
+            } else {
+                b"\t".to_vec()
+            }
+        }
+        "Escape" => b"\x1b".to_vec(),
+        "ArrowUp" => cursor('A'),
+        "ArrowDown" => cursor('B'),
+        "ArrowRight" => cursor('C'),
+        "ArrowLeft" => cursor('D'),
+        "Home" => cursor('H'),
+        "End" => cursor('F'),
+        "Insert" => b"\x1b[2~".to_vec(),
+        "Delete" => b"\x1b[3~".to_vec(),
+        "PageUp" => b"\x1b[5~".to_vec(),
+        "PageDown" => b"\x1b[6~".to_vec(),
+        "F1" => b"\x1bOP".to_vec(),
+        "F2" => b"\x1bOQ".to_vec(),
+        "F3" => b"\x1bOR".to_vec(),
+        "F4" => b"\x1bOS".to_vec(),
+        "F5" => b"\x1b[15~".to_vec(),
+        "F6" => b"\x1b[17~".to_vec(),
+        "F7" => b"\x1b[18~".to_vec(),
+        "F8" => b"\x1b[19~".to_vec(),
+        "F9" => b"\x1b[20~".to_vec(),
+        "F10" => b"\x1b[21~".to_vec(),
+        "F11" => b"\x1b[23~".to_vec(),
+        "F12" => b"\x1b[24~".to_vec(),
+        _ => {
+            let mut chars = key.chars();
+            let c = chars.next()?;
+            if chars.next().is_some() || e.meta_key() {
+                // a named key we do not handle
+                return None;
+            }
+            if e.ctrl_key() {
+                // Ctrl-@ .. Ctrl-_
+                let upper = c.to_ascii_uppercase();
+                if ('@'..='_').contains(&upper) {
+                    vec![upper as u8 - b'@']
+                } else if c == ' ' {
+                    vec![0]
+                } else {
+                    return None;
+                }
+            } else {
+                let mut buf = [0u8; 4];
+                c.encode_utf8(&mut buf).as_bytes().to_vec()
+            }
+        }
+    };
+    if e.alt_key() && out.len() == 1 {
+        Some([b"\x1b".as_slice(), &out].concat())
+    } else {
+        Some(out)
+    }
+}
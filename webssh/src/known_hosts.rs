@@ -0,0 +1,355 @@
+// Trust on first use storage of server host keys
+// Entries use the `known_hosts` line format `<target> <algorithm> <base64 key>`,
+// `@cert-authority <host patterns> <algorithm> <base64 key>` trusts the
+// certificates a CA signed for the matching hosts instead
+
+use crate::ssh::cert::{Certificate, HOST_CERT};
+use ssh_key::PublicKey;
+use std::fmt;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{HtmlButtonElement, HtmlTextAreaElement};
+
+const STORAGE_KEY: &str = "webssh_known_hosts";
+const CERT_AUTHORITY: &str = "@cert-authority";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HostKeyStatus {
+    Trusted,
+    Unknown,
+    Changed,
+}
+
+struct Entry {
+    cert_authority: bool,
+    target: String,
+    key: PublicKey,
+}
+
+#[derive(Default)]
+pub struct KnownHosts {
+    entries: Vec<Entry>,
+}
+
+// `*` matches any run of characters and `?` a single one
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    match pattern.chars().next() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len())
+            .filter(|i| name.is_char_boundary(*i))
+            .any(|i| glob_matches(&pattern[1..], &name[i..])),
+        Some(c) => {
+            let mut chars = name.chars();
+            match chars.next() {
+                Some(n) if c == '?' || c.eq_ignore_ascii_case(&n) => {
+                    glob_matches(&pattern[c.len_utf8()..], chars.as_str())
+                }
+                _ => false,
+            }
+        }
+    }
+}
+
+/// Whether a comma separated list of patterns covers `host`,
+/// a matching `!` pattern excludes the host
+fn host_matches(patterns: &str, host: &str) -> bool {
+    let mut found = false;
+    for pattern in patterns.split(',') {
+        match pattern.strip_prefix('!') {
+            Some(pattern) if glob_matches(pattern, host) => return false,
+            Some(_) => {}
+            None => found |= glob_matches(pattern, host),
+        }
+    }
+    found
+}
+
+impl KnownHosts {
+    /// Parse the known hosts file, blank lines and `#` comments are skipped
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut known_hosts = Self::default();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (cert_authority, line) = match line.strip_prefix(CERT_AUTHORITY) {
+                Some(rest) if rest.starts_with(char::is_whitespace) => (true, rest.trim_start()),
+                _ if line.starts_with('@') => {
+                    return Err(format!("line {}: unsupported marker", idx + 1));
+                }
+                _ => (false, line),
+            };
+            let (target, key) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| format!("line {}: missing host key", idx + 1))?;
+            let key = PublicKey::from_openssh(key.trim())
+                .map_err(|e| format!("line {}: {}", idx + 1, e))?;
+            if cert_authority {
+                known_hosts.add_authority(target, &key);
+            } else {
+                known_hosts.add(target, &key);
+            }
+        }
+        Ok(known_hosts)
+    }
+
+    pub fn check(&self, target: &str, key: &PublicKey) -> HostKeyStatus {
+        let mut known = self
+            .entries
+            .iter()
+            .filter(|e| !e.cert_authority && e.target == target)
+            .peekable();
+        if known.peek().is_none() {
+            HostKeyStatus::Unknown
+        } else if known.any(|e| e.key.key_data() == key.key_data()) {
+            HostKeyStatus::Trusted
+        } else {
+            HostKeyStatus::Changed
+        }
+    }
+
+    /// Check a host certificate against the authorities trusted for `host`,
+    /// `None` if no authority is configured for it
+    pub fn check_certificate(
+        &self,
+        host: &str,
+        cert: &Certificate,
+        now: u64,
+    ) -> Option<Result<(), String>> {
+        let mut authorities = self
+            .entries
+            .iter()
+            .filter(|e| e.cert_authority && host_matches(&e.target, host))
+            .peekable();
+        authorities.peek()?;
+        if !authorities.any(|e| e.key.key_data() == cert.signature_key().key_data()) {
+            return Some(Err(
+                "certificate is not signed by a trusted authority".to_owned()
+            ));
+        }
+        Some(cert.validate(HOST_CERT, host, now))
+    }
+
+    pub fn add(&mut self, target: &str, key: &PublicKey) {
+        if self.check(target, key) == HostKeyStatus::Trusted {
+            return;
+        }
+        self.push(false, target, key);
+    }
+
+    /// Trust the certificates `key` signs for the hosts matching `patterns`
+    pub fn add_authority(&mut self, patterns: &str, key: &PublicKey) {
+        if self
+            .entries
+            .iter()
+            .any(|e| e.cert_authority && e.target == patterns && e.key.key_data() == key.key_data())
+        {
+            return;
+        }
+        self.push(true, patterns, key);
+    }
+
+    fn push(&mut self, cert_authority: bool, target: &str, key: &PublicKey) {
+        let mut key = key.clone();
+        key.set_comment("");
+        self.entries.push(Entry {
+            cert_authority,
+            target: target.to_owned(),
+            key,
+        });
+    }
+
+    /// Add the entries of `other`, returns the number of new entries
+    pub fn merge(&mut self, other: KnownHosts) -> usize {
+        let before = self.entries.len();
+        for entry in other.entries {
+            if entry.cert_authority {
+                self.add_authority(&entry.target, &entry.key);
+            } else {
+                self.add(&entry.target, &entry.key);
+            }
+        }
+        self.entries.len() - before
+    }
+
+    pub fn load() -> Self {
+        let text = web_sys::window()
+            .unwrap()
+            .local_storage()
+            .ok()
+            .flatten()
+            .and_then(|storage| storage.get_item(STORAGE_KEY).ok().flatten())
+            .unwrap_or_default();
+        Self::parse(&text).unwrap_or_else(|e| {
+            tracing::error!("Ignoring corrupted known hosts: {}", e);
+            Self::default()
+        })
+    }
+
+    pub fn save(&self) {
+        if let Some(storage) = web_sys::window().unwrap().local_storage().ok().flatten() {
+            let _ = storage.set_item(STORAGE_KEY, &self.to_string());
+        }
+    }
+
+    pub fn bind() {
+        let document = web_sys::window().unwrap().document().unwrap();
+        let hosts_txt = document
+            .get_element_by_id("knownhoststxt")
+            .unwrap()
+            .dyn_into::<HtmlTextAreaElement>()
+            .map_err(|_| ())
+            .unwrap();
+        let button = |id: &str| {
+            document
+                .get_element_by_id(id)
+                .unwrap()
+                .dyn_into::<HtmlButtonElement>()
+                .map_err(|_| ())
+                .unwrap()
+        };
+
+        let txt = hosts_txt.clone();
+        let export = move || {
+            txt.set_value(&KnownHosts::load().to_string());
+        };
+        let handler = Box::new(export) as Box<dyn FnMut()>;
+
+        let cb = Closure::wrap(handler);
+
+        button("knownhostsexport").set_onclick(Some(cb.as_ref().unchecked_ref()));
+        cb.forget();
+
+        let txt = hosts_txt;
+        let import = move || match KnownHosts::parse(&txt.value()) {
+            Ok(imported) => {
+                let mut known_hosts = KnownHosts::load();
+                let added = known_hosts.merge(imported);
+                known_hosts.save();
+                txt.set_value("");
+                crate::alert(&format!("Imported {} host keys", added));
+            }
+            Err(e) => crate::alert(&format!("Unable to import known hosts: {}", e)),
+        };
+        let handler = Box::new(import) as Box<dyn FnMut()>;
+
+        let cb = Closure::wrap(handler);
+
+        button("knownhostsimport").set_onclick(Some(cb.as_ref().unchecked_ref()));
+        cb.forget();
+    }
+}
+
+impl fmt::Display for KnownHosts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            if entry.cert_authority {
+                write!(f, "{} ", CERT_AUTHORITY)?;
+            }
+            writeln!(
+                f,
+                "{} {}",
+                entry.target,
+                entry.key.to_openssh().map_err(|_| fmt::Error)?
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::cert::test::issue;
+    use rand_core::OsRng;
+    use ssh_key::{Algorithm, PrivateKey};
+
+    const ED25519_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJZYZDcvRg6o0+vky3NOdq7MUe3KCl4ha+jjX1m4d1MV";
+    const ECDSA_KEY: &str = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBN5cqtBb+nmweUBqQo3nXfxFX7SRlA+01SOnjPNP7XlsbW7/wIgbBq4citS80vV+Rx3jW8FmugiBDXz4JDSUzJw=";
+
+    #[test]
+    fn test_check() {
+        let ed25519 = PublicKey::from_openssh(ED25519_KEY).unwrap();
+        let ecdsa = PublicKey::from_openssh(ECDSA_KEY).unwrap();
+        let mut known_hosts = KnownHosts::default();
+        assert_eq!(known_hosts.check("host", &ed25519), HostKeyStatus::Unknown);
+        known_hosts.add("host", &ed25519);
+        assert_eq!(known_hosts.check("host", &ed25519), HostKeyStatus::Trusted);
+        assert_eq!(known_hosts.check("host", &ecdsa), HostKeyStatus::Changed);
+        assert_eq!(known_hosts.check("other", &ecdsa), HostKeyStatus::Unknown);
+    }
+
+    #[test]
+    fn test_parse_and_merge() {
+        let text = format!(
+            "# comment\n\nhost {} user@laptop\nother {}\n",
+            ED25519_KEY, ECDSA_KEY
+        );
+        let known_hosts = KnownHosts::parse(&text).unwrap();
+        assert_eq!(
+            known_hosts.to_string(),
+            format!("host {}\nother {}\n", ED25519_KEY, ECDSA_KEY)
+        );
+        assert!(KnownHosts::parse("host").is_err());
+        assert!(KnownHosts::parse("host ssh-ed25519 AAAA").is_err());
+
+        let mut merged = KnownHosts::parse(&format!("host {}\n", ED25519_KEY)).unwrap();
+        assert_eq!(merged.merge(known_hosts), 1);
+        assert_eq!(merged.entries.len(), 2);
+    }
+
+    #[test]
+    fn test_host_matches() {
+        assert!(host_matches("*.example.com", "web.example.com"));
+        assert!(host_matches("*.example.com", "WEB.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(host_matches("lab?,*.example.com", "lab1"));
+        assert!(!host_matches(
+            "*.example.com,!db.example.com",
+            "db.example.com"
+        ));
+        assert!(!host_matches("!db.example.com", "web.example.com"));
+    }
+
+    #[test]
+    fn test_check_certificate() {
+        let ca = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let other_ca = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let host_key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let text = format!(
+            "@cert-authority *.example.com {}\n",
+            ca.public_key().to_openssh().unwrap()
+        );
+        let known_hosts = KnownHosts::parse(&text).unwrap();
+        assert_eq!(known_hosts.to_string(), text);
+        assert!(KnownHosts::parse(&format!("@revoked host {}", ED25519_KEY)).is_err());
+
+        let now = 1_700_000_000;
+        let cert = |ca: &PrivateKey, principals: &[&str], validity| {
+            let blob = issue(ca, host_key.public_key(), HOST_CERT, principals, validity);
+            Certificate::from_bytes(&blob).unwrap()
+        };
+        let valid = cert(&ca, &["web.example.com"], (0, u64::MAX));
+        assert_eq!(
+            known_hosts.check_certificate("web.example.com", &valid, now),
+            Some(Ok(()))
+        );
+        // no authority covers the host, the plain key is checked instead
+        assert_eq!(known_hosts.check_certificate("other", &valid, now), None);
+        for (host, cert) in [
+            ("db.example.com", valid),
+            (
+                "web.example.com",
+                cert(&other_ca, &["web.example.com"], (0, u64::MAX)),
+            ),
+            ("web.example.com", cert(&ca, &["web.example.com"], (0, now))),
+        ] {
+            assert!(matches!(
+                known_hosts.check_certificate(host, &cert, now),
+                Some(Err(_))
+            ));
+        }
+    }
+}
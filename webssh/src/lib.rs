@@ -1,24 +1,248 @@
+mod agent;
+mod asciicast;
+mod canvas;
+mod dialog;
+mod input;
+mod keys;
+mod known_hosts;
+mod player;
+mod sftp;
+mod sftp_panel;
+mod ssh;
+mod ssh_ws;
+mod tabs;
+mod telnet;
+mod term;
 mod utils;
 
+use keys::KeyStore;
+use known_hosts::KnownHosts;
+use ssh::AuthResult;
+use ssh_ws::{Protocol, Ssh, TcpClient};
+use tracing::{info, warn};
+use tracing_wasm::WASMLayerConfigBuilder;
 use wasm_bindgen::prelude::*;
-
-// When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
-// allocator.
-#[cfg(feature = "wee_alloc")]
-#[global_allocator]
-static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{HtmlButtonElement, HtmlSelectElement};
 
 #[wasm_bindgen]
 extern "C" {
-    fn alert(s: &str);
+    pub fn alert(s: &str);
+    pub fn confirm(msg: &str) -> bool;
+    pub fn prompt(msg: &str) -> String;
+}
+
+const MAX_TRIES: usize = 3;
+
+/// Answer the rounds of prompts of keyboard-interactive authentication,
+/// returns `None` if the user cancelled
+async fn keyboard_interactive(ssh: &mut Ssh, username: &str) -> Result<Option<AuthResult>, String> {
+    let mut result = ssh.login_keyboard_interactive(username).await?;
+    while let AuthResult::InfoRequest(request) = result {
+        // servers may send a round without prompts
+        let answers = if request.prompts.is_empty() {
+            Vec::new()
+        } else {
+            let title = if request.name.is_empty() {
+                format!("Authentication for {}", username)
+            } else {
+                request.name
+            };
+            match dialog::ask(&title, &request.instruction, &request.prompts).await {
+                Some(answers) => answers,
+                None => return Ok(None),
+            }
+        };
+        result = ssh.info_response(username, &answers).await?;
+    }
+    Ok(Some(result))
+}
+
+/// Go through the methods the server allows until one succeeds,
+/// servers requiring several factors answer with a partial success
+async fn authenticate(ssh: &mut Ssh, keys: &KeyStore) -> Result<(), String> {
+    loop {
+        let username = match dialog::ask("Login", "", &[("User:".to_owned(), true)]).await {
+            Some(mut answers) => answers.remove(0),
+            None => return Err("Authentication cancelled".to_owned()),
+        };
+        let mut result = ssh.login_none(&username).await?;
+        let mut keys_tried = false;
+        let mut tries = 0;
+        loop {
+            let methods = match result {
+                AuthResult::Success => return Ok(()),
+                AuthResult::Failure {
+                    methods,
+                    partial_success,
+                } => {
+                    if partial_success {
+                        info!("Further authentication required: {:?}", methods);
+                        tries = 0;
+                    }
+                    methods
+                }
+                // only answered during keyboard-interactive
+                AuthResult::InfoRequest(_) => break,
+            };
+            let allowed = |method: &str| methods.iter().any(|m| m == method);
+
+            result = if allowed("publickey") && !keys_tried && !keys.keys().is_empty() {
+                keys_tried = true;
+                let mut result = None;
+                'keys: for key in keys.keys() {
+                    // a certificate is offered before its bare key
+                    let offers = keys.certificate(&key).map(Some).into_iter().chain([None]);
+                    for certificate in offers {
+                        let r = ssh
+                            .login_publickey(&username, &key, certificate.as_ref())
+                            .await?;
+                        let accepted = !matches!(
+                            r,
+                            AuthResult::Failure {
+                                partial_success: false,
+                                ..
+                            }
+                        );
+                        result = Some(r);
+                        if accepted {
+                            break 'keys;
+                        }
+                    }
+                }
+                result.unwrap()
+            } else if tries >= MAX_TRIES {
+                break;
+            } else if allowed("keyboard-interactive") {
+                tries += 1;
+                match keyboard_interactive(ssh, &username).await? {
+                    Some(result) => result,
+                    None => break,
+                }
+            } else if allowed("password") {
+                tries += 1;
+                let prompt = [("Password:".to_owned(), false)];
+                match dialog::ask(&format!("Password for {}", username), "", &prompt).await {
+                    Some(answers) => ssh.login(&username, &answers[0]).await?,
+                    None => break,
+                }
+            } else {
+                break;
+            };
+        }
+        warn!("Wrong credientials");
+    }
+}
+
+// The target as a path of the url, replay/<id> names a recording
+fn encode_path(name: &str) -> String {
+    name.split('/')
+        .map(|segment| String::from(js_sys::encode_uri_component(segment)))
+        .collect::<Vec<_>>()
+        .join("/")
 }
 
-fn start_websocket() -> Result<(), JsValue> {
+fn start_websocket(keys: KeyStore, protocol: Option<Protocol>) -> Result<(), JsValue> {
+    // connect
+    let location = web_sys::window().unwrap().location();
+    let params = web_sys::UrlSearchParams::new_with_str(&location.search()?)?;
+    // ?target=<name> picks one of the targets of the gateway
+    let mut path = match params.get("target") {
+        Some(name) => format!("websockify/{}", encode_path(&name)),
+        None => "websockify".to_owned(),
+    };
+    // a portal hands out pages with a signed token for the gateway
+    if let Some(token) = params.get("token") {
+        path = format!("{}?token={}", path, js_sys::encode_uri_component(&token));
+    }
+    let url = format!(
+        "{scheme}://{host}/{path}",
+        scheme = if location.protocol()?.starts_with("https") {
+            "wss"
+        } else {
+            "ws"
+        },
+        host = location.host()?
+    );
+
+    spawn_local(async move {
+        if let Some(protocol) = protocol {
+            match TcpClient::connect(&url, protocol).await {
+                Ok(client) => client.main_loop().await,
+                Err(e) => alert(&e),
+            }
+            return;
+        }
+        let mut ssh = match Ssh::connect(&url).await {
+            Ok(ssh) => ssh,
+            Err(e) => {
+                alert(&e);
+                return;
+            }
+        };
+        if let Err(e) = authenticate(&mut ssh, &keys).await {
+            alert(&e);
+            return;
+        }
+        ssh.main_loop(keys).await
+    });
+
     Ok(())
 }
 
 #[wasm_bindgen(start)]
 pub fn run_app() -> Result<(), JsValue> {
     utils::set_panic_hook();
-    start_websocket()
+    tracing_wasm::set_as_global_default_with_config(
+        WASMLayerConfigBuilder::new()
+            .set_max_level(tracing::Level::INFO)
+            .build(),
+    );
+
+    let keys = KeyStore::new();
+    keys.bind();
+    KnownHosts::bind();
+    player::bind();
+
+    // wait for the user to import keys before connecting
+    let connect_btn = web_sys::window()
+        .unwrap()
+        .document()
+        .unwrap()
+        .get_element_by_id("connect")
+        .unwrap()
+        .dyn_into::<HtmlButtonElement>()
+        .map_err(|_| ())
+        .unwrap();
+    let protocol_select = web_sys::window()
+        .unwrap()
+        .document()
+        .unwrap()
+        .get_element_by_id("protocol")
+        .unwrap()
+        .dyn_into::<HtmlSelectElement>()
+        .map_err(|_| ())
+        .unwrap();
+    let btn = connect_btn.clone();
+    let connect = move || {
+        btn.set_disabled(true);
+        protocol_select.set_disabled(true);
+        // devices without SSH are reached over telnet or bare TCP
+        let protocol = match protocol_select.value().as_str() {
+            "telnet" => Some(Protocol::Telnet),
+            "raw" => Some(Protocol::Raw),
+            _ => None,
+        };
+        if let Err(e) = start_websocket(keys.clone(), protocol) {
+            alert(&format!("{:?}", e));
+        }
+    };
+    let handler = Box::new(connect) as Box<dyn FnMut()>;
+
+    let cb = Closure::wrap(handler);
+
+    connect_btn.set_onclick(Some(cb.as_ref().unchecked_ref()));
+    cb.forget();
+    Ok(())
 }
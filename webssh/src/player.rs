@@ -0,0 +1,174 @@
+use crate::asciicast::{Cast, Player};
+use crate::canvas::CanvasUtils;
+
+use std::cell::Cell;
+use std::rc::Rc;
+use tokio::sync::mpsc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{Element, HtmlCanvasElement, HtmlElement, HtmlInputElement, HtmlSelectElement};
+
+// how often the screen is updated during playback
+const TICK_MS: i32 = 30;
+
+fn element<T: JsCast>(id: &str) -> T {
+    web_sys::window()
+        .unwrap()
+        .document()
+        .unwrap()
+        .get_element_by_id(id)
+        .unwrap()
+        .dyn_into::<T>()
+        .map_err(|_| ())
+        .unwrap()
+}
+
+fn format_time(secs: f64) -> String {
+    let secs = secs as u64;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// State shared between the playback controls and the playback loop
+#[derive(Clone)]
+struct Controls {
+    paused: Rc<Cell<bool>>,
+    speed: Rc<Cell<f64>>,
+    seek: Rc<Cell<Option<f64>>>,
+    // bumped to stop the running playback
+    generation: Rc<Cell<u32>>,
+}
+
+/// Plays `.cast` recordings picked by the user in the player overlay
+pub fn bind() {
+    let controls = Controls {
+        paused: Rc::new(Cell::new(false)),
+        speed: Rc::new(Cell::new(1.0)),
+        seek: Rc::new(Cell::new(None)),
+        generation: Rc::new(Cell::new(0)),
+    };
+    // key presses are ignored during playback
+    let (sender, _) = mpsc::channel(1);
+    let mut canvas = CanvasUtils::new(sender, element::<HtmlCanvasElement>("cast-canvas"));
+    // bind the canvas once, every playback works on a clone
+    canvas.init(80, 24);
+
+    let c = controls.clone();
+    let play_btn = element::<HtmlElement>("castplay");
+    let btn = play_btn.clone();
+    let play = move || {
+        c.paused.set(!c.paused.get());
+        btn.set_text_content(Some(if c.paused.get() { "Play" } else { "Pause" }));
+    };
+    let handler = Box::new(play) as Box<dyn FnMut()>;
+    let cb = Closure::wrap(handler);
+    play_btn.set_onclick(Some(cb.as_ref().unchecked_ref()));
+    cb.forget();
+
+    let c = controls.clone();
+    let seek_input = element::<HtmlInputElement>("castseek");
+    let input = seek_input.clone();
+    let seek = move || c.seek.set(Some(input.value_as_number()));
+    let handler = Box::new(seek) as Box<dyn FnMut()>;
+    let cb = Closure::wrap(handler);
+    seek_input.set_oninput(Some(cb.as_ref().unchecked_ref()));
+    cb.forget();
+
+    let c = controls.clone();
+    let speed_select = element::<HtmlSelectElement>("castspeed");
+    let select = speed_select.clone();
+    let speed = move || c.speed.set(select.value().parse().unwrap_or(1.0));
+    let handler = Box::new(speed) as Box<dyn FnMut()>;
+    let cb = Closure::wrap(handler);
+    speed_select.set_onchange(Some(cb.as_ref().unchecked_ref()));
+    cb.forget();
+
+    let c = controls.clone();
+    let close = move || {
+        c.generation.set(c.generation.get().wrapping_add(1));
+        element::<HtmlElement>("player").set_hidden(true);
+    };
+    let handler = Box::new(close) as Box<dyn FnMut()>;
+    let cb = Closure::wrap(handler);
+    element::<HtmlElement>("castclose").set_onclick(Some(cb.as_ref().unchecked_ref()));
+    cb.forget();
+
+    let file_input = element::<HtmlInputElement>("castfile");
+    let picker = file_input.clone();
+    let open = move || {
+        let file = match picker.files().and_then(|files| files.get(0)) {
+            Some(file) => file,
+            None => return,
+        };
+        picker.set_value("");
+        let controls = controls.clone();
+        let canvas = canvas.clone();
+        spawn_local(async move {
+            let text = match JsFuture::from(file.text()).await {
+                Ok(text) => text.as_string().unwrap_or_default(),
+                Err(e) => {
+                    crate::alert(&format!("Unable to read file {:?}", e));
+                    return;
+                }
+            };
+            match Cast::parse(&text) {
+                Ok(cast) => play_cast(cast, canvas, controls).await,
+                Err(e) => crate::alert(&format!("Unable to play recording: {}", e)),
+            }
+        });
+    };
+    let handler = Box::new(open) as Box<dyn FnMut()>;
+    let cb = Closure::wrap(handler);
+    file_input.set_onchange(Some(cb.as_ref().unchecked_ref()));
+    cb.forget();
+}
+
+async fn play_cast(cast: Cast, mut canvas: CanvasUtils, controls: Controls) {
+    let generation = controls.generation.get().wrapping_add(1);
+    controls.generation.set(generation);
+    controls.paused.set(false);
+    controls.seek.set(None);
+    element::<HtmlElement>("castplay").set_text_content(Some("Pause"));
+    element::<HtmlElement>("player").set_hidden(false);
+    let seek_input = element::<HtmlInputElement>("castseek");
+    let time_label = element::<Element>("casttime");
+
+    let mut player = Player::new(cast);
+    let duration = player.duration();
+    seek_input.set_max(&duration.to_string());
+    let mut size = (0, 0);
+    let mut last = js_sys::Date::now();
+    while controls.generation.get() == generation {
+        let now = js_sys::Date::now();
+        let mut time = player.time();
+        if let Some(seek) = controls.seek.take() {
+            time = seek;
+        } else if !controls.paused.get() && player.is_finished() {
+            // play again from the start
+            time = 0.0;
+        } else if !controls.paused.get() {
+            time += (now - last) / 1000.0 * controls.speed.get();
+        }
+        last = now;
+        player.seek(time);
+
+        let screen = player.terminal_mut().screen_mut();
+        if (screen.cols(), screen.rows()) != size {
+            size = (screen.cols(), screen.rows());
+            canvas.init(size.0, size.1);
+            screen.invalidate();
+        }
+        canvas.draw(screen);
+        seek_input.set_value_as_number(player.time());
+        time_label.set_text_content(Some(&format!(
+            "{} / {}",
+            format_time(player.time()),
+            format_time(duration)
+        )));
+        if player.is_finished() && !controls.paused.get() {
+            controls.paused.set(true);
+            element::<HtmlElement>("castplay").set_text_content(Some("Play"));
+        }
+        crate::utils::sleep(TICK_MS).await;
+    }
+}
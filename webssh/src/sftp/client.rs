@@ -0,0 +1,588 @@
+use super::*;
+use crate::ssh::SshError;
+use std::collections::HashMap;
+use tracing::debug;
+
+// stay below the 32k packet size every server must accept
+const READ_CHUNK: u32 = 32 * 1024;
+const WRITE_CHUNK: usize = 32 * 1024 - 64;
+
+// What to do with the response to a pending request
+enum Op {
+    RealPath,
+    OpenDir(String),
+    ReadDir {
+        path: String,
+        handle: Vec<u8>,
+        entries: Vec<DirEntry>,
+    },
+    OpenRead(String),
+    Read {
+        path: String,
+        handle: Vec<u8>,
+        data: Vec<u8>,
+    },
+    OpenWrite(String, Vec<u8>),
+    Write {
+        path: String,
+        handle: Vec<u8>,
+        data: Vec<u8>,
+        offset: usize,
+    },
+    Close,
+    Simple(String),
+}
+
+/// A sans-io SFTP client
+///
+/// Feed it the data of the subsystem channel and send whatever
+/// [`SftpClient::take_output`] returns.
+pub struct SftpClient {
+    rbuf: Vec<u8>,
+    output: Vec<u8>,
+    next_id: u32,
+    ops: HashMap<u32, Op>,
+    version: Option<u32>,
+    // commands issued before the version exchange completed
+    deferred: Vec<Command>,
+}
+
+impl Default for SftpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SftpClient {
+    pub fn new() -> Self {
+        let mut client = Self {
+            rbuf: Vec::new(),
+            output: Vec::new(),
+            next_id: 0,
+            ops: HashMap::new(),
+            version: None,
+            deferred: Vec::new(),
+        };
+        let mut writer = SshWriter::new(Vec::with_capacity(5));
+        writer.write_u8(FXP_INIT);
+        writer.write_u32(VERSION);
+        client.send(writer.into_inner());
+        client
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    pub fn command(&mut self, command: Command) {
+        if self.version.is_none() {
+            self.deferred.push(command);
+            return;
+        }
+        match command {
+            // resolve the path first so `..` and `.` show up as real paths
+            Command::List(path) => self.request(FXP_REALPATH, Op::RealPath, |w| w.write_str(&path)),
+            Command::Download(path) => self.request(FXP_OPEN, Op::OpenRead(path.clone()), |w| {
+                w.write_str(&path);
+                w.write_u32(FXF_READ);
+                Attrs::default().write(w);
+            }),
+            Command::Upload(path, data) => {
+                self.request(FXP_OPEN, Op::OpenWrite(path.clone(), data), |w| {
+                    w.write_str(&path);
+                    w.write_u32(FXF_WRITE | FXF_CREAT | FXF_TRUNC);
+                    Attrs::default().write(w);
+                })
+            }
+            Command::Rename(from, to) => {
+                let desc = format!("Renamed {} to {}", from, to);
+                self.request(FXP_RENAME, Op::Simple(desc), |w| {
+                    w.write_str(&from);
+                    w.write_str(&to);
+                })
+            }
+            Command::Remove(path) => {
+                let desc = format!("Removed {}", path);
+                self.request(FXP_REMOVE, Op::Simple(desc), |w| w.write_str(&path))
+            }
+            Command::RemoveDir(path) => {
+                let desc = format!("Removed {}", path);
+                self.request(FXP_RMDIR, Op::Simple(desc), |w| w.write_str(&path))
+            }
+            Command::Mkdir(path) => {
+                let desc = format!("Created {}", path);
+                self.request(FXP_MKDIR, Op::Simple(desc), |w| {
+                    w.write_str(&path);
+                    Attrs::default().write(w);
+                })
+            }
+        }
+    }
+
+    /// Process data received on the channel
+    pub fn feed(&mut self, data: &[u8]) -> SshResult<Vec<Event>> {
+        self.rbuf.extend_from_slice(data);
+        let mut events = Vec::new();
+        loop {
+            if self.rbuf.len() < 4 {
+                break;
+            }
+            let len = u32::from_be_bytes(self.rbuf[..4].try_into().unwrap()) as usize;
+            if self.rbuf.len() < 4 + len {
+                break;
+            }
+            let packet: Vec<u8> = self.rbuf.drain(..4 + len).skip(4).collect();
+            if packet.is_empty() {
+                return Err(SshError::Protocol("Empty SFTP packet".to_owned()));
+            }
+            self.handle_packet(&packet, &mut events)?;
+        }
+        Ok(events)
+    }
+
+    fn send(&mut self, payload: Vec<u8>) {
+        self.output
+            .extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        self.output.extend_from_slice(&payload);
+    }
+
+    fn request(&mut self, kind: u8, op: Op, build: impl FnOnce(&mut SshWriter)) {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut writer = SshWriter::new(Vec::with_capacity(64));
+        writer.write_u8(kind);
+        writer.write_u32(id);
+        build(&mut writer);
+        self.send(writer.into_inner());
+        self.ops.insert(id, op);
+    }
+
+    fn close(&mut self, handle: &[u8]) {
+        self.request(FXP_CLOSE, Op::Close, |w| w.write_string(handle));
+    }
+
+    fn write_next(&mut self, path: String, handle: Vec<u8>, data: Vec<u8>, offset: usize) {
+        if offset >= data.len() {
+            self.close(&handle);
+            return;
+        }
+        let end = (offset + WRITE_CHUNK).min(data.len());
+        let chunk = data[offset..end].to_vec();
+        let op = Op::Write {
+            path,
+            handle: handle.clone(),
+            data,
+            offset: end,
+        };
+        self.request(FXP_WRITE, op, |w| {
+            w.write_string(&handle);
+            w.write_u64(offset as u64);
+            w.write_string(&chunk);
+        });
+    }
+
+    fn handle_packet(&mut self, packet: &[u8], events: &mut Vec<Event>) -> SshResult<()> {
+        let mut reader = SshReader::new(&packet[1..]);
+        if packet[0] == FXP_VERSION {
+            let version = reader.read_u32()?;
+            debug!("SFTP server version {}", version);
+            self.version = Some(version);
+            for command in std::mem::take(&mut self.deferred) {
+                self.command(command);
+            }
+            return Ok(());
+        }
+
+        let id = reader.read_u32()?;
+        let op = match self.ops.remove(&id) {
+            Some(op) => op,
+            None => return Err(SshError::Protocol(format!("Unknown SFTP request {}", id))),
+        };
+        match (op, packet[0]) {
+            (Op::RealPath, FXP_NAME) => {
+                let _count = reader.read_u32()?;
+                let path = reader.read_utf8()?;
+                self.request(FXP_OPENDIR, Op::OpenDir(path.clone()), |w| {
+                    w.write_str(&path)
+                });
+            }
+            (Op::OpenDir(path), FXP_HANDLE) => {
+                let handle = reader.read_string()?.to_vec();
+                self.request(
+                    FXP_READDIR,
+                    Op::ReadDir {
+                        path,
+                        handle: handle.clone(),
+                        entries: Vec::new(),
+                    },
+                    |w| w.write_string(&handle),
+                );
+            }
+            (
+                Op::ReadDir {
+                    path,
+                    handle,
+                    mut entries,
+                },
+                FXP_NAME,
+            ) => {
+                for _ in 0..reader.read_u32()? {
+                    let name = reader.read_utf8()?;
+                    let longname = reader.read_utf8()?;
+                    let attrs = Attrs::read(&mut reader)?;
+                    if name != "." && name != ".." {
+                        entries.push(DirEntry {
+                            name,
+                            longname,
+                            attrs,
+                        });
+                    }
+                }
+                let h = handle.clone();
+                self.request(
+                    FXP_READDIR,
+                    Op::ReadDir {
+                        path,
+                        handle,
+                        entries,
+                    },
+                    |w| w.write_string(&h),
+                );
+            }
+            (
+                Op::ReadDir {
+                    path,
+                    handle,
+                    mut entries,
+                },
+                FXP_STATUS,
+            ) => {
+                self.close(&handle);
+                match read_status(&mut reader)? {
+                    Ok(()) | Err((FX_EOF, _)) => {
+                        entries.sort_by(|a, b| {
+                            b.attrs
+                                .is_dir()
+                                .cmp(&a.attrs.is_dir())
+                                .then_with(|| a.name.cmp(&b.name))
+                        });
+                        events.push(Event::Listing(path, entries));
+                    }
+                    Err((_, msg)) => events.push(Event::Error(msg)),
+                }
+            }
+            (Op::OpenRead(path), FXP_HANDLE) => {
+                let handle = reader.read_string()?.to_vec();
+                self.request(
+                    FXP_READ,
+                    Op::Read {
+                        path,
+                        handle: handle.clone(),
+                        data: Vec::new(),
+                    },
+                    |w| {
+                        w.write_string(&handle);
+                        w.write_u64(0);
+                        w.write_u32(READ_CHUNK);
+                    },
+                );
+            }
+            (
+                Op::Read {
+                    path,
+                    handle,
+                    mut data,
+                },
+                FXP_DATA,
+            ) => {
+                data.extend_from_slice(reader.read_string()?);
+                let offset = data.len() as u64;
+                let h = handle.clone();
+                self.request(FXP_READ, Op::Read { path, handle, data }, |w| {
+                    w.write_string(&h);
+                    w.write_u64(offset);
+                    w.write_u32(READ_CHUNK);
+                });
+            }
+            (Op::Read { path, handle, data }, FXP_STATUS) => {
+                self.close(&handle);
+                match read_status(&mut reader)? {
+                    Ok(()) | Err((FX_EOF, _)) => events.push(Event::Downloaded(path, data)),
+                    Err((_, msg)) => events.push(Event::Error(msg)),
+                }
+            }
+            (Op::OpenWrite(path, data), FXP_HANDLE) => {
+                let handle = reader.read_string()?.to_vec();
+                if data.is_empty() {
+                    events.push(Event::Uploaded(path.clone()));
+                }
+                self.write_next(path, handle, data, 0);
+            }
+            (
+                Op::Write {
+                    path,
+                    handle,
+                    data,
+                    offset,
+                },
+                FXP_STATUS,
+            ) => match read_status(&mut reader)? {
+                Ok(()) => {
+                    if offset >= data.len() {
+                        events.push(Event::Uploaded(path.clone()));
+                    }
+                    self.write_next(path, handle, data, offset);
+                }
+                Err((_, msg)) => {
+                    self.close(&handle);
+                    events.push(Event::Error(msg));
+                }
+            },
+            (Op::Close, _) => {}
+            (Op::Simple(desc), FXP_STATUS) => match read_status(&mut reader)? {
+                Ok(()) => events.push(Event::Done(desc)),
+                Err((_, msg)) => events.push(Event::Error(msg)),
+            },
+            // a failed open, opendir or realpath
+            (_, FXP_STATUS) => match read_status(&mut reader)? {
+                Ok(()) => {}
+                Err((_, msg)) => events.push(Event::Error(msg)),
+            },
+            (_, kind) => {
+                return Err(SshError::Protocol(format!(
+                    "Unexpected SFTP response {}",
+                    kind
+                )))
+            }
+        }
+        Ok(())
+    }
+}
+
+// Returns the code and message of a failure status
+fn read_status(reader: &mut SshReader) -> SshResult<Result<(), (u32, String)>> {
+    let code = reader.read_u32()?;
+    // some old servers leave out the message
+    let msg = if reader.remain() > 0 {
+        reader.read_utf8()?
+    } else {
+        String::new()
+    };
+    if code == FX_OK {
+        return Ok(Ok(()));
+    }
+    let msg = if msg.is_empty() {
+        format!("SFTP error {}", code)
+    } else {
+        msg
+    };
+    Ok(Err((code, msg)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Minimal in-memory server answering the client's requests
+    struct Server {
+        files: HashMap<String, Vec<u8>>,
+        handles: HashMap<Vec<u8>, (String, bool)>,
+    }
+
+    impl Server {
+        fn reply(&mut self, input: &[u8]) -> Vec<u8> {
+            let mut out = Vec::new();
+            let mut pos = 0;
+            while pos < input.len() {
+                let len = u32::from_be_bytes(input[pos..pos + 4].try_into().unwrap()) as usize;
+                let packet = &input[pos + 4..pos + 4 + len];
+                pos += 4 + len;
+                let payload = self.handle(packet);
+                out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+                out.extend_from_slice(&payload);
+            }
+            out
+        }
+
+        fn status(id: u32, code: u32) -> Vec<u8> {
+            let mut w = SshWriter::new(Vec::new());
+            w.write_u8(FXP_STATUS);
+            w.write_u32(id);
+            w.write_u32(code);
+            w.write_str("");
+            w.write_str("");
+            w.into_inner()
+        }
+
+        fn handle(&mut self, packet: &[u8]) -> Vec<u8> {
+            let mut r = SshReader::new(&packet[1..]);
+            let mut w = SshWriter::new(Vec::new());
+            if packet[0] == FXP_INIT {
+                w.write_u8(FXP_VERSION);
+                w.write_u32(VERSION);
+                return w.into_inner();
+            }
+            let id = r.read_u32().unwrap();
+            match packet[0] {
+                FXP_REALPATH => {
+                    w.write_u8(FXP_NAME);
+                    w.write_u32(id);
+                    w.write_u32(1);
+                    w.write_str("/home");
+                    w.write_str("");
+                    Attrs::default().write(&mut w);
+                }
+                FXP_OPENDIR | FXP_OPEN => {
+                    let path = r.read_utf8().unwrap();
+                    let handle = format!("h{}", id).into_bytes();
+                    self.handles.insert(handle.clone(), (path, false));
+                    w.write_u8(FXP_HANDLE);
+                    w.write_u32(id);
+                    w.write_string(&handle);
+                }
+                FXP_READDIR => {
+                    let handle = r.read_string().unwrap().to_vec();
+                    let (_, done) = self.handles.get_mut(&handle).unwrap();
+                    if *done {
+                        return Self::status(id, FX_EOF);
+                    }
+                    *done = true;
+                    w.write_u8(FXP_NAME);
+                    w.write_u32(id);
+                    w.write_u32(self.files.len() as u32 + 3);
+                    for name in [".", "..", "dir"]
+                        .into_iter()
+                        .chain(self.files.keys().map(String::as_str))
+                    {
+                        if name == "dir" {
+                            w.write_str(name);
+                            w.write_str(name);
+                            Attrs {
+                                permissions: Some(0o040755),
+                                ..Attrs::default()
+                            }
+                            .write(&mut w);
+                        } else if name.starts_with('.') || self.files.contains_key(name) {
+                            w.write_str(name);
+                            w.write_str(name);
+                            Attrs::default().write(&mut w);
+                        }
+                    }
+                }
+                FXP_READ => {
+                    let handle = r.read_string().unwrap().to_vec();
+                    let offset = r.read_u64().unwrap() as usize;
+                    let len = r.read_u32().unwrap() as usize;
+                    let (path, _) = &self.handles[&handle];
+                    let data = &self.files[path.trim_start_matches("/home/")];
+                    if offset >= data.len() {
+                        return Self::status(id, FX_EOF);
+                    }
+                    w.write_u8(FXP_DATA);
+                    w.write_u32(id);
+                    w.write_string(&data[offset..(offset + len).min(data.len())]);
+                }
+                FXP_WRITE => {
+                    let handle = r.read_string().unwrap().to_vec();
+                    let offset = r.read_u64().unwrap() as usize;
+                    let data = r.read_string().unwrap();
+                    let (path, _) = &self.handles[&handle];
+                    let file = self
+                        .files
+                        .entry(path.trim_start_matches("/home/").to_owned())
+                        .or_default();
+                    assert_eq!(file.len(), offset);
+                    file.extend_from_slice(data);
+                    return Self::status(id, FX_OK);
+                }
+                FXP_CLOSE => return Self::status(id, FX_OK),
+                FXP_REMOVE => {
+                    let path = r.read_utf8().unwrap();
+                    let code = match self.files.remove(path.trim_start_matches("/home/")) {
+                        Some(_) => FX_OK,
+                        None => 2,
+                    };
+                    return Self::status(id, code);
+                }
+                _ => return Self::status(id, 8),
+            }
+            w.into_inner()
+        }
+    }
+
+    fn run(client: &mut SftpClient, server: &mut Server) -> Vec<Event> {
+        let mut events = Vec::new();
+        loop {
+            let output = client.take_output();
+            if output.is_empty() {
+                return events;
+            }
+            let reply = server.reply(&output);
+            events.extend(client.feed(&reply).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_client() {
+        let mut server = Server {
+            files: HashMap::new(),
+            handles: HashMap::new(),
+        };
+        let mut client = SftpClient::new();
+        client.command(Command::List(".".to_owned()));
+        assert_eq!(
+            run(&mut client, &mut server),
+            vec![Event::Listing(
+                "/home".to_owned(),
+                vec![DirEntry {
+                    name: "dir".to_owned(),
+                    longname: "dir".to_owned(),
+                    attrs: Attrs {
+                        permissions: Some(0o040755),
+                        ..Attrs::default()
+                    }
+                }]
+            )]
+        );
+
+        let big: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        client.command(Command::Upload("/home/big".to_owned(), big.clone()));
+        assert_eq!(
+            run(&mut client, &mut server),
+            vec![Event::Uploaded("/home/big".to_owned())]
+        );
+        assert_eq!(server.files["big"], big);
+
+        client.command(Command::Download("/home/big".to_owned()));
+        assert_eq!(
+            run(&mut client, &mut server),
+            vec![Event::Downloaded("/home/big".to_owned(), big)]
+        );
+
+        client.command(Command::Remove("/home/big".to_owned()));
+        client.command(Command::Remove("/home/big".to_owned()));
+        client.command(Command::Mkdir("/home/new".to_owned()));
+        assert_eq!(
+            run(&mut client, &mut server),
+            vec![
+                Event::Done("Removed /home/big".to_owned()),
+                Event::Error("SFTP error 2".to_owned()),
+                Event::Error("SFTP error 8".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_packets() {
+        let mut client = SftpClient::new();
+        client.take_output();
+        client.command(Command::List("/".to_owned()));
+        assert!(client.take_output().is_empty());
+        let version = [0, 0, 0, 5, FXP_VERSION, 0, 0, 0, 3];
+        for b in version {
+            assert!(client.feed(&[b]).unwrap().is_empty());
+        }
+        // the deferred listing is sent once the version is known
+        assert!(!client.take_output().is_empty());
+    }
+}
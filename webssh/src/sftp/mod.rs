@@ -0,0 +1,176 @@
+// SSH File Transfer Protocol version 3
+// https://datatracker.ietf.org/doc/html/draft-ietf-secsh-filexfer-02
+
+mod client;
+
+pub use client::SftpClient;
+
+use crate::ssh::{SshReader, SshResult, SshWriter};
+
+pub const VERSION: u32 = 3;
+
+// Packet types
+pub const FXP_INIT: u8 = 1;
+pub const FXP_VERSION: u8 = 2;
+pub const FXP_OPEN: u8 = 3;
+pub const FXP_CLOSE: u8 = 4;
+pub const FXP_READ: u8 = 5;
+pub const FXP_WRITE: u8 = 6;
+pub const FXP_OPENDIR: u8 = 11;
+pub const FXP_READDIR: u8 = 12;
+pub const FXP_REMOVE: u8 = 13;
+pub const FXP_MKDIR: u8 = 14;
+pub const FXP_RMDIR: u8 = 15;
+pub const FXP_REALPATH: u8 = 16;
+pub const FXP_RENAME: u8 = 18;
+pub const FXP_STATUS: u8 = 101;
+pub const FXP_HANDLE: u8 = 102;
+pub const FXP_DATA: u8 = 103;
+pub const FXP_NAME: u8 = 104;
+
+// Status codes
+pub const FX_OK: u32 = 0;
+pub const FX_EOF: u32 = 1;
+
+// Open flags
+pub const FXF_READ: u32 = 0x01;
+pub const FXF_WRITE: u32 = 0x02;
+pub const FXF_CREAT: u32 = 0x08;
+pub const FXF_TRUNC: u32 = 0x10;
+
+// Attribute flags
+const ATTR_SIZE: u32 = 0x01;
+const ATTR_UIDGID: u32 = 0x02;
+const ATTR_PERMISSIONS: u32 = 0x04;
+const ATTR_ACMODTIME: u32 = 0x08;
+const ATTR_EXTENDED: u32 = 0x8000_0000;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attrs {
+    pub size: Option<u64>,
+    pub uid_gid: Option<(u32, u32)>,
+    pub permissions: Option<u32>,
+    pub atime_mtime: Option<(u32, u32)>,
+}
+
+impl Attrs {
+    pub fn read(reader: &mut SshReader) -> SshResult<Self> {
+        let flags = reader.read_u32()?;
+        let mut attrs = Self::default();
+        if flags & ATTR_SIZE != 0 {
+            attrs.size = Some(reader.read_u64()?);
+        }
+        if flags & ATTR_UIDGID != 0 {
+            attrs.uid_gid = Some((reader.read_u32()?, reader.read_u32()?));
+        }
+        if flags & ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(reader.read_u32()?);
+        }
+        if flags & ATTR_ACMODTIME != 0 {
+            attrs.atime_mtime = Some((reader.read_u32()?, reader.read_u32()?));
+        }
+        if flags & ATTR_EXTENDED != 0 {
+            for _ in 0..reader.read_u32()? {
+                reader.read_string()?;
+                reader.read_string()?;
+            }
+        }
+        Ok(attrs)
+    }
+
+    pub fn write(&self, writer: &mut SshWriter) {
+        let mut flags = 0;
+        flags |= self.size.map_or(0, |_| ATTR_SIZE);
+        flags |= self.uid_gid.map_or(0, |_| ATTR_UIDGID);
+        flags |= self.permissions.map_or(0, |_| ATTR_PERMISSIONS);
+        flags |= self.atime_mtime.map_or(0, |_| ATTR_ACMODTIME);
+        writer.write_u32(flags);
+        if let Some(size) = self.size {
+            writer.write_u64(size);
+        }
+        if let Some((uid, gid)) = self.uid_gid {
+            writer.write_u32(uid);
+            writer.write_u32(gid);
+        }
+        if let Some(permissions) = self.permissions {
+            writer.write_u32(permissions);
+        }
+        if let Some((atime, mtime)) = self.atime_mtime {
+            writer.write_u32(atime);
+            writer.write_u32(mtime);
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.permissions
+            .is_some_and(|mode| mode & 0o170000 == 0o040000)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    // `ls -l` style description of the entry
+    pub longname: String,
+    pub attrs: Attrs,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    List(String),
+    Download(String),
+    Upload(String, Vec<u8>),
+    Rename(String, String),
+    Remove(String),
+    RemoveDir(String),
+    Mkdir(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Directory listing of a canonical path, directories first
+    Listing(String, Vec<DirEntry>),
+    Downloaded(String, Vec<u8>),
+    Uploaded(String),
+    /// A rename, remove or mkdir succeeded
+    Done(String),
+    Error(String),
+}
+
+/// Append `name` to a remote directory path
+pub fn join(dir: &str, name: &str) -> String {
+    if dir.ends_with('/') {
+        format!("{}{}", dir, name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_attrs() {
+        let attrs = Attrs {
+            size: Some(1234),
+            uid_gid: None,
+            permissions: Some(0o040755),
+            atime_mtime: Some((1, 2)),
+        };
+        let mut writer = SshWriter::new(Vec::new());
+        attrs.write(&mut writer);
+        let buf = writer.into_inner();
+        let mut reader = SshReader::new(&buf);
+        let parsed = Attrs::read(&mut reader).unwrap();
+        assert_eq!(parsed, attrs);
+        assert!(parsed.is_dir());
+        assert_eq!(reader.remain(), 0);
+    }
+
+    #[test]
+    fn test_join() {
+        assert_eq!(join("/", "etc"), "/etc");
+        assert_eq!(join("/home/user", ".."), "/home/user/..");
+    }
+}
@@ -0,0 +1,268 @@
+use crate::sftp::{self, Command, DirEntry, Event};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use tokio::sync::mpsc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{Element, HtmlButtonElement, HtmlInputElement, MouseEvent};
+
+fn button(id: &str) -> HtmlButtonElement {
+    web_sys::window()
+        .unwrap()
+        .document()
+        .unwrap()
+        .get_element_by_id(id)
+        .unwrap()
+        .dyn_into::<HtmlButtonElement>()
+        .map_err(|_| ())
+        .unwrap()
+}
+
+fn input(id: &str) -> HtmlInputElement {
+    web_sys::window()
+        .unwrap()
+        .document()
+        .unwrap()
+        .get_element_by_id(id)
+        .unwrap()
+        .dyn_into::<HtmlInputElement>()
+        .map_err(|_| ())
+        .unwrap()
+}
+
+fn parent(dir: &str) -> String {
+    match dir.trim_end_matches('/').rsplit_once('/') {
+        Some(("", _)) | None => "/".to_owned(),
+        Some((parent, _)) => parent.to_owned(),
+    }
+}
+
+/// File browser drawer of the sftp subsystem
+pub struct SftpPanel {
+    status: Element,
+    list: Element,
+    path: HtmlInputElement,
+    cwd: Rc<RefCell<String>>,
+    sender: mpsc::Sender<Command>,
+}
+
+impl SftpPanel {
+    pub fn new(sender: mpsc::Sender<Command>) -> Self {
+        let document = web_sys::window().unwrap().document().unwrap();
+        Self {
+            status: document.get_element_by_id("sftpstatus").unwrap(),
+            list: document.get_element_by_id("sftplist").unwrap(),
+            path: input("sftppath"),
+            cwd: Rc::new(RefCell::new(".".to_owned())),
+            sender,
+        }
+    }
+
+    pub fn set_status(&self, status: &str) {
+        self.status.set_text_content(Some(status));
+    }
+
+    pub fn bind(&self) {
+        let send = {
+            let sender = self.sender.clone();
+            move |command: Command| {
+                let _ = futures::executor::block_on(sender.send(command));
+            }
+        };
+
+        // go to the typed path
+        let path = self.path.clone();
+        let s = send.clone();
+        let go = move || s(Command::List(path.value()));
+        let handler = Box::new(go) as Box<dyn FnMut()>;
+        let cb = Closure::wrap(handler);
+        button("sftpgo").set_onclick(Some(cb.as_ref().unchecked_ref()));
+        cb.forget();
+
+        let cwd = self.cwd.clone();
+        let s = send.clone();
+        let up = move || s(Command::List(parent(&cwd.borrow())));
+        let handler = Box::new(up) as Box<dyn FnMut()>;
+        let cb = Closure::wrap(handler);
+        button("sftpup").set_onclick(Some(cb.as_ref().unchecked_ref()));
+        cb.forget();
+
+        let cwd = self.cwd.clone();
+        let s = send.clone();
+        let refresh = move || s(Command::List(cwd.borrow().clone()));
+        let handler = Box::new(refresh) as Box<dyn FnMut()>;
+        let cb = Closure::wrap(handler);
+        button("sftprefresh").set_onclick(Some(cb.as_ref().unchecked_ref()));
+        cb.forget();
+
+        let cwd = self.cwd.clone();
+        let s = send.clone();
+        let mkdir = move || {
+            let name = crate::prompt("Directory name:");
+            if !name.is_empty() {
+                s(Command::Mkdir(sftp::join(&cwd.borrow(), &name)));
+            }
+        };
+        let handler = Box::new(mkdir) as Box<dyn FnMut()>;
+        let cb = Closure::wrap(handler);
+        button("sftpmkdir").set_onclick(Some(cb.as_ref().unchecked_ref()));
+        cb.forget();
+
+        // read the picked files and upload them to the current directory
+        let cwd = self.cwd.clone();
+        let sender = self.sender.clone();
+        let status = self.status.clone();
+        let upload_input = input("sftpupload");
+        let picker = upload_input.clone();
+        let upload = move || {
+            let files = match picker.files() {
+                Some(files) => files,
+                None => return,
+            };
+            for idx in 0..files.length() {
+                let file = files.get(idx).unwrap();
+                let path = sftp::join(&cwd.borrow(), &file.name());
+                let sender = sender.clone();
+                let status = status.clone();
+                status.set_text_content(Some(&format!("Uploading {}", path)));
+                spawn_local(async move {
+                    match JsFuture::from(file.array_buffer()).await {
+                        Ok(buf) => {
+                            let data = js_sys::Uint8Array::new(&buf).to_vec();
+                            let _ = sender.send(Command::Upload(path, data)).await;
+                        }
+                        Err(e) => {
+                            status.set_text_content(Some(&format!("Unable to read file {:?}", e)))
+                        }
+                    }
+                });
+            }
+            picker.set_value("");
+        };
+        let handler = Box::new(upload) as Box<dyn FnMut()>;
+        let cb = Closure::wrap(handler);
+        upload_input.set_onchange(Some(cb.as_ref().unchecked_ref()));
+        cb.forget();
+
+        // one listener for the actions of every entry in the listing
+        let cwd = self.cwd.clone();
+        let s = send;
+        let click = move |e: MouseEvent| {
+            let target = match e
+                .target()
+                .and_then(|t| t.dyn_into::<Element>().ok())
+                .and_then(|t| t.closest("[data-action]").ok().flatten())
+            {
+                Some(target) => target,
+                None => return,
+            };
+            let name = target.get_attribute("data-name").unwrap_or_default();
+            let is_dir = target.has_attribute("data-dir");
+            let path = sftp::join(&cwd.borrow(), &name);
+            match target.get_attribute("data-action").as_deref() {
+                Some("open") if is_dir => s(Command::List(path)),
+                Some("open") => s(Command::Download(path)),
+                Some("rename") => {
+                    let new_name = crate::prompt(&format!("Rename {} to:", name));
+                    if !new_name.is_empty() && new_name != name {
+                        s(Command::Rename(path, sftp::join(&cwd.borrow(), &new_name)));
+                    }
+                }
+                Some("delete") if crate::confirm(&format!("Delete {}?", path)) => {
+                    s(if is_dir {
+                        Command::RemoveDir(path)
+                    } else {
+                        Command::Remove(path)
+                    });
+                }
+                _ => {}
+            }
+        };
+        let handler = Box::new(click) as Box<dyn FnMut(_)>;
+        let cb = Closure::wrap(handler);
+        self.list
+            .add_event_listener_with_callback("click", cb.as_ref().unchecked_ref())
+            .unwrap();
+        cb.forget();
+    }
+
+    /// Show the result of a command, returns a follow up command if any
+    pub fn handle(&self, event: Event) -> Option<Command> {
+        match event {
+            Event::Listing(path, entries) => {
+                self.show_listing(&path, &entries);
+                self.set_status(&format!("{} entries", entries.len()));
+                *self.cwd.borrow_mut() = path;
+                None
+            }
+            Event::Downloaded(path, data) => {
+                let name = path.rsplit('/').next().unwrap_or(&path);
+                crate::utils::download(name, &data, "application/octet-stream");
+                self.set_status(&format!("Downloaded {}", path));
+                None
+            }
+            Event::Uploaded(path) => {
+                self.set_status(&format!("Uploaded {}", path));
+                Some(Command::List(self.cwd.borrow().clone()))
+            }
+            Event::Done(desc) => {
+                self.set_status(&desc);
+                Some(Command::List(self.cwd.borrow().clone()))
+            }
+            Event::Error(e) => {
+                self.set_status(&e);
+                None
+            }
+        }
+    }
+
+    fn show_listing(&self, path: &str, entries: &[DirEntry]) {
+        let document = web_sys::window().unwrap().document().unwrap();
+        self.path.set_value(path);
+        self.list.set_text_content(None);
+        for entry in entries {
+            let row = document.create_element("div").unwrap();
+            row.set_class_name("sftp-entry");
+
+            // names are set as text, never parsed as html
+            let name = document.create_element("span").unwrap();
+            name.set_class_name("sftp-name");
+            name.set_text_content(Some(&if entry.attrs.is_dir() {
+                format!("{}/", entry.name)
+            } else {
+                entry.name.clone()
+            }));
+            let _ = name.set_attribute("title", &entry.longname);
+            row.append_child(&name).unwrap();
+            let rename = document.create_element("button").unwrap();
+            rename.set_text_content(Some("mv"));
+            row.append_child(&rename).unwrap();
+            let delete = document.create_element("button").unwrap();
+            delete.set_text_content(Some("rm"));
+            row.append_child(&delete).unwrap();
+
+            for (element, action) in [(&name, "open"), (&rename, "rename"), (&delete, "delete")] {
+                let _ = element.set_attribute("data-action", action);
+                let _ = element.set_attribute("data-name", &entry.name);
+                if entry.attrs.is_dir() {
+                    let _ = element.set_attribute("data-dir", "");
+                }
+            }
+            self.list.append_child(&row).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parent() {
+        assert_eq!(parent("/home/user"), "/home");
+        assert_eq!(parent("/home/"), "/");
+        assert_eq!(parent("/"), "/");
+    }
+}
@@ -0,0 +1,245 @@
+// OpenSSH certificates
+// https://cvsweb.openbsd.org/src/usr.bin/ssh/PROTOCOL.certkeys
+
+use super::{SshError, SshReader, SshResult, SshWriter};
+use base64ct::{Base64, Encoding};
+use signature::Verifier;
+use ssh_key::{PublicKey, Signature};
+
+/// Appended to the key or signature algorithm to name its certificate variant
+pub const CERT_SUFFIX: &str = "-cert-v01@openssh.com";
+
+pub const USER_CERT: u32 = 1;
+pub const HOST_CERT: u32 = 2;
+
+/// A certified public key
+///
+/// ssh-key refuses the "forever" validity ssh-keygen signs by default,
+/// so the certificate is taken apart here.
+#[derive(Clone, Debug)]
+pub struct Certificate {
+    blob: Vec<u8>,
+    key: PublicKey,
+    pub cert_type: u32,
+    pub key_id: String,
+    pub principals: Vec<String>,
+    pub valid_after: u64,
+    pub valid_before: u64,
+    pub critical_options: Vec<String>,
+    signature_key: PublicKey,
+    signature: Signature,
+    // the signature covers the blob up to here
+    signed_len: usize,
+}
+
+fn invalid(what: &str) -> SshError {
+    SshError::Key(format!("Invalid certificate: {}", what))
+}
+
+impl Certificate {
+    pub fn from_bytes(blob: &[u8]) -> SshResult<Self> {
+        let mut reader = SshReader::new(blob);
+        let cert_type = reader.read_utf8()?;
+        let key_type = cert_type
+            .strip_suffix(CERT_SUFFIX)
+            .ok_or_else(|| invalid("not a certificate"))?;
+        let _nonce = reader.read_string()?;
+
+        // the certified key is inlined, copy its fields into a plain key blob
+        let fields = match key_type {
+            "ssh-ed25519" => 1,
+            // curve and point, or exponent and modulus
+            "ssh-rsa" | "ecdsa-sha2-nistp256" | "ecdsa-sha2-nistp384" | "ecdsa-sha2-nistp521" => 2,
+            _ => {
+                return Err(SshError::Key(format!(
+                    "Unsupported certificate type {}",
+                    cert_type
+                )))
+            }
+        };
+        let mut key = SshWriter::new(Vec::with_capacity(512));
+        key.write_str(key_type);
+        for _ in 0..fields {
+            key.write_string(reader.read_string()?);
+        }
+        let key = PublicKey::from_bytes(key.get_inner()).map_err(|_| invalid("public key"))?;
+
+        let _serial = reader.read_u64()?;
+        let cert_type = reader.read_u32()?;
+        let key_id = reader.read_utf8()?;
+        let principals = strings(reader.read_string()?)?;
+        let valid_after = reader.read_u64()?;
+        let valid_before = reader.read_u64()?;
+        // options are name and data pairs, only the names matter here
+        let critical_options = strings(reader.read_string()?)?
+            .into_iter()
+            .step_by(2)
+            .collect();
+        let _extensions = reader.read_string()?;
+        let _reserved = reader.read_string()?;
+        let signature_key =
+            PublicKey::from_bytes(reader.read_string()?).map_err(|_| invalid("CA key"))?;
+        let signed_len = blob.len() - reader.remain();
+        let signature =
+            Signature::try_from(reader.read_string()?).map_err(|_| invalid("signature"))?;
+
+        Ok(Self {
+            blob: blob.to_vec(),
+            key,
+            cert_type,
+            key_id,
+            principals,
+            valid_after,
+            valid_before,
+            critical_options,
+            signature_key,
+            signature,
+            signed_len,
+        })
+    }
+
+    /// Parse the `<type> <base64> [comment]` line of a `-cert.pub` file
+    pub fn from_openssh(line: &str) -> SshResult<Self> {
+        let mut parts = line.split_whitespace();
+        let (cert_type, data) = match (parts.next(), parts.next()) {
+            (Some(cert_type), Some(data)) => (cert_type, data),
+            _ => return Err(invalid("expected a certificate line")),
+        };
+        let blob = Base64::decode_vec(data).map_err(|_| invalid("base64"))?;
+        if SshReader::new(&blob).read_string()? != cert_type.as_bytes() {
+            return Err(invalid("type does not match"));
+        }
+        Self::from_bytes(&blob)
+    }
+
+    pub fn to_bytes(&self) -> &[u8] {
+        &self.blob
+    }
+
+    /// The key the certificate is issued for
+    pub fn public_key(&self) -> &PublicKey {
+        &self.key
+    }
+
+    /// The key of the authority that signed the certificate
+    pub fn signature_key(&self) -> &PublicKey {
+        &self.signature_key
+    }
+
+    /// Check the signature, the type, the validity window and the principals,
+    /// `now` is in seconds since the unix epoch
+    ///
+    /// Whether the signer is a trusted authority is up to the caller.
+    pub fn validate(&self, cert_type: u32, principal: &str, now: u64) -> Result<(), String> {
+        Verifier::verify(
+            self.signature_key.key_data(),
+            &self.blob[..self.signed_len],
+            &self.signature,
+        )
+        .map_err(|_| "invalid signature".to_owned())?;
+        if self.cert_type != cert_type {
+            return Err("wrong certificate type".to_owned());
+        }
+        if now < self.valid_after {
+            return Err("certificate is not yet valid".to_owned());
+        }
+        if now >= self.valid_before {
+            return Err("certificate has expired".to_owned());
+        }
+        // an empty list is valid for any principal
+        if !self.principals.is_empty() && !self.principals.iter().any(|p| p == principal) {
+            return Err(format!("certificate is not valid for {}", principal));
+        }
+        if let Some(option) = self.critical_options.first() {
+            return Err(format!("unsupported critical option {}", option));
+        }
+        Ok(())
+    }
+}
+
+// A packed list of strings
+fn strings(data: &[u8]) -> SshResult<Vec<String>> {
+    let mut reader = SshReader::new(data);
+    let mut strings = Vec::new();
+    while reader.remain() > 0 {
+        strings.push(reader.read_utf8()?);
+    }
+    Ok(strings)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use rand_core::OsRng;
+    use signature::Signer;
+    use ssh_key::{Algorithm, PrivateKey};
+
+    /// Issue a certificate for `key` signed by `ca`
+    pub fn issue(
+        ca: &PrivateKey,
+        key: &PublicKey,
+        cert_type: u32,
+        principals: &[&str],
+        validity: (u64, u64),
+    ) -> Vec<u8> {
+        let key_blob = key.to_bytes().unwrap();
+        let mut key_reader = SshReader::new(&key_blob);
+        let key_type = key_reader.read_utf8().unwrap();
+
+        let mut writer = SshWriter::new(Vec::new());
+        writer.write_str(&format!("{}{}", key_type, CERT_SUFFIX));
+        writer.write_string(&[0; 16]);
+        writer.write_slice(key_reader.read_to_end());
+        writer.write_u64(1);
+        writer.write_u32(cert_type);
+        writer.write_str("test");
+        let mut list = SshWriter::new(Vec::new());
+        for principal in principals {
+            list.write_str(principal);
+        }
+        writer.write_string(list.get_inner());
+        writer.write_u64(validity.0);
+        writer.write_u64(validity.1);
+        writer.write_string(&[]);
+        writer.write_string(&[]);
+        writer.write_string(&[]);
+        writer.write_string(&ca.public_key().to_bytes().unwrap());
+        let signature: Vec<u8> = ca.try_sign(writer.get_inner()).unwrap().try_into().unwrap();
+        writer.write_string(&signature);
+        writer.into_inner()
+    }
+
+    #[test]
+    fn test_certificate() {
+        let ca = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        // ssh-keygen signs forever by default
+        let blob = issue(&ca, key.public_key(), HOST_CERT, &["host"], (0, u64::MAX));
+        let line = format!(
+            "ssh-ed25519-cert-v01@openssh.com {} host",
+            Base64::encode_string(&blob)
+        );
+        let cert = Certificate::from_openssh(&line).unwrap();
+        assert_eq!(cert.to_bytes(), blob);
+        assert_eq!(cert.public_key().key_data(), key.public_key().key_data());
+        assert_eq!(cert.signature_key().key_data(), ca.public_key().key_data());
+        assert_eq!(cert.principals, ["host"]);
+        assert!(cert.validate(HOST_CERT, "host", 1_700_000_000).is_ok());
+        assert!(cert.validate(USER_CERT, "host", 1_700_000_000).is_err());
+        assert!(cert.validate(HOST_CERT, "other", 1_700_000_000).is_err());
+
+        let blob = issue(&ca, key.public_key(), HOST_CERT, &[], (100, 200));
+        let cert = Certificate::from_bytes(&blob).unwrap();
+        assert!(cert.validate(HOST_CERT, "any", 150).is_ok());
+        assert!(cert.validate(HOST_CERT, "any", 99).is_err());
+        assert!(cert.validate(HOST_CERT, "any", 200).is_err());
+
+        // tampering breaks the signature
+        let mut blob = blob;
+        let idx = blob.len() - 100;
+        blob[idx] ^= 1;
+        assert!(Certificate::from_bytes(&blob)
+            .map_or(true, |cert| cert.validate(HOST_CERT, "any", 150).is_err()));
+        assert!(Certificate::from_openssh("ssh-ed25519 AAAA").is_err());
+    }
+}
@@ -0,0 +1,235 @@
+// Packet ciphers
+// chacha20-poly1305@openssh.com:
+//   https://cvsweb.openbsd.org/src/usr.bin/ssh/PROTOCOL.chacha20poly1305?annotate=HEAD
+// aes128-gcm@openssh.com & aes256-gcm@openssh.com:
+//   https://www.rfc-editor.org/rfc/rfc5647
+
+use super::{SshError, SshResult};
+use aes_gcm::{aead::AeadInPlace, Aes128Gcm, Aes256Gcm, KeyInit, Nonce, Tag};
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
+    ChaCha20Legacy,
+};
+use poly1305::Poly1305;
+use subtle::ConstantTimeEq;
+
+pub const CHACHA20_POLY1305: &str = "chacha20-poly1305@openssh.com";
+pub const AES256_GCM: &str = "aes256-gcm@openssh.com";
+pub const AES128_GCM: &str = "aes128-gcm@openssh.com";
+
+pub const SUPPORTED: &[&str] = &[CHACHA20_POLY1305, AES256_GCM, AES128_GCM];
+
+const PLAIN_BLOCK_SIZE: usize = 8;
+const TAG_LEN: usize = 16;
+
+/// Key and IV sizes in bytes for a negotiated cipher
+pub fn key_len(name: &str) -> (usize, usize) {
+    match name {
+        CHACHA20_POLY1305 => (64, 0),
+        AES256_GCM => (32, 12),
+        AES128_GCM => (16, 12),
+        _ => unreachable!(),
+    }
+}
+
+pub enum Gcm {
+    Aes128(Box<Aes128Gcm>),
+    Aes256(Box<Aes256Gcm>),
+}
+
+pub enum Cipher {
+    None,
+    ChaCha20Poly1305 {
+        main_key: [u8; 32],
+        header_key: [u8; 32],
+    },
+    AesGcm {
+        gcm: Gcm,
+        iv: [u8; 12],
+    },
+}
+
+impl Cipher {
+    pub fn new(name: &str, key: &[u8], iv: &[u8]) -> Self {
+        match name {
+            CHACHA20_POLY1305 => {
+                let mut main_key = [0u8; 32];
+                let mut header_key = [0u8; 32];
+                main_key.copy_from_slice(&key[..32]);
+                header_key.copy_from_slice(&key[32..64]);
+                Cipher::ChaCha20Poly1305 {
+                    main_key,
+                    header_key,
+                }
+            }
+            AES256_GCM | AES128_GCM => {
+                let gcm = if name == AES256_GCM {
+                    Gcm::Aes256(Box::new(Aes256Gcm::new_from_slice(key).unwrap()))
+                } else {
+                    Gcm::Aes128(Box::new(Aes128Gcm::new_from_slice(key).unwrap()))
+                };
+                let mut nonce = [0u8; 12];
+                nonce.copy_from_slice(iv);
+                Cipher::AesGcm { gcm, iv: nonce }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        match self {
+            Cipher::None | Cipher::ChaCha20Poly1305 { .. } => PLAIN_BLOCK_SIZE,
+            Cipher::AesGcm { .. } => 16,
+        }
+    }
+
+    pub fn tag_len(&self) -> usize {
+        match self {
+            Cipher::None => 0,
+            _ => TAG_LEN,
+        }
+    }
+
+    /// Both AEAD modes leave the length field out of the padding alignment
+    pub fn aad_len(&self) -> usize {
+        match self {
+            Cipher::None => 0,
+            _ => 4,
+        }
+    }
+
+    pub fn decrypt_length(&self, seq: u32, header: &[u8]) -> u32 {
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&header[..4]);
+        if let Cipher::ChaCha20Poly1305 { header_key, .. } = self {
+            let mut chacha = ChaCha20Legacy::new(header_key.into(), &nonce_of(seq).into());
+            chacha.apply_keystream(&mut len);
+        }
+        u32::from_be_bytes(len)
+    }
+
+    /// Encrypt `packet` (length field included) in place and append the tag
+    pub fn seal(&mut self, seq: u32, packet: &mut Vec<u8>) {
+        match self {
+            Cipher::None => {}
+            Cipher::ChaCha20Poly1305 {
+                main_key,
+                header_key,
+            } => {
+                let nonce = nonce_of(seq);
+                let mut chacha = ChaCha20Legacy::new((&*header_key).into(), &nonce.into());
+                chacha.apply_keystream(&mut packet[..4]);
+
+                let mut chacha = ChaCha20Legacy::new((&*main_key).into(), &nonce.into());
+                let poly_key = poly1305_key(&mut chacha);
+                chacha.seek(64);
+                chacha.apply_keystream(&mut packet[4..]);
+
+                let tag = Poly1305::new(&poly_key.into()).compute_unpadded(packet);
+                packet.extend_from_slice(&tag);
+            }
+            Cipher::AesGcm { gcm, iv } => {
+                let (aad, payload) = packet.split_at_mut(4);
+                let nonce = Nonce::from_slice(iv);
+                let tag = match gcm {
+                    Gcm::Aes128(c) => c.encrypt_in_place_detached(nonce, aad, payload),
+                    Gcm::Aes256(c) => c.encrypt_in_place_detached(nonce, aad, payload),
+                }
+                .unwrap();
+                packet.extend_from_slice(&tag);
+                increase_iv(iv);
+            }
+        }
+    }
+
+    /// Verify the tag and decrypt `packet` (length field included) in place
+    pub fn open(&mut self, seq: u32, packet: &mut [u8], tag: &[u8]) -> SshResult<()> {
+        match self {
+            Cipher::None => Ok(()),
+            Cipher::ChaCha20Poly1305 { main_key, .. } => {
+                let mut chacha = ChaCha20Legacy::new((&*main_key).into(), &nonce_of(seq).into());
+                let poly_key = poly1305_key(&mut chacha);
+                let expected = Poly1305::new(&poly_key.into()).compute_unpadded(packet);
+                if expected.as_slice().ct_eq(tag).unwrap_u8() != 1 {
+                    return Err(SshError::Crypto);
+                }
+                chacha.seek(64);
+                chacha.apply_keystream(&mut packet[4..]);
+                Ok(())
+            }
+            Cipher::AesGcm { gcm, iv } => {
+                let (aad, payload) = packet.split_at_mut(4);
+                let nonce = Nonce::from_slice(iv);
+                let tag = Tag::from_slice(tag);
+                match gcm {
+                    Gcm::Aes128(c) => c.decrypt_in_place_detached(nonce, aad, payload, tag),
+                    Gcm::Aes256(c) => c.decrypt_in_place_detached(nonce, aad, payload, tag),
+                }
+                .map_err(|_| SshError::Crypto)?;
+                increase_iv(iv);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn nonce_of(seq: u32) -> [u8; 8] {
+    (seq as u64).to_be_bytes()
+}
+
+fn poly1305_key(chacha: &mut ChaCha20Legacy) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    chacha.apply_keystream(&mut key);
+    key
+}
+
+// The invocation counter is the low 64 bits of the nonce
+// https://www.rfc-editor.org/rfc/rfc5647#section-7.1
+fn increase_iv(iv: &mut [u8; 12]) {
+    let mut counter = [0u8; 8];
+    counter.copy_from_slice(&iv[4..]);
+    let counter = u64::from_be_bytes(counter).wrapping_add(1);
+    iv[4..].copy_from_slice(&counter.to_be_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(name: &str) {
+        let (key_len, iv_len) = key_len(name);
+        let key = vec![0x42u8; key_len];
+        let iv = vec![0x24u8; iv_len];
+        let mut sealing = Cipher::new(name, &key, &iv);
+        let mut opening = Cipher::new(name, &key, &iv);
+
+        for seq in 3..6 {
+            let plain = [0, 0, 0, 12, 7, 94, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+            let mut packet = plain.to_vec();
+            sealing.seal(seq, &mut packet);
+            assert_ne!(&packet[4..16], &plain[4..]);
+            assert_eq!(opening.decrypt_length(seq, &packet), 12);
+
+            let (body, tag) = packet.split_at_mut(16);
+            opening.open(seq, body, tag).unwrap();
+            assert_eq!(&body[4..], &plain[4..]);
+        }
+
+        let mut packet = vec![0, 0, 0, 4, 1, 2, 3, 4];
+        sealing.seal(6, &mut packet);
+        packet[5] ^= 1;
+        let (body, tag) = packet.split_at_mut(8);
+        assert!(opening.open(6, body, tag).is_err());
+    }
+
+    #[test]
+    fn test_chacha20_poly1305() {
+        round_trip(CHACHA20_POLY1305);
+    }
+
+    #[test]
+    fn test_aes_gcm() {
+        round_trip(AES256_GCM);
+        round_trip(AES128_GCM);
+    }
+}
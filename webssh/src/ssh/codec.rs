@@ -0,0 +1,170 @@
+// Data type representations used in the SSH protocols
+// https://www.rfc-editor.org/rfc/rfc4251#section-5
+
+use super::{protocol_err, SshResult};
+
+pub struct SshReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+#[allow(dead_code)]
+impl<'a> SshReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn remain(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    pub fn read_slice(&mut self, n: usize) -> SshResult<&'a [u8]> {
+        if self.remain() < n {
+            return protocol_err("Truncated message");
+        }
+        let out = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    pub fn read_to_end(&mut self) -> &'a [u8] {
+        let out = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        out
+    }
+
+    pub fn read_u8(&mut self) -> SshResult<u8> {
+        Ok(self.read_slice(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> SshResult<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u32(&mut self) -> SshResult<u32> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.read_slice(4)?);
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    pub fn read_u64(&mut self) -> SshResult<u64> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.read_slice(8)?);
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    pub fn read_string(&mut self) -> SshResult<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        self.read_slice(len)
+    }
+
+    pub fn read_utf8(&mut self) -> SshResult<String> {
+        Ok(String::from_utf8_lossy(self.read_string()?).into_owned())
+    }
+
+    pub fn read_name_list(&mut self) -> SshResult<Vec<String>> {
+        let list = self.read_utf8()?;
+        if list.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(list.split(',').map(str::to_owned).collect())
+    }
+}
+
+pub struct SshWriter {
+    buf: Vec<u8>,
+}
+
+#[allow(dead_code)]
+impl SshWriter {
+    pub fn new(buf: Vec<u8>) -> Self {
+        Self { buf }
+    }
+
+    pub fn write_u8(&mut self, b: u8) {
+        self.buf.push(b);
+    }
+
+    pub fn write_bool(&mut self, b: bool) {
+        self.buf.push(b as u8);
+    }
+
+    pub fn write_u32(&mut self, b: u32) {
+        self.buf.extend_from_slice(&b.to_be_bytes());
+    }
+
+    pub fn write_u64(&mut self, b: u64) {
+        self.buf.extend_from_slice(&b.to_be_bytes());
+    }
+
+    pub fn write_slice(&mut self, s: &[u8]) {
+        self.buf.extend_from_slice(s);
+    }
+
+    pub fn write_string(&mut self, s: &[u8]) {
+        self.write_u32(s.len() as u32);
+        self.buf.extend_from_slice(s);
+    }
+
+    pub fn write_str(&mut self, s: &str) {
+        self.write_string(s.as_bytes());
+    }
+
+    pub fn write_name_list(&mut self, names: &[&str]) {
+        self.write_str(&names.join(","));
+    }
+
+    /// Encode an unsigned big-endian integer as an mpint
+    pub fn write_mpint(&mut self, n: &[u8]) {
+        let start = n.iter().position(|b| *b != 0).unwrap_or(n.len());
+        let n = &n[start..];
+        if n.first().is_some_and(|b| b & 0x80 != 0) {
+            self.write_u32(n.len() as u32 + 1);
+            self.buf.push(0);
+            self.buf.extend_from_slice(n);
+        } else {
+            self.write_string(n);
+        }
+    }
+
+    pub fn get_inner(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mpint() {
+        // examples from rfc4251 section 5
+        let mut writer = SshWriter::new(Vec::new());
+        writer.write_mpint(&[]);
+        writer.write_mpint(&[0x00, 0x80]);
+        writer.write_mpint(&[0x09, 0xa3, 0x78, 0xf9, 0xb2, 0xe3, 0x32, 0xa7]);
+        assert_eq!(
+            writer.get_inner(),
+            &[
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x80, 0x00, 0x00, 0x00, 0x08,
+                0x09, 0xa3, 0x78, 0xf9, 0xb2, 0xe3, 0x32, 0xa7
+            ]
+        );
+    }
+
+    #[test]
+    fn test_name_list() {
+        let mut writer = SshWriter::new(Vec::new());
+        writer.write_name_list(&["zlib", "none"]);
+        writer.write_name_list(&[]);
+        let buf = writer.into_inner();
+        let mut reader = SshReader::new(&buf);
+        assert_eq!(reader.read_name_list().unwrap(), vec!["zlib", "none"]);
+        assert!(reader.read_name_list().unwrap().is_empty());
+        assert!(reader.read_u8().is_err());
+    }
+}
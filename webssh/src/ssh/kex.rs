@@ -0,0 +1,323 @@
+// Algorithm negotiation & key exchange
+// https://www.rfc-editor.org/rfc/rfc4253#section-7
+// curve25519-sha256:
+//   https://www.rfc-editor.org/rfc/rfc8731
+
+use super::cert::{Certificate, CERT_SUFFIX};
+use super::{cipher, msg, protocol_err, SshError, SshReader, SshResult, SshWriter};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use ssh_key::{PublicKey, Signature};
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey};
+
+pub const CURVE25519_SHA256: &str = "curve25519-sha256";
+pub const CURVE25519_SHA256_LIBSSH: &str = "curve25519-sha256@libssh.org";
+// Extension negotiation, rfc8308
+const EXT_INFO_C: &str = "ext-info-c";
+// Strict key exchange (CVE-2023-48795)
+const STRICT_KEX_C: &str = "kex-strict-c-v00@openssh.com";
+const STRICT_KEX_S: &str = "kex-strict-s-v00@openssh.com";
+
+const KEX_ALGORITHMS: &[&str] = &[CURVE25519_SHA256, CURVE25519_SHA256_LIBSSH];
+// certificates first, hosts without a trusted authority fall back to the key inside
+const HOST_KEY_ALGORITHMS: &[&str] = &[
+    "ssh-ed25519-cert-v01@openssh.com",
+    "ecdsa-sha2-nistp256-cert-v01@openssh.com",
+    "rsa-sha2-512-cert-v01@openssh.com",
+    "rsa-sha2-256-cert-v01@openssh.com",
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "rsa-sha2-512",
+    "rsa-sha2-256",
+];
+const COMPRESSION: &[&str] = &["none"];
+
+pub struct KexInit {
+    kex: Vec<String>,
+    host_key: Vec<String>,
+    enc_c2s: Vec<String>,
+    enc_s2c: Vec<String>,
+    comp_c2s: Vec<String>,
+    comp_s2c: Vec<String>,
+    first_kex_follows: bool,
+}
+
+pub struct Negotiated {
+    pub host_key: String,
+    pub enc_c2s: String,
+    pub enc_s2c: String,
+    pub strict: bool,
+    /// The server sent a guessed kex packet we have to discard
+    pub ignore_guess: bool,
+}
+
+impl KexInit {
+    pub fn client(initial: bool) -> Vec<u8> {
+        let mut cookie = [0u8; 16];
+        OsRng.fill_bytes(&mut cookie);
+
+        let mut kex = KEX_ALGORITHMS.to_vec();
+        if initial {
+            kex.push(EXT_INFO_C);
+            kex.push(STRICT_KEX_C);
+        }
+
+        let mut writer = SshWriter::new(Vec::with_capacity(512));
+        writer.write_u8(msg::KEXINIT);
+        writer.write_slice(&cookie);
+        writer.write_name_list(&kex);
+        writer.write_name_list(HOST_KEY_ALGORITHMS);
+        writer.write_name_list(cipher::SUPPORTED);
+        writer.write_name_list(cipher::SUPPORTED);
+        // both ciphers are AEAD, no separate MAC is negotiated
+        writer.write_name_list(&[]);
+        writer.write_name_list(&[]);
+        writer.write_name_list(COMPRESSION);
+        writer.write_name_list(COMPRESSION);
+        writer.write_name_list(&[]);
+        writer.write_name_list(&[]);
+        writer.write_bool(false);
+        writer.write_u32(0);
+        writer.into_inner()
+    }
+
+    pub fn parse(payload: &[u8]) -> SshResult<Self> {
+        let mut reader = SshReader::new(payload);
+        if reader.read_u8()? != msg::KEXINIT {
+            return protocol_err("Expect KEXINIT");
+        }
+        let _cookie = reader.read_slice(16)?;
+        let kex = reader.read_name_list()?;
+        let host_key = reader.read_name_list()?;
+        let enc_c2s = reader.read_name_list()?;
+        let enc_s2c = reader.read_name_list()?;
+        let _mac_c2s = reader.read_name_list()?;
+        let _mac_s2c = reader.read_name_list()?;
+        let comp_c2s = reader.read_name_list()?;
+        let comp_s2c = reader.read_name_list()?;
+        let _lang_c2s = reader.read_name_list()?;
+        let _lang_s2c = reader.read_name_list()?;
+        let first_kex_follows = reader.read_bool()?;
+        Ok(Self {
+            kex,
+            host_key,
+            enc_c2s,
+            enc_s2c,
+            comp_c2s,
+            comp_s2c,
+            first_kex_follows,
+        })
+    }
+
+    pub fn negotiate(&self, initial: bool) -> SshResult<Negotiated> {
+        fn choose(ours: &[&str], theirs: &[String], what: &str) -> SshResult<String> {
+            ours.iter()
+                .find(|a| theirs.iter().any(|b| b == *a))
+                .map(|a| a.to_string())
+                .ok_or_else(|| SshError::Protocol(format!("No matching {} algorithm", what)))
+        }
+
+        let kex = choose(KEX_ALGORITHMS, &self.kex, "key exchange")?;
+        let host_key = choose(HOST_KEY_ALGORITHMS, &self.host_key, "host key")?;
+        let enc_c2s = choose(cipher::SUPPORTED, &self.enc_c2s, "cipher")?;
+        let enc_s2c = choose(cipher::SUPPORTED, &self.enc_s2c, "cipher")?;
+        choose(COMPRESSION, &self.comp_c2s, "compression")?;
+        choose(COMPRESSION, &self.comp_s2c, "compression")?;
+
+        let guess_right =
+            self.kex.first() == Some(&kex) && self.host_key.first() == Some(&host_key);
+        Ok(Negotiated {
+            host_key,
+            enc_c2s,
+            enc_s2c,
+            strict: initial && self.kex.iter().any(|a| a == STRICT_KEX_S),
+            ignore_guess: self.first_kex_follows && !guess_right,
+        })
+    }
+}
+
+pub struct Curve25519 {
+    secret: EphemeralSecret,
+    public: X25519PublicKey,
+}
+
+impl Curve25519 {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = X25519PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn init_msg(&self) -> Vec<u8> {
+        let mut writer = SshWriter::new(Vec::with_capacity(64));
+        writer.write_u8(msg::KEX_ECDH_INIT);
+        writer.write_string(self.public.as_bytes());
+        writer.into_inner()
+    }
+
+    pub fn client_public(&self) -> &[u8] {
+        self.public.as_bytes()
+    }
+
+    /// Returns the shared secret as an encoded mpint
+    pub fn compute(self, server_public: &[u8]) -> SshResult<Vec<u8>> {
+        let server_public: [u8; 32] = server_public
+            .try_into()
+            .map_err(|_| SshError::Protocol("Invalid curve25519 public key".to_owned()))?;
+        let shared = self
+            .secret
+            .diffie_hellman(&X25519PublicKey::from(server_public));
+        if !shared.was_contributory() {
+            return protocol_err("Invalid curve25519 shared secret");
+        }
+        let mut writer = SshWriter::new(Vec::with_capacity(37));
+        writer.write_mpint(shared.as_bytes());
+        Ok(writer.into_inner())
+    }
+}
+
+pub struct EcdhReply<'a> {
+    pub host_key: &'a [u8],
+    pub server_public: &'a [u8],
+    pub signature: &'a [u8],
+}
+
+impl<'a> EcdhReply<'a> {
+    pub fn parse(payload: &'a [u8]) -> SshResult<Self> {
+        let mut reader = SshReader::new(payload);
+        if reader.read_u8()? != msg::KEX_ECDH_REPLY {
+            return protocol_err("Expect KEX_ECDH_REPLY");
+        }
+        Ok(Self {
+            host_key: reader.read_string()?,
+            server_public: reader.read_string()?,
+            signature: reader.read_string()?,
+        })
+    }
+}
+
+pub struct ExchangeHash<'a> {
+    pub client_version: &'a str,
+    pub server_version: &'a str,
+    pub client_kexinit: &'a [u8],
+    pub server_kexinit: &'a [u8],
+    pub host_key: &'a [u8],
+    pub client_public: &'a [u8],
+    pub server_public: &'a [u8],
+    pub shared_secret: &'a [u8],
+}
+
+impl ExchangeHash<'_> {
+    pub fn digest(&self) -> Vec<u8> {
+        let mut writer = SshWriter::new(Vec::with_capacity(2048));
+        writer.write_str(self.client_version);
+        writer.write_str(self.server_version);
+        writer.write_string(self.client_kexinit);
+        writer.write_string(self.server_kexinit);
+        writer.write_string(self.host_key);
+        writer.write_string(self.client_public);
+        writer.write_string(self.server_public);
+        writer.write_slice(self.shared_secret);
+        Sha256::digest(writer.get_inner()).to_vec()
+    }
+}
+
+/// Check the server's signature over the exchange hash,
+/// returns the host key and the certificate it came in, if any
+pub fn verify_host_key(
+    algorithm: &str,
+    host_key: &[u8],
+    signature: &[u8],
+    hash: &[u8],
+) -> SshResult<(PublicKey, Option<Certificate>)> {
+    use signature::Verifier;
+
+    let (key, certificate, algorithm) = match algorithm.strip_suffix(CERT_SUFFIX) {
+        // the certified key signs with the plain algorithm
+        Some(algorithm) => {
+            let cert = Certificate::from_bytes(host_key)
+                .map_err(|e| SshError::HostKey(format!("Unable to parse certificate: {}", e)))?;
+            (cert.public_key().clone(), Some(cert), algorithm)
+        }
+        None => (
+            PublicKey::from_bytes(host_key)
+                .map_err(|e| SshError::HostKey(format!("Unable to parse host key: {}", e)))?,
+            None,
+            algorithm,
+        ),
+    };
+    let signature = Signature::try_from(signature)
+        .map_err(|e| SshError::HostKey(format!("Unable to parse signature: {}", e)))?;
+    if signature.algorithm().as_str() != algorithm {
+        return Err(SshError::HostKey(format!(
+            "Signature algorithm {} does not match {}",
+            signature.algorithm().as_str(),
+            algorithm
+        )));
+    }
+    Verifier::verify(key.key_data(), hash, &signature)
+        .map_err(|_| SshError::HostKey("Invalid signature on exchange hash".to_owned()))?;
+    Ok((key, certificate))
+}
+
+// Output from key exchange
+// https://www.rfc-editor.org/rfc/rfc4253#section-7.2
+pub fn derive_key(
+    shared_secret: &[u8],
+    hash: &[u8],
+    letter: u8,
+    session_id: &[u8],
+    len: usize,
+) -> Vec<u8> {
+    let mut key = Sha256::new()
+        .chain_update(shared_secret)
+        .chain_update(hash)
+        .chain_update([letter])
+        .chain_update(session_id)
+        .finalize()
+        .to_vec();
+    while key.len() < len {
+        let more = Sha256::new()
+            .chain_update(shared_secret)
+            .chain_update(hash)
+            .chain_update(&key)
+            .finalize();
+        key.extend_from_slice(&more);
+    }
+    key.truncate(len);
+    key
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::cert::{test::issue, HOST_CERT};
+    use signature::Signer;
+    use ssh_key::{Algorithm, PrivateKey};
+
+    #[test]
+    fn test_verify_host_key() {
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let host_key = key.public_key().to_bytes().unwrap();
+        let hash = Sha256::digest(b"exchange hash").to_vec();
+        let signature: Vec<u8> = key.try_sign(&hash).unwrap().try_into().unwrap();
+
+        assert!(verify_host_key("ssh-ed25519", &host_key, &signature, &hash).is_ok());
+        assert!(verify_host_key("rsa-sha2-256", &host_key, &signature, &hash).is_err());
+        assert!(verify_host_key("ssh-ed25519", &host_key, &signature, &hash[1..]).is_err());
+
+        let ca = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let cert = issue(&ca, key.public_key(), HOST_CERT, &[], (0, u64::MAX));
+        let algorithm = "ssh-ed25519-cert-v01@openssh.com";
+        let (host_key, cert) = verify_host_key(algorithm, &cert, &signature, &hash).unwrap();
+        assert_eq!(host_key.key_data(), key.public_key().key_data());
+        assert!(cert.is_some());
+        assert!(verify_host_key(
+            algorithm,
+            key.public_key().to_bytes().as_deref().unwrap(),
+            &signature,
+            &hash
+        )
+        .is_err());
+    }
+}
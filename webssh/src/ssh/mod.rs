@@ -0,0 +1,51 @@
+pub mod auth;
+pub mod cert;
+mod cipher;
+mod codec;
+mod kex;
+pub mod msg;
+mod session;
+mod transport;
+
+pub use codec::{SshReader, SshWriter};
+pub use session::{AuthResult, Session, SessionEvent};
+pub use transport::Transport;
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum SshError {
+    Io(std::io::Error),
+    Protocol(String),
+    Disconnect(u32, String),
+    HostKey(String),
+    Key(String),
+    Crypto,
+}
+
+pub type SshResult<T> = Result<T, SshError>;
+
+impl fmt::Display for SshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SshError::Io(e) => write!(f, "I/O error: {}", e),
+            SshError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            SshError::Disconnect(code, msg) => write!(f, "Disconnected ({}): {}", code, msg),
+            SshError::HostKey(msg) => write!(f, "Host key error: {}", msg),
+            SshError::Key(msg) => write!(f, "Private key error: {}", msg),
+            SshError::Crypto => write!(f, "Packet integrity check failed"),
+        }
+    }
+}
+
+impl std::error::Error for SshError {}
+
+impl From<std::io::Error> for SshError {
+    fn from(e: std::io::Error) -> Self {
+        SshError::Io(e)
+    }
+}
+
+fn protocol_err<T>(msg: &str) -> SshResult<T> {
+    Err(SshError::Protocol(msg.to_owned()))
+}
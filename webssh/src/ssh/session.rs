@@ -0,0 +1,672 @@
+// The Secure Shell (SSH) Authentication Protocol
+// https://www.rfc-editor.org/rfc/rfc4252
+// The Secure Shell (SSH) Connection Protocol
+// https://www.rfc-editor.org/rfc/rfc4254
+
+use super::cert::{Certificate, CERT_SUFFIX};
+use super::{auth, msg, protocol_err, SshError, SshReader, SshResult, SshWriter, Transport};
+use ssh_key::{HashAlg, PrivateKey};
+use std::collections::{HashMap, VecDeque};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{debug, info, warn};
+
+const USERAUTH_SERVICE: &str = "ssh-userauth";
+const CONNECTION_SERVICE: &str = "ssh-connection";
+const INITIAL_WINDOW: u32 = 2 * 1024 * 1024;
+const MAX_PACKET: u32 = 32 * 1024;
+// https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent#section-6
+const AGENT_REQUEST: &str = "auth-agent-req@openssh.com";
+const AGENT_CHANNEL: &str = "auth-agent@openssh.com";
+
+pub enum SessionEvent {
+    Data(u32, Vec<u8>),
+    // stderr output
+    ExtendedData(u32, Vec<u8>),
+    /// The server opened a channel to the forwarded agent
+    AgentOpened(u32),
+    Eof(u32),
+    Closed(u32),
+}
+
+/// The server's answer to an authentication request
+#[derive(Debug)]
+pub enum AuthResult {
+    Success,
+    /// `methods` lists what can be tried next, with `partial_success` the
+    /// request was accepted but more authentication is required
+    Failure {
+        methods: Vec<String>,
+        partial_success: bool,
+    },
+    /// Questions to answer with [`Session::info_response`]
+    InfoRequest(InfoRequest),
+}
+
+/// Prompts of a keyboard-interactive round
+#[derive(Debug)]
+pub struct InfoRequest {
+    pub name: String,
+    pub instruction: String,
+    /// The prompt text and whether the answer may be echoed
+    pub prompts: Vec<(String, bool)>,
+}
+
+struct Channel {
+    remote_id: u32,
+    remote_window: u32,
+    remote_max_packet: u32,
+    local_window: u32,
+    pending: Vec<u8>,
+    closed: bool,
+}
+
+pub struct Session<S> {
+    transport: Transport<S>,
+    channels: HashMap<u32, Channel>,
+    next_channel: u32,
+    // events received while waiting for a reply to our own request
+    queued: VecDeque<SessionEvent>,
+    userauth_requested: bool,
+    agent_forwarding: bool,
+    // signature algorithms the server accepts for user authentication
+    // https://www.rfc-editor.org/rfc/rfc8308#section-3.1
+    server_sig_algs: Option<Vec<String>>,
+}
+
+impl<S> Session<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(transport: Transport<S>) -> Self {
+        Self {
+            transport,
+            channels: HashMap::new(),
+            next_channel: 0,
+            queued: VecDeque::new(),
+            userauth_requested: false,
+            agent_forwarding: false,
+            server_sig_algs: None,
+        }
+    }
+
+    /// Ask for the authentication methods that can continue
+    pub async fn auth_none(&mut self, username: &str) -> SshResult<AuthResult> {
+        self.request_userauth().await?;
+
+        let mut writer = SshWriter::new(Vec::with_capacity(64));
+        writer.write_u8(msg::USERAUTH_REQUEST);
+        writer.write_str(username);
+        writer.write_str(CONNECTION_SERVICE);
+        writer.write_str("none");
+        self.transport.write_packet(writer.get_inner()).await?;
+        self.wait_userauth(username, false).await
+    }
+
+    pub async fn auth_password(&mut self, username: &str, password: &str) -> SshResult<AuthResult> {
+        self.request_userauth().await?;
+
+        // https://www.rfc-editor.org/rfc/rfc4252#section-8
+        let mut writer = SshWriter::new(Vec::with_capacity(128));
+        writer.write_u8(msg::USERAUTH_REQUEST);
+        writer.write_str(username);
+        writer.write_str(CONNECTION_SERVICE);
+        writer.write_str("password");
+        writer.write_bool(false);
+        writer.write_str(password);
+        self.transport.write_packet(writer.get_inner()).await?;
+        self.wait_userauth(username, false).await
+    }
+
+    /// Start keyboard-interactive authentication, the server usually
+    /// answers with an [`AuthResult::InfoRequest`]
+    pub async fn auth_keyboard_interactive(&mut self, username: &str) -> SshResult<AuthResult> {
+        self.request_userauth().await?;
+
+        // https://www.rfc-editor.org/rfc/rfc4256#section-3.1
+        let mut writer = SshWriter::new(Vec::with_capacity(128));
+        writer.write_u8(msg::USERAUTH_REQUEST);
+        writer.write_str(username);
+        writer.write_str(CONNECTION_SERVICE);
+        writer.write_str("keyboard-interactive");
+        // language tag and submethods
+        writer.write_str("");
+        writer.write_str("");
+        self.transport.write_packet(writer.get_inner()).await?;
+        self.wait_userauth(username, true).await
+    }
+
+    /// Answer the prompts of the last [`AuthResult::InfoRequest`]
+    pub async fn info_response(
+        &mut self,
+        username: &str,
+        responses: &[String],
+    ) -> SshResult<AuthResult> {
+        // https://www.rfc-editor.org/rfc/rfc4256#section-3.4
+        let mut writer = SshWriter::new(Vec::with_capacity(128));
+        writer.write_u8(msg::USERAUTH_INFO_RESPONSE);
+        writer.write_u32(responses.len() as u32);
+        for response in responses {
+            writer.write_str(response);
+        }
+        self.transport.write_packet(writer.get_inner()).await?;
+        self.wait_userauth(username, true).await
+    }
+
+    pub async fn auth_publickey(
+        &mut self,
+        username: &str,
+        key: &PrivateKey,
+        certificate: Option<&Certificate>,
+    ) -> SshResult<AuthResult> {
+        self.request_userauth().await?;
+
+        let algorithm = match auth::signature_algorithms(key).iter().find(|alg| {
+            self.server_sig_algs
+                .as_ref()
+                .is_none_or(|algs| algs.iter().any(|a| a == *alg))
+        }) {
+            Some(algorithm) => *algorithm,
+            None => {
+                warn!(
+                    "No signature algorithm for {} key accepted by the server",
+                    key.algorithm().as_str()
+                );
+                return Ok(AuthResult::Failure {
+                    methods: vec!["publickey".to_owned()],
+                    partial_success: false,
+                });
+            }
+        };
+        // a certificate is offered under the certificate variant of the algorithm
+        let (offered, public_key) = match certificate {
+            Some(cert) => (
+                format!("{}{}", algorithm, CERT_SUFFIX),
+                cert.to_bytes().to_vec(),
+            ),
+            None => (
+                algorithm.to_owned(),
+                key.public_key()
+                    .to_bytes()
+                    .map_err(|e| SshError::Key(e.to_string()))?,
+            ),
+        };
+
+        // https://www.rfc-editor.org/rfc/rfc4252#section-7
+        let mut writer = SshWriter::new(Vec::with_capacity(512));
+        writer.write_u8(msg::USERAUTH_REQUEST);
+        writer.write_str(username);
+        writer.write_str(CONNECTION_SERVICE);
+        writer.write_str("publickey");
+        writer.write_bool(true);
+        writer.write_str(&offered);
+        writer.write_string(&public_key);
+
+        let session_id = self.transport.session_id();
+        let mut signed = SshWriter::new(Vec::with_capacity(session_id.len() + 512));
+        signed.write_string(session_id);
+        signed.write_slice(writer.get_inner());
+        writer.write_string(&auth::sign(key, algorithm, signed.get_inner())?);
+        self.transport.write_packet(writer.get_inner()).await?;
+
+        info!(
+            "Offering {} key {}",
+            offered,
+            key.fingerprint(HashAlg::Sha256)
+        );
+        self.wait_userauth(username, false).await
+    }
+
+    /// Open a session channel with a pseudo terminal and start the user's shell
+    pub async fn open_shell(
+        &mut self,
+        term: &str,
+        cols: u32,
+        rows: u32,
+        forward_agent: bool,
+    ) -> SshResult<u32> {
+        let id = self.open_session().await?;
+
+        // https://www.rfc-editor.org/rfc/rfc4254#section-6.2
+        let mut writer = self.channel_request(id, "pty-req", true)?;
+        writer.write_str(term);
+        writer.write_u32(cols);
+        writer.write_u32(rows);
+        writer.write_u32(0);
+        writer.write_u32(0);
+        // no terminal modes
+        writer.write_string(&[0]);
+        self.transport.write_packet(writer.get_inner()).await?;
+        if !self.wait_channel_reply(id).await? {
+            warn!("Server refused to allocate a pty");
+        }
+
+        if forward_agent {
+            let writer = self.channel_request(id, AGENT_REQUEST, true)?;
+            self.transport.write_packet(writer.get_inner()).await?;
+            self.agent_forwarding = self.wait_channel_reply(id).await?;
+            if !self.agent_forwarding {
+                warn!("Server refused agent forwarding");
+            }
+        }
+
+        // https://www.rfc-editor.org/rfc/rfc4254#section-6.5
+        let writer = self.channel_request(id, "shell", true)?;
+        self.transport.write_packet(writer.get_inner()).await?;
+        if !self.wait_channel_reply(id).await? {
+            return protocol_err("Server refused to start a shell");
+        }
+        Ok(id)
+    }
+
+    /// Open a session channel running a subsystem such as `sftp`
+    pub async fn open_subsystem(&mut self, name: &str) -> SshResult<u32> {
+        let id = self.open_session().await?;
+
+        // https://www.rfc-editor.org/rfc/rfc4254#section-6.5
+        let mut writer = self.channel_request(id, "subsystem", true)?;
+        writer.write_str(name);
+        self.transport.write_packet(writer.get_inner()).await?;
+        if !self.wait_channel_reply(id).await? {
+            self.close_channel(id).await?;
+            return Err(SshError::Protocol(format!(
+                "Server refused to start the {} subsystem",
+                name
+            )));
+        }
+        Ok(id)
+    }
+
+    /// Tell the server the terminal of a shell channel changed size
+    pub async fn window_change(&mut self, id: u32, cols: u32, rows: u32) -> SshResult<()> {
+        // https://www.rfc-editor.org/rfc/rfc4254#section-6.7
+        // the tab of a closed channel may still be resized
+        match self.channels.get(&id) {
+            Some(channel) if !channel.closed => {}
+            _ => return Ok(()),
+        }
+        let mut writer = self.channel_request(id, "window-change", false)?;
+        writer.write_u32(cols);
+        writer.write_u32(rows);
+        writer.write_u32(0);
+        writer.write_u32(0);
+        self.transport.write_packet(writer.get_inner()).await
+    }
+
+    pub async fn send_data(&mut self, id: u32, data: &[u8]) -> SshResult<()> {
+        match self.channels.get_mut(&id) {
+            Some(channel) if !channel.closed => channel.pending.extend_from_slice(data),
+            _ => return Ok(()),
+        }
+        self.flush_channel(id).await
+    }
+
+    pub async fn close_channel(&mut self, id: u32) -> SshResult<()> {
+        if let Some(channel) = self.channels.get_mut(&id) {
+            if !channel.closed {
+                channel.closed = true;
+                let remote_id = channel.remote_id;
+                let mut writer = SshWriter::new(Vec::with_capacity(8));
+                writer.write_u8(msg::CHANNEL_CLOSE);
+                writer.write_u32(remote_id);
+                self.transport.write_packet(writer.get_inner()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the next packet from the server.
+    ///
+    /// This is cancel safe, the packet should be passed to [`Session::handle`].
+    pub async fn recv(&mut self) -> SshResult<Vec<u8>> {
+        self.transport.read_packet().await
+    }
+
+    /// Events that arrived while the session was busy with a request,
+    /// these should be processed before calling [`Session::recv`] again.
+    pub fn next_queued(&mut self) -> Option<SessionEvent> {
+        self.queued.pop_front()
+    }
+
+    pub async fn handle(&mut self, payload: Vec<u8>) -> SshResult<Option<SessionEvent>> {
+        let mut reader = SshReader::new(&payload[1..]);
+        match payload[0] {
+            msg::CHANNEL_DATA => {
+                let id = reader.read_u32()?;
+                let data = reader.read_string()?.to_vec();
+                self.consume_window(id, data.len() as u32).await?;
+                Ok(Some(SessionEvent::Data(id, data)))
+            }
+            msg::CHANNEL_EXTENDED_DATA => {
+                let id = reader.read_u32()?;
+                // only SSH_EXTENDED_DATA_STDERR is defined
+                let _ = reader.read_u32()?;
+                let data = reader.read_string()?.to_vec();
+                self.consume_window(id, data.len() as u32).await?;
+                Ok(Some(SessionEvent::ExtendedData(id, data)))
+            }
+            msg::CHANNEL_WINDOW_ADJUST => {
+                let id = reader.read_u32()?;
+                let add = reader.read_u32()?;
+                if let Some(channel) = self.channels.get_mut(&id) {
+                    channel.remote_window = channel.remote_window.saturating_add(add);
+                }
+                self.flush_channel(id).await?;
+                Ok(None)
+            }
+            msg::CHANNEL_EOF => Ok(Some(SessionEvent::Eof(reader.read_u32()?))),
+            msg::CHANNEL_CLOSE => {
+                let id = reader.read_u32()?;
+                self.close_channel(id).await?;
+                self.channels.remove(&id);
+                Ok(Some(SessionEvent::Closed(id)))
+            }
+            msg::CHANNEL_OPEN => {
+                // https://www.rfc-editor.org/rfc/rfc4254#section-5.1
+                let kind = reader.read_utf8()?;
+                let remote_id = reader.read_u32()?;
+                let remote_window = reader.read_u32()?;
+                let remote_max_packet = reader.read_u32()?;
+                if kind != AGENT_CHANNEL || !self.agent_forwarding {
+                    debug!("Rejecting {} channel", kind);
+                    let mut writer = SshWriter::new(Vec::with_capacity(32));
+                    writer.write_u8(msg::CHANNEL_OPEN_FAILURE);
+                    writer.write_u32(remote_id);
+                    if kind == AGENT_CHANNEL {
+                        writer.write_u32(msg::OPEN_ADMINISTRATIVELY_PROHIBITED);
+                    } else {
+                        writer.write_u32(msg::OPEN_UNKNOWN_CHANNEL_TYPE);
+                    }
+                    writer.write_str("Channel not supported");
+                    writer.write_str("");
+                    self.transport.write_packet(writer.get_inner()).await?;
+                    return Ok(None);
+                }
+
+                let id = self.next_channel;
+                self.next_channel += 1;
+                self.channels.insert(
+                    id,
+                    Channel {
+                        remote_id,
+                        remote_window,
+                        remote_max_packet,
+                        local_window: INITIAL_WINDOW,
+                        pending: Vec::new(),
+                        closed: false,
+                    },
+                );
+                let mut writer = SshWriter::new(Vec::with_capacity(32));
+                writer.write_u8(msg::CHANNEL_OPEN_CONFIRMATION);
+                writer.write_u32(remote_id);
+                writer.write_u32(id);
+                writer.write_u32(INITIAL_WINDOW);
+                writer.write_u32(MAX_PACKET);
+                self.transport.write_packet(writer.get_inner()).await?;
+                Ok(Some(SessionEvent::AgentOpened(id)))
+            }
+            msg::CHANNEL_REQUEST => {
+                let id = reader.read_u32()?;
+                let request = reader.read_utf8()?;
+                let want_reply = reader.read_bool()?;
+                debug!("Channel {} request {}", id, request);
+                if want_reply {
+                    if let Some(channel) = self.channels.get(&id) {
+                        let mut writer = SshWriter::new(Vec::with_capacity(8));
+                        writer.write_u8(msg::CHANNEL_FAILURE);
+                        writer.write_u32(channel.remote_id);
+                        self.transport.write_packet(writer.get_inner()).await?;
+                    }
+                }
+                Ok(None)
+            }
+            _ => {
+                self.handle_transport(payload).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    pub async fn disconnect(&mut self) {
+        self.transport
+            .disconnect(msg::DISCONNECT_BY_APPLICATION, "Bye")
+            .await;
+    }
+}
+
+impl<S> Session<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn request_userauth(&mut self) -> SshResult<()> {
+        if self.userauth_requested {
+            return Ok(());
+        }
+        // https://www.rfc-editor.org/rfc/rfc4253#section-10
+        let mut writer = SshWriter::new(Vec::with_capacity(32));
+        writer.write_u8(msg::SERVICE_REQUEST);
+        writer.write_str(USERAUTH_SERVICE);
+        self.transport.write_packet(writer.get_inner()).await?;
+        loop {
+            let payload = self.transport.read_packet().await?;
+            if payload[0] == msg::SERVICE_ACCEPT {
+                self.userauth_requested = true;
+                return Ok(());
+            }
+            self.handle_transport(payload).await?;
+        }
+    }
+
+    // Message 60 is an info request for keyboard-interactive
+    // and a password change request otherwise
+    async fn wait_userauth(
+        &mut self,
+        username: &str,
+        keyboard_interactive: bool,
+    ) -> SshResult<AuthResult> {
+        loop {
+            let payload = self.transport.read_packet().await?;
+            let mut reader = SshReader::new(&payload[1..]);
+            match payload[0] {
+                msg::USERAUTH_SUCCESS => {
+                    info!("Authenticated as {}", username);
+                    return Ok(AuthResult::Success);
+                }
+                msg::USERAUTH_FAILURE => {
+                    let methods = reader.read_name_list()?;
+                    let partial_success = reader.read_bool()?;
+                    debug!(
+                        "Authentication failed (partial success: {}), can continue with {:?}",
+                        partial_success, methods
+                    );
+                    return Ok(AuthResult::Failure {
+                        methods,
+                        partial_success,
+                    });
+                }
+                msg::USERAUTH_BANNER => {
+                    info!("{}", reader.read_utf8()?);
+                }
+                msg::USERAUTH_INFO_REQUEST if keyboard_interactive => {
+                    // https://www.rfc-editor.org/rfc/rfc4256#section-3.2
+                    let name = reader.read_utf8()?;
+                    let instruction = reader.read_utf8()?;
+                    let _language = reader.read_string()?;
+                    let mut prompts = Vec::new();
+                    for _ in 0..reader.read_u32()? {
+                        prompts.push((reader.read_utf8()?, reader.read_bool()?));
+                    }
+                    return Ok(AuthResult::InfoRequest(InfoRequest {
+                        name,
+                        instruction,
+                        prompts,
+                    }));
+                }
+                msg::USERAUTH_PASSWD_CHANGEREQ => {
+                    warn!("Server requires a password change");
+                    return Ok(AuthResult::Failure {
+                        methods: Vec::new(),
+                        partial_success: false,
+                    });
+                }
+                _ => self.handle_transport(payload).await?,
+            }
+        }
+    }
+
+    /// Messages that may show up at any time after the key exchange
+    async fn handle_transport(&mut self, payload: Vec<u8>) -> SshResult<()> {
+        match payload[0] {
+            msg::KEXINIT => self.transport.rekey(payload).await,
+            msg::EXT_INFO => {
+                // https://www.rfc-editor.org/rfc/rfc8308#section-2.3
+                let mut reader = SshReader::new(&payload[1..]);
+                for _ in 0..reader.read_u32()? {
+                    let name = reader.read_utf8()?;
+                    let value = reader.read_utf8()?;
+                    if name == "server-sig-algs" {
+                        debug!("Server signature algorithms {}", value);
+                        self.server_sig_algs = Some(value.split(',').map(str::to_owned).collect());
+                    }
+                }
+                Ok(())
+            }
+            msg::GLOBAL_REQUEST => {
+                let mut reader = SshReader::new(&payload[1..]);
+                let request = reader.read_utf8()?;
+                debug!("Global request {}", request);
+                if reader.read_bool()? {
+                    self.transport.write_packet(&[msg::REQUEST_FAILURE]).await?;
+                }
+                Ok(())
+            }
+            other => {
+                warn!("Unexpected message {}", other);
+                Ok(())
+            }
+        }
+    }
+
+    async fn open_session(&mut self) -> SshResult<u32> {
+        let id = self.next_channel;
+        self.next_channel += 1;
+
+        // https://www.rfc-editor.org/rfc/rfc4254#section-5.1
+        let mut writer = SshWriter::new(Vec::with_capacity(32));
+        writer.write_u8(msg::CHANNEL_OPEN);
+        writer.write_str("session");
+        writer.write_u32(id);
+        writer.write_u32(INITIAL_WINDOW);
+        writer.write_u32(MAX_PACKET);
+        self.transport.write_packet(writer.get_inner()).await?;
+
+        loop {
+            let payload = self.transport.read_packet().await?;
+            let mut reader = SshReader::new(&payload[1..]);
+            match payload[0] {
+                msg::CHANNEL_OPEN_CONFIRMATION if reader.read_u32()? == id => {
+                    let remote_id = reader.read_u32()?;
+                    let remote_window = reader.read_u32()?;
+                    let remote_max_packet = reader.read_u32()?;
+                    self.channels.insert(
+                        id,
+                        Channel {
+                            remote_id,
+                            remote_window,
+                            remote_max_packet,
+                            local_window: INITIAL_WINDOW,
+                            pending: Vec::new(),
+                            closed: false,
+                        },
+                    );
+                    return Ok(id);
+                }
+                msg::CHANNEL_OPEN_FAILURE if reader.read_u32()? == id => {
+                    let _reason = reader.read_u32()?;
+                    let desc = reader.read_utf8()?;
+                    return Err(SshError::Protocol(format!(
+                        "Failed to open channel: {}",
+                        desc
+                    )));
+                }
+                _ => {
+                    if let Some(event) = self.handle(payload).await? {
+                        self.queued.push_back(event);
+                    }
+                }
+            }
+        }
+    }
+
+    fn channel_request(&self, id: u32, request: &str, want_reply: bool) -> SshResult<SshWriter> {
+        let channel = self
+            .channels
+            .get(&id)
+            .ok_or_else(|| SshError::Protocol(format!("No channel {}", id)))?;
+        let mut writer = SshWriter::new(Vec::with_capacity(64));
+        writer.write_u8(msg::CHANNEL_REQUEST);
+        writer.write_u32(channel.remote_id);
+        writer.write_str(request);
+        writer.write_bool(want_reply);
+        Ok(writer)
+    }
+
+    async fn wait_channel_reply(&mut self, id: u32) -> SshResult<bool> {
+        loop {
+            let payload = self.transport.read_packet().await?;
+            let mut reader = SshReader::new(&payload[1..]);
+            match payload[0] {
+                msg::CHANNEL_SUCCESS if reader.read_u32()? == id => return Ok(true),
+                msg::CHANNEL_FAILURE if reader.read_u32()? == id => return Ok(false),
+                _ => {
+                    if let Some(event) = self.handle(payload).await? {
+                        self.queued.push_back(event);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn consume_window(&mut self, id: u32, len: u32) -> SshResult<()> {
+        let channel = match self.channels.get_mut(&id) {
+            Some(channel) => channel,
+            None => return protocol_err("Data on unknown channel"),
+        };
+        channel.local_window = channel.local_window.saturating_sub(len);
+        if channel.local_window < INITIAL_WINDOW / 2 && !channel.closed {
+            let add = INITIAL_WINDOW - channel.local_window;
+            channel.local_window = INITIAL_WINDOW;
+            let mut writer = SshWriter::new(Vec::with_capacity(16));
+            writer.write_u8(msg::CHANNEL_WINDOW_ADJUST);
+            writer.write_u32(channel.remote_id);
+            writer.write_u32(add);
+            self.transport.write_packet(writer.get_inner()).await?;
+        }
+        Ok(())
+    }
+
+    /// Send as much pending data as the remote window allows
+    async fn flush_channel(&mut self, id: u32) -> SshResult<()> {
+        loop {
+            let channel = match self.channels.get_mut(&id) {
+                Some(channel) => channel,
+                None => return Ok(()),
+            };
+            let len = channel
+                .pending
+                .len()
+                .min(channel.remote_window as usize)
+                .min(channel.remote_max_packet as usize);
+            if len == 0 {
+                return Ok(());
+            }
+            let data: Vec<u8> = channel.pending.drain(..len).collect();
+            channel.remote_window -= len as u32;
+
+            let mut writer = SshWriter::new(Vec::with_capacity(len + 16));
+            writer.write_u8(msg::CHANNEL_DATA);
+            writer.write_u32(channel.remote_id);
+            writer.write_string(&data);
+            self.transport.write_packet(writer.get_inner()).await?;
+        }
+    }
+}
@@ -0,0 +1,451 @@
+// The Secure Shell (SSH) Transport Layer Protocol
+// https://www.rfc-editor.org/rfc/rfc4253
+
+use super::{
+    cert::Certificate,
+    cipher::{self, Cipher},
+    kex::{self, Curve25519, EcdhReply, ExchangeHash, KexInit},
+    msg, protocol_err, SshError, SshReader, SshResult, SshWriter,
+};
+use rand_core::{OsRng, RngCore};
+use ssh_key::PublicKey;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, info, trace};
+
+const CLIENT_VERSION: &str = "SSH-2.0-webssh_0.1";
+const MAX_VERSION_LEN: usize = 255;
+// https://www.rfc-editor.org/rfc/rfc4253#section-6.1
+const MAX_PACKET_LEN: usize = 256 * 1024;
+
+pub struct Transport<S> {
+    stream: S,
+    rbuf: Vec<u8>,
+    server_version: String,
+    session_id: Vec<u8>,
+    host_key: Option<PublicKey>,
+    host_certificate: Option<Certificate>,
+    sealing: Cipher,
+    opening: Cipher,
+    send_seq: u32,
+    recv_seq: u32,
+    // negotiated by the first key exchange, resets the sequence numbers on
+    // every NEWKEYS after it
+    strict: bool,
+}
+
+impl<S> Transport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Exchange versions and run the initial key exchange
+    pub async fn connect(stream: S) -> SshResult<Self> {
+        let mut transport = Self {
+            stream,
+            rbuf: Vec::with_capacity(4096),
+            server_version: String::new(),
+            session_id: Vec::new(),
+            host_key: None,
+            host_certificate: None,
+            sealing: Cipher::None,
+            opening: Cipher::None,
+            send_seq: 0,
+            recv_seq: 0,
+            strict: false,
+        };
+        transport.exchange_version().await?;
+        transport.key_exchange(None).await?;
+        Ok(transport)
+    }
+
+    pub fn server_version(&self) -> &str {
+        &self.server_version
+    }
+
+    /// The host key verified during the key exchange
+    pub fn host_key(&self) -> &PublicKey {
+        self.host_key.as_ref().unwrap()
+    }
+
+    /// The certificate the host key was presented in, if any
+    pub fn host_certificate(&self) -> Option<&Certificate> {
+        self.host_certificate.as_ref()
+    }
+
+    /// The exchange hash of the first key exchange
+    pub fn session_id(&self) -> &[u8] {
+        &self.session_id
+    }
+
+    /// Read the next packet that is not handled by the transport itself.
+    ///
+    /// This is cancel safe: partial reads are kept in the receive buffer.
+    pub async fn read_packet(&mut self) -> SshResult<Vec<u8>> {
+        loop {
+            let payload = self.read_raw_packet().await?;
+            match payload[0] {
+                msg::IGNORE | msg::DEBUG | msg::UNIMPLEMENTED => {
+                    trace!("Skip message {}", payload[0]);
+                }
+                msg::DISCONNECT => {
+                    let mut reader = SshReader::new(&payload[1..]);
+                    let code = reader.read_u32()?;
+                    let desc = reader.read_utf8()?;
+                    return Err(SshError::Disconnect(code, desc));
+                }
+                _ => return Ok(payload),
+            }
+        }
+    }
+
+    pub async fn write_packet(&mut self, payload: &[u8]) -> SshResult<()> {
+        // https://www.rfc-editor.org/rfc/rfc4253#section-6
+        let block_size = self.sealing.block_size();
+        let unaligned = 4 + 1 + payload.len() - self.sealing.aad_len();
+        let mut padding = block_size - unaligned % block_size;
+        if padding < 4 {
+            padding += block_size;
+        }
+
+        let mut packet = Vec::with_capacity(5 + payload.len() + padding + self.sealing.tag_len());
+        packet.extend_from_slice(&((1 + payload.len() + padding) as u32).to_be_bytes());
+        packet.push(padding as u8);
+        packet.extend_from_slice(payload);
+        let mut random_padding = [0u8; 32];
+        OsRng.fill_bytes(&mut random_padding[..padding]);
+        packet.extend_from_slice(&random_padding[..padding]);
+
+        self.sealing.seal(self.send_seq, &mut packet);
+        self.send_seq = self.send_seq.wrapping_add(1);
+
+        self.stream.write_all(&packet).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Re-exchange keys after the server sent `server_kexinit`
+    pub async fn rekey(&mut self, server_kexinit: Vec<u8>) -> SshResult<()> {
+        self.key_exchange(Some(server_kexinit)).await
+    }
+
+    pub async fn disconnect(&mut self, reason: u32, desc: &str) {
+        let mut writer = SshWriter::new(Vec::with_capacity(64));
+        writer.write_u8(msg::DISCONNECT);
+        writer.write_u32(reason);
+        writer.write_str(desc);
+        writer.write_str("");
+        let _ = self.write_packet(writer.get_inner()).await;
+        let _ = self.stream.shutdown().await;
+    }
+}
+
+impl<S> Transport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn fill_buf(&mut self) -> SshResult<()> {
+        let mut buf = [0u8; 4096];
+        let n = self.stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(SshError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        self.rbuf.extend_from_slice(&buf[..n]);
+        Ok(())
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc4253#section-4.2
+    async fn exchange_version(&mut self) -> SshResult<()> {
+        self.stream
+            .write_all(format!("{}\r\n", CLIENT_VERSION).as_bytes())
+            .await?;
+        self.stream.flush().await?;
+
+        loop {
+            while let Some(end) = self.rbuf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.rbuf.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line)
+                    .trim_end_matches(['\r', '\n'])
+                    .to_owned();
+                if line.starts_with("SSH-2.0-") || line.starts_with("SSH-1.99-") {
+                    info!("Server version {}", line);
+                    self.server_version = line;
+                    return Ok(());
+                } else if line.starts_with("SSH-") {
+                    return Err(SshError::Protocol(format!("Unsupported version {}", line)));
+                }
+                debug!("Server banner: {}", line);
+            }
+            if self.rbuf.len() > MAX_VERSION_LEN {
+                return protocol_err("No SSH version identification received");
+            }
+            self.fill_buf().await?;
+        }
+    }
+
+    async fn read_raw_packet(&mut self) -> SshResult<Vec<u8>> {
+        loop {
+            if let Some(payload) = self.decode_packet()? {
+                return Ok(payload);
+            }
+            self.fill_buf().await?;
+        }
+    }
+
+    fn decode_packet(&mut self) -> SshResult<Option<Vec<u8>>> {
+        if self.rbuf.len() < 4 {
+            return Ok(None);
+        }
+        let len = self.opening.decrypt_length(self.recv_seq, &self.rbuf) as usize;
+        let block_size = self.opening.block_size();
+        if !(5..=MAX_PACKET_LEN).contains(&len)
+            || !(4 + len - self.opening.aad_len()).is_multiple_of(block_size)
+        {
+            return protocol_err("Bad packet length");
+        }
+        let total = 4 + len + self.opening.tag_len();
+        if self.rbuf.len() < total {
+            return Ok(None);
+        }
+
+        let (packet, tag) = self.rbuf[..total].split_at_mut(4 + len);
+        self.opening.open(self.recv_seq, packet, tag)?;
+        self.recv_seq = self.recv_seq.wrapping_add(1);
+
+        let padding = packet[4] as usize;
+        if padding + 1 >= len {
+            return protocol_err("Bad padding length");
+        }
+        let payload = packet[5..4 + len - padding].to_vec();
+        self.rbuf.drain(..total);
+        Ok(Some(payload))
+    }
+
+    async fn read_kex_packet(&mut self, strict: bool, expect: u8) -> SshResult<Vec<u8>> {
+        let payload = if strict {
+            self.read_raw_packet().await?
+        } else {
+            self.read_packet().await?
+        };
+        match payload[0] {
+            x if x == expect => Ok(payload),
+            msg::DISCONNECT => {
+                let mut reader = SshReader::new(&payload[1..]);
+                let code = reader.read_u32()?;
+                let desc = reader.read_utf8()?;
+                Err(SshError::Disconnect(code, desc))
+            }
+            x => Err(SshError::Protocol(format!(
+                "Unexpected message {} during key exchange",
+                x
+            ))),
+        }
+    }
+
+    async fn key_exchange(&mut self, server_kexinit: Option<Vec<u8>>) -> SshResult<()> {
+        let initial = self.session_id.is_empty();
+        let client_kexinit = KexInit::client(initial);
+        self.write_packet(&client_kexinit).await?;
+
+        let server_kexinit = match server_kexinit {
+            Some(payload) => payload,
+            None => self.read_kex_packet(false, msg::KEXINIT).await?,
+        };
+        let negotiated = KexInit::parse(&server_kexinit)?.negotiate(initial)?;
+        // only the initial exchange refuses other messages
+        let strict = negotiated.strict;
+        if initial && strict && self.recv_seq != 1 {
+            // KEXINIT must be the first packet under strict kex
+            return protocol_err("Unexpected message before KEXINIT");
+        }
+        if initial {
+            self.strict = strict;
+        }
+        debug!(
+            "Negotiated host key {}, ciphers {}/{}, strict {}",
+            negotiated.host_key, negotiated.enc_c2s, negotiated.enc_s2c, self.strict
+        );
+        if negotiated.ignore_guess {
+            let _ = self.read_raw_packet().await?;
+        }
+
+        let ecdh = Curve25519::new();
+        self.write_packet(&ecdh.init_msg()).await?;
+        let reply = self.read_kex_packet(strict, msg::KEX_ECDH_REPLY).await?;
+        let reply = EcdhReply::parse(&reply)?;
+
+        let client_public = ecdh.client_public().to_vec();
+        let shared_secret = ecdh.compute(reply.server_public)?;
+        let hash = ExchangeHash {
+            client_version: CLIENT_VERSION,
+            server_version: &self.server_version,
+            client_kexinit: &client_kexinit,
+            server_kexinit: &server_kexinit,
+            host_key: reply.host_key,
+            client_public: &client_public,
+            server_public: reply.server_public,
+            shared_secret: &shared_secret,
+        }
+        .digest();
+
+        let (host_key, host_certificate) =
+            kex::verify_host_key(&negotiated.host_key, reply.host_key, reply.signature, &hash)?;
+        if let Some(known) = &self.host_key {
+            if known.key_data() != host_key.key_data() {
+                return Err(SshError::HostKey(
+                    "Host key changed during re-exchange".to_owned(),
+                ));
+            }
+        }
+        self.host_key = Some(host_key);
+        self.host_certificate = host_certificate;
+        if initial {
+            self.session_id = hash.clone();
+        }
+
+        let derive = |letter: u8, len: usize| {
+            kex::derive_key(&shared_secret, &hash, letter, &self.session_id, len)
+        };
+        let (key_c2s, iv_c2s) = cipher::key_len(&negotiated.enc_c2s);
+        let (key_s2c, iv_s2c) = cipher::key_len(&negotiated.enc_s2c);
+        let sealing = Cipher::new(
+            &negotiated.enc_c2s,
+            &derive(b'C', key_c2s),
+            &derive(b'A', iv_c2s),
+        );
+        let opening = Cipher::new(
+            &negotiated.enc_s2c,
+            &derive(b'D', key_s2c),
+            &derive(b'B', iv_s2c),
+        );
+
+        // https://www.rfc-editor.org/rfc/rfc4253#section-7.3
+        self.write_packet(&[msg::NEWKEYS]).await?;
+        self.sealing = sealing;
+        if self.strict {
+            self.send_seq = 0;
+        }
+
+        self.read_kex_packet(strict, msg::NEWKEYS).await?;
+        self.opening = opening;
+        if self.strict {
+            self.recv_seq = 0;
+        }
+        info!("Key exchange done");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use signature::Signer;
+    use ssh_key::{Algorithm, PrivateKey};
+    use tokio::io::DuplexStream;
+
+    fn server_kexinit() -> Vec<u8> {
+        let mut writer = SshWriter::new(Vec::new());
+        writer.write_u8(msg::KEXINIT);
+        writer.write_slice(&[0; 16]);
+        writer.write_name_list(&[kex::CURVE25519_SHA256, "kex-strict-s-v00@openssh.com"]);
+        writer.write_name_list(&["ssh-ed25519"]);
+        writer.write_name_list(&[cipher::CHACHA20_POLY1305]);
+        writer.write_name_list(&[cipher::CHACHA20_POLY1305]);
+        writer.write_name_list(&[]);
+        writer.write_name_list(&[]);
+        writer.write_name_list(&["none"]);
+        writer.write_name_list(&["none"]);
+        writer.write_name_list(&[]);
+        writer.write_name_list(&[]);
+        writer.write_bool(false);
+        writer.write_u32(0);
+        writer.into_inner()
+    }
+
+    // The server side of a strict key exchange, on a transport whose
+    // ciphers run the other way
+    async fn serve_kex(server: &mut Transport<DuplexStream>, host_key: &PrivateKey) {
+        let server_kexinit = server_kexinit();
+        server.write_packet(&server_kexinit).await.unwrap();
+        let client_kexinit = server.read_raw_packet().await.unwrap();
+        let init = server.read_raw_packet().await.unwrap();
+        let client_public = SshReader::new(&init[1..]).read_string().unwrap().to_vec();
+
+        let ecdh = Curve25519::new();
+        let server_public = ecdh.client_public().to_vec();
+        let shared_secret = ecdh.compute(&client_public).unwrap();
+        let host_key_blob = host_key.public_key().to_bytes().unwrap();
+        let hash = ExchangeHash {
+            client_version: CLIENT_VERSION,
+            server_version: CLIENT_VERSION,
+            client_kexinit: &client_kexinit,
+            server_kexinit: &server_kexinit,
+            host_key: &host_key_blob,
+            client_public: &client_public,
+            server_public: &server_public,
+            shared_secret: &shared_secret,
+        }
+        .digest();
+        if server.session_id.is_empty() {
+            server.session_id = hash.clone();
+        }
+        let signature: Vec<u8> = host_key.try_sign(&hash).unwrap().try_into().unwrap();
+        let mut reply = SshWriter::new(Vec::new());
+        reply.write_u8(msg::KEX_ECDH_REPLY);
+        reply.write_string(&host_key_blob);
+        reply.write_string(&server_public);
+        reply.write_string(&signature);
+        server.write_packet(reply.get_inner()).await.unwrap();
+
+        let derive =
+            |letter: u8| kex::derive_key(&shared_secret, &hash, letter, &server.session_id, 64);
+        let sealing = Cipher::new(cipher::CHACHA20_POLY1305, &derive(b'D'), &[]);
+        let opening = Cipher::new(cipher::CHACHA20_POLY1305, &derive(b'C'), &[]);
+        server.write_packet(&[msg::NEWKEYS]).await.unwrap();
+        server.sealing = sealing;
+        server.send_seq = 0;
+        assert_eq!(server.read_raw_packet().await.unwrap(), [msg::NEWKEYS]);
+        server.opening = opening;
+        server.recv_seq = 0;
+    }
+
+    #[tokio::test]
+    async fn test_rekey() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut server = Transport {
+            stream: server,
+            rbuf: Vec::new(),
+            server_version: String::new(),
+            session_id: Vec::new(),
+            host_key: None,
+            host_certificate: None,
+            sealing: Cipher::None,
+            opening: Cipher::None,
+            send_seq: 0,
+            recv_seq: 0,
+            strict: true,
+        };
+        let host_key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let serve = async {
+            server.exchange_version().await.unwrap();
+            serve_kex(&mut server, &host_key).await;
+        };
+        let (client, ()) = tokio::join!(Transport::connect(client), serve);
+        let mut client = client.unwrap();
+        assert!(client.strict);
+
+        // some traffic, then the server starts a new exchange
+        client.write_packet(&[msg::IGNORE, 1]).await.unwrap();
+        server.read_raw_packet().await.unwrap();
+        let rekey = async {
+            let kexinit = client.read_packet().await.unwrap();
+            client.rekey(kexinit).await.unwrap();
+        };
+        tokio::join!(rekey, serve_kex(&mut server, &host_key));
+
+        // the sequence numbers started over on both sides
+        assert_eq!((client.send_seq, client.recv_seq), (0, 0));
+        client.write_packet(&[200, 1]).await.unwrap();
+        assert_eq!(server.read_raw_packet().await.unwrap(), [200, 1]);
+        server.write_packet(&[201, 2]).await.unwrap();
+        assert_eq!(client.read_packet().await.unwrap(), [201, 2]);
+    }
+}
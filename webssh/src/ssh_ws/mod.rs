@@ -0,0 +1,7 @@
+mod ssh_client;
+mod tcp_client;
+mod ws_bio;
+pub use ssh_client::Ssh;
+pub use tcp_client::{Protocol, TcpClient};
+
+const TERM: &str = "xterm-256color";
@@ -0,0 +1,549 @@
+use super::ws_bio::*;
+use super::TERM;
+use crate::agent::Agent;
+use crate::canvas::CanvasUtils;
+use crate::keys::KeyStore;
+use crate::known_hosts::{HostKeyStatus, KnownHosts};
+use crate::sftp::{self, SftpClient};
+use crate::sftp_panel::SftpPanel;
+use crate::ssh::cert::Certificate;
+use crate::ssh::{msg, AuthResult, Session, SessionEvent, SshError, Transport};
+use crate::tabs::{TabCommand, Tabs};
+use crate::term::Terminal;
+use base64ct::{Base64UrlUnpadded, Encoding};
+use serde_json::Value;
+use ssh_key::{HashAlg, PrivateKey};
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+use wasm_bindgen::JsCast;
+use web_sys::{Element, HtmlCanvasElement};
+use ws_stream_wasm::WsMeta;
+
+pub struct Ssh {
+    status_bar: Element,
+    canvas: HtmlCanvasElement,
+    session: Session<WsStream>,
+    _ws_meta: WsMeta,
+}
+
+// The target the token in `query` names, signed by the portal but readable
+// by anyone
+fn token_target(query: &str) -> Option<String> {
+    let token = query.split('&').find_map(|p| p.strip_prefix("token="))?;
+    let (claims, _) = token.split_once('.')?;
+    let claims = Base64UrlUnpadded::decode_vec(claims).ok()?;
+    let claims: Value = serde_json::from_slice(&claims).ok()?;
+    claims["target"].as_str().map(str::to_owned)
+}
+
+/// What the host keys of `url` are known as, the gateway and the route to
+/// the target without the token, which changes with every page
+fn known_host(url: &str) -> String {
+    let target = url.split_once("://").map_or(url, |(_, target)| target);
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    match token_target(query) {
+        Some(routed) => format!("{}/{}", path, routed),
+        None => path.to_owned(),
+    }
+}
+
+/// The name host certificates of `url` are issued for: the host of the
+/// target the token names, the target picked with ?target=, or the gateway
+/// itself when it relays its one target
+fn host_name(url: &str) -> String {
+    let target = url.split_once("://").map_or(url, |(_, target)| target);
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let routed = token_target(query).or_else(|| {
+        path.split_once("/websockify/")
+            .map(|(_, name)| name.to_owned())
+    });
+    let authority = match &routed {
+        Some(routed) => routed.as_str(),
+        None => path.split('/').next().unwrap_or(path),
+    };
+    let host = match authority.strip_prefix('[') {
+        // [v6 address]:port
+        Some(rest) => rest.split(']').next().unwrap_or(rest),
+        None => authority.split(':').next().unwrap_or(authority),
+    };
+    host.to_owned()
+}
+
+impl Ssh {
+    pub async fn connect(url: &str) -> Result<Self, String> {
+        let document = web_sys::window().unwrap().document().unwrap();
+        let status_bar = document.get_element_by_id("ssh_status").unwrap();
+        let canvas = document
+            .get_element_by_id("ssh-canvas")
+            .unwrap()
+            .dyn_into::<HtmlCanvasElement>()
+            .map_err(|_| ())
+            .unwrap();
+
+        status_bar.set_text_content(Some("Connecting"));
+        let (ws_stream, ws_meta) = WsBio::new(url).await.map_err(|e| e.to_string())?.split();
+        let mut transport = Transport::connect(ws_stream)
+            .await
+            .map_err(|e| e.to_string())?;
+        Self::verify_host_key(&mut transport, url).await?;
+        status_bar.set_text_content(Some(transport.server_version()));
+        Ok(Self {
+            status_bar,
+            canvas,
+            session: Session::new(transport),
+            _ws_meta: ws_meta,
+        })
+    }
+
+    /// Certificates are checked against the trusted authorities of the host,
+    /// otherwise trust on first use, a known host must always present the same key
+    async fn verify_host_key(transport: &mut Transport<WsStream>, url: &str) -> Result<(), String> {
+        let target = &known_host(url);
+        let key = transport.host_key().clone();
+        let fingerprint = key.fingerprint(HashAlg::Sha256);
+        let mut known_hosts = KnownHosts::load();
+        if let Some(cert) = transport.host_certificate() {
+            let host = &host_name(url);
+            let now = (js_sys::Date::now() / 1000.0) as u64;
+            match known_hosts.check_certificate(host, cert, now) {
+                Some(Ok(())) => {
+                    info!("Host certificate {} of {} is trusted", cert.key_id, host);
+                    return Ok(());
+                }
+                Some(Err(e)) => {
+                    crate::alert(&format!(
+                        "The host certificate of '{}' is not valid: {}.",
+                        host, e
+                    ));
+                    transport
+                        .disconnect(
+                            msg::DISCONNECT_HOST_KEY_NOT_VERIFIABLE,
+                            "Host certificate verification failed",
+                        )
+                        .await;
+                    return Err("Host certificate verification failed".to_owned());
+                }
+                // no authority for this host, fall back to the certified key
+                None => {}
+            }
+        }
+        match known_hosts.check(target, &key) {
+            HostKeyStatus::Trusted => return Ok(()),
+            HostKeyStatus::Unknown => {
+                if crate::confirm(&format!(
+                    "The authenticity of host '{}' can't be established.\n\
+                     {} key fingerprint is {}.\n\
+                     Are you sure you want to continue connecting?",
+                    target,
+                    key.algorithm().as_str(),
+                    fingerprint
+                )) {
+                    info!("Permanently added {} to the known hosts", target);
+                    known_hosts.add(target, &key);
+                    known_hosts.save();
+                    return Ok(());
+                }
+            }
+            HostKeyStatus::Changed => {
+                crate::alert(&format!(
+                    "WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!\n\
+                     The {} key sent by '{}' does not match the stored one.\n\
+                     Someone could be eavesdropping on you right now.\n\
+                     The fingerprint for the key sent by the remote host is\n{}\n\
+                     Remove the old entry from the known hosts to connect.",
+                    key.algorithm().as_str(),
+                    target,
+                    fingerprint
+                ));
+            }
+        }
+        transport
+            .disconnect(
+                msg::DISCONNECT_HOST_KEY_NOT_VERIFIABLE,
+                "Host key verification failed",
+            )
+            .await;
+        Err("Host key verification failed".to_owned())
+    }
+
+    pub async fn login_none(&mut self, username: &str) -> Result<AuthResult, String> {
+        self.session
+            .auth_none(username)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    pub async fn login(&mut self, username: &str, password: &str) -> Result<AuthResult, String> {
+        self.session
+            .auth_password(username, password)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    pub async fn login_publickey(
+        &mut self,
+        username: &str,
+        key: &PrivateKey,
+        certificate: Option<&Certificate>,
+    ) -> Result<AuthResult, String> {
+        self.session
+            .auth_publickey(username, key, certificate)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    pub async fn login_keyboard_interactive(
+        &mut self,
+        username: &str,
+    ) -> Result<AuthResult, String> {
+        self.session
+            .auth_keyboard_interactive(username)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    pub async fn info_response(
+        &mut self,
+        username: &str,
+        responses: &[String],
+    ) -> Result<AuthResult, String> {
+        self.session
+            .info_response(username, responses)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Start another shell on the connection and show it in a new tab
+    async fn open_tab(
+        &mut self,
+        tabs: &mut Tabs,
+        forward_agent: bool,
+        cols: usize,
+        rows: usize,
+    ) -> Result<(), SshError> {
+        let channel = self
+            .session
+            .open_shell(TERM, cols as u32, rows as u32, forward_agent)
+            .await?;
+        tabs.add(channel, Terminal::new(cols, rows));
+        Ok(())
+    }
+
+    pub async fn main_loop(mut self, keys: KeyStore) {
+        let (input_sender, mut input_reciver) = mpsc::channel(100);
+        let mut canvas = CanvasUtils::new(input_sender, self.canvas.clone());
+        let (mut cols, mut rows) = canvas.grid_size();
+        let (tab_sender, mut tab_reciver) = mpsc::channel(10);
+        let mut tabs = Tabs::new(tab_sender);
+        if let Err(e) = self
+            .open_tab(&mut tabs, keys.forward_agent(), cols, rows)
+            .await
+        {
+            self.disconnect_with_msg(&e.to_string());
+            return;
+        }
+        tabs.bind();
+
+        // the file browser is optional, the shell works without it
+        let (sftp_sender, mut sftp_reciver) = mpsc::channel(100);
+        let panel = SftpPanel::new(sftp_sender);
+        let mut sftp = match self.session.open_subsystem("sftp").await {
+            Ok(id) => {
+                panel.bind();
+                let mut client = SftpClient::new();
+                client.command(sftp::Command::List(".".to_owned()));
+                let _ = self.session.send_data(id, &client.take_output()).await;
+                Some((id, client))
+            }
+            Err(e) => {
+                panel.set_status(&e.to_string());
+                None
+            }
+        };
+
+        let (resize_sender, mut resize_reciver) = mpsc::channel(10);
+        let (scroll_sender, mut scroll_reciver) = mpsc::channel(10);
+        canvas.init(cols, rows);
+        canvas.bind_resize(resize_sender);
+        canvas.bind_scroll(scroll_sender);
+        canvas.focus();
+        let mut agent = Agent::new(keys.clone());
+        'main: loop {
+            let event = match self.session.next_queued() {
+                Some(event) => Ok(Some(event)),
+                None => tokio::select! {
+                    packet = self.session.recv() => {
+                        match packet {
+                            Ok(packet) => self.session.handle(packet).await,
+                            Err(e) => Err(e),
+                        }
+                    },
+                    input_recv = input_reciver.recv() => {
+                        if let (Some(data), Some(channel)) = (input_recv, tabs.active()) {
+                            // typing brings the view back to the bottom
+                            let terminal = tabs.terminal_mut(channel).unwrap();
+                            if terminal.screen().view_offset() > 0 {
+                                terminal.screen_mut().scroll_view(isize::MIN);
+                                canvas.draw(terminal.screen_mut());
+                            }
+                            let _ = self.session.send_data(channel, &data).await;
+                        }
+                        continue;
+                    }
+                    lines = scroll_reciver.recv() => {
+                        if let (Some(lines), Some(terminal)) = (lines, tabs.active_terminal_mut()) {
+                            terminal.screen_mut().scroll_view(lines);
+                            canvas.draw(terminal.screen_mut());
+                        }
+                        continue;
+                    }
+                    size = resize_reciver.recv() => {
+                        if let Some(size) = size.filter(|size| *size != (cols, rows)) {
+                            (cols, rows) = size;
+                            canvas.init(cols, rows);
+                            tabs.resize(cols, rows, js_sys::Date::now());
+                            for channel in tabs.channels() {
+                                let _ = self.session.window_change(channel, cols as u32, rows as u32).await;
+                            }
+                            if let Some(terminal) = tabs.active_terminal_mut() {
+                                canvas.draw(terminal.screen_mut());
+                            }
+                        }
+                        continue;
+                    }
+                    command = tab_reciver.recv() => {
+                        match command {
+                            Some(TabCommand::New) => {
+                                if let Err(e) = self.open_tab(&mut tabs, keys.forward_agent(), cols, rows).await {
+                                    error!("Unable to open a new tab: {}", e);
+                                }
+                            }
+                            Some(TabCommand::Select(channel)) => tabs.select(channel),
+                            Some(TabCommand::Record) => {
+                                if let Some(cast) = tabs.toggle_recording(TERM, js_sys::Date::now()) {
+                                    crate::utils::download("session.cast", cast.as_bytes(), "application/x-asciicast");
+                                }
+                            }
+                            Some(TabCommand::Close(channel)) => {
+                                // the tab goes away once the server confirms
+                                let _ = self.session.close_channel(channel).await;
+                            }
+                            None => {}
+                        }
+                        if let Some(terminal) = tabs.active_terminal_mut() {
+                            if let Some(title) = terminal.screen().title() {
+                                web_sys::window().unwrap().document().unwrap().set_title(title);
+                            }
+                            canvas.draw(terminal.screen_mut());
+                        }
+                        canvas.focus();
+                        continue;
+                    }
+                    command = sftp_reciver.recv() => {
+                        if let (Some(command), Some((id, client))) = (command, sftp.as_mut()) {
+                            client.command(command);
+                            let _ = self.session.send_data(*id, &client.take_output()).await;
+                        }
+                        continue;
+                    }
+                },
+            };
+            match event {
+                Ok(Some(SessionEvent::Data(id, data)))
+                | Ok(Some(SessionEvent::ExtendedData(id, data)))
+                    if tabs.contains(id) =>
+                {
+                    tabs.record_output(id, js_sys::Date::now(), &data);
+                    let terminal = tabs.terminal_mut(id).unwrap();
+                    let old_title = terminal.screen().title().map(str::to_owned);
+                    terminal.feed(&data);
+                    let response = terminal.screen_mut().take_response();
+                    let title_changed = terminal.screen().title() != old_title.as_deref();
+                    if !response.is_empty() {
+                        let _ = self.session.send_data(id, &response).await;
+                    }
+                    if title_changed {
+                        tabs.update_label(id);
+                    }
+                    if tabs.active() == Some(id) {
+                        let terminal = tabs.terminal_mut(id).unwrap();
+                        if let Some(title) = terminal.screen().title() {
+                            web_sys::window()
+                                .unwrap()
+                                .document()
+                                .unwrap()
+                                .set_title(title);
+                        }
+                        canvas.draw(terminal.screen_mut());
+                    }
+                }
+                Ok(Some(SessionEvent::Data(id, data)))
+                    if sftp.as_ref().is_some_and(|(s, _)| *s == id) =>
+                {
+                    let (_, client) = sftp.as_mut().unwrap();
+                    match client.feed(&data) {
+                        Ok(events) => {
+                            for event in events {
+                                if let Some(command) = panel.handle(event) {
+                                    client.command(command);
+                                }
+                            }
+                            let _ = self.session.send_data(id, &client.take_output()).await;
+                        }
+                        Err(e) => {
+                            error!("sftp: {}", e);
+                            panel.set_status(&e.to_string());
+                            let _ = self.session.close_channel(id).await;
+                            sftp = None;
+                        }
+                    }
+                }
+                Ok(Some(SessionEvent::AgentOpened(id))) => {
+                    info!("Agent channel {} opened", id);
+                    agent.open(id);
+                }
+                Ok(Some(SessionEvent::Data(id, data))) if agent.is_agent_channel(id) => {
+                    let reply = agent.feed(id, &data, |key| {
+                        !keys.confirm_signatures()
+                            || crate::confirm(&format!(
+                                "Allow the remote host to sign with {} key {}?",
+                                key.algorithm().as_str(),
+                                key.fingerprint(HashAlg::Sha256)
+                            ))
+                    });
+                    match reply {
+                        Ok(reply) => {
+                            let _ = self.session.send_data(id, &reply).await;
+                        }
+                        Err(e) => {
+                            warn!("Agent channel {}: {}", id, e);
+                            agent.close(id);
+                            let _ = self.session.close_channel(id).await;
+                        }
+                    }
+                }
+                Ok(Some(SessionEvent::Eof(id))) => info!("Channel {} EOF", id),
+                Ok(Some(SessionEvent::Closed(id))) if tabs.contains(id) => {
+                    info!("Shell {} closed", id);
+                    tabs.remove(id);
+                    if tabs.is_empty() {
+                        self.session.disconnect().await;
+                        self.disconnect_with_msg("Disconnected");
+                        break 'main;
+                    }
+                    if let Some(terminal) = tabs.active_terminal_mut() {
+                        canvas.draw(terminal.screen_mut());
+                    }
+                }
+                Ok(Some(SessionEvent::Closed(id)))
+                    if sftp.as_ref().is_some_and(|(s, _)| *s == id) =>
+                {
+                    panel.set_status("SFTP session closed");
+                    sftp = None;
+                }
+                Ok(Some(SessionEvent::Closed(id))) => agent.close(id),
+                Ok(_) => {}
+                Err(SshError::Disconnect(_, msg)) => {
+                    info!("Server ask for disconnect");
+                    self.disconnect_with_msg(&msg);
+                    break 'main;
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    self.disconnect_with_msg(&e.to_string());
+                    break 'main;
+                }
+            }
+        }
+    }
+
+    fn disconnect_with_msg(&self, msg: &str) {
+        self.status_bar.set_text_content(Some(msg));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_host_name() {
+        // the gateway relays its one target
+        assert_eq!(
+            host_name("wss://gw.example.com:8080/websockify"),
+            "gw.example.com"
+        );
+        assert_eq!(host_name("ws://[::1]:8080/websockify"), "::1");
+        // routed targets are named by the route, not the gateway
+        assert_eq!(host_name("wss://gw.example.com/websockify/db-1"), "db-1");
+        let claims = Base64UrlUnpadded::encode_string(br#"{"target":"[fd00::5]:22","exp":1}"#);
+        assert_eq!(
+            host_name(&format!(
+                "wss://gw.example.com/websockify?token={}.c2ln",
+                claims
+            )),
+            "fd00::5"
+        );
+    }
+
+    #[test]
+    fn test_routed_certificate() {
+        use crate::ssh::cert::{test::issue, HOST_CERT};
+        use rand_core::OsRng;
+        use ssh_key::Algorithm;
+
+        let ca = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let host_key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let text = format!(
+            "@cert-authority *.example.com {}\n",
+            ca.public_key().to_openssh().unwrap()
+        );
+        let known_hosts = KnownHosts::parse(&text).unwrap();
+        let cert = |principal: &str| {
+            let blob = issue(
+                &ca,
+                host_key.public_key(),
+                HOST_CERT,
+                &[principal],
+                (0, u64::MAX),
+            );
+            Certificate::from_bytes(&blob).unwrap()
+        };
+        let claims = Base64UrlUnpadded::encode_string(br#"{"target":"db.example.com:22","exp":1}"#);
+        let url = format!("wss://gw.example.com/websockify?token={}.c2ln", claims);
+        let host = host_name(&url);
+        let now = 1_700_000_000;
+        assert_eq!(
+            known_hosts.check_certificate(&host, &cert("db.example.com"), now),
+            Some(Ok(()))
+        );
+        // the gateway does not stand for the hosts behind it
+        assert!(matches!(
+            known_hosts.check_certificate(&host, &cert("gw.example.com"), now),
+            Some(Err(_))
+        ));
+    }
+
+    #[test]
+    fn test_known_host() {
+        assert_eq!(
+            known_host("wss://gw.example.com/websockify/router"),
+            "gw.example.com/websockify/router"
+        );
+        // a token per page, the target it names stays
+        let claims = Base64UrlUnpadded::encode_string(br#"{"target":"10.0.0.5:22","exp":1}"#);
+        for signature in ["c2ln", "b3RoZXI"] {
+            assert_eq!(
+                known_host(&format!(
+                    "wss://gw.example.com/websockify?token={}.{}",
+                    claims, signature
+                )),
+                "gw.example.com/websockify/10.0.0.5:22"
+            );
+        }
+        assert_eq!(
+            known_host("ws://gw.example.com/websockify?token=bad"),
+            "gw.example.com/websockify"
+        );
+    }
+}
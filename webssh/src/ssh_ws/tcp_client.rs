@@ -0,0 +1,194 @@
+use super::ws_bio::*;
+use super::TERM;
+use crate::canvas::CanvasUtils;
+use crate::tabs::{TabCommand, Tabs};
+use crate::telnet::Telnet;
+use crate::term::Terminal;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::{error, info};
+use wasm_bindgen::JsCast;
+use web_sys::{Element, HtmlCanvasElement};
+use ws_stream_wasm::WsMeta;
+
+// the only tab of the connection
+const CHANNEL: u32 = 0;
+
+/// What the bytes relayed by the websocket proxy carry
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Protocol {
+    Telnet,
+    /// Bare TCP, the bytes go to the terminal untouched
+    Raw,
+}
+
+/// A terminal on a telnet or raw TCP connection, for devices without SSH
+pub struct TcpClient {
+    status_bar: Element,
+    canvas: HtmlCanvasElement,
+    stream: WsStream,
+    protocol: Protocol,
+    _ws_meta: WsMeta,
+}
+
+impl TcpClient {
+    pub async fn connect(url: &str, protocol: Protocol) -> Result<Self, String> {
+        let document = web_sys::window().unwrap().document().unwrap();
+        let status_bar = document.get_element_by_id("ssh_status").unwrap();
+        let canvas = document
+            .get_element_by_id("ssh-canvas")
+            .unwrap()
+            .dyn_into::<HtmlCanvasElement>()
+            .map_err(|_| ())
+            .unwrap();
+
+        status_bar.set_text_content(Some("Connecting"));
+        let (stream, ws_meta) = WsBio::new(url).await.map_err(|e| e.to_string())?.split();
+        status_bar.set_text_content(Some(match protocol {
+            Protocol::Telnet => "Telnet",
+            Protocol::Raw => "Raw TCP",
+        }));
+        Ok(Self {
+            status_bar,
+            canvas,
+            stream,
+            protocol,
+            _ws_meta: ws_meta,
+        })
+    }
+
+    async fn send(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.stream.write_all(data).await?;
+        self.stream.flush().await
+    }
+
+    pub async fn main_loop(mut self) {
+        let (input_sender, mut input_reciver) = mpsc::channel(100);
+        let mut canvas = CanvasUtils::new(input_sender, self.canvas.clone());
+        let (mut cols, mut rows) = canvas.grid_size();
+        let (tab_sender, mut tab_reciver) = mpsc::channel(10);
+        let mut tabs = Tabs::new(tab_sender);
+        tabs.add(CHANNEL, Terminal::new(cols, rows));
+        tabs.bind();
+
+        let mut telnet = match self.protocol {
+            Protocol::Telnet => Some(Telnet::new(TERM, cols, rows)),
+            Protocol::Raw => None,
+        };
+        // data for the server, escaped if the connection speaks telnet
+        let encode = |telnet: &mut Option<Telnet>, data: &[u8]| match telnet {
+            Some(telnet) => {
+                telnet.send(data);
+                telnet.take_output()
+            }
+            None => data.to_vec(),
+        };
+        if let Some(output) = telnet.as_mut().map(Telnet::take_output) {
+            if let Err(e) = self.send(&output).await {
+                self.disconnect_with_msg(&e.to_string());
+                return;
+            }
+        }
+
+        let (resize_sender, mut resize_reciver) = mpsc::channel(10);
+        let (scroll_sender, mut scroll_reciver) = mpsc::channel(10);
+        canvas.init(cols, rows);
+        canvas.bind_resize(resize_sender);
+        canvas.bind_scroll(scroll_sender);
+        canvas.focus();
+        let mut buf = vec![0; 16 * 1024];
+        loop {
+            tokio::select! {
+                read = self.stream.read(&mut buf) => {
+                    let data = match read {
+                        Ok(0) => {
+                            self.disconnect_with_msg("Disconnected");
+                            break;
+                        }
+                        Ok(n) => &buf[..n],
+                        Err(e) => {
+                            error!("{}", e);
+                            self.disconnect_with_msg(&e.to_string());
+                            break;
+                        }
+                    };
+                    let (data, mut reply) = match telnet.as_mut() {
+                        Some(telnet) => (telnet.feed(data), telnet.take_output()),
+                        None => (data.to_vec(), Vec::new()),
+                    };
+                    tabs.record_output(CHANNEL, js_sys::Date::now(), &data);
+                    let terminal = tabs.terminal_mut(CHANNEL).unwrap();
+                    let old_title = terminal.screen().title().map(str::to_owned);
+                    terminal.feed(&data);
+                    let response = terminal.screen_mut().take_response();
+                    let title_changed = terminal.screen().title() != old_title.as_deref();
+                    reply.extend(encode(&mut telnet, &response));
+                    let _ = self.send(&reply).await;
+                    if title_changed {
+                        tabs.update_label(CHANNEL);
+                    }
+                    let terminal = tabs.terminal_mut(CHANNEL).unwrap();
+                    if let Some(title) = terminal.screen().title() {
+                        web_sys::window().unwrap().document().unwrap().set_title(title);
+                    }
+                    canvas.draw(terminal.screen_mut());
+                }
+                input_recv = input_reciver.recv() => {
+                    if let Some(data) = input_recv {
+                        // typing brings the view back to the bottom
+                        let terminal = tabs.terminal_mut(CHANNEL).unwrap();
+                        if terminal.screen().view_offset() > 0 {
+                            terminal.screen_mut().scroll_view(isize::MIN);
+                            canvas.draw(terminal.screen_mut());
+                        }
+                        let _ = self.send(&encode(&mut telnet, &data)).await;
+                    }
+                }
+                lines = scroll_reciver.recv() => {
+                    if let (Some(lines), Some(terminal)) = (lines, tabs.active_terminal_mut()) {
+                        terminal.screen_mut().scroll_view(lines);
+                        canvas.draw(terminal.screen_mut());
+                    }
+                }
+                size = resize_reciver.recv() => {
+                    if let Some(size) = size.filter(|size| *size != (cols, rows)) {
+                        (cols, rows) = size;
+                        canvas.init(cols, rows);
+                        tabs.resize(cols, rows, js_sys::Date::now());
+                        if let Some(telnet) = telnet.as_mut() {
+                            telnet.resize(cols, rows);
+                            let output = telnet.take_output();
+                            let _ = self.send(&output).await;
+                        }
+                        canvas.draw(tabs.terminal_mut(CHANNEL).unwrap().screen_mut());
+                    }
+                }
+                command = tab_reciver.recv() => {
+                    match command {
+                        Some(TabCommand::New) => info!("A {:?} connection has a single terminal", self.protocol),
+                        Some(TabCommand::Record) => {
+                            if let Some(cast) = tabs.toggle_recording(TERM, js_sys::Date::now()) {
+                                crate::utils::download("session.cast", cast.as_bytes(), "application/x-asciicast");
+                            }
+                        }
+                        Some(TabCommand::Close(_)) => {
+                            let _ = self.stream.shutdown().await;
+                            tabs.remove(CHANNEL);
+                            self.disconnect_with_msg("Disconnected");
+                            break;
+                        }
+                        Some(TabCommand::Select(_)) | None => {}
+                    }
+                    canvas.focus();
+                }
+            }
+        }
+    }
+
+    fn disconnect_with_msg(&self, msg: &str) {
+        self.status_bar.set_text_content(Some(msg));
+    }
+}
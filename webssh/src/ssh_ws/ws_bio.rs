@@ -0,0 +1,41 @@
+use async_io_stream::IoStream;
+use tracing::info;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use ws_stream_wasm::*;
+
+pub type WsStream = IoStream<WsStreamIo, Vec<u8>>;
+
+pub struct WsBio {
+    ws_stream: WsStream,
+    ws_meta: WsMeta,
+}
+
+impl WsBio {
+    pub async fn new(url: &str) -> Result<Self, WsErr> {
+        let (ws, wsio) = WsMeta::connect(url, vec!["binary"]).await?;
+
+        let onclose_callback = Closure::<dyn FnMut()>::new(move || {
+            info!("socket close");
+            let status_bar = web_sys::window()
+                .unwrap()
+                .document()
+                .unwrap()
+                .get_element_by_id("ssh_status")
+                .unwrap();
+            status_bar.set_text_content(Some("Server Disconnected"));
+        });
+
+        ws.wrapped()
+            .set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
+        onclose_callback.forget();
+        Ok(Self {
+            ws_stream: wsio.into_io(),
+            ws_meta: ws,
+        })
+    }
+
+    pub fn split(self) -> (WsStream, WsMeta) {
+        (self.ws_stream, self.ws_meta)
+    }
+}
@@ -0,0 +1,267 @@
+use crate::asciicast::Recorder;
+use crate::term::Terminal;
+
+use tokio::sync::mpsc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{Element, HtmlButtonElement, MouseEvent};
+
+pub enum TabCommand {
+    New,
+    Select(u32),
+    Close(u32),
+    /// Start or stop recording the selected tab
+    Record,
+}
+
+struct Tab {
+    channel: u32,
+    terminal: Terminal,
+    label: Element,
+    number: usize,
+    recorder: Option<Recorder>,
+}
+
+/// Shell channels of the connection, only the selected one is drawn
+pub struct Tabs {
+    bar: Element,
+    record_btn: HtmlButtonElement,
+    tabs: Vec<Tab>,
+    active: Option<u32>,
+    opened: usize,
+    sender: mpsc::Sender<TabCommand>,
+}
+
+impl Tabs {
+    pub fn new(sender: mpsc::Sender<TabCommand>) -> Self {
+        let document = web_sys::window().unwrap().document().unwrap();
+        Self {
+            bar: document.get_element_by_id("tablist").unwrap(),
+            record_btn: document
+                .get_element_by_id("recordbtn")
+                .unwrap()
+                .dyn_into::<HtmlButtonElement>()
+                .map_err(|_| ())
+                .unwrap(),
+            tabs: Vec::new(),
+            active: None,
+            opened: 0,
+            sender,
+        }
+    }
+
+    pub fn bind(&self) {
+        let sender = self.sender.clone();
+        let new_tab = move || {
+            let _ = futures::executor::block_on(sender.send(TabCommand::New));
+        };
+        let handler = Box::new(new_tab) as Box<dyn FnMut()>;
+
+        let cb = Closure::wrap(handler);
+
+        web_sys::window()
+            .unwrap()
+            .document()
+            .unwrap()
+            .get_element_by_id("newtab")
+            .unwrap()
+            .dyn_into::<HtmlButtonElement>()
+            .map_err(|_| ())
+            .unwrap()
+            .set_onclick(Some(cb.as_ref().unchecked_ref()));
+        cb.forget();
+
+        let sender = self.sender.clone();
+        let record = move || {
+            let _ = futures::executor::block_on(sender.send(TabCommand::Record));
+        };
+        let handler = Box::new(record) as Box<dyn FnMut()>;
+
+        let cb = Closure::wrap(handler);
+
+        self.record_btn
+            .set_onclick(Some(cb.as_ref().unchecked_ref()));
+        cb.forget();
+
+        // one listener for the buttons of every tab
+        let sender = self.sender.clone();
+        let click = move |e: MouseEvent| {
+            let target = match e
+                .target()
+                .and_then(|t| t.dyn_into::<Element>().ok())
+                .and_then(|t| t.closest("[data-channel]").ok().flatten())
+            {
+                Some(target) => target,
+                None => return,
+            };
+            let channel = match target
+                .get_attribute("data-channel")
+                .and_then(|c| c.parse().ok())
+            {
+                Some(channel) => channel,
+                None => return,
+            };
+            let command = if target.has_attribute("data-close") {
+                TabCommand::Close(channel)
+            } else {
+                TabCommand::Select(channel)
+            };
+            let _ = futures::executor::block_on(sender.send(command));
+        };
+        let handler = Box::new(click) as Box<dyn FnMut(_)>;
+
+        let cb = Closure::wrap(handler);
+
+        self.bar
+            .add_event_listener_with_callback("click", cb.as_ref().unchecked_ref())
+            .unwrap();
+        cb.forget();
+    }
+
+    /// Add the tab of a new shell channel and select it
+    pub fn add(&mut self, channel: u32, terminal: Terminal) {
+        let document = web_sys::window().unwrap().document().unwrap();
+        self.opened += 1;
+
+        let tab = document.create_element("span").unwrap();
+        tab.set_class_name("tab");
+        let _ = tab.set_attribute("data-channel", &channel.to_string());
+        let label = document.create_element("span").unwrap();
+        tab.append_child(&label).unwrap();
+        let close = document.create_element("button").unwrap();
+        close.set_text_content(Some("×"));
+        let _ = close.set_attribute("data-channel", &channel.to_string());
+        let _ = close.set_attribute("data-close", "");
+        tab.append_child(&close).unwrap();
+        self.bar.append_child(&tab).unwrap();
+
+        self.tabs.push(Tab {
+            channel,
+            terminal,
+            label,
+            number: self.opened,
+            recorder: None,
+        });
+        self.update_label(channel);
+        self.select(channel);
+    }
+
+    /// Returns whether `channel` belonged to a tab
+    pub fn remove(&mut self, channel: u32) -> bool {
+        let idx = match self.tabs.iter().position(|t| t.channel == channel) {
+            Some(idx) => idx,
+            None => return false,
+        };
+        let tab = self.tabs.remove(idx);
+        if let Some(element) = tab.label.parent_element() {
+            element.remove();
+        }
+        if self.active == Some(channel) {
+            self.active = None;
+            // select the neighbour of the closed tab
+            if let Some(channel) = self.tabs.get(idx.saturating_sub(1)).map(|t| t.channel) {
+                self.select(channel);
+            }
+        }
+        true
+    }
+
+    pub fn select(&mut self, channel: u32) {
+        if !self.contains(channel) {
+            return;
+        }
+        self.active = Some(channel);
+        for tab in &mut self.tabs {
+            if let Some(element) = tab.label.parent_element() {
+                let _ = element
+                    .class_list()
+                    .toggle_with_force("tab-active", tab.channel == channel);
+            }
+            if tab.channel == channel {
+                tab.terminal.screen_mut().invalidate();
+                self.record_btn
+                    .set_text_content(Some(if tab.recorder.is_some() {
+                        "stop"
+                    } else {
+                        "rec"
+                    }));
+            }
+        }
+    }
+
+    /// Start recording the selected tab, or stop and return the recording
+    pub fn toggle_recording(&mut self, term: &str, now: f64) -> Option<String> {
+        let channel = self.active?;
+        let tab = self.tabs.iter_mut().find(|t| t.channel == channel)?;
+        let cast = match tab.recorder.take() {
+            Some(recorder) => Some(recorder.finish()),
+            None => {
+                let screen = tab.terminal.screen();
+                tab.recorder = Some(Recorder::new(screen.cols(), screen.rows(), term, now));
+                None
+            }
+        };
+        self.select(channel);
+        cast
+    }
+
+    pub fn record_output(&mut self, channel: u32, now: f64, data: &[u8]) {
+        if let Some(recorder) = self
+            .tabs
+            .iter_mut()
+            .find(|t| t.channel == channel)
+            .and_then(|t| t.recorder.as_mut())
+        {
+            recorder.output(now, data);
+        }
+    }
+
+    /// Resize the terminal of every tab
+    pub fn resize(&mut self, cols: usize, rows: usize, now: f64) {
+        for tab in &mut self.tabs {
+            tab.terminal.resize(cols, rows);
+            if let Some(recorder) = tab.recorder.as_mut() {
+                recorder.resize(now, cols, rows);
+            }
+        }
+    }
+
+    pub fn contains(&self, channel: u32) -> bool {
+        self.tabs.iter().any(|t| t.channel == channel)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tabs.is_empty()
+    }
+
+    pub fn active(&self) -> Option<u32> {
+        self.active
+    }
+
+    pub fn channels(&self) -> Vec<u32> {
+        self.tabs.iter().map(|t| t.channel).collect()
+    }
+
+    pub fn terminal_mut(&mut self, channel: u32) -> Option<&mut Terminal> {
+        self.tabs
+            .iter_mut()
+            .find(|t| t.channel == channel)
+            .map(|t| &mut t.terminal)
+    }
+
+    pub fn active_terminal_mut(&mut self) -> Option<&mut Terminal> {
+        let channel = self.active?;
+        self.terminal_mut(channel)
+    }
+
+    /// Show the title the shell set, if any
+    pub fn update_label(&self, channel: u32) {
+        if let Some(tab) = self.tabs.iter().find(|t| t.channel == channel) {
+            let label = match tab.terminal.screen().title() {
+                Some(title) if !title.is_empty() => title.to_owned(),
+                _ => format!("shell {}", tab.number),
+            };
+            tab.label.set_text_content(Some(&label));
+        }
+    }
+}
@@ -0,0 +1,310 @@
+// Telnet protocol
+// https://datatracker.ietf.org/doc/html/rfc854
+
+use tracing::debug;
+
+// Commands
+const SE: u8 = 240;
+const SB: u8 = 250;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const IAC: u8 = 255;
+
+// Options
+const OPT_BINARY: u8 = 0;
+const OPT_ECHO: u8 = 1;
+const OPT_SGA: u8 = 3;
+const OPT_TTYPE: u8 = 24;
+const OPT_NAWS: u8 = 31;
+
+// Terminal type subnegotiation
+const TTYPE_IS: u8 = 0;
+const TTYPE_SEND: u8 = 1;
+
+// Longer subnegotiations are dropped, ours are a few bytes
+const MAX_SUB: usize = 1024;
+
+// Options we perform ourselves
+const LOCAL_OPTIONS: [u8; 3] = [OPT_BINARY, OPT_TTYPE, OPT_NAWS];
+// Options we let the server perform
+const REMOTE_OPTIONS: [u8; 3] = [OPT_BINARY, OPT_ECHO, OPT_SGA];
+
+#[derive(Clone, Copy)]
+enum State {
+    Data,
+    // a CR may be followed by a NUL to drop
+    Cr,
+    Iac,
+    Command(u8),
+    Sub,
+    SubIac,
+}
+
+/// Negotiation state of one option on one side, see RFC 1143
+#[derive(Clone, Copy, Default)]
+struct Opt {
+    enabled: bool,
+    // we asked for a change and wait for the answer
+    pending: bool,
+}
+
+/// A sans-io telnet client
+///
+/// Feed it the data from the server, draw what [`Telnet::feed`] returns
+/// and send whatever [`Telnet::take_output`] returns.
+pub struct Telnet {
+    term: String,
+    cols: usize,
+    rows: usize,
+    state: State,
+    sub: Vec<u8>,
+    local: [Opt; 256],
+    remote: [Opt; 256],
+    output: Vec<u8>,
+}
+
+impl Telnet {
+    pub fn new(term: &str, cols: usize, rows: usize) -> Self {
+        let mut telnet = Self {
+            term: term.to_owned(),
+            cols,
+            rows,
+            state: State::Data,
+            sub: Vec::new(),
+            local: [Opt::default(); 256],
+            remote: [Opt::default(); 256],
+            output: Vec::new(),
+        };
+        // servers wait for the client to offer what it supports
+        telnet.request(WILL, OPT_TTYPE);
+        telnet.request(WILL, OPT_NAWS);
+        telnet.request(DO, OPT_SGA);
+        telnet
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Process data from the server, returns the bytes for the terminal
+    pub fn feed(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut screen = Vec::with_capacity(data.len());
+        for &b in data {
+            self.state = match (self.state, b) {
+                (State::Data | State::Cr, IAC) => State::Iac,
+                // CR NUL is a bare carriage return
+                (State::Cr, 0) => State::Data,
+                (State::Data | State::Cr, b'\r') if !self.remote[OPT_BINARY as usize].enabled => {
+                    screen.push(b);
+                    State::Cr
+                }
+                (State::Data | State::Cr, _) => {
+                    screen.push(b);
+                    State::Data
+                }
+                (State::Iac, IAC) => {
+                    screen.push(IAC);
+                    State::Data
+                }
+                (State::Iac, WILL | WONT | DO | DONT) => State::Command(b),
+                (State::Iac, SB) => {
+                    self.sub.clear();
+                    State::Sub
+                }
+                // GA, NOP and the like mean nothing to a terminal
+                (State::Iac, _) => State::Data,
+                (State::Command(command), option) => {
+                    self.negotiate(command, option);
+                    State::Data
+                }
+                (State::Sub, IAC) => State::SubIac,
+                (State::Sub, _) => {
+                    if self.sub.len() < MAX_SUB {
+                        self.sub.push(b);
+                    }
+                    State::Sub
+                }
+                (State::SubIac, SE) => {
+                    self.subnegotiation();
+                    State::Data
+                }
+                (State::SubIac, IAC) => {
+                    if self.sub.len() < MAX_SUB {
+                        self.sub.push(IAC);
+                    }
+                    State::Sub
+                }
+                // malformed, give up on this subnegotiation
+                (State::SubIac, _) => State::Data,
+            };
+        }
+        screen
+    }
+
+    /// Queue the user's input for the server
+    pub fn send(&mut self, data: &[u8]) {
+        let binary = self.local[OPT_BINARY as usize].enabled;
+        for &b in data {
+            match b {
+                IAC => self.output.extend_from_slice(&[IAC, IAC]),
+                // the enter key sends the NVT end of line
+                b'\r' if !binary => self.output.extend_from_slice(b"\r\n"),
+                _ => self.output.push(b),
+            }
+        }
+    }
+
+    pub fn resize(&mut self, cols: usize, rows: usize) {
+        self.cols = cols;
+        self.rows = rows;
+        if self.local[OPT_NAWS as usize].enabled {
+            self.send_window_size();
+        }
+    }
+
+    fn request(&mut self, command: u8, option: u8) {
+        let opt = match command {
+            WILL | WONT => &mut self.local[option as usize],
+            _ => &mut self.remote[option as usize],
+        };
+        opt.pending = true;
+        self.output.extend_from_slice(&[IAC, command, option]);
+    }
+
+    fn negotiate(&mut self, command: u8, option: u8) {
+        debug!("telnet: received {} {}", command, option);
+        let (opt, supported, accept, refuse) = match command {
+            DO | DONT => (
+                &mut self.local[option as usize],
+                LOCAL_OPTIONS.contains(&option),
+                WILL,
+                WONT,
+            ),
+            _ => (
+                &mut self.remote[option as usize],
+                REMOTE_OPTIONS.contains(&option),
+                DO,
+                DONT,
+            ),
+        };
+        let enable = matches!(command, DO | WILL);
+        let pending = std::mem::take(&mut opt.pending);
+        let reply = if enable && !supported {
+            Some(refuse)
+        } else if enable == opt.enabled {
+            // already in the asked state, answering would loop
+            None
+        } else {
+            opt.enabled = enable;
+            match (pending, enable) {
+                (true, _) => None,
+                (false, true) => Some(accept),
+                (false, false) => Some(refuse),
+            }
+        };
+        if let Some(reply) = reply {
+            self.output.extend_from_slice(&[IAC, reply, option]);
+        }
+        if command == DO && option == OPT_NAWS && self.local[OPT_NAWS as usize].enabled {
+            self.send_window_size();
+        }
+    }
+
+    fn subnegotiation(&mut self) {
+        if self.sub[..] == [OPT_TTYPE, TTYPE_SEND] && self.local[OPT_TTYPE as usize].enabled {
+            self.output
+                .extend_from_slice(&[IAC, SB, OPT_TTYPE, TTYPE_IS]);
+            self.output.extend_from_slice(self.term.as_bytes());
+            self.output.extend_from_slice(&[IAC, SE]);
+        }
+    }
+
+    fn send_window_size(&mut self) {
+        self.output.extend_from_slice(&[IAC, SB, OPT_NAWS]);
+        let size = [
+            self.cols.min(u16::MAX as usize) as u16,
+            self.rows.min(u16::MAX as usize) as u16,
+        ];
+        for b in size.iter().flat_map(|n| n.to_be_bytes()) {
+            // a size byte of 255 must be doubled too
+            if b == IAC {
+                self.output.push(IAC);
+            }
+            self.output.push(b);
+        }
+        self.output.extend_from_slice(&[IAC, SE]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_negotiation() {
+        let mut telnet = Telnet::new("xterm-256color", 80, 24);
+        assert_eq!(
+            telnet.take_output(),
+            [IAC, WILL, OPT_TTYPE, IAC, WILL, OPT_NAWS, IAC, DO, OPT_SGA]
+        );
+
+        // answers to our requests are not acknowledged again
+        let screen = telnet.feed(&[
+            b'h', IAC, DO, OPT_TTYPE, IAC, DO, OPT_NAWS, IAC, WILL, OPT_SGA, b'i',
+        ]);
+        assert_eq!(screen, b"hi");
+        assert_eq!(
+            telnet.take_output(),
+            [IAC, SB, OPT_NAWS, 0, 80, 0, 24, IAC, SE]
+        );
+
+        telnet.feed(&[IAC, SB, OPT_TTYPE, TTYPE_SEND, IAC, SE]);
+        let mut expected = vec![IAC, SB, OPT_TTYPE, TTYPE_IS];
+        expected.extend_from_slice(b"xterm-256color");
+        expected.extend_from_slice(&[IAC, SE]);
+        assert_eq!(telnet.take_output(), expected);
+
+        // new options are accepted or refused
+        telnet.feed(&[IAC, WILL, OPT_ECHO, IAC, DO, 42, IAC, WILL, OPT_ECHO]);
+        assert_eq!(telnet.take_output(), [IAC, DO, OPT_ECHO, IAC, WONT, 42]);
+        assert!(telnet.remote[OPT_ECHO as usize].enabled);
+        telnet.feed(&[IAC, WONT, OPT_ECHO]);
+        assert_eq!(telnet.take_output(), [IAC, DONT, OPT_ECHO]);
+        assert!(!telnet.remote[OPT_ECHO as usize].enabled);
+
+        telnet.resize(255, 30);
+        assert_eq!(
+            telnet.take_output(),
+            [IAC, SB, OPT_NAWS, 0, IAC, IAC, 0, 30, IAC, SE]
+        );
+    }
+
+    #[test]
+    fn test_data() {
+        let mut telnet = Telnet::new("xterm", 80, 24);
+        telnet.take_output();
+
+        // escaped IAC and commands split across chunks
+        assert_eq!(telnet.feed(&[b'a', IAC]), b"a");
+        assert_eq!(
+            telnet.feed(&[IAC, b'b', IAC, 241, b'\r', 0]),
+            [IAC, b'b', b'\r']
+        );
+        assert_eq!(telnet.feed(b"\r\n"), b"\r\n");
+
+        telnet.send(&[b'l', b's', b'\r', IAC]);
+        assert_eq!(telnet.take_output(), [b'l', b's', b'\r', b'\n', IAC, IAC]);
+
+        // binary mode passes CR through both ways
+        telnet.feed(&[IAC, DO, OPT_BINARY, IAC, WILL, OPT_BINARY]);
+        assert_eq!(
+            telnet.take_output(),
+            [IAC, WILL, OPT_BINARY, IAC, DO, OPT_BINARY]
+        );
+        assert_eq!(telnet.feed(&[b'\r', 0]), [b'\r', 0]);
+        telnet.send(b"\r");
+        assert_eq!(telnet.take_output(), b"\r");
+    }
+}
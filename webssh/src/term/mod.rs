@@ -0,0 +1,88 @@
+mod parser;
+mod screen;
+
+pub use parser::Parser;
+pub use screen::{Attr, Color, Screen};
+
+/// A terminal emulator, feed it the bytes from the host and draw its screen
+pub struct Terminal {
+    parser: Parser,
+    screen: Screen,
+}
+
+impl Terminal {
+    pub fn new(cols: usize, rows: usize) -> Self {
+        Self {
+            parser: Parser::new(),
+            screen: Screen::new(cols, rows),
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.parser.advance(&mut self.screen, data);
+    }
+
+    pub fn resize(&mut self, cols: usize, rows: usize) {
+        self.screen.resize(cols, rows);
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    pub fn screen_mut(&mut self) -> &mut Screen {
+        &mut self.screen
+    }
+}
+
+const ANSI_COLORS: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00),
+    (0xcd, 0x00, 0x00),
+    (0x00, 0xcd, 0x00),
+    (0xcd, 0xcd, 0x00),
+    (0x00, 0x00, 0xee),
+    (0xcd, 0x00, 0xcd),
+    (0x00, 0xcd, 0xcd),
+    (0xe5, 0xe5, 0xe5),
+    (0x7f, 0x7f, 0x7f),
+    (0xff, 0x00, 0x00),
+    (0x00, 0xff, 0x00),
+    (0xff, 0xff, 0x00),
+    (0x5c, 0x5c, 0xff),
+    (0xff, 0x00, 0xff),
+    (0x00, 0xff, 0xff),
+    (0xff, 0xff, 0xff),
+];
+
+/// The xterm 256 colour palette
+pub fn palette(idx: u8) -> (u8, u8, u8) {
+    match idx {
+        0..=15 => ANSI_COLORS[idx as usize],
+        // 6x6x6 colour cube
+        16..=231 => {
+            let level = |v: u8| if v == 0 { 0 } else { v * 40 + 55 };
+            let i = idx - 16;
+            (level(i / 36), level(i / 6 % 6), level(i % 6))
+        }
+        // grayscale ramp
+        232..=255 => {
+            let v = (idx - 232) * 10 + 8;
+            (v, v, v)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_palette() {
+        assert_eq!(palette(1), (0xcd, 0x00, 0x00));
+        assert_eq!(palette(16), (0, 0, 0));
+        assert_eq!(palette(196), (0xff, 0, 0));
+        assert_eq!(palette(231), (0xff, 0xff, 0xff));
+        assert_eq!(palette(232), (8, 8, 8));
+        assert_eq!(palette(255), (0xee, 0xee, 0xee));
+    }
+}
@@ -0,0 +1,347 @@
+// Escape sequence parser for VT100/xterm terminals
+// Based on the DEC ANSI parser state machine
+// https://vt100.net/emu/dec_ansi_parser
+
+const MAX_PARAMS: usize = 32;
+const MAX_INTERMEDIATES: usize = 2;
+const MAX_OSC_LEN: usize = 4096;
+
+/// Receiver of the actions produced by the parser
+pub trait Perform {
+    /// Draw a printable character
+    fn print(&mut self, c: char);
+
+    /// Execute a C0/C1 control function
+    fn execute(&mut self, byte: u8);
+
+    /// A complete control sequence `CSI <intermediates> <params> <action>`
+    ///
+    /// Private markers such as `?` and `>` are reported as intermediates
+    fn csi_dispatch(&mut self, params: &[u16], intermediates: &[u8], action: char);
+
+    /// A complete escape sequence `ESC <intermediates> <byte>`
+    fn esc_dispatch(&mut self, intermediates: &[u8], byte: u8);
+
+    /// An operating system command, split on `;`
+    fn osc_dispatch(&mut self, params: &[&[u8]]);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    EscapeIntermediate,
+    CsiEntry,
+    CsiParam,
+    CsiIntermediate,
+    CsiIgnore,
+    OscString,
+    // DCS, SOS, PM and APC strings are swallowed until ST
+    StringIgnore,
+}
+
+pub struct Parser {
+    state: State,
+    params: Vec<u16>,
+    param: u32,
+    param_started: bool,
+    intermediates: Vec<u8>,
+    ignoring: bool,
+    osc: Vec<u8>,
+    utf8: [u8; 4],
+    utf8_len: usize,
+    utf8_need: usize,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self {
+            state: State::Ground,
+            params: Vec::with_capacity(MAX_PARAMS),
+            param: 0,
+            param_started: false,
+            intermediates: Vec::with_capacity(MAX_INTERMEDIATES),
+            ignoring: false,
+            osc: Vec::new(),
+            utf8: [0; 4],
+            utf8_len: 0,
+            utf8_need: 0,
+        }
+    }
+
+    pub fn advance<P: Perform>(&mut self, performer: &mut P, bytes: &[u8]) {
+        for &byte in bytes {
+            self.advance_byte(performer, byte);
+        }
+    }
+
+    fn advance_byte<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+        // a multibyte UTF-8 sequence is in progress
+        if self.utf8_need > 0 {
+            if byte & 0xc0 == 0x80 {
+                self.utf8[self.utf8_len] = byte;
+                self.utf8_len += 1;
+                if self.utf8_len == self.utf8_need {
+                    let c = std::str::from_utf8(&self.utf8[..self.utf8_len])
+                        .ok()
+                        .and_then(|s| s.chars().next())
+                        .unwrap_or(char::REPLACEMENT_CHARACTER);
+                    self.utf8_need = 0;
+                    performer.print(c);
+                }
+                return;
+            }
+            // truncated sequence, reprocess this byte from scratch
+            self.utf8_need = 0;
+            performer.print(char::REPLACEMENT_CHARACTER);
+        }
+
+        // transitions from anywhere
+        match byte {
+            0x18 | 0x1a => {
+                performer.execute(byte);
+                self.state = State::Ground;
+                return;
+            }
+            0x1b => {
+                if self.state == State::OscString {
+                    self.osc_end(performer);
+                }
+                self.enter_escape();
+                return;
+            }
+            _ => {}
+        }
+
+        match self.state {
+            State::Ground => match byte {
+                0x00..=0x1f => performer.execute(byte),
+                0x20..=0x7e => performer.print(byte as char),
+                0x7f => {}
+                _ => self.utf8_start(performer, byte),
+            },
+            State::Escape => match byte {
+                0x00..=0x1f => performer.execute(byte),
+                0x20..=0x2f => {
+                    self.collect(byte);
+                    self.state = State::EscapeIntermediate;
+                }
+                b'[' => self.enter_csi(),
+                b']' => {
+                    self.osc.clear();
+                    self.state = State::OscString;
+                }
+                b'P' | b'X' | b'^' | b'_' => self.state = State::StringIgnore,
+                0x30..=0x7e => {
+                    performer.esc_dispatch(&self.intermediates, byte);
+                    self.state = State::Ground;
+                }
+                _ => {}
+            },
+            State::EscapeIntermediate => match byte {
+                0x00..=0x1f => performer.execute(byte),
+                0x20..=0x2f => self.collect(byte),
+                0x30..=0x7e => {
+                    performer.esc_dispatch(&self.intermediates, byte);
+                    self.state = State::Ground;
+                }
+                _ => {}
+            },
+            State::CsiEntry | State::CsiParam => match byte {
+                0x00..=0x1f => performer.execute(byte),
+                b'0'..=b'9' => {
+                    self.param = (self.param * 10 + (byte - b'0') as u32).min(u16::MAX as u32);
+                    self.param_started = true;
+                    self.state = State::CsiParam;
+                }
+                // sub-parameters are flattened into the parameter list
+                b';' | b':' => {
+                    self.push_param();
+                    self.state = State::CsiParam;
+                }
+                b'<'..=b'?' => {
+                    if self.state == State::CsiEntry {
+                        self.collect(byte);
+                        self.state = State::CsiParam;
+                    } else {
+                        self.state = State::CsiIgnore;
+                    }
+                }
+                0x20..=0x2f => {
+                    self.collect(byte);
+                    self.state = State::CsiIntermediate;
+                }
+                0x40..=0x7e => self.csi_end(performer, byte),
+                _ => {}
+            },
+            State::CsiIntermediate => match byte {
+                0x00..=0x1f => performer.execute(byte),
+                0x20..=0x2f => self.collect(byte),
+                0x30..=0x3f => self.state = State::CsiIgnore,
+                0x40..=0x7e => self.csi_end(performer, byte),
+                _ => {}
+            },
+            State::CsiIgnore => match byte {
+                0x00..=0x1f => performer.execute(byte),
+                0x40..=0x7e => self.state = State::Ground,
+                _ => {}
+            },
+            State::OscString => match byte {
+                0x07 => {
+                    self.osc_end(performer);
+                    self.state = State::Ground;
+                }
+                0x00..=0x1f => {}
+                _ => {
+                    if self.osc.len() < MAX_OSC_LEN {
+                        self.osc.push(byte);
+                    }
+                }
+            },
+            State::StringIgnore => {}
+        }
+    }
+
+    fn utf8_start<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+        let need = match byte {
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => {
+                performer.print(char::REPLACEMENT_CHARACTER);
+                return;
+            }
+        };
+        self.utf8[0] = byte;
+        self.utf8_len = 1;
+        self.utf8_need = need;
+    }
+
+    fn enter_escape(&mut self) {
+        self.intermediates.clear();
+        self.state = State::Escape;
+    }
+
+    fn enter_csi(&mut self) {
+        self.params.clear();
+        self.param = 0;
+        self.param_started = false;
+        self.ignoring = false;
+        self.intermediates.clear();
+        self.state = State::CsiEntry;
+    }
+
+    fn collect(&mut self, byte: u8) {
+        if self.intermediates.len() < MAX_INTERMEDIATES {
+            self.intermediates.push(byte);
+        } else {
+            self.ignoring = true;
+        }
+    }
+
+    fn push_param(&mut self) {
+        if self.params.len() < MAX_PARAMS {
+            self.params.push(self.param as u16);
+        } else {
+            self.ignoring = true;
+        }
+        self.param = 0;
+        self.param_started = false;
+    }
+
+    fn csi_end<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+        if self.param_started || !self.params.is_empty() {
+            self.push_param();
+        }
+        if !self.ignoring {
+            performer.csi_dispatch(&self.params, &self.intermediates, byte as char);
+        }
+        self.state = State::Ground;
+    }
+
+    fn osc_end<P: Perform>(&mut self, performer: &mut P) {
+        let params: Vec<&[u8]> = self.osc.split(|b| *b == b';').collect();
+        performer.osc_dispatch(&params);
+        self.osc.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Default)]
+    struct Log(Vec<String>);
+
+    impl Perform for Log {
+        fn print(&mut self, c: char) {
+            self.0.push(format!("print {}", c));
+        }
+
+        fn execute(&mut self, byte: u8) {
+            self.0.push(format!("execute {:02x}", byte));
+        }
+
+        fn csi_dispatch(&mut self, params: &[u16], intermediates: &[u8], action: char) {
+            self.0.push(format!(
+                "csi {:?} {} {}",
+                params,
+                String::from_utf8_lossy(intermediates),
+                action
+            ));
+        }
+
+        fn esc_dispatch(&mut self, intermediates: &[u8], byte: u8) {
+            self.0.push(format!(
+                "esc {} {}",
+                String::from_utf8_lossy(intermediates),
+                byte as char
+            ));
+        }
+
+        fn osc_dispatch(&mut self, params: &[&[u8]]) {
+            let params: Vec<_> = params.iter().map(|p| String::from_utf8_lossy(p)).collect();
+            self.0.push(format!("osc {}", params.join("|")));
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> Vec<String> {
+        let mut log = Log::default();
+        Parser::new().advance(&mut log, bytes);
+        log.0
+    }
+
+    #[test]
+    fn test_csi() {
+        assert_eq!(parse(b"\x1b[1;31m"), vec!["csi [1, 31]  m"]);
+        assert_eq!(parse(b"\x1b[m"), vec!["csi []  m"]);
+        assert_eq!(parse(b"\x1b[;5H"), vec!["csi [0, 5]  H"]);
+        assert_eq!(parse(b"\x1b[?1049h"), vec!["csi [1049] ? h"]);
+        assert_eq!(parse(b"\x1b[38:2:1:2:3m"), vec!["csi [38, 2, 1, 2, 3]  m"]);
+        // C0 controls are executed in the middle of a sequence
+        assert_eq!(parse(b"\x1b[2\nJ"), vec!["execute 0a", "csi [2]  J"]);
+        // a misplaced private marker invalidates the sequence
+        assert_eq!(parse(b"\x1b[1?hA"), vec!["print A"]);
+    }
+
+    #[test]
+    fn test_esc_and_osc() {
+        assert_eq!(parse(b"\x1b(0"), vec!["esc ( 0"]);
+        assert_eq!(parse(b"\x1b]0;title\x07"), vec!["osc 0|title"]);
+        assert_eq!(parse(b"\x1b]2;a;b\x1b\\"), vec!["osc 2|a|b", "esc  \\"]);
+        assert_eq!(parse(b"\x1bPq#0\x1b\\x"), vec!["esc  \\", "print x"]);
+    }
+
+    #[test]
+    fn test_utf8() {
+        assert_eq!(parse("é€".as_bytes()), vec!["print é", "print €"]);
+        assert_eq!(parse(b"\xe2\x82a"), vec!["print \u{fffd}", "print a"]);
+        assert_eq!(parse(b"\xff"), vec!["print \u{fffd}"]);
+    }
+}
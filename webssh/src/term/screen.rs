@@ -0,0 +1,1088 @@
+// Screen model of an xterm compatible terminal
+// https://invisible-island.net/xterm/ctlseqs/ctlseqs.html
+
+use super::parser::Perform;
+use std::collections::VecDeque;
+use unicode_width::UnicodeWidthChar;
+
+// lines kept after scrolling off the top of the primary screen
+const SCROLLBACK: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Color {
+    #[default]
+    Default,
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Attr {
+    pub fg: Color,
+    pub bg: Color,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub blink: bool,
+    pub inverse: bool,
+    pub hidden: bool,
+    pub strike: bool,
+}
+
+/// The content of a single character cell
+///
+/// A wide character occupies its own cell plus a following spacer cell
+/// holding `'\0'`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub c: char,
+    pub attr: Attr,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            c: ' ',
+            attr: Attr::default(),
+        }
+    }
+}
+
+impl Cell {
+    pub fn is_spacer(&self) -> bool {
+        self.c == '\0'
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Charset {
+    Ascii,
+    DecSpecial,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SavedCursor {
+    x: usize,
+    y: usize,
+    attr: Attr,
+    origin: bool,
+    charsets: [Charset; 2],
+    gl: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Modes {
+    /// DECCKM, cursor keys send SS3 sequences
+    pub app_cursor: bool,
+    /// DECKPAM, keypad sends application sequences
+    pub app_keypad: bool,
+    /// DECAWM
+    pub autowrap: bool,
+    /// DECOM, cursor addressing relative to the scroll region
+    pub origin: bool,
+    /// IRM
+    pub insert: bool,
+    /// DECTCEM
+    pub cursor_visible: bool,
+    pub bracketed_paste: bool,
+}
+
+impl Default for Modes {
+    fn default() -> Self {
+        Self {
+            app_cursor: false,
+            app_keypad: false,
+            autowrap: true,
+            origin: false,
+            insert: false,
+            cursor_visible: true,
+            bracketed_paste: false,
+        }
+    }
+}
+
+pub struct Screen {
+    cols: usize,
+    rows: usize,
+    grid: Vec<Vec<Cell>>,
+    // the primary screen while the alternate one is shown
+    primary: Option<Vec<Vec<Cell>>>,
+    scrollback: VecDeque<Vec<Cell>>,
+    // how many lines the view is scrolled back
+    view: usize,
+    x: usize,
+    y: usize,
+    // the cursor sits past the last column until the next printable character
+    wrap_pending: bool,
+    attr: Attr,
+    saved: Option<SavedCursor>,
+    scroll_top: usize,
+    scroll_bottom: usize,
+    tabs: Vec<bool>,
+    charsets: [Charset; 2],
+    gl: usize,
+    last_char: Option<char>,
+    modes: Modes,
+    dirty: Vec<bool>,
+    title: Option<String>,
+    response: Vec<u8>,
+}
+
+impl Screen {
+    pub fn new(cols: usize, rows: usize) -> Self {
+        let cols = cols.max(1);
+        let rows = rows.max(1);
+        Self {
+            cols,
+            rows,
+            grid: vec![vec![Cell::default(); cols]; rows],
+            primary: None,
+            scrollback: VecDeque::new(),
+            view: 0,
+            x: 0,
+            y: 0,
+            wrap_pending: false,
+            attr: Attr::default(),
+            saved: None,
+            scroll_top: 0,
+            scroll_bottom: rows - 1,
+            tabs: (0..cols).map(|i| i % 8 == 0).collect(),
+            charsets: [Charset::Ascii; 2],
+            gl: 0,
+            last_char: None,
+            modes: Modes::default(),
+            dirty: vec![true; rows],
+            title: None,
+            response: Vec::new(),
+        }
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    #[allow(dead_code)]
+    pub fn cell(&self, x: usize, y: usize) -> &Cell {
+        &self.grid[y][x]
+    }
+
+    #[allow(dead_code)]
+    pub fn row(&self, y: usize) -> &[Cell] {
+        &self.grid[y]
+    }
+
+    /// A row as currently shown, taking the scrolled back view into account
+    ///
+    /// Lines from the scrollback keep the width they were printed with
+    pub fn view_row(&self, y: usize) -> &[Cell] {
+        if y < self.view {
+            &self.scrollback[self.scrollback.len() - self.view + y]
+        } else {
+            &self.grid[y - self.view]
+        }
+    }
+
+    pub fn view_offset(&self) -> usize {
+        self.view
+    }
+
+    /// Scroll the view back in history by `lines`, forward if negative
+    pub fn scroll_view(&mut self, lines: isize) {
+        let view = self
+            .view
+            .saturating_add_signed(lines)
+            .min(self.scrollback.len());
+        if view != self.view {
+            self.view = view;
+            self.invalidate();
+        }
+    }
+
+    /// Mark every row for redrawing
+    pub fn invalidate(&mut self) {
+        self.dirty.iter_mut().for_each(|d| *d = true);
+    }
+
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.x, self.y)
+    }
+
+    pub fn modes(&self) -> &Modes {
+        &self.modes
+    }
+
+    #[allow(dead_code)]
+    pub fn is_alternate(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    pub fn is_dirty(&self, y: usize) -> bool {
+        self.dirty[y]
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.dirty.iter_mut().for_each(|d| *d = false);
+    }
+
+    /// Bytes the terminal wants to send back to the host
+    /// e.g. replies to device status reports
+    pub fn take_response(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.response)
+    }
+
+    /// Change the size of the screen, the line with the cursor is kept visible
+    pub fn resize(&mut self, cols: usize, rows: usize) {
+        let cols = cols.max(1);
+        let rows = rows.max(1);
+        if cols == self.cols && rows == self.rows {
+            return;
+        }
+
+        // drop lines from the top when the cursor would fall off the screen
+        let shift = (self.y + 1).saturating_sub(rows);
+        let fit = |grid: &mut Vec<Vec<Cell>>, shift: usize| {
+            grid.drain(..shift);
+            grid.resize(rows, vec![Cell::default(); cols]);
+            for line in grid.iter_mut() {
+                line.resize(cols, Cell::default());
+                // a wide character cut in half by the new width
+                if line[cols - 1].c.width() == Some(2) {
+                    line[cols - 1] = Cell::default();
+                }
+                if line[0].is_spacer() {
+                    line[0] = Cell::default();
+                }
+            }
+        };
+        if self.primary.is_none() {
+            self.scrollback.extend(self.grid[..shift].iter().cloned());
+            self.trim_scrollback();
+        }
+        fit(&mut self.grid, shift);
+        if let Some(primary) = self.primary.as_mut() {
+            fit(primary, 0);
+        }
+
+        self.tabs = (0..cols)
+            .map(|i| self.tabs.get(i).copied().unwrap_or(i % 8 == 0))
+            .collect();
+        self.cols = cols;
+        self.rows = rows;
+        self.x = self.x.min(cols - 1);
+        self.y -= shift;
+        self.wrap_pending = false;
+        if let Some(saved) = self.saved.as_mut() {
+            saved.x = saved.x.min(cols - 1);
+            saved.y = saved.y.saturating_sub(shift).min(rows - 1);
+        }
+        self.scroll_top = 0;
+        self.scroll_bottom = rows - 1;
+        self.view = 0;
+        self.dirty = vec![true; rows];
+    }
+
+    fn trim_scrollback(&mut self) {
+        while self.scrollback.len() > SCROLLBACK {
+            self.scrollback.pop_front();
+        }
+        self.view = self.view.min(self.scrollback.len());
+    }
+
+    fn blank(&self) -> Cell {
+        // erased cells take the current background colour (BCE)
+        Cell {
+            c: ' ',
+            attr: Attr {
+                bg: self.attr.bg,
+                ..Attr::default()
+            },
+        }
+    }
+
+    fn mark_dirty(&mut self, top: usize, bottom: usize) {
+        for d in &mut self.dirty[top..=bottom] {
+            *d = true;
+        }
+    }
+
+    fn goto(&mut self, x: usize, y: usize) {
+        let (top, bottom) = if self.modes.origin {
+            (self.scroll_top, self.scroll_bottom)
+        } else {
+            (0, self.rows - 1)
+        };
+        self.x = x.min(self.cols - 1);
+        self.y = (y + top).min(bottom);
+        self.wrap_pending = false;
+    }
+
+    fn goto_row(&mut self, y: usize) {
+        let x = self.x;
+        self.goto(x, y);
+    }
+
+    fn goto_col(&mut self, x: usize) {
+        self.x = x.min(self.cols - 1);
+        self.wrap_pending = false;
+    }
+
+    fn cursor_up(&mut self, n: usize) {
+        let top = if self.y >= self.scroll_top {
+            self.scroll_top
+        } else {
+            0
+        };
+        self.y = self.y.saturating_sub(n).max(top);
+        self.wrap_pending = false;
+    }
+
+    fn cursor_down(&mut self, n: usize) {
+        let bottom = if self.y <= self.scroll_bottom {
+            self.scroll_bottom
+        } else {
+            self.rows - 1
+        };
+        self.y = (self.y + n).min(bottom);
+        self.wrap_pending = false;
+    }
+
+    fn scroll_up(&mut self, n: usize) {
+        let n = n.min(self.scroll_bottom - self.scroll_top + 1);
+        let blank = self.blank();
+        // only lines leaving the whole primary screen go to the scrollback
+        let keep = self.scroll_top == 0 && self.primary.is_none();
+        for _ in 0..n {
+            let line = self.grid.remove(self.scroll_top);
+            self.grid.insert(self.scroll_bottom, vec![blank; self.cols]);
+            if keep {
+                self.scrollback.push_back(line);
+                // a scrolled back view stays on the same lines
+                if self.view > 0 {
+                    self.view += 1;
+                }
+            }
+        }
+        if keep {
+            self.trim_scrollback();
+        }
+        if self.view > 0 {
+            self.invalidate();
+        } else {
+            self.mark_dirty(self.scroll_top, self.scroll_bottom);
+        }
+    }
+
+    fn scroll_down(&mut self, n: usize) {
+        let n = n.min(self.scroll_bottom - self.scroll_top + 1);
+        let blank = self.blank();
+        for _ in 0..n {
+            self.grid.remove(self.scroll_bottom);
+            self.grid.insert(self.scroll_top, vec![blank; self.cols]);
+        }
+        self.mark_dirty(self.scroll_top, self.scroll_bottom);
+    }
+
+    fn linefeed(&mut self) {
+        if self.y == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.y < self.rows - 1 {
+            self.y += 1;
+        }
+        self.wrap_pending = false;
+    }
+
+    fn reverse_index(&mut self) {
+        if self.y == self.scroll_top {
+            self.scroll_down(1);
+        } else if self.y > 0 {
+            self.y -= 1;
+        }
+        self.wrap_pending = false;
+    }
+
+    fn insert_lines(&mut self, n: usize) {
+        if self.y < self.scroll_top || self.y > self.scroll_bottom {
+            return;
+        }
+        let top = self.scroll_top;
+        self.scroll_top = self.y;
+        self.scroll_down(n);
+        self.scroll_top = top;
+        self.x = 0;
+        self.wrap_pending = false;
+    }
+
+    fn delete_lines(&mut self, n: usize) {
+        if self.y < self.scroll_top || self.y > self.scroll_bottom {
+            return;
+        }
+        let top = self.scroll_top;
+        self.scroll_top = self.y;
+        self.scroll_up(n);
+        self.scroll_top = top;
+        self.x = 0;
+        self.wrap_pending = false;
+    }
+
+    fn erase_cells(&mut self, y: usize, from: usize, to: usize) {
+        let blank = self.blank();
+        for cell in &mut self.grid[y][from..to] {
+            *cell = blank;
+        }
+        self.dirty[y] = true;
+    }
+
+    fn insert_chars(&mut self, n: usize) {
+        let blank = self.blank();
+        let row = &mut self.grid[self.y];
+        let n = n.min(self.cols - self.x);
+        row.truncate(self.cols - n);
+        for _ in 0..n {
+            row.insert(self.x, blank);
+        }
+        self.dirty[self.y] = true;
+        self.wrap_pending = false;
+    }
+
+    fn delete_chars(&mut self, n: usize) {
+        let blank = self.blank();
+        let row = &mut self.grid[self.y];
+        let n = n.min(self.cols - self.x);
+        row.drain(self.x..self.x + n);
+        row.resize(self.cols, blank);
+        self.dirty[self.y] = true;
+        self.wrap_pending = false;
+    }
+
+    fn erase_display(&mut self, mode: u16) {
+        match mode {
+            0 => {
+                self.erase_cells(self.y, self.x, self.cols);
+                for y in self.y + 1..self.rows {
+                    self.erase_cells(y, 0, self.cols);
+                }
+            }
+            1 => {
+                for y in 0..self.y {
+                    self.erase_cells(y, 0, self.cols);
+                }
+                self.erase_cells(self.y, 0, self.x + 1);
+            }
+            2 | 3 => {
+                for y in 0..self.rows {
+                    self.erase_cells(y, 0, self.cols);
+                }
+            }
+            _ => {}
+        }
+        self.wrap_pending = false;
+    }
+
+    fn erase_line(&mut self, mode: u16) {
+        match mode {
+            0 => self.erase_cells(self.y, self.x, self.cols),
+            1 => self.erase_cells(self.y, 0, self.x + 1),
+            2 => self.erase_cells(self.y, 0, self.cols),
+            _ => {}
+        }
+        self.wrap_pending = false;
+    }
+
+    fn tab_forward(&mut self, n: usize) {
+        for _ in 0..n {
+            let next = (self.x + 1..self.cols).find(|&x| self.tabs[x]);
+            self.x = next.unwrap_or(self.cols - 1);
+        }
+        self.wrap_pending = false;
+    }
+
+    fn tab_backward(&mut self, n: usize) {
+        for _ in 0..n {
+            let prev = (0..self.x).rev().find(|&x| self.tabs[x]);
+            self.x = prev.unwrap_or(0);
+        }
+        self.wrap_pending = false;
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = Some(SavedCursor {
+            x: self.x,
+            y: self.y,
+            attr: self.attr,
+            origin: self.modes.origin,
+            charsets: self.charsets,
+            gl: self.gl,
+        });
+    }
+
+    fn restore_cursor(&mut self) {
+        let saved = self.saved.unwrap_or(SavedCursor {
+            x: 0,
+            y: 0,
+            attr: Attr::default(),
+            origin: false,
+            charsets: [Charset::Ascii; 2],
+            gl: 0,
+        });
+        self.x = saved.x.min(self.cols - 1);
+        self.y = saved.y.min(self.rows - 1);
+        self.attr = saved.attr;
+        self.modes.origin = saved.origin;
+        self.charsets = saved.charsets;
+        self.gl = saved.gl;
+        self.wrap_pending = false;
+    }
+
+    fn enter_alternate(&mut self) {
+        if self.primary.is_none() {
+            let blank = vec![vec![Cell::default(); self.cols]; self.rows];
+            self.primary = Some(std::mem::replace(&mut self.grid, blank));
+            self.mark_dirty(0, self.rows - 1);
+        }
+    }
+
+    fn leave_alternate(&mut self) {
+        if let Some(primary) = self.primary.take() {
+            self.grid = primary;
+            self.mark_dirty(0, self.rows - 1);
+        }
+    }
+
+    fn reset(&mut self) {
+        let title = self.title.take();
+        *self = Self::new(self.cols, self.rows);
+        self.title = title;
+    }
+
+    fn set_private_mode(&mut self, mode: u16, on: bool) {
+        match mode {
+            1 => self.modes.app_cursor = on,
+            6 => {
+                self.modes.origin = on;
+                self.goto(0, 0);
+            }
+            7 => self.modes.autowrap = on,
+            25 => self.modes.cursor_visible = on,
+            47 | 1047 => {
+                if on {
+                    self.enter_alternate();
+                } else {
+                    self.leave_alternate();
+                }
+            }
+            1048 => {
+                if on {
+                    self.save_cursor();
+                } else {
+                    self.restore_cursor();
+                }
+            }
+            1049 => {
+                if on {
+                    self.save_cursor();
+                    self.enter_alternate();
+                    self.erase_display(2);
+                } else {
+                    self.leave_alternate();
+                    self.restore_cursor();
+                }
+            }
+            2004 => self.modes.bracketed_paste = on,
+            _ => {}
+        }
+    }
+
+    fn set_mode(&mut self, mode: u16, on: bool) {
+        if mode == 4 {
+            self.modes.insert = on;
+        }
+    }
+
+    fn set_scroll_region(&mut self, top: usize, bottom: usize) {
+        let bottom = if bottom == 0 { self.rows } else { bottom };
+        let top = top.max(1);
+        if top < bottom && bottom <= self.rows {
+            self.scroll_top = top - 1;
+            self.scroll_bottom = bottom - 1;
+            self.goto(0, 0);
+        }
+    }
+
+    fn sgr(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.attr = Attr::default();
+            return;
+        }
+        let mut iter = params.iter().copied();
+        while let Some(p) = iter.next() {
+            match p {
+                0 => self.attr = Attr::default(),
+                1 => self.attr.bold = true,
+                2 => self.attr.dim = true,
+                3 => self.attr.italic = true,
+                4 => self.attr.underline = true,
+                5 | 6 => self.attr.blink = true,
+                7 => self.attr.inverse = true,
+                8 => self.attr.hidden = true,
+                9 => self.attr.strike = true,
+                21 | 22 => {
+                    self.attr.bold = false;
+                    self.attr.dim = false;
+                }
+                23 => self.attr.italic = false,
+                24 => self.attr.underline = false,
+                25 => self.attr.blink = false,
+                27 => self.attr.inverse = false,
+                28 => self.attr.hidden = false,
+                29 => self.attr.strike = false,
+                30..=37 => self.attr.fg = Color::Indexed((p - 30) as u8),
+                38 => {
+                    if let Some(c) = Self::extended_color(&mut iter) {
+                        self.attr.fg = c;
+                    }
+                }
+                39 => self.attr.fg = Color::Default,
+                40..=47 => self.attr.bg = Color::Indexed((p - 40) as u8),
+                48 => {
+                    if let Some(c) = Self::extended_color(&mut iter) {
+                        self.attr.bg = c;
+                    }
+                }
+                49 => self.attr.bg = Color::Default,
+                90..=97 => self.attr.fg = Color::Indexed((p - 90 + 8) as u8),
+                100..=107 => self.attr.bg = Color::Indexed((p - 100 + 8) as u8),
+                _ => {}
+            }
+        }
+    }
+
+    // `38;5;n` or `38;2;r;g;b`
+    fn extended_color(iter: &mut impl Iterator<Item = u16>) -> Option<Color> {
+        match iter.next()? {
+            5 => Some(Color::Indexed(iter.next()?.min(255) as u8)),
+            2 => {
+                let r = iter.next()?.min(255) as u8;
+                let g = iter.next()?.min(255) as u8;
+                let b = iter.next()?.min(255) as u8;
+                Some(Color::Rgb(r, g, b))
+            }
+            _ => None,
+        }
+    }
+
+    fn device_status(&mut self, mode: u16) {
+        match mode {
+            5 => self.response.extend_from_slice(b"\x1b[0n"),
+            6 => {
+                let y = if self.modes.origin {
+                    self.y - self.scroll_top
+                } else {
+                    self.y
+                };
+                self.response
+                    .extend_from_slice(format!("\x1b[{};{}R", y + 1, self.x + 1).as_bytes());
+            }
+            _ => {}
+        }
+    }
+
+    fn put_char(&mut self, c: char, width: usize) {
+        if self.wrap_pending && self.modes.autowrap {
+            self.x = 0;
+            self.linefeed();
+        }
+        // a wide character never straddles the right margin
+        if width == 2 && self.x == self.cols - 1 {
+            if self.modes.autowrap {
+                self.erase_cells(self.y, self.x, self.cols);
+                self.x = 0;
+                self.linefeed();
+            } else {
+                return;
+            }
+        }
+        if self.modes.insert {
+            self.insert_chars(width);
+        }
+
+        let attr = self.attr;
+        let row = &mut self.grid[self.y];
+        // overwriting half of a wide character clears the other half
+        if row[self.x].is_spacer() && self.x > 0 {
+            row[self.x - 1].c = ' ';
+        }
+        if self.x + width < self.cols && row[self.x + width].is_spacer() {
+            row[self.x + width].c = ' ';
+        }
+        row[self.x] = Cell { c, attr };
+        if width == 2 {
+            row[self.x + 1] = Cell { c: '\0', attr };
+        }
+        self.dirty[self.y] = true;
+
+        if self.x + width >= self.cols {
+            self.x = self.cols - 1;
+            self.wrap_pending = true;
+        } else {
+            self.x += width;
+            self.wrap_pending = false;
+        }
+    }
+}
+
+// DEC special graphics, used for line drawing
+// https://vt100.net/docs/vt102-ug/table5-13.html
+fn dec_special(c: char) -> char {
+    match c {
+        '`' => '◆',
+        'a' => '▒',
+        'b' => '␉',
+        'c' => '␌',
+        'd' => '␍',
+        'e' => '␊',
+        'f' => '°',
+        'g' => '±',
+        'h' => '␤',
+        'i' => '␋',
+        'j' => '┘',
+        'k' => '┐',
+        'l' => '┌',
+        'm' => '└',
+        'n' => '┼',
+        'o' => '⎺',
+        'p' => '⎻',
+        'q' => '─',
+        'r' => '⎼',
+        's' => '⎽',
+        't' => '├',
+        'u' => '┤',
+        'v' => '┴',
+        'w' => '┬',
+        'x' => '│',
+        'y' => '≤',
+        'z' => '≥',
+        '{' => 'π',
+        '|' => '≠',
+        '}' => '£',
+        '~' => '·',
+        _ => c,
+    }
+}
+
+fn param(params: &[u16], idx: usize, default: u16) -> u16 {
+    match params.get(idx) {
+        Some(0) | None => default,
+        Some(p) => *p,
+    }
+}
+
+impl Perform for Screen {
+    fn print(&mut self, c: char) {
+        let c = match self.charsets[self.gl] {
+            Charset::DecSpecial => dec_special(c),
+            Charset::Ascii => c,
+        };
+        match c.width() {
+            // combining characters are dropped
+            None | Some(0) => {}
+            Some(width) => {
+                self.put_char(c, width.min(2));
+                self.last_char = Some(c);
+            }
+        }
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            // BS
+            0x08 => {
+                self.x = self.x.saturating_sub(1);
+                self.wrap_pending = false;
+            }
+            // HT
+            0x09 => self.tab_forward(1),
+            // LF, VT, FF
+            0x0a..=0x0c => self.linefeed(),
+            // CR
+            0x0d => {
+                self.x = 0;
+                self.wrap_pending = false;
+            }
+            // SO, SI
+            0x0e => self.gl = 1,
+            0x0f => self.gl = 0,
+            _ => {}
+        }
+    }
+
+    fn csi_dispatch(&mut self, params: &[u16], intermediates: &[u8], action: char) {
+        let p = |idx, default| param(params, idx, default) as usize;
+        match (intermediates, action) {
+            (b"", '@') => self.insert_chars(p(0, 1)),
+            (b"", 'A') => self.cursor_up(p(0, 1)),
+            (b"", 'B' | 'e') => self.cursor_down(p(0, 1)),
+            (b"", 'C' | 'a') => self.goto_col(self.x + p(0, 1)),
+            (b"", 'D') => self.goto_col(self.x.saturating_sub(p(0, 1))),
+            (b"", 'E') => {
+                self.cursor_down(p(0, 1));
+                self.x = 0;
+            }
+            (b"", 'F') => {
+                self.cursor_up(p(0, 1));
+                self.x = 0;
+            }
+            (b"", 'G' | '`') => self.goto_col(p(0, 1) - 1),
+            (b"", 'H' | 'f') => self.goto(p(1, 1) - 1, p(0, 1) - 1),
+            (b"", 'I') => self.tab_forward(p(0, 1)),
+            (b"" | b"?", 'J') => self.erase_display(param(params, 0, 0)),
+            (b"" | b"?", 'K') => self.erase_line(param(params, 0, 0)),
+            (b"", 'L') => self.insert_lines(p(0, 1)),
+            (b"", 'M') => self.delete_lines(p(0, 1)),
+            (b"", 'P') => self.delete_chars(p(0, 1)),
+            (b"", 'S') => self.scroll_up(p(0, 1)),
+            (b"", 'T') => self.scroll_down(p(0, 1)),
+            (b"", 'X') => {
+                let end = (self.x + p(0, 1)).min(self.cols);
+                self.erase_cells(self.y, self.x, end);
+            }
+            (b"", 'Z') => self.tab_backward(p(0, 1)),
+            (b"", 'b') => {
+                if let Some(c) = self.last_char {
+                    for _ in 0..p(0, 1).min(self.cols * self.rows) {
+                        self.print(c);
+                    }
+                }
+            }
+            (b"", 'c') => self.response.extend_from_slice(b"\x1b[?1;2c"),
+            (b">", 'c') => self.response.extend_from_slice(b"\x1b[>0;10;0c"),
+            (b"", 'd') => self.goto_row(p(0, 1) - 1),
+            (b"", 'g') => match param(params, 0, 0) {
+                0 => self.tabs[self.x] = false,
+                3 => self.tabs.iter_mut().for_each(|t| *t = false),
+                _ => {}
+            },
+            (b"", 'h') => params.iter().for_each(|m| self.set_mode(*m, true)),
+            (b"", 'l') => params.iter().for_each(|m| self.set_mode(*m, false)),
+            (b"?", 'h') => params.iter().for_each(|m| self.set_private_mode(*m, true)),
+            (b"?", 'l') => params.iter().for_each(|m| self.set_private_mode(*m, false)),
+            (b"", 'm') => self.sgr(params),
+            (b"", 'n') => self.device_status(param(params, 0, 0)),
+            (b"", 'r') => self.set_scroll_region(p(0, 1), p(1, 0)),
+            (b"", 's') => self.save_cursor(),
+            (b"", 'u') => self.restore_cursor(),
+            (b"!", 'p') => {
+                // DECSTR, soft reset
+                self.modes = Modes::default();
+                self.attr = Attr::default();
+                self.scroll_top = 0;
+                self.scroll_bottom = self.rows - 1;
+                self.charsets = [Charset::Ascii; 2];
+                self.gl = 0;
+                self.saved = None;
+            }
+            _ => {}
+        }
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], byte: u8) {
+        match (intermediates, byte) {
+            (b"", b'7') => self.save_cursor(),
+            (b"", b'8') => self.restore_cursor(),
+            (b"", b'D') => self.linefeed(),
+            (b"", b'E') => {
+                self.x = 0;
+                self.linefeed();
+            }
+            (b"", b'H') => self.tabs[self.x] = true,
+            (b"", b'M') => self.reverse_index(),
+            (b"", b'c') => self.reset(),
+            (b"", b'=') => self.modes.app_keypad = true,
+            (b"", b'>') => self.modes.app_keypad = false,
+            (b"(", b'0') => self.charsets[0] = Charset::DecSpecial,
+            (b"(", _) => self.charsets[0] = Charset::Ascii,
+            (b")", b'0') => self.charsets[1] = Charset::DecSpecial,
+            (b")", _) => self.charsets[1] = Charset::Ascii,
+            (b"#", b'8') => {
+                // DECALN, fill the screen with E
+                for row in &mut self.grid {
+                    row.iter_mut().for_each(|cell| {
+                        *cell = Cell {
+                            c: 'E',
+                            ..Cell::default()
+                        }
+                    });
+                }
+                self.mark_dirty(0, self.rows - 1);
+            }
+            _ => {}
+        }
+    }
+
+    fn osc_dispatch(&mut self, params: &[&[u8]]) {
+        if let [b"0" | b"2", title @ ..] = params {
+            let title: Vec<_> = title.iter().map(|t| String::from_utf8_lossy(t)).collect();
+            self.title = Some(title.join(";"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::term::parser::Parser;
+
+    fn screen(cols: usize, rows: usize, input: &[u8]) -> Screen {
+        let mut screen = Screen::new(cols, rows);
+        Parser::new().advance(&mut screen, input);
+        screen
+    }
+
+    fn text(screen: &Screen, y: usize) -> String {
+        screen
+            .row(y)
+            .iter()
+            .filter(|c| !c.is_spacer())
+            .map(|c| c.c)
+            .collect::<String>()
+            .trim_end()
+            .to_owned()
+    }
+
+    #[test]
+    fn test_print_and_wrap() {
+        let s = screen(5, 4, b"hello world\r\nab");
+        assert_eq!(text(&s, 0), "hello");
+        assert_eq!(text(&s, 1), " worl");
+        assert_eq!(text(&s, 2), "d");
+        assert_eq!(text(&s, 3), "ab");
+        // filling the last column only sets the pending wrap, CR LF must not
+        // produce an extra blank line
+        let s = screen(5, 2, b"12345\r\nab\r\ncd");
+        assert_eq!(text(&s, 0), "ab");
+        assert_eq!(text(&s, 1), "cd");
+        assert_eq!(s.cursor(), (2, 1));
+    }
+
+    #[test]
+    fn test_cursor_and_erase() {
+        let s = screen(10, 3, b"abcdefghij\x1b[2;3Hxy\x1b[1;4H\x1b[K");
+        assert_eq!(text(&s, 0), "abc");
+        assert_eq!(text(&s, 1), "  xy");
+        let s = screen(10, 3, b"abcdef\x1b[3D\x1b[1P\x1b[2@");
+        assert_eq!(text(&s, 0), "abc  ef");
+        let s = screen(4, 2, b"ab\r\ncd\x1b[H\x1b[J");
+        assert_eq!(text(&s, 0), "");
+        assert_eq!(text(&s, 1), "");
+    }
+
+    #[test]
+    fn test_sgr() {
+        let s = screen(10, 1, b"\x1b[1;31;48;5;200ma\x1b[38;2;1;2;3;49mb\x1b[0mc");
+        let a = s.cell(0, 0).attr;
+        assert!(a.bold);
+        assert_eq!(a.fg, Color::Indexed(1));
+        assert_eq!(a.bg, Color::Indexed(200));
+        let b = s.cell(1, 0).attr;
+        assert!(b.bold);
+        assert_eq!(b.fg, Color::Rgb(1, 2, 3));
+        assert_eq!(b.bg, Color::Default);
+        assert_eq!(s.cell(2, 0).attr, Attr::default());
+    }
+
+    #[test]
+    fn test_scroll_region() {
+        let s = screen(3, 4, b"a\r\nb\r\nc\r\nd\x1b[2;3r\x1b[3;1H\nx");
+        assert_eq!(text(&s, 0), "a");
+        assert_eq!(text(&s, 1), "c");
+        assert_eq!(text(&s, 2), "x");
+        assert_eq!(text(&s, 3), "d");
+        let s = screen(3, 4, b"a\r\nb\r\nc\r\nd\x1b[2;3r\x1b[2;1H\x1bM");
+        assert_eq!(text(&s, 1), "");
+        assert_eq!(text(&s, 2), "b");
+        assert_eq!(text(&s, 3), "d");
+    }
+
+    #[test]
+    fn test_alternate_screen() {
+        let mut s = screen(5, 2, b"main\x1b[?1049h");
+        assert!(s.is_alternate());
+        assert_eq!(text(&s, 0), "");
+        Parser::new().advance(&mut s, b"alt\x1b[?1049l");
+        assert!(!s.is_alternate());
+        assert_eq!(text(&s, 0), "main");
+        assert_eq!(s.cursor(), (4, 0));
+    }
+
+    #[test]
+    fn test_wide_chars() {
+        let s = screen(4, 2, "中文字".as_bytes());
+        assert_eq!(text(&s, 0), "中文");
+        assert!(s.cell(1, 0).is_spacer());
+        assert_eq!(text(&s, 1), "字");
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut s = screen(4, 3, "a\r\nb\r\nc中".as_bytes());
+        s.resize(2, 3);
+        assert_eq!(text(&s, 2), "c");
+        assert_eq!(s.cursor(), (1, 2));
+        s.resize(2, 2);
+        assert_eq!(text(&s, 0), "b");
+        assert_eq!(text(&s, 1), "c");
+        assert_eq!(s.cursor(), (1, 1));
+        s.resize(6, 4);
+        assert_eq!(text(&s, 1), "c");
+        assert_eq!(text(&s, 3), "");
+        Parser::new().advance(&mut s, b"\x1b[4;1Hxyzxyz");
+        assert_eq!(text(&s, 3), "xyzxyz");
+        assert!((0..4).all(|y| s.is_dirty(y)));
+    }
+
+    #[test]
+    fn test_scrollback() {
+        let mut s = screen(3, 2, b"a\r\nb\r\nc\r\nd");
+        assert_eq!(text(&s, 0), "c");
+        s.scroll_view(1);
+        assert_eq!(s.view_offset(), 1);
+        assert_eq!(s.view_row(0)[0].c, 'b');
+        assert_eq!(s.view_row(1)[0].c, 'c');
+        Parser::new().advance(&mut s, b"\r\ne");
+        assert_eq!(s.view_row(0)[0].c, 'b');
+        s.scroll_view(10);
+        assert_eq!(s.view_row(0)[0].c, 'a');
+        s.scroll_view(-10);
+        assert_eq!(s.view_offset(), 0);
+        assert_eq!(s.view_row(1)[0].c, 'e');
+
+        // the alternate screen and scroll regions keep no history
+        let mut s = screen(3, 2, b"\x1b[?1049ha\r\nb\r\nc");
+        s.scroll_view(1);
+        assert_eq!(s.view_offset(), 0);
+    }
+
+    #[test]
+    fn test_responses() {
+        let mut s = screen(10, 5, b"\x1b[3;4H\x1b[6n\x1b[c");
+        assert_eq!(s.take_response(), b"\x1b[3;4R\x1b[?1;2c");
+        assert!(s.take_response().is_empty());
+        let s = screen(10, 5, b"\x1b]0;my title\x07\x1b[?1h");
+        assert_eq!(s.title(), Some("my title"));
+        assert!(s.modes().app_cursor);
+    }
+
+    #[test]
+    fn test_line_drawing() {
+        let s = screen(5, 1, b"\x1b(0lqk\x1b(Bq");
+        assert_eq!(text(&s, 0), "┌─┐q");
+    }
+}
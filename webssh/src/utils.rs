@@ -1,3 +1,7 @@
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Blob, BlobPropertyBag, HtmlAnchorElement, Url};
+
 pub fn set_panic_hook() {
     // When the `console_error_panic_hook` feature is enabled, we can call the
     // `set_panic_hook` function at least once during initialization, and then
@@ -8,3 +12,34 @@ pub fn set_panic_hook() {
     #[cfg(feature = "console_error_panic_hook")]
     console_error_panic_hook::set_once();
 }
+
+/// Hand `data` to the browser as a file download
+pub fn download(name: &str, data: &[u8], mime: &str) {
+    let document = web_sys::window().unwrap().document().unwrap();
+    let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(data));
+    let props = BlobPropertyBag::new();
+    props.set_type(mime);
+    let blob = Blob::new_with_u8_array_sequence_and_options(&parts, &props).unwrap();
+    // a temporary object url for an anchor to click on
+    let url = Url::create_object_url_with_blob(&blob).unwrap();
+    let anchor = document
+        .create_element("a")
+        .unwrap()
+        .dyn_into::<HtmlAnchorElement>()
+        .map_err(|_| ())
+        .unwrap();
+    anchor.set_href(&url);
+    anchor.set_download(name);
+    anchor.click();
+    let _ = Url::revoke_object_url(&url);
+}
+
+/// Resolve after `ms` milliseconds without blocking the page
+pub async fn sleep(ms: i32) {
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        let _ = web_sys::window()
+            .unwrap()
+            .set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, ms);
+    });
+    let _ = JsFuture::from(promise).await;
+}
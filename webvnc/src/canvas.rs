@@ -1,28 +1,49 @@
 // use crate::input::{X11Event, KeyEventType, MouseEventType};
 // use rdp::core::event::BitmapEvent;
-use crate::{
-    x11cursor::MouseUtils,
-    x11keyboard::{self, KeyboardUtils},
-};
+use crate::{pixel::Depth, utils, x11cursor::MouseUtils, x11keyboard::KeyboardUtils};
 
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
 use std::rc::Rc;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Notify};
+use tracing::warn;
 use vnc::{Rect, X11Event};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::{Clamped, JsCast};
+use wasm_bindgen_futures::{spawn_local, JsFuture};
 use web_sys::{
-    CanvasRenderingContext2d, HtmlButtonElement, HtmlCanvasElement, HtmlImageElement,
-    KeyboardEvent, MouseEvent,
+    Blob, BlobPropertyBag, CanvasRenderingContext2d, HtmlCanvasElement, ImageBitmap, KeyboardEvent,
+    MouseEvent,
 };
 
+enum Decoded {
+    Pending,
+    Done(ImageBitmap),
+    Failed,
+}
+
+// The framebuffer updates, in the order of the server
+enum Update {
+    Resize(u32, u32),
+    Draw(Rect, Vec<u8>),
+    Copy(Rect, Rect),
+    Jpeg(Rect, Rc<RefCell<Decoded>>),
+}
+
 struct Canvas {
     canvas: HtmlCanvasElement,
     ctx: CanvasRenderingContext2d,
     output: mpsc::Sender<X11Event>,
+    depth: Depth,
+    // held back behind a JPEG being decoded
+    updates: RefCell<VecDeque<Update>>,
+    // woken once none are held back
+    drained: Notify,
 }
 
 impl Canvas {
-    fn new(sender: mpsc::Sender<X11Event>, canvas: HtmlCanvasElement) -> Self {
+    fn new(sender: mpsc::Sender<X11Event>, canvas: HtmlCanvasElement, depth: Depth) -> Self {
         // let document = web_sys::window().unwrap().document().unwrap();
         // let canvas = document.get_element_by_id("vnc-canvas").unwrap();
         // let canvas: HtmlCanvasElement = canvas
@@ -39,6 +60,9 @@ impl Canvas {
             canvas,
             ctx,
             output: sender,
+            depth,
+            updates: RefCell::new(VecDeque::new()),
+            drained: Notify::new(),
         }
     }
 
@@ -97,48 +121,6 @@ impl Canvas {
             .unwrap();
         cb.forget();
 
-        let sender = self.output.clone();
-        let ctrl_alt_del_btn = web_sys::window()
-            .unwrap()
-            .document()
-            .unwrap()
-            .get_element_by_id("ctrlaltdel")
-            .unwrap()
-            .dyn_into::<HtmlButtonElement>()
-            .map_err(|_| ())
-            .unwrap();
-        let ctrl_alt_del = move || {
-            let sender = sender.clone();
-            futures::executor::block_on(async move {
-                let _ = sender
-                    .send(X11Event::KeyEvent((x11keyboard::XK_Control_L, true).into()))
-                    .await;
-                let _ = sender
-                    .send(X11Event::KeyEvent((x11keyboard::XK_Alt_L, true).into()))
-                    .await;
-                let _ = sender
-                    .send(X11Event::KeyEvent((x11keyboard::XK_Delete, true).into()))
-                    .await;
-                let _ = sender
-                    .send(X11Event::KeyEvent((x11keyboard::XK_Delete, false).into()))
-                    .await;
-                let _ = sender
-                    .send(X11Event::KeyEvent((x11keyboard::XK_Alt_L, false).into()))
-                    .await;
-                let _ = sender
-                    .send(X11Event::KeyEvent(
-                        (x11keyboard::XK_Control_L, false).into(),
-                    ))
-                    .await;
-            });
-        };
-        let handler = Box::new(ctrl_alt_del) as Box<dyn FnMut()>;
-
-        let cb = Closure::wrap(handler);
-
-        ctrl_alt_del_btn.set_onclick(Some(cb.as_ref().unchecked_ref()));
-        cb.forget();
-
         // On a conventional mouse, buttons 1, 2, and 3 correspond to the left,
         // middle, and right buttons on the mouse.  On a wheel mouse, each step
         // of the wheel upwards is represented by a press and release of button
@@ -228,12 +210,8 @@ impl Canvas {
         cb.forget();
     }
 
-    fn draw(&self, rect: Rect, mut data: Vec<u8>) {
-        let mut idx = 0;
-        while idx < data.len() {
-            data[idx + 3] = 255;
-            idx += 4;
-        }
+    fn draw(&self, rect: Rect, data: Vec<u8>) {
+        let data = self.depth.to_rgba(data);
         let data = web_sys::ImageData::new_with_u8_clamped_array_and_sh(
             Clamped(&data),
             rect.width as u32,
@@ -244,6 +222,45 @@ impl Canvas {
         let _ = self.ctx.put_image_data(&data, rect.x as f64, rect.y as f64);
     }
 
+    // Shown by the browser as the pointer moves, instead of drawn in the screen
+    fn set_cursor(&self, rect: Rect, data: Vec<u8>) {
+        let style = self.canvas.style();
+        if rect.width == 0 || rect.height == 0 {
+            let _ = style.set_property("cursor", "none");
+            return;
+        }
+        let (width, height) = (rect.width as u32, rect.height as u32);
+        if data.len() != width as usize * height as usize * 4 {
+            warn!("Cursor of {}x{} with {} bytes", width, height, data.len());
+            return;
+        }
+        let rgba = self.depth.cursor_to_rgba(&data);
+        let image = web_sys::window()
+            .unwrap()
+            .document()
+            .unwrap()
+            .create_element("canvas")
+            .unwrap()
+            .dyn_into::<HtmlCanvasElement>()
+            .unwrap();
+        image.set_width(width);
+        image.set_height(height);
+        let ctx = image
+            .get_context("2d")
+            .unwrap()
+            .unwrap()
+            .dyn_into::<CanvasRenderingContext2d>()
+            .unwrap();
+        let data =
+            web_sys::ImageData::new_with_u8_clamped_array_and_sh(Clamped(&rgba), width, height)
+                .unwrap();
+        let _ = ctx.put_image_data(&data, 0.0, 0.0);
+        let png = image.to_data_url().unwrap();
+        // the position of the rectangle is the hotspot
+        let cursor = format!("url({}) {} {}, auto", png, rect.x, rect.y);
+        let _ = style.set_property("cursor", &cursor);
+    }
+
     fn copy(&self, dst: Rect, src: Rect) {
         //copy
         let _ = self
@@ -261,23 +278,76 @@ impl Canvas {
             );
     }
 
-    fn jpeg(&self, rect: Rect, data: Vec<u8>) {
-        let image = HtmlImageElement::new().unwrap();
-        let base64 = crate::utils::base64_encode(&data);
-        image.set_src(&format!(
-            "data:image/jpeg;base64,{}",
-            std::str::from_utf8(&base64).unwrap()
-        ));
-        let _ = self.ctx.draw_image_with_html_image_element_and_dw_and_dh(
-            &image,
+    // Start decoding, the browser does it off the main thread
+    fn decode_jpeg(&self, data: &[u8]) -> Result<js_sys::Promise, JsValue> {
+        let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(data));
+        let options = BlobPropertyBag::new();
+        options.set_type("image/jpeg");
+        let blob = Blob::new_with_u8_array_sequence_and_options(&parts, &options)?;
+        web_sys::window()
+            .unwrap()
+            .create_image_bitmap_with_blob(&blob)
+    }
+
+    fn jpeg(&self, rect: Rect, bitmap: ImageBitmap) {
+        let _ = self.ctx.draw_image_with_image_bitmap_and_dw_and_dh(
+            &bitmap,
             rect.x as f64,
             rect.y as f64,
             rect.width as f64,
             rect.height as f64,
         );
+        bitmap.close();
+    }
+
+    fn apply(&self, update: Update) {
+        match update {
+            Update::Resize(width, height) => self.set_resolution(width, height),
+            Update::Draw(rect, data) => self.draw(rect, data),
+            Update::Copy(dst, src) => self.copy(dst, src),
+            Update::Jpeg(rect, decoded) => {
+                if let Decoded::Done(bitmap) = decoded.replace(Decoded::Failed) {
+                    self.jpeg(rect, bitmap);
+                }
+            }
+        }
+    }
+
+    // Updates wait for the JPEGs before them
+    fn push(&self, update: Update) {
+        let idle = self.updates.borrow().is_empty();
+        match update {
+            Update::Jpeg(..) => self.updates.borrow_mut().push_back(update),
+            _ if !idle => self.updates.borrow_mut().push_back(update),
+            _ => self.apply(update),
+        }
+    }
+
+    // Apply the updates up to the next JPEG still being decoded
+    fn flush(&self) {
+        loop {
+            let update = {
+                let mut updates = self.updates.borrow_mut();
+                match updates.front() {
+                    Some(Update::Jpeg(_, decoded))
+                        if matches!(*decoded.borrow(), Decoded::Pending) =>
+                    {
+                        return
+                    }
+                    Some(_) => updates.pop_front().unwrap(),
+                    None => {
+                        self.drained.notify_one();
+                        return;
+                    }
+                }
+            };
+            self.apply(update);
+        }
     }
 
     fn close(&self) {
+        self.updates.borrow_mut().clear();
+        self.drained.notify_one();
         self.ctx.fill();
     }
 }
@@ -297,15 +367,15 @@ impl Clone for CanvasUtils {
 }
 
 impl CanvasUtils {
-    pub fn new(sender: mpsc::Sender<X11Event>,canvas: HtmlCanvasElement) -> Self {
+    pub fn new(sender: mpsc::Sender<X11Event>, canvas: HtmlCanvasElement, depth: Depth) -> Self {
         Self {
-            inner: Rc::new(Canvas::new(sender,canvas)),
+            inner: Rc::new(Canvas::new(sender, canvas, depth)),
             bind: false,
         }
     }
 
     pub fn init(&mut self, width: u32, height: u32) {
-        self.inner.as_ref().set_resolution(width, height);
+        self.inner.as_ref().push(Update::Resize(width, height));
         if !self.bind {
             self.inner.as_ref().bind();
             self.bind = true;
@@ -313,15 +383,52 @@ impl CanvasUtils {
     }
 
     pub fn draw(&self, rect: Rect, data: Vec<u8>) {
-        self.inner.as_ref().draw(rect, data);
+        self.inner.as_ref().push(Update::Draw(rect, data));
     }
 
     pub fn copy(&self, dst: Rect, src: Rect) {
-        self.inner.as_ref().copy(dst, src);
+        self.inner.as_ref().push(Update::Copy(dst, src));
+    }
+
+    pub fn set_cursor(&self, rect: Rect, data: Vec<u8>) {
+        self.inner.as_ref().set_cursor(rect, data);
     }
 
     pub fn jpeg(&self, rect: Rect, data: Vec<u8>) {
-        self.inner.as_ref().jpeg(rect, data);
+        let promise = self.inner.as_ref().decode_jpeg(&data);
+        let decoded = Rc::new(RefCell::new(Decoded::Pending));
+        self.inner
+            .as_ref()
+            .push(Update::Jpeg(rect, decoded.clone()));
+        let inner = self.inner.clone();
+        spawn_local(async move {
+            let bitmap = match promise {
+                Ok(promise) => JsFuture::from(promise)
+                    .await
+                    .and_then(|bitmap| bitmap.dyn_into::<ImageBitmap>()),
+                Err(e) => Err(e),
+            };
+            *decoded.borrow_mut() = match bitmap {
+                Ok(bitmap) => Decoded::Done(bitmap),
+                Err(e) => {
+                    warn!("Unable to decode a JPEG rectangle: {:?}", e);
+                    Decoded::Failed
+                }
+            };
+            inner.flush();
+        });
+    }
+
+    /// Resolves once the updates so far are on the screen and the browser is
+    /// about to paint the next frame
+    pub fn ready(&self) -> impl Future<Output = ()> + 'static {
+        let inner = self.inner.clone();
+        async move {
+            while !inner.updates.borrow().is_empty() {
+                inner.drained.notified().await;
+            }
+            utils::animation_frame().await;
+        }
     }
 
     pub fn close(&self) {
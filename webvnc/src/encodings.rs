@@ -0,0 +1,204 @@
+// vnc-rs only sends the encodings it knows of. The quality and compression
+// levels of Tight are pseudo-encodings it has no names for, so they are
+// appended to its SetEncodings on the way to the server.
+//
+// The stream keeps back what could be the start of the exact SetEncodings the
+// connector was set up with, and passes the rest through. `replaced` tells
+// whether the server got the extra encodings, the session fails without.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use vnc::VncEncoding;
+
+const SET_ENCODINGS: u8 = 2;
+
+/// The Tight JPEG quality pseudo-encoding, `level` 0 to 9
+pub fn quality(level: u8) -> i32 {
+    -32 + level as i32
+}
+
+/// The compression level pseudo-encoding, `level` 0 to 9
+pub fn compression(level: u8) -> i32 {
+    -256 + level as i32
+}
+
+fn set_encodings(encodings: &[i32]) -> Vec<u8> {
+    let mut message = vec![SET_ENCODINGS, 0];
+    message.extend_from_slice(&(encodings.len() as u16).to_be_bytes());
+    for encoding in encodings {
+        message.extend_from_slice(&encoding.to_be_bytes());
+    }
+    message
+}
+
+// The SetEncodings to swap, `held` bytes of it were written by vnc-rs and
+// kept back, `written` of what goes out instead are sent
+struct Replace {
+    sent: Vec<u8>,
+    replacement: Vec<u8>,
+    held: usize,
+    written: usize,
+}
+
+pub struct ExtraEncodings<S> {
+    inner: Pin<Box<S>>,
+    // none once replaced
+    replace: Option<Replace>,
+    replaced: Arc<AtomicBool>,
+}
+
+impl<S> ExtraEncodings<S> {
+    /// Append `extra` to the SetEncodings of `encodings` written to `inner`
+    pub fn new(inner: S, encodings: &[VncEncoding], extra: &[i32]) -> Self {
+        let encodings: Vec<i32> = encodings.iter().map(|&e| e as i32).collect();
+        let replace = (!extra.is_empty()).then(|| {
+            let all: Vec<i32> = encodings.iter().chain(extra).copied().collect();
+            Replace {
+                sent: set_encodings(&encodings),
+                replacement: set_encodings(&all),
+                held: 0,
+                written: 0,
+            }
+        });
+        Self {
+            replaced: Arc::new(AtomicBool::new(replace.is_none())),
+            inner: Box::pin(inner),
+            replace,
+        }
+    }
+
+    /// Set once the extra encodings went to the server
+    pub fn replaced(&self) -> Arc<AtomicBool> {
+        self.replaced.clone()
+    }
+}
+
+// Write all of `data` from `*written` on, for a caller retrying after pending
+fn poll_write_all<S: AsyncWrite>(
+    inner: &mut Pin<Box<S>>,
+    cx: &mut Context<'_>,
+    data: &[u8],
+    written: &mut usize,
+) -> Poll<io::Result<()>> {
+    while *written < data.len() {
+        let n = ready!(inner.as_mut().poll_write(cx, &data[*written..]))?;
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        *written += n;
+    }
+    Poll::Ready(Ok(()))
+}
+
+impl<S: AsyncRead> AsyncRead for ExtraEncodings<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.get_mut().inner.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for ExtraEncodings<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(replace) = &mut this.replace else {
+            return this.inner.as_mut().poll_write(cx, buf);
+        };
+        // the caller writes the same buffer again after pending
+        let rest = &replace.sent[replace.held..];
+        let n = rest.len().min(buf.len());
+        if n > 0 && buf[..n] == rest[..n] {
+            if replace.held + n < replace.sent.len() {
+                replace.held += n;
+                return Poll::Ready(Ok(n));
+            }
+            ready!(poll_write_all(
+                &mut this.inner,
+                cx,
+                &replace.replacement,
+                &mut replace.written
+            ))?;
+            this.replace = None;
+            this.replaced.store(true, Ordering::Relaxed);
+            return Poll::Ready(Ok(n));
+        }
+        if replace.held > 0 {
+            // not the SetEncodings after all, what was kept back goes first
+            ready!(poll_write_all(
+                &mut this.inner,
+                cx,
+                &replace.sent[..replace.held],
+                &mut replace.written
+            ))?;
+            replace.held = 0;
+            replace.written = 0;
+        }
+        this.inner.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner.as_mut().poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    const ENCODINGS: [VncEncoding; 2] = [VncEncoding::Tight, VncEncoding::Raw];
+
+    #[test]
+    fn test_levels() {
+        assert_eq!(quality(0), -32);
+        assert_eq!(quality(9), -23);
+        assert_eq!(compression(0), -256);
+        assert_eq!(compression(9), -247);
+    }
+
+    async fn write(parts: &[&[u8]], extra: &[i32]) -> (Vec<u8>, bool) {
+        let mut stream = ExtraEncodings::new(Vec::new(), &ENCODINGS, extra);
+        let replaced = stream.replaced();
+        for part in parts {
+            stream.write_all(part).await.unwrap();
+        }
+        let written = stream.inner.as_ref().get_ref().clone();
+        (written, replaced.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test]
+    async fn test_extra_encodings() {
+        let sent = set_encodings(&[7, 0]);
+        let replacement = set_encodings(&[7, 0, quality(6), compression(2)]);
+        let extra = [quality(6), compression(2)];
+        let pixel_format = [0; 20];
+
+        // at once, in pieces, or after another message
+        let expected = [pixel_format.as_slice(), &replacement].concat();
+        for parts in [
+            vec![pixel_format.as_slice(), &sent],
+            vec![&pixel_format, &sent[..3], &sent[3..7], &sent[7..]],
+        ] {
+            assert_eq!(write(&parts, &extra).await, (expected.clone(), true));
+        }
+
+        // something like the start of it passes through unchanged
+        let other = [&sent[..5], &[9, 9]].concat();
+        assert_eq!(write(&[&sent[..5], &[9, 9]], &extra).await, (other, false));
+        assert_eq!(write(&[&sent], &[]).await, (sent.clone(), true));
+    }
+}
@@ -0,0 +1,144 @@
+// The object `connect` returns to the page, to act on the session and hear
+// of it, like a WebSocket:
+//
+//     const vnc = connect({ url, canvas: "vnc-canvas" });
+//     vnc.onconnect = (viewOnly) => ...;
+//     vnc.ondisconnect = (reason) => ...;   // null when closed normally
+//     vnc.onclipboard = (text) => ...;
+//     vnc.onbell = () => ...;
+//     vnc.sendClipboard("text");
+//     vnc.sendCtrlAltDel();
+//     vnc.disconnect();
+
+use crate::x11keyboard::{XK_Alt_L, XK_Control_L, XK_Delete};
+use js_sys::Function;
+use std::cell::RefCell;
+use std::rc::Rc;
+use tokio::sync::{mpsc, Notify};
+use tracing::warn;
+use vnc::X11Event;
+use wasm_bindgen::prelude::*;
+
+const CTRL_ALT_DEL: [(u32, bool); 6] = [
+    (XK_Control_L, true),
+    (XK_Alt_L, true),
+    (XK_Delete, true),
+    (XK_Delete, false),
+    (XK_Alt_L, false),
+    (XK_Control_L, false),
+];
+
+#[derive(Default)]
+struct Callbacks {
+    connect: RefCell<Option<Function>>,
+    disconnect: RefCell<Option<Function>>,
+    clipboard: RefCell<Option<Function>>,
+    bell: RefCell<Option<Function>>,
+}
+
+fn call(callback: &RefCell<Option<Function>>, arg: &JsValue) {
+    if let Some(callback) = &*callback.borrow() {
+        if let Err(e) = callback.call1(&JsValue::NULL, arg) {
+            warn!("A callback failed: {:?}", e);
+        }
+    }
+}
+
+/// What the session and its handle share
+pub struct Shared {
+    /// The input for the server, the canvas sends to it as well
+    pub input: mpsc::Sender<X11Event>,
+    closing: Notify,
+    callbacks: Callbacks,
+}
+
+impl Shared {
+    pub fn new(input: mpsc::Sender<X11Event>) -> Rc<Self> {
+        Rc::new(Self {
+            input,
+            closing: Notify::new(),
+            callbacks: Callbacks::default(),
+        })
+    }
+
+    /// Wait for the page to ask to disconnect
+    pub async fn closed(&self) {
+        self.closing.notified().await
+    }
+
+    pub fn connected(&self, view_only: bool) {
+        call(&self.callbacks.connect, &view_only.into());
+    }
+
+    pub fn disconnected(&self, reason: Option<String>) {
+        call(&self.callbacks.disconnect, &reason.into());
+    }
+
+    pub fn clipboard(&self, text: String) {
+        call(&self.callbacks.clipboard, &text.into());
+    }
+
+    pub fn bell(&self) {
+        call(&self.callbacks.bell, &JsValue::UNDEFINED);
+    }
+}
+
+/// A VNC session started by `connect`
+#[wasm_bindgen]
+pub struct Connection {
+    shared: Rc<Shared>,
+}
+
+impl Connection {
+    pub fn new(shared: Rc<Shared>) -> Self {
+        Self { shared }
+    }
+
+    fn send(&self, event: X11Event) {
+        if self.shared.input.try_send(event).is_err() {
+            warn!("Input dropped, the session is busy or over");
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Connection {
+    /// Close the session, ondisconnect follows
+    pub fn disconnect(&self) {
+        // kept for the session if it is not waiting yet
+        self.shared.closing.notify_one();
+    }
+
+    #[wasm_bindgen(js_name = sendCtrlAltDel)]
+    pub fn send_ctrl_alt_del(&self) {
+        for key in CTRL_ALT_DEL {
+            self.send(X11Event::KeyEvent(key.into()));
+        }
+    }
+
+    /// Put `text` on the clipboard of the server
+    #[wasm_bindgen(js_name = sendClipboard)]
+    pub fn send_clipboard(&self, text: String) {
+        self.send(X11Event::CopyText(text));
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onconnect(&self, callback: Option<Function>) {
+        *self.shared.callbacks.connect.borrow_mut() = callback;
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_ondisconnect(&self, callback: Option<Function>) {
+        *self.shared.callbacks.disconnect.borrow_mut() = callback;
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onclipboard(&self, callback: Option<Function>) {
+        *self.shared.callbacks.clipboard.borrow_mut() = callback;
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onbell(&self, callback: Option<Function>) {
+        *self.shared.callbacks.bell.borrow_mut() = callback;
+    }
+}
@@ -1,68 +1,127 @@
 mod canvas;
+mod encodings;
+mod handle;
+mod options;
+mod pixel;
 mod utils;
 mod x11cursor;
 mod x11keyboard;
 
-use ::vnc::{client::connector::VncConnector, PixelFormat, VncEncoding, VncEvent, X11Event};
+use ::vnc::{client::connector::VncConnector, VncEncoding, VncEvent, X11Event};
 use canvas::CanvasUtils;
-use futures::StreamExt;
+use encodings::ExtraEncodings;
+use futures::future::{Fuse, FusedFuture, FutureExt};
+pub use handle::Connection;
+use handle::Shared;
+pub use options::Options;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::{error, info};
 use tracing_wasm::WASMLayerConfigBuilder;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::spawn_local;
-use web_sys::{
-    CanvasRenderingContext2d, HtmlButtonElement, HtmlCanvasElement, HtmlImageElement,
-    KeyboardEvent, MouseEvent,
-};
 use ws_stream_wasm::WsMeta;
 
 #[wasm_bindgen]
 extern "C" {
-    fn alert(s: &str);
-    pub fn setClipBoard(s: String);
-    pub fn getClipBoard() -> String;
     fn prompt(msg: &str) -> String;
 }
 
-pub async fn run(
+/// The subprotocol axum-websockify answers the clients watching a shared
+/// session with, offer it after "binary" when connecting
+pub const VIEW_ONLY_PROTOCOL: &str = "binary.view-only";
+
+/// Whether the gateway lets the client on `ws` only watch
+pub fn view_only(ws: &WsMeta) -> bool {
+    ws.protocol() == VIEW_ONLY_PROTOCOL
+}
+
+/// Connect to the VNC server behind the websocket `options.url` and draw
+/// its screen on `options.canvas`, see the options and handle modules
+#[wasm_bindgen]
+pub fn connect(options: JsValue) -> Result<Connection, JsValue> {
+    let options = Options::parse(&options)?;
+    let (input, inputs) = tokio::sync::mpsc::channel(4096);
+    let shared = Shared::new(input);
+    let events = shared.clone();
+    spawn_local(async move {
+        let reason = session(options, inputs, &events).await.err();
+        if let Some(reason) = &reason {
+            error!("VNC session failed: {}", reason);
+        }
+        events.disconnected(reason);
+    });
+    Ok(Connection::new(shared))
+}
+
+async fn session(
+    options: Options,
+    inputs: tokio::sync::mpsc::Receiver<X11Event>,
+    shared: &Rc<Shared>,
+) -> Result<(), String> {
+    let (meta, ws) = WsMeta::connect(&options.url, vec!["binary", VIEW_ONLY_PROTOCOL])
+        .await
+        .map_err(|e| e.to_string())?;
+    let view_only = view_only(&meta);
+    run(ws.into_io(), options, view_only, inputs, shared).await
+}
+
+async fn run(
     io: impl AsyncWrite + AsyncRead + Send + 'static,
-    password: String,
-    canvas: HtmlCanvasElement,
-) -> Result<(), JsValue> {
-    // let vnc = loop {
-    // connect
+    options: Options,
+    view_only: bool,
+    mut x11_events_receiver: tokio::sync::mpsc::Receiver<X11Event>,
+    shared: &Rc<Shared>,
+) -> Result<(), String> {
+    let mut encodings = options.encodings.clone();
+    // the browser draws the pointer where it is, not where the server saw it
+    encodings.push(VncEncoding::CursorPseudo);
+    encodings.push(VncEncoding::DesktopSizePseudo);
+    let io = ExtraEncodings::new(io, &encodings, &options.extra_encodings());
+    // checked once the encodings must have been sent
+    let mut replaced = Some(io.replaced());
 
-    // vnc connect
-    let vnc = VncConnector::new(Box::pin(io))
-        .set_auth_method(async move { Ok(password) })
-        .add_encoding(VncEncoding::Tight)
-        .add_encoding(VncEncoding::Zrle)
-        .add_encoding(VncEncoding::CopyRect)
-        .add_encoding(VncEncoding::Raw)
-        // .add_encoding(VncEncoding::CursorPseudo)
-        .add_encoding(VncEncoding::DesktopSizePseudo)
-        .allow_shared(true)
-        .set_pixel_format(PixelFormat::rgba())
-        .set_version(vnc::VncVersion::RFB33)
+    let password = options.password;
+    let mut connector = VncConnector::new(Box::pin(io))
+        // only asked for when the server wants one
+        .set_auth_method(async move { Ok(password.unwrap_or_else(|| prompt("Password:"))) });
+    for encoding in encodings {
+        connector = connector.add_encoding(encoding);
+    }
+    let vnc = connector
+        .allow_shared(options.shared)
+        .set_pixel_format(options.depth.pixel_format())
+        .set_version(options.version)
         .build()
-        .unwrap()
+        .map_err(|e| e.to_string())?
         .try_start()
-        .await;
-
-    let vnc = match vnc {
-        Ok(vnc) => vnc,
-        Err(err) => return Err(JsValue::from_str(&err.to_string())),
-    };
-    // };
-
-    let vnc = vnc.finish().unwrap();
+        .await
+        .map_err(|e| e.to_string())?
+        .finish()
+        .map_err(|e| e.to_string())?;
+    shared.connected(view_only);
 
-    let (x11_events_sender, mut x11_events_receiver) = tokio::sync::mpsc::channel(4096);
+    let mut canvas = CanvasUtils::new(shared.input.clone(), options.canvas, options.depth);
 
-    let mut canvas = CanvasUtils::new(x11_events_sender.clone(), canvas);
+    // Whether the server answered an update request with `event`
+    fn is_update(event: &VncEvent) -> bool {
+        matches!(
+            event,
+            VncEvent::SetResolution(_)
+                | VncEvent::RawImage(..)
+                | VncEvent::Copy(..)
+                | VncEvent::JpegImage(..)
+                | VncEvent::SetCursor(..)
+        )
+    }
 
-    fn hande_vnc_event(event: VncEvent, canvas: &mut CanvasUtils) {
+    // Returns why the session is over, if it is
+    fn hande_vnc_event(
+        event: VncEvent,
+        canvas: &mut CanvasUtils,
+        shared: &Shared,
+    ) -> Option<Result<(), String>> {
         match event {
             VncEvent::SetResolution(screen) => {
                 info!("Resize {:?}", screen);
@@ -71,9 +130,7 @@ pub async fn run(
             VncEvent::RawImage(rect, data) => {
                 canvas.draw(rect, data);
             }
-            VncEvent::Bell => {
-                //ignore
-            }
+            VncEvent::Bell => shared.bell(),
             VncEvent::SetPixelFormat(_) => unreachable!(),
             VncEvent::Copy(dst, src) => {
                 canvas.copy(dst, src);
@@ -81,56 +138,67 @@ pub async fn run(
             VncEvent::JpegImage(rect, data) => {
                 canvas.jpeg(rect, data);
             }
-            VncEvent::SetCursor(rect, data) => {
-                if rect.width != 0 {
-                    canvas.draw(rect, data)
-                }
-            }
-            VncEvent::Text(string) => {
-                setClipBoard(string);
-            }
-            VncEvent::Error(msg) => {
-                error!(msg);
-                alert(&msg);
-                panic!()
-            }
+            VncEvent::SetCursor(rect, data) => canvas.set_cursor(rect, data),
+            VncEvent::Text(string) => shared.clipboard(string),
+            VncEvent::Error(msg) => return Some(Err(msg)),
             _ => unreachable!(),
         }
+        None
     }
 
-    spawn_local(async move {
-        let mut interval = fluvio_wasm_timer::Interval::new(std::time::Duration::from_millis(1));
-        loop {
-            match vnc.poll_event().await {
-                Ok(Some(e)) => hande_vnc_event(e, &mut canvas),
-                Ok(None) => {
-                    let _ = interval.next().await;
-                    let _ = vnc.input(X11Event::Refresh).await;
+    // One incremental update request at a time. vnc-rs does not tell when an
+    // update ends, the first rectangle of the answer arms the next request.
+    // It goes out once what arrived is drawn and the browser is about to
+    // paint, the biased select takes the rest of the update first. The
+    // server holds the answer back while the screen does not change.
+    let refresh = Fuse::terminated();
+    futures::pin_mut!(refresh);
+    refresh.set(canvas.ready().fuse());
+    let result = loop {
+        tokio::select! {
+            biased;
+            _ = shared.closed() => break Ok(()),
+            event = vnc.recv_event() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => break Err(e.to_string()),
+                };
+                // the options asked for would silently do nothing
+                let check = replaced.take_if(|_| is_update(&event));
+                if check.is_some_and(|replaced| !replaced.load(Ordering::Relaxed)) {
+                    let reason = "The quality and compression levels did not reach the server";
+                    break Err(reason.to_owned());
+                }
+                if is_update(&event) && refresh.is_terminated() {
+                    refresh.set(canvas.ready().fuse());
                 }
-                Err(e) => {
-                    alert(&e.to_string());
-                    break;
+                if let Some(result) = hande_vnc_event(event, &mut canvas, shared) {
+                    break result;
                 }
             }
-
-            while let Ok(x11event) = x11_events_receiver.try_recv() {
-                let _ = vnc.input(x11event).await;
+            Some(x11event) = x11_events_receiver.recv() => {
+                // the gateway would drop it anyway
+                if !view_only {
+                    let _ = vnc.input(x11event).await;
+                }
+            }
+            _ = refresh.as_mut() => {
+                let _ = vnc.input(X11Event::Refresh).await;
             }
         }
-        canvas.close();
-        let _ = vnc.close().await;
-    });
+    };
+    canvas.close();
+    let _ = vnc.close().await;
+    result
+}
 
+#[wasm_bindgen(start)]
+pub fn run_app() -> Result<(), JsValue> {
+    utils::set_panic_hook();
+    tracing_wasm::set_as_global_default_with_config(
+        WASMLayerConfigBuilder::new()
+            .set_max_level(tracing::Level::INFO)
+            .build(),
+    );
     Ok(())
 }
-
-// #[wasm_bindgen(start)]
-// pub fn run_app() -> Result<(), JsValue> {
-//     utils::set_panic_hook();
-//     tracing_wasm::set_as_global_default_with_config(
-//         WASMLayerConfigBuilder::new()
-//             .set_max_level(tracing::Level::INFO)
-//             .build(),
-//     );
-//     run()
-// }
@@ -0,0 +1,165 @@
+// What a page passes to `connect`, a plain object:
+//
+//     {
+//         url: "wss://gateway/websockify/desktop",
+//         canvas: "vnc-canvas",           // or the element itself
+//         password: "secret",             // prompted for when the server asks
+//         encodings: ["tight", "zrle", "copyrect", "raw"],
+//         version: "3.8",                 // or "3.3", "3.7"
+//         shared: true,                   // false disconnects the other clients
+//         quality: 6,                     // Tight JPEG quality, 0 to 9
+//         compression: 2,                 // 0 to 9
+//         depth: 16,                      // bits per pixel, 32, 16 or 8
+//     }
+//
+// `encodings` lists the ones to offer, in the order of preference. Slow
+// links do better with a low quality, a high compression and fewer bits per
+// pixel. Without them the server picks the quality and compression.
+
+use crate::encodings;
+use crate::pixel::Depth;
+use vnc::{VncEncoding, VncVersion};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::HtmlCanvasElement;
+
+const DEFAULT_ENCODINGS: [VncEncoding; 4] = [
+    VncEncoding::Tight,
+    VncEncoding::Zrle,
+    VncEncoding::CopyRect,
+    VncEncoding::Raw,
+];
+
+pub struct Options {
+    pub url: String,
+    pub canvas: HtmlCanvasElement,
+    pub password: Option<String>,
+    pub encodings: Vec<VncEncoding>,
+    pub version: VncVersion,
+    pub shared: bool,
+    pub quality: Option<u8>,
+    pub compression: Option<u8>,
+    pub depth: Depth,
+}
+
+fn error(message: String) -> JsValue {
+    js_sys::Error::new(&message).into()
+}
+
+// Missing fields are undefined or null
+fn field(options: &JsValue, name: &str) -> Result<Option<JsValue>, JsValue> {
+    let value = js_sys::Reflect::get(options, &name.into())?;
+    Ok((!value.is_undefined() && !value.is_null()).then_some(value))
+}
+
+fn string(options: &JsValue, name: &str) -> Result<Option<String>, JsValue> {
+    match field(options, name)? {
+        Some(value) => value
+            .as_string()
+            .map(Some)
+            .ok_or_else(|| error(format!("{} must be a string", name))),
+        None => Ok(None),
+    }
+}
+
+fn boolean(options: &JsValue, name: &str) -> Result<Option<bool>, JsValue> {
+    match field(options, name)? {
+        Some(value) => value
+            .as_bool()
+            .map(Some)
+            .ok_or_else(|| error(format!("{} must be a boolean", name))),
+        None => Ok(None),
+    }
+}
+
+fn number(options: &JsValue, name: &str, max: u32) -> Result<Option<u32>, JsValue> {
+    let Some(value) = field(options, name)? else {
+        return Ok(None);
+    };
+    match value.as_f64() {
+        Some(n) if n.fract() == 0.0 && (0.0..=max as f64).contains(&n) => Ok(Some(n as u32)),
+        _ => Err(error(format!(
+            "{} must be a number from 0 to {}",
+            name, max
+        ))),
+    }
+}
+
+fn version(name: &str) -> Result<VncVersion, JsValue> {
+    match name {
+        "3.3" => Ok(VncVersion::RFB33),
+        "3.7" => Ok(VncVersion::RFB37),
+        "3.8" => Ok(VncVersion::RFB38),
+        _ => Err(error(format!("unknown version {}", name))),
+    }
+}
+
+fn encoding(name: &str) -> Result<VncEncoding, JsValue> {
+    match name.to_ascii_lowercase().as_str() {
+        "raw" => Ok(VncEncoding::Raw),
+        "copyrect" => Ok(VncEncoding::CopyRect),
+        "tight" => Ok(VncEncoding::Tight),
+        "trle" => Ok(VncEncoding::Trle),
+        "zrle" => Ok(VncEncoding::Zrle),
+        _ => Err(error(format!("unknown encoding {}", name))),
+    }
+}
+
+fn canvas(value: JsValue) -> Result<HtmlCanvasElement, JsValue> {
+    let element = match value.as_string() {
+        Some(id) => web_sys::window()
+            .unwrap()
+            .document()
+            .unwrap()
+            .get_element_by_id(&id)
+            .ok_or_else(|| error(format!("no element {}", id)))?
+            .into(),
+        None => value,
+    };
+    element
+        .dyn_into()
+        .map_err(|_| error("canvas must be a canvas element or its id".to_owned()))
+}
+
+impl Options {
+    pub fn parse(options: &JsValue) -> Result<Self, JsValue> {
+        let url = string(options, "url")?.ok_or_else(|| error("url is required".to_owned()))?;
+        let canvas = canvas(
+            field(options, "canvas")?.ok_or_else(|| error("canvas is required".to_owned()))?,
+        )?;
+        let encodings = match field(options, "encodings")? {
+            Some(names) => js_sys::Array::from(&names)
+                .iter()
+                .map(|name| match name.as_string() {
+                    Some(name) => encoding(&name),
+                    None => Err(error("encodings must be names".to_owned())),
+                })
+                .collect::<Result<_, _>>()?,
+            None => DEFAULT_ENCODINGS.to_vec(),
+        };
+        let depth = match number(options, "depth", 32)? {
+            Some(bits) => Depth::from_bits(bits)
+                .ok_or_else(|| error(format!("unsupported depth {}", bits)))?,
+            None => Depth::default(),
+        };
+        Ok(Self {
+            url,
+            canvas,
+            password: string(options, "password")?,
+            encodings,
+            // the servers all speak the oldest
+            version: version(string(options, "version")?.as_deref().unwrap_or("3.3"))?,
+            shared: boolean(options, "shared")?.unwrap_or(true),
+            quality: number(options, "quality", 9)?.map(|n| n as u8),
+            compression: number(options, "compression", 9)?.map(|n| n as u8),
+            depth,
+        })
+    }
+
+    /// The pseudo-encodings vnc-rs has no names for
+    pub fn extra_encodings(&self) -> Vec<i32> {
+        let quality = self.quality.map(encodings::quality);
+        let compression = self.compression.map(encodings::compression);
+        quality.into_iter().chain(compression).collect()
+    }
+}
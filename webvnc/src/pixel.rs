@@ -0,0 +1,176 @@
+// The pixel formats the client asks the server for, fewer bits per pixel
+// trade colours for bandwidth
+
+use vnc::PixelFormat;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Depth {
+    /// 32 bits per pixel, 8 per colour
+    #[default]
+    True,
+    /// 16 bits per pixel, RGB565
+    High,
+    /// 8 bits per pixel, RGB332
+    Low,
+}
+
+// Bits and shift of a colour in the pixel value
+struct Colour {
+    bits: u32,
+    shift: u32,
+}
+
+impl Colour {
+    fn value(&self, pixel: u32) -> u8 {
+        let max = (1 << self.bits) - 1;
+        (((pixel >> self.shift) & max) * 255 / max) as u8
+    }
+}
+
+impl Depth {
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            24 | 32 => Some(Depth::True),
+            16 => Some(Depth::High),
+            8 => Some(Depth::Low),
+            _ => None,
+        }
+    }
+
+    fn colours(self) -> [Colour; 3] {
+        let colour = |bits, shift| Colour { bits, shift };
+        match self {
+            Depth::True => [colour(8, 0), colour(8, 8), colour(8, 16)],
+            Depth::High => [colour(5, 11), colour(6, 5), colour(5, 0)],
+            Depth::Low => [colour(3, 5), colour(3, 2), colour(2, 0)],
+        }
+    }
+
+    fn bytes(self) -> usize {
+        match self {
+            Depth::True => 4,
+            Depth::High => 2,
+            Depth::Low => 1,
+        }
+    }
+
+    /// What the connector sends in SetPixelFormat
+    pub fn pixel_format(self) -> PixelFormat {
+        match self {
+            Depth::True => PixelFormat::rgba(),
+            _ => PixelFormat::try_from(self.format()).expect("a valid pixel format"),
+        }
+    }
+
+    // The PIXEL_FORMAT of the lower depths
+    fn format(self) -> [u8; 16] {
+        let bits = self.bytes() as u8 * 8;
+        // bits per pixel, depth, little endian, true colour
+        let mut format = [bits, bits, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        for (i, colour) in self.colours().iter().enumerate() {
+            let max = (1u16 << colour.bits) - 1;
+            format[4 + 2 * i..6 + 2 * i].copy_from_slice(&max.to_be_bytes());
+            format[10 + i] = colour.shift as u8;
+        }
+        format
+    }
+
+    /// The pixels of a rectangle, opaque RGBA for the canvas
+    pub fn to_rgba(self, mut data: Vec<u8>) -> Vec<u8> {
+        if self == Depth::True {
+            for pixel in data.chunks_exact_mut(4) {
+                pixel[3] = 255;
+            }
+            return data;
+        }
+        let colours = self.colours();
+        let mut rgba = Vec::with_capacity(data.len() / self.bytes() * 4);
+        for pixel in data.chunks_exact(self.bytes()) {
+            let pixel = match *pixel {
+                [low, high] => u16::from_le_bytes([low, high]) as u32,
+                [value] => value as u32,
+                _ => unreachable!(),
+            };
+            rgba.extend(colours.iter().map(|colour| colour.value(pixel)));
+            rgba.push(255);
+        }
+        rgba
+    }
+
+    /// The Cursor pseudo-encoding rectangle as RGBA. vnc-rs hands it over
+    /// with 4 bytes a pixel whatever the depth: the pixel value of the depth
+    /// in the low bytes, little endian, and the bitmask applied as the alpha.
+    pub fn cursor_to_rgba(self, data: &[u8]) -> Vec<u8> {
+        let colours = self.colours();
+        let bits = self.bytes() * 8;
+        let mut rgba = Vec::with_capacity(data.len());
+        for pixel in data.chunks_exact(4) {
+            let value = u32::from_le_bytes([pixel[0], pixel[1], pixel[2], 0]);
+            let value = match bits {
+                32 => value,
+                _ => value & ((1 << bits) - 1),
+            };
+            rgba.extend(colours.iter().map(|colour| colour.value(value)));
+            rgba.push(pixel[3]);
+        }
+        rgba
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            Depth::High.format(),
+            [16, 16, 0, 1, 0, 31, 0, 63, 0, 31, 11, 5, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            Depth::Low.format(),
+            [8, 8, 0, 1, 0, 7, 0, 7, 0, 3, 5, 2, 0, 0, 0, 0]
+        );
+        assert_eq!(Depth::from_bits(24), Some(Depth::True));
+        assert_eq!(Depth::from_bits(15), None);
+    }
+
+    #[test]
+    fn test_to_rgba() {
+        // the alpha the server leaves is made opaque
+        assert_eq!(Depth::True.to_rgba(vec![1, 2, 3, 0]), [1, 2, 3, 255]);
+        // RGB565 little endian white, green
+        assert_eq!(
+            Depth::High.to_rgba(vec![0xff, 0xff, 0xe0, 0x07]),
+            [255, 255, 255, 255, 0, 255, 0, 255]
+        );
+        // RGB332 red, blue
+        assert_eq!(
+            Depth::Low.to_rgba(vec![0xe0, 0x03]),
+            [255, 0, 0, 255, 0, 0, 255, 255]
+        );
+    }
+
+    #[test]
+    fn test_cursor_to_rgba() {
+        // an opaque pixel and a transparent one
+        let data = [0x10, 0x20, 0x30, 255, 0x40, 0x50, 0x60, 0];
+        assert_eq!(
+            Depth::True.cursor_to_rgba(&data),
+            [0x10, 0x20, 0x30, 255, 0x40, 0x50, 0x60, 0]
+        );
+        // RGB565 red and blue, the third byte is padding
+        let data = [0x00, 0xf8, 0xff, 255, 0x1f, 0x00, 0x00, 0];
+        assert_eq!(
+            Depth::High.cursor_to_rgba(&data),
+            [255, 0, 0, 255, 0, 0, 255, 0]
+        );
+        // RGB332 green and white
+        let data = [0x1c, 0xff, 0xff, 255, 0xff, 0x00, 0x00, 255];
+        assert_eq!(
+            Depth::Low.cursor_to_rgba(&data),
+            [0, 255, 0, 255, 255, 255, 255, 255]
+        );
+        assert!(Depth::High.cursor_to_rgba(&[]).is_empty());
+    }
+}